@@ -0,0 +1,268 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::VecDeque;
+
+use super::bus::Bus;
+use super::computer::{Computer, ComputerError};
+use super::cpu::{CPU, Model};
+use super::pia::Pia;
+
+// The Apple 1. A 6502 with RAM, the 256 byte Woz Monitor ROM at $FF00 and a
+// 6821 PIA that connects the keyboard and the terminal:
+//
+//   $D010  KBD    Port A, the last key with bit 7 set
+//   $D011  KBDCR  Bit 7 is set when a key was pressed, reading KBD clears it
+//   $D012  DSP    Port B, the character to display. Bit 7 is an input that
+//                 is set while the terminal is busy
+//   $D013  DSPCR
+//
+// The Woz Monitor is not part of this repository, it has to be loaded from a
+// file.
+
+pub const APPLE1_ROM_SIZE: usize = 256;
+pub const APPLE1_ROM_ADDRESS: u16 = 0xff00;
+pub const APPLE1_PIA_ADDRESS: u16 = 0xd010;
+
+// The Apple 1 has 4K of RAM at $0000, and another 4K that is usually
+// jumpered to $E000 for Integer BASIC. Expanded machines have 32K at $0000.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Apple1Memory {
+    Ram4K,
+    Ram8K,
+    Ram32K,
+}
+
+impl Apple1Memory {
+    fn low_ram_size(&self) -> usize {
+        match self {
+            Apple1Memory::Ram4K | Apple1Memory::Ram8K => 0x1000,
+            Apple1Memory::Ram32K => 0x8000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Apple1Bus {
+    memory: Apple1Memory,
+    ram: Vec<u8>,
+    high_ram: Vec<u8>,
+    rom: Vec<u8>,
+    pub pia: Pia,
+    // What the CPU wrote to the terminal that the front end did not pick
+    // up yet
+    display: VecDeque<u8>,
+}
+
+impl Apple1Bus {
+    pub fn new(memory: Apple1Memory, rom: Vec<u8>) -> Result<Self, ComputerError> {
+        if rom.len() != APPLE1_ROM_SIZE {
+            return Err(ComputerError::InvalidRom { expected: APPLE1_ROM_SIZE, actual: rom.len() });
+        }
+        let high_ram = if memory == Apple1Memory::Ram8K { vec![0; 0x1000] } else { Vec::new() };
+        Ok(Apple1Bus { memory, ram: vec![0; memory.low_ram_size()], high_ram, rom, pia: Pia::new(), display: VecDeque::new() })
+    }
+
+    fn is_pia(addr: u16) -> bool {
+        (APPLE1_PIA_ADDRESS..APPLE1_PIA_ADDRESS + 4).contains(&addr)
+    }
+
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        let addr = addr as usize;
+        if addr < self.ram.len() {
+            Some(addr)
+        } else {
+            None
+        }
+    }
+
+    fn high_ram_index(&self, addr: u16) -> Option<usize> {
+        if self.memory == Apple1Memory::Ram8K && (0xe000..0xf000).contains(&addr) {
+            Some((addr - 0xe000) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl Bus for Apple1Bus {
+    fn read(&mut self, addr: u16) -> u8 {
+        if Apple1Bus::is_pia(addr) {
+            return self.pia.read(addr - APPLE1_PIA_ADDRESS);
+        }
+        self.peek(addr)
+    }
+
+    fn write(&mut self, addr: u16, b: u8) {
+        if let Some(index) = self.ram_index(addr) {
+            self.ram[index] = b;
+        } else if let Some(index) = self.high_ram_index(addr) {
+            self.high_ram[index] = b;
+        } else if Apple1Bus::is_pia(addr) {
+            self.pia.write(addr - APPLE1_PIA_ADDRESS, b);
+            if let Some(b) = self.pia.take_port_b_output() {
+                self.display.push_back(b & 0x7f);
+            }
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        if let Some(index) = self.ram_index(addr) {
+            self.ram[index]
+        } else if let Some(index) = self.high_ram_index(addr) {
+            self.high_ram[index]
+        } else if Apple1Bus::is_pia(addr) {
+            self.pia.peek(addr - APPLE1_PIA_ADDRESS)
+        } else if addr >= APPLE1_ROM_ADDRESS {
+            self.rom[(addr - APPLE1_ROM_ADDRESS) as usize]
+        } else {
+            0
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pia.reset();
+    }
+}
+
+impl Computer<Apple1Bus> {
+    pub fn apple1(memory: Apple1Memory, rom: Vec<u8>) -> Result<Self, ComputerError> {
+        let bus = Apple1Bus::new(memory, rom)?;
+        let mut computer = Computer::with_cpu(CPU::with_model(bus, Model::Nmos6502));
+        computer.reset();
+        Ok(computer)
+    }
+
+    // True when the program has read the last key, so that it is safe to
+    // press the next one without losing it.
+
+    pub fn keyboard_ready(&self) -> bool {
+        !self.cpu.bus.pia.ca1_pending()
+    }
+
+    // Takes ASCII. The Apple 1 keyboard only has upper case, uses CR to end
+    // a line and the monitor treats _ as a backspace.
+
+    pub fn press_key(&mut self, key: u8) {
+        let key = match key.to_ascii_uppercase() {
+            b'\n' => b'\r',
+            0x08 | 0x7f => b'_',
+            key => key,
+        };
+        self.cpu.bus.pia.set_port_a_input(key | 0x80);
+        self.cpu.bus.pia.ca1();
+    }
+
+    // The characters written to the terminal since the last call, as ASCII
+    // with CR at the end of lines.
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.cpu.bus.display.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod apple1_tests {
+    use super::*;
+    use super::super::asm::assemble;
+
+    // A tiny monitor that talks to the PIA the same way the Woz Monitor does:
+    // it sets up the PIA, prints a \ and echoes the keyboard.
+
+    fn rom() -> Vec<u8> {
+        assemble("
+            KBD   = $D010
+            KBDCR = $D011
+            DSP   = $D012
+            DSPCR = $D013
+                    .org $FF00
+            reset:  CLD
+                    LDY #$7F
+                    STY DSP         ; DDRB, bit 7 is an input
+                    LDA #$A7
+                    STA KBDCR
+                    STA DSPCR
+                    LDA #'\\'+$80
+                    JSR echo
+                    LDA #$8D
+                    JSR echo
+            loop:   LDA KBDCR
+                    BPL loop
+                    LDA KBD
+                    JSR echo
+                    JMP loop
+            echo:   BIT DSP
+                    BMI echo
+                    STA DSP
+                    RTS
+                    .org $FFFC
+                    .word reset, 0
+        ").unwrap()
+    }
+
+    #[test]
+    fn test_boot_and_echo() {
+        let mut apple1 = Computer::apple1(Apple1Memory::Ram4K, rom()).unwrap();
+        apple1.run_cycles(1000).unwrap();
+        assert_eq!(apple1.take_output(), b"\\\r");
+
+        assert!(apple1.keyboard_ready());
+        apple1.press_key(b'a');
+        assert!(!apple1.keyboard_ready());
+        apple1.run_cycles(1000).unwrap();
+        assert!(apple1.keyboard_ready());
+        apple1.press_key(b'\n');
+        apple1.run_cycles(1000).unwrap();
+        assert_eq!(apple1.take_output(), b"A\r");
+        assert_eq!(apple1.take_output(), b"");
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut bus = Apple1Bus::new(Apple1Memory::Ram4K, rom()).unwrap();
+        bus.write(0x0fff, 0x42);
+        bus.write(0x1000, 0x42);
+        bus.write(0xe000, 0x42);
+        bus.write(0xff00, 0x42);
+        assert_eq!(bus.peek(0x0fff), 0x42);
+        assert_eq!(bus.peek(0x1000), 0x00);
+        assert_eq!(bus.peek(0xe000), 0x00);
+        assert_eq!(bus.peek(0xff00), 0xd8); // CLD
+
+        let mut bus = Apple1Bus::new(Apple1Memory::Ram8K, rom()).unwrap();
+        bus.write(0xe000, 0x42);
+        bus.write(0xefff, 0x43);
+        assert_eq!(bus.peek(0xe000), 0x42);
+        assert_eq!(bus.peek(0xefff), 0x43);
+
+        let mut bus = Apple1Bus::new(Apple1Memory::Ram32K, rom()).unwrap();
+        bus.write(0x7fff, 0x42);
+        assert_eq!(bus.peek(0x7fff), 0x42);
+    }
+
+    #[test]
+    fn test_invalid_rom() {
+        let error = Apple1Bus::new(Apple1Memory::Ram4K, vec![0; 100]).unwrap_err();
+        assert_eq!(error, ComputerError::InvalidRom { expected: 256, actual: 100 });
+    }
+}
@@ -0,0 +1,592 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::any::Any;
+use std::fmt;
+
+use super::bus::Bus;
+use super::computer::{Computer, ComputerError};
+use super::cpu::{CPU, Model};
+
+mod disk;
+mod disk2;
+#[cfg(test)]
+mod fixtures;
+mod iie;
+mod language_card;
+mod png;
+mod speaker;
+mod video;
+mod woz;
+pub use disk::*;
+pub use disk2::*;
+pub use iie::*;
+pub use language_card::*;
+pub use speaker::*;
+pub use video::*;
+
+// The Apple ][+. The memory map is:
+//
+//   $0000-$BFFF  48K RAM
+//   $C000-$C07F  Soft switches for the keyboard, speaker, display and game
+//                controller. Most of them act on reads as well as writes.
+//   $C080-$C08F  Soft switches of the language card in slot 0
+//   $C090-$C0FF  16 I/O addresses for each of the slots 1-7
+//   $C100-$C7FF  256 bytes of ROM for each of the slots 1-7
+//   $C800-$CFFF  Expansion ROM of the slot whose ROM was accessed last,
+//                accessing $CFFF gives it up
+//   $D000-$FFFF  Applesoft BASIC and the Autostart Monitor, or the 16K RAM
+//                of the language card. Its RAM has two banks of $D000-$DFFF.
+//
+// The //e adds 64K of auxiliary memory and internal firmware at $C100-$CFFF,
+// see iie.rs.
+//
+// The ROMs are not part of this repository, they have to be loaded from
+// files.
+
+pub const APPLE2_ROM_SIZE: usize = 12 * 1024;
+pub const APPLE2_ROM_ADDRESS: u16 = 0xd000;
+pub const APPLE2E_ROM_SIZE: usize = 16 * 1024;
+pub const APPLE2E_ROM_ADDRESS: u16 = 0xc000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Apple2Model {
+    Plus,
+    IIe,
+    EnhancedIIe,
+}
+
+impl Apple2Model {
+    pub fn is_iie(&self) -> bool {
+        *self != Apple2Model::Plus
+    }
+
+    pub fn cpu_model(&self) -> Model {
+        match self {
+            Apple2Model::Plus | Apple2Model::IIe => Model::Nmos6502,
+            Apple2Model::EnhancedIIe => Model::Cmos65C02,
+        }
+    }
+
+    fn rom_size(&self) -> usize {
+        if self.is_iie() { APPLE2E_ROM_SIZE } else { APPLE2_ROM_SIZE }
+    }
+
+    fn rom_address(&self) -> u16 {
+        if self.is_iie() { APPLE2E_ROM_ADDRESS } else { APPLE2_ROM_ADDRESS }
+    }
+}
+
+// Auxiliary memory follows main memory in ram
+const AUX: usize = 0x10000;
+
+// The 558 timer of a paddle runs for about 11 cycles per unit of the paddle
+// position, which matches the loop in the monitor's PREAD routine.
+const PADDLE_CYCLES_PER_UNIT: u64 = 11;
+
+// A peripheral card. Every slot gets 16 I/O addresses at $C080 + slot * 16,
+// 256 bytes at $Cn00 for slots 1-7 and can share the 2K at $C800. Offsets
+// are relative to the start of each area.
+
+pub trait Card: Any + fmt::Debug {
+    fn read_io(&mut self, offset: u8) -> u8 {
+        self.peek_io(offset)
+    }
+
+    fn write_io(&mut self, _offset: u8, _b: u8) {}
+
+    fn peek_io(&self, _offset: u8) -> u8 {
+        0
+    }
+
+    fn peek_rom(&self, _offset: u8) -> u8 {
+        0
+    }
+
+    fn peek_expansion_rom(&self, _offset: u16) -> u8 {
+        0
+    }
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self, _cycles: u64) {}
+}
+
+// The last three are only on the //e
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoMode {
+    pub text: bool,
+    pub mixed: bool,
+    pub page2: bool,
+    pub hires: bool,
+    pub col80: bool,
+    pub altchar: bool,
+    pub double_hires: bool,
+}
+
+impl Default for VideoMode {
+    fn default() -> Self {
+        VideoMode { text: true, mixed: false, page2: false, hires: false, col80: false, altchar: false, double_hires: false }
+    }
+}
+
+#[derive(Debug)]
+pub struct Apple2Bus {
+    pub model: Apple2Model,
+    // 64K of main memory, followed by 64K of auxiliary memory on the //e
+    ram: Vec<u8>,
+    rom: Vec<u8>,
+    pub switches: MemorySwitches,
+    pub language_card: LanguageCard,
+    slots: [Option<Box<dyn Card>>; 8],
+    expansion_rom_slot: Option<usize>,
+    // The last key with bit 7 set until the program clears the strobe
+    pub keyboard: u8,
+    pub video: VideoMode,
+    pub characters: CharacterRom,
+    pub monitor: Monitor,
+    pub annunciators: [bool; 4],
+    pub speaker: Speaker,
+    pub buttons: [bool; 3],
+    pub paddles: [u8; 4],
+    paddle_trigger: u64,
+    clock: u64,
+}
+
+impl Apple2Bus {
+    pub fn new(model: Apple2Model, rom: Vec<u8>) -> Result<Self, ComputerError> {
+        if rom.len() != model.rom_size() {
+            return Err(ComputerError::InvalidRom { expected: model.rom_size(), actual: rom.len() });
+        }
+        let ram_size = if model.is_iie() { 128 * 1024 } else { 64 * 1024 };
+        Ok(Apple2Bus {
+            model,
+            ram: vec![0; ram_size],
+            rom,
+            switches: MemorySwitches::default(),
+            language_card: LanguageCard::default(),
+            slots: Default::default(),
+            expansion_rom_slot: None,
+            keyboard: 0,
+            video: VideoMode::default(),
+            characters: CharacterRom::default(),
+            monitor: Monitor::default(),
+            annunciators: [false; 4],
+            speaker: Speaker::new(),
+            buttons: [false; 3],
+            // Centered, like a joystick that nobody touches
+            paddles: [128; 4],
+            paddle_trigger: 0,
+            clock: 0,
+        })
+    }
+
+    pub fn insert_card(&mut self, slot: usize, card: Box<dyn Card>) {
+        assert!((1..8).contains(&slot), "There is no slot {}", slot);
+        self.slots[slot] = Some(card);
+    }
+
+    pub fn remove_card(&mut self, slot: usize) -> Option<Box<dyn Card>> {
+        self.slots.get_mut(slot)?.take()
+    }
+
+    pub fn card(&self, slot: usize) -> Option<&dyn Card> {
+        self.slots.get(slot)?.as_deref()
+    }
+
+    // The card in a slot, if it is a T
+
+    pub fn card_as<T: Card>(&self, slot: usize) -> Option<&T> {
+        let card: &dyn Any = self.slots.get(slot)?.as_deref()?;
+        card.downcast_ref()
+    }
+
+    pub fn card_as_mut<T: Card>(&mut self, slot: usize) -> Option<&mut T> {
+        let card: &mut dyn Any = self.slots.get_mut(slot)?.as_deref_mut()?;
+        card.downcast_mut()
+    }
+
+    // The number of cycles since power on
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    // What reading a soft switch returns. Only bit 7 means something for
+    // the inputs, the other bits float and we make them 0.
+
+    fn peek_switch(&self, addr: u16) -> u8 {
+        if self.model.is_iie() {
+            if let Some(b) = self.peek_iie_switch(addr) {
+                return b;
+            }
+        }
+        let bit7 = |on: bool| if on { 0x80 } else { 0x00 };
+        match addr {
+            0xc000..=0xc00f => self.keyboard,
+            0xc061..=0xc063 | 0xc069..=0xc06b => bit7(self.buttons[(addr & 0x3) as usize - 1]),
+            0xc064..=0xc067 | 0xc06c..=0xc06f => {
+                let paddle = self.paddles[(addr & 0x3) as usize] as u64;
+                bit7(self.clock < self.paddle_trigger + paddle * PADDLE_CYCLES_PER_UNIT)
+            }
+            _ => 0,
+        }
+    }
+
+    // What touching a soft switch does. Most do the same for reads and
+    // writes.
+
+    fn access_switch(&mut self, addr: u16, write: bool) {
+        if self.model.is_iie() {
+            self.access_iie_switch(addr, write);
+        }
+        match addr {
+            0xc010..=0xc01f => self.keyboard &= 0x7f,
+            0xc030..=0xc03f => self.speaker.toggle(self.clock),
+            0xc050 => self.video.text = false,
+            0xc051 => self.video.text = true,
+            0xc052 => self.video.mixed = false,
+            0xc053 => self.video.mixed = true,
+            0xc054 => self.video.page2 = false,
+            0xc055 => self.video.page2 = true,
+            0xc056 => self.video.hires = false,
+            0xc057 => self.video.hires = true,
+            0xc058..=0xc05f => self.annunciators[((addr - 0xc058) >> 1) as usize] = addr & 1 != 0,
+            0xc070..=0xc07f => self.paddle_trigger = self.clock,
+            _ => {}
+        }
+    }
+
+    fn slot_io(addr: u16) -> (usize, u8) {
+        (((addr >> 4) & 0x7) as usize, (addr & 0xf) as u8)
+    }
+
+    fn select_expansion_rom(&mut self, addr: u16) {
+        if self.model.is_iie() {
+            self.select_internal_c8_rom(addr);
+        }
+        match addr {
+            0xc100..=0xc7ff => self.expansion_rom_slot = Some(((addr >> 8) & 0x7) as usize),
+            0xcfff => self.expansion_rom_slot = None,
+            _ => {}
+        }
+    }
+
+    // The bank switched memory map: where in ram an address of $0000-$BFFF,
+    // or of the language card, ends up
+
+    fn ram_index(&self, addr: u16, write: bool) -> usize {
+        let index = match addr {
+            // $C000-$CFFF is never RAM, so bank 1 takes its place
+            0xd000..=0xdfff if !self.language_card.bank2 => addr as usize - 0x1000,
+            _ => addr as usize,
+        };
+        if self.model.is_iie() && self.aux_selected(addr, write) { AUX + index } else { index }
+    }
+
+    fn peek_rom(&self, addr: u16) -> u8 {
+        self.rom[(addr - self.model.rom_address()) as usize]
+    }
+
+    fn peek_slots(&self, addr: u16) -> u8 {
+        if self.model.is_iie() && self.internal_rom_selected(addr) {
+            return self.peek_rom(addr);
+        }
+        match addr {
+            0xc100..=0xc7ff => {
+                let slot = ((addr >> 8) & 0x7) as usize;
+                self.slots[slot].as_ref().map_or(0, |card| card.peek_rom(addr as u8))
+            }
+            _ => {
+                let card = self.expansion_rom_slot.and_then(|slot| self.slots[slot].as_ref());
+                card.map_or(0, |card| card.peek_expansion_rom(addr - 0xc800))
+            }
+        }
+    }
+}
+
+impl Bus for Apple2Bus {
+    fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0xc000..=0xc07f => {
+                let b = self.peek_switch(addr);
+                self.access_switch(addr, false);
+                b
+            }
+            0xc080..=0xc08f => {
+                self.language_card.access(addr, false);
+                0
+            }
+            0xc090..=0xc0ff => {
+                let (slot, offset) = Apple2Bus::slot_io(addr);
+                self.slots[slot].as_mut().map_or(0, |card| card.read_io(offset))
+            }
+            0xc100..=0xcfff => {
+                let b = self.peek(addr);
+                self.select_expansion_rom(addr);
+                b
+            }
+            _ => self.peek(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, b: u8) {
+        match addr {
+            0x0000..=0xbfff => {
+                let index = self.ram_index(addr, true);
+                self.ram[index] = b;
+            }
+            0xc000..=0xc07f => self.access_switch(addr, true),
+            0xc080..=0xc08f => self.language_card.access(addr, true),
+            0xc090..=0xc0ff => {
+                let (slot, offset) = Apple2Bus::slot_io(addr);
+                if let Some(card) = self.slots[slot].as_mut() {
+                    card.write_io(offset, b);
+                }
+            }
+            0xc100..=0xcfff => self.select_expansion_rom(addr),
+            _ if self.language_card.write_enabled => {
+                let index = self.ram_index(addr, true);
+                self.ram[index] = b;
+            }
+            _ => {}
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0xbfff => self.ram[self.ram_index(addr, false)],
+            0xc000..=0xc07f => self.peek_switch(addr),
+            0xc080..=0xc08f => 0,
+            0xc090..=0xc0ff => {
+                let (slot, offset) = Apple2Bus::slot_io(addr);
+                self.slots[slot].as_ref().map_or(0, |card| card.peek_io(offset))
+            }
+            0xc100..=0xcfff => self.peek_slots(addr),
+            _ if self.language_card.read_ram => self.ram[self.ram_index(addr, false)],
+            _ => self.peek_rom(addr),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.expansion_rom_slot = None;
+        self.language_card.reset();
+        if self.model.is_iie() {
+            self.reset_iie();
+        }
+        for card in self.slots.iter_mut().flatten() {
+            card.reset();
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.clock += cycles;
+        self.speaker.update(self.clock);
+        for card in self.slots.iter_mut().flatten() {
+            card.tick(cycles);
+        }
+    }
+}
+
+impl Computer<Apple2Bus> {
+    pub fn apple2(model: Apple2Model, rom: Vec<u8>) -> Result<Self, ComputerError> {
+        let bus = Apple2Bus::new(model, rom)?;
+        let mut computer = Computer::with_cpu(CPU::with_model(bus, model.cpu_model()));
+        computer.reset();
+        Ok(computer)
+    }
+
+    pub fn apple2plus(rom: Vec<u8>) -> Result<Self, ComputerError> {
+        Computer::apple2(Apple2Model::Plus, rom)
+    }
+
+    pub fn apple2e(rom: Vec<u8>) -> Result<Self, ComputerError> {
+        Computer::apple2(Apple2Model::IIe, rom)
+    }
+
+    pub fn apple2e_enhanced(rom: Vec<u8>) -> Result<Self, ComputerError> {
+        Computer::apple2(Apple2Model::EnhancedIIe, rom)
+    }
+
+    // True when the program has cleared the strobe of the last key
+    pub fn keyboard_ready(&self) -> bool {
+        self.cpu.bus.keyboard & 0x80 == 0
+    }
+
+    // Takes ASCII, with CR to end a line. The ][+ keyboard only has upper
+    // case and no delete key, the left arrow, a backspace, does that job.
+
+    pub fn press_key(&mut self, key: u8) {
+        let key = if self.cpu.bus.model.is_iie() { key } else { key.to_ascii_uppercase() };
+        let key = match key {
+            b'\n' => b'\r',
+            0x7f if !self.cpu.bus.model.is_iie() => 0x08,
+            key => key & 0x7f,
+        };
+        self.cpu.bus.keyboard = key | 0x80;
+    }
+}
+
+#[cfg(test)]
+mod apple2_tests {
+    use super::*;
+    use super::fixtures::rom;
+
+    #[derive(Debug, Default)]
+    struct TestCard {
+        io: [u8; 16],
+        resets: usize,
+    }
+
+    impl Card for TestCard {
+        fn write_io(&mut self, offset: u8, b: u8) {
+            self.io[offset as usize] = b;
+        }
+
+        fn peek_io(&self, offset: u8) -> u8 {
+            self.io[offset as usize]
+        }
+
+        fn peek_rom(&self, offset: u8) -> u8 {
+            offset
+        }
+
+        fn peek_expansion_rom(&self, offset: u16) -> u8 {
+            (offset >> 8) as u8 | 0x80
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let mut apple2 = Computer::apple2plus(rom(Apple2Model::Plus)).unwrap();
+        assert_eq!(apple2.cpu.pc, 0xd000);
+
+        apple2.cpu.set_byte(0xbfff, 0x42);
+        apple2.cpu.set_byte(0xd000, 0x42);
+        assert_eq!(apple2.cpu.peek_byte(0xbfff), 0x42);
+        assert_eq!(apple2.cpu.peek_byte(0xd000), 0xea);
+        assert_eq!(apple2.cpu.peek_byte(0xc600), 0x00); // Empty slot
+    }
+
+    #[test]
+    fn test_invalid_rom() {
+        let error = Apple2Bus::new(Apple2Model::Plus, vec![0; 10 * 1024]).unwrap_err();
+        assert_eq!(error, ComputerError::InvalidRom { expected: 12 * 1024, actual: 10 * 1024 });
+    }
+
+    #[test]
+    fn test_keyboard() {
+        let mut apple2 = Computer::apple2plus(rom(Apple2Model::Plus)).unwrap();
+        assert!(apple2.keyboard_ready());
+        apple2.press_key(b'a');
+        assert!(!apple2.keyboard_ready());
+        assert_eq!(apple2.cpu.get_byte(0xc000), 0xc1);
+        assert_eq!(apple2.cpu.get_byte(0xc000), 0xc1);
+        apple2.cpu.get_byte(0xc010);
+        assert!(apple2.keyboard_ready());
+        assert_eq!(apple2.cpu.get_byte(0xc000), 0x41);
+    }
+
+    #[test]
+    fn test_soft_switches() {
+        let mut apple2 = Computer::apple2plus(rom(Apple2Model::Plus)).unwrap();
+        assert_eq!(apple2.cpu.bus.video, VideoMode::default());
+
+        apple2.cpu.get_byte(0xc050);
+        apple2.cpu.set_byte(0xc053, 0);
+        apple2.cpu.get_byte(0xc055);
+        apple2.cpu.get_byte(0xc057);
+        assert_eq!(apple2.cpu.bus.video, VideoMode { text: false, mixed: true, page2: true, hires: true, ..VideoMode::default() });
+
+        // Peeking does not flip switches
+        apple2.cpu.peek_byte(0xc051);
+        apple2.cpu.peek_byte(0xc030);
+        assert!(!apple2.cpu.bus.video.text);
+        assert!(!apple2.cpu.bus.speaker.on());
+
+        apple2.cpu.get_byte(0xc030);
+        assert!(apple2.cpu.bus.speaker.on());
+        apple2.cpu.bus.tick(100);
+        apple2.cpu.set_byte(0xc030, 0);
+        assert!(!apple2.cpu.bus.speaker.on());
+        apple2.cpu.bus.tick(APPLE2_CLOCK / 100);
+        assert!(apple2.cpu.bus.speaker.take_samples().iter().any(|&sample| sample != 0));
+
+        apple2.cpu.get_byte(0xc05b);
+        apple2.cpu.get_byte(0xc05e);
+        assert_eq!(apple2.cpu.bus.annunciators, [false, true, false, false]);
+        apple2.cpu.get_byte(0xc05c);
+        assert_eq!(apple2.cpu.bus.annunciators, [false, true, false, false]);
+    }
+
+    #[test]
+    fn test_game_controller() {
+        let mut apple2 = Computer::apple2plus(rom(Apple2Model::Plus)).unwrap();
+        apple2.cpu.bus.buttons[1] = true;
+        assert_eq!(apple2.cpu.get_byte(0xc061), 0x00);
+        assert_eq!(apple2.cpu.get_byte(0xc062), 0x80);
+
+        apple2.cpu.bus.paddles[0] = 10;
+        apple2.cpu.get_byte(0xc070);
+        assert_eq!(apple2.cpu.get_byte(0xc064), 0x80);
+        apple2.run_cycles(10 * PADDLE_CYCLES_PER_UNIT - 10).unwrap();
+        assert_eq!(apple2.cpu.get_byte(0xc064), 0x80);
+        apple2.run_cycles(10).unwrap();
+        assert_eq!(apple2.cpu.get_byte(0xc064), 0x00);
+    }
+
+    #[test]
+    fn test_slots() {
+        let mut apple2 = Computer::apple2plus(rom(Apple2Model::Plus)).unwrap();
+        apple2.cpu.bus.insert_card(6, Box::<TestCard>::default());
+
+        apple2.cpu.set_byte(0xc0e3, 0x42);
+        assert_eq!(apple2.cpu.get_byte(0xc0e3), 0x42);
+        assert_eq!(apple2.cpu.get_byte(0xc0d3), 0x00); // Slot 5 is empty
+        assert_eq!(apple2.cpu.get_byte(0xc65c), 0x5c);
+
+        // The expansion ROM belongs to the slot we accessed last
+        assert_eq!(apple2.cpu.get_byte(0xc923), 0x81);
+        apple2.cpu.get_byte(0xcfff);
+        assert_eq!(apple2.cpu.get_byte(0xc923), 0x00);
+        apple2.cpu.get_byte(0xc500);
+        assert_eq!(apple2.cpu.get_byte(0xc923), 0x00);
+        apple2.cpu.get_byte(0xc600);
+        assert_eq!(apple2.cpu.get_byte(0xc923), 0x81);
+
+        apple2.reset();
+        assert_eq!(apple2.cpu.get_byte(0xc923), 0x00);
+        assert!(format!("{:?}", apple2.cpu.bus.card(6)).contains("resets: 1"));
+
+        // There are no slots past 7
+        assert!(apple2.cpu.bus.card(8).is_none());
+        assert!(apple2.cpu.bus.card_as::<TestCard>(8).is_none());
+        assert!(apple2.cpu.bus.card_as_mut::<TestCard>(8).is_none());
+        assert!(apple2.cpu.bus.remove_card(8).is_none());
+        assert!(apple2.cpu.bus.remove_card(6).is_some());
+        assert!(apple2.cpu.bus.card(6).is_none());
+    }
+}
@@ -0,0 +1,659 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::woz;
+
+// A 5.25" disk as the Disk II sees it: a track is the stream of bits that
+// passes under the head in one revolution, four microseconds to a bit. The
+// head can stop on quarter tracks, so a map tells which track, if any, can be
+// read at each of them.
+//
+// Sector images are nibblized when they are loaded, and de-nibblized again
+// when they are flushed to their file. A sector that cannot be decoded any
+// more, because a program wrote something else in its place, keeps its old
+// content in the image.
+//
+// Copy protected disks need the nibble formats: .nib has the nibbles of each
+// track, without the zero bits of sync bytes, and WOZ has the bits, see
+// woz.rs.
+
+pub const QUARTER_TRACKS: usize = 160;
+pub const TRACKS: usize = 35;
+pub const SECTORS: usize = 16;
+pub const SECTOR_SIZE: usize = 256;
+pub const SECTOR_IMAGE_SIZE: usize = TRACKS * SECTORS * SECTOR_SIZE;
+pub const NIB_TRACK_SIZE: usize = 6656;
+pub const NIB_IMAGE_SIZE: usize = TRACKS * NIB_TRACK_SIZE;
+
+// The time a bit takes to pass under the head, in units of 125 ns. 4 µs is 4
+// cycles of the Apple II.
+pub const DEFAULT_BIT_TIMING: u8 = 32;
+
+const VOLUME: u8 = 254;
+
+// The sync bytes before the first sector, between the address and data
+// field, and after the data field
+const GAP1: usize = 48;
+const GAP2: usize = 6;
+const GAP3: usize = 20;
+
+// For sector images, the order of the sectors in the file. DOS 3.3 and
+// ProDOS number the sectors of a track in a different order than the one in
+// which they pass under the head. Nibble images are de-nibblized in DOS 3.3
+// order.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFormat {
+    Dos33,
+    ProDos,
+    Nib,
+    Woz,
+}
+
+// The sector in the image for each physical sector
+const DOS33_SECTORS: [usize; SECTORS] = [0x0, 0x7, 0xe, 0x6, 0xd, 0x5, 0xc, 0x4, 0xb, 0x3, 0xa, 0x2, 0x9, 0x1, 0x8, 0xf];
+const PRODOS_SECTORS: [usize; SECTORS] = [0x0, 0x8, 0x1, 0x9, 0x2, 0xa, 0x3, 0xb, 0x4, 0xc, 0x5, 0xd, 0x6, 0xe, 0x7, 0xf];
+
+impl DiskFormat {
+    pub fn from_path(path: &Path) -> Option<DiskFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "dsk" | "do" => Some(DiskFormat::Dos33),
+            "po" => Some(DiskFormat::ProDos),
+            "nib" => Some(DiskFormat::Nib),
+            "woz" => Some(DiskFormat::Woz),
+            _ => None,
+        }
+    }
+
+    pub fn is_sector_image(&self) -> bool {
+        matches!(self, DiskFormat::Dos33 | DiskFormat::ProDos)
+    }
+
+    fn image_sector(&self, physical: usize) -> usize {
+        match self {
+            DiskFormat::ProDos => PRODOS_SECTORS[physical],
+            _ => DOS33_SECTORS[physical],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    // Eight bits to a byte, the first one in bit 7
+    data: Vec<u8>,
+    bit_count: usize,
+}
+
+impl Track {
+    pub fn new(data: Vec<u8>, bit_count: usize) -> Self {
+        assert!(bit_count <= data.len() * 8, "A track of {} bytes cannot have {} bits", data.len(), bit_count);
+        Track { data, bit_count }
+    }
+
+    pub fn bit_count(&self) -> usize {
+        self.bit_count
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn bit(&self, index: usize) -> u8 {
+        (self.data[index >> 3] >> (7 - (index & 7))) & 1
+    }
+
+    pub fn set_bit(&mut self, index: usize, bit: u8) {
+        let mask = 0x80 >> (index & 7);
+        if bit != 0 {
+            self.data[index >> 3] |= mask;
+        } else {
+            self.data[index >> 3] &= !mask;
+        }
+    }
+
+    // The nibbles of the track the way the controller reads them: zeros are
+    // skipped until a one starts the next eight bits. The track is read
+    // twice, so that fields that wrap around are complete.
+
+    pub fn nibbles(&self) -> Vec<u8> {
+        self.read_nibbles(self.bit_count * 2)
+    }
+
+    fn read_nibbles(&self, bit_count: usize) -> Vec<u8> {
+        let mut nibbles = Vec::new();
+        let mut nibble = 0u8;
+        for index in 0..bit_count {
+            nibble = (nibble << 1) | self.bit(index % self.bit_count);
+            if nibble & 0x80 != 0 {
+                nibbles.push(nibble);
+                nibble = 0;
+            }
+        }
+        nibbles
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrackWriter {
+    data: Vec<u8>,
+    bit_count: usize,
+}
+
+impl TrackWriter {
+    fn bits(&mut self, value: u8, count: usize) {
+        for i in (8 - count..8).rev() {
+            if self.bit_count.is_multiple_of(8) {
+                self.data.push(0);
+            }
+            let bit = (value >> i) & 1;
+            self.data[self.bit_count >> 3] |= bit << (7 - (self.bit_count & 7));
+            self.bit_count += 1;
+        }
+    }
+
+    fn nibbles(&mut self, nibbles: &[u8]) {
+        for &nibble in nibbles {
+            self.bits(nibble, 8);
+        }
+    }
+
+    // Self-synchronizing $FF bytes, which are followed by two zero bits
+    fn sync(&mut self, count: usize) {
+        for _ in 0..count {
+            self.bits(0xff, 8);
+            self.bits(0x00, 2);
+        }
+    }
+
+    fn track(self) -> Track {
+        Track::new(self.data, self.bit_count)
+    }
+}
+
+const ADDRESS_PROLOGUE: [u8; 3] = [0xd5, 0xaa, 0x96];
+const DATA_PROLOGUE: [u8; 3] = [0xd5, 0xaa, 0xad];
+const EPILOGUE: [u8; 3] = [0xde, 0xaa, 0xeb];
+
+// 4-and-4 puts the odd bits of a byte in one nibble and the even bits in
+// the next one
+
+fn encode_4and4(b: u8) -> [u8; 2] {
+    [(b >> 1) | 0xaa, b | 0xaa]
+}
+
+fn decode_4and4(nibbles: &[u8]) -> u8 {
+    ((nibbles[0] << 1) | 0x01) & nibbles[1]
+}
+
+// 6-and-2 writes the 256 bytes of a sector as 342 six bit values: 86 with
+// the low two bits of bytes n, n + 86 and n + 172, followed by the high six
+// bits of each byte. Each value is written XORed with the previous one, and a
+// checksum follows.
+
+const WRITE_TABLE: [u8; 64] = [
+    0x96, 0x97, 0x9a, 0x9b, 0x9d, 0x9e, 0x9f, 0xa6, 0xa7, 0xab, 0xac, 0xad, 0xae, 0xaf, 0xb2, 0xb3,
+    0xb4, 0xb5, 0xb6, 0xb7, 0xb9, 0xba, 0xbb, 0xbc, 0xbd, 0xbe, 0xbf, 0xcb, 0xcd, 0xce, 0xcf, 0xd3,
+    0xd6, 0xd7, 0xd9, 0xda, 0xdb, 0xdc, 0xdd, 0xde, 0xdf, 0xe5, 0xe6, 0xe7, 0xe9, 0xea, 0xeb, 0xec,
+    0xed, 0xee, 0xef, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
+];
+
+const TWOS: usize = 86;
+
+// The low two bits of a byte, swapped
+fn twos(b: u8) -> u8 {
+    ((b & 0x01) << 1) | ((b >> 1) & 0x01)
+}
+
+fn encode_6and2(sector: &[u8]) -> Vec<u8> {
+    let mut values = Vec::with_capacity(TWOS + SECTOR_SIZE);
+    for i in 0..TWOS {
+        let mut value = twos(sector[i]) | twos(sector[i + TWOS]) << 2;
+        if i + 2 * TWOS < SECTOR_SIZE {
+            value |= twos(sector[i + 2 * TWOS]) << 4;
+        }
+        values.push(value);
+    }
+    values.extend(sector.iter().map(|b| b >> 2));
+
+    let mut nibbles = Vec::with_capacity(values.len() + 1);
+    let mut previous = 0;
+    for value in values {
+        nibbles.push(WRITE_TABLE[(value ^ previous) as usize]);
+        previous = value;
+    }
+    nibbles.push(WRITE_TABLE[previous as usize]);
+    nibbles
+}
+
+fn decode_6and2(nibbles: &[u8]) -> Option<Vec<u8>> {
+    if nibbles.len() < TWOS + SECTOR_SIZE + 1 {
+        return None;
+    }
+    let mut values = Vec::with_capacity(TWOS + SECTOR_SIZE);
+    let mut previous = 0;
+    for &nibble in &nibbles[..TWOS + SECTOR_SIZE] {
+        let value = WRITE_TABLE.iter().position(|&b| b == nibble)? as u8 ^ previous;
+        values.push(value);
+        previous = value;
+    }
+    if WRITE_TABLE[previous as usize] != nibbles[TWOS + SECTOR_SIZE] {
+        return None;
+    }
+    let sector = (0..SECTOR_SIZE).map(|i| {
+        let low = (values[i % TWOS] >> (2 * (i / TWOS))) & 0x03;
+        (values[TWOS + i] << 2) | twos(low)
+    });
+    Some(sector.collect())
+}
+
+fn nibblize_track(format: DiskFormat, track: usize, sectors: &[u8]) -> Track {
+    let track_number = track as u8;
+    let mut writer = TrackWriter::default();
+    writer.sync(GAP1);
+    for physical in 0..SECTORS {
+        let sector = physical as u8;
+        writer.nibbles(&ADDRESS_PROLOGUE);
+        for b in [VOLUME, track_number, sector, VOLUME ^ track_number ^ sector] {
+            writer.nibbles(&encode_4and4(b));
+        }
+        writer.nibbles(&EPILOGUE);
+        writer.sync(GAP2);
+
+        let offset = format.image_sector(physical) * SECTOR_SIZE;
+        writer.nibbles(&DATA_PROLOGUE);
+        writer.nibbles(&encode_6and2(&sectors[offset..offset + SECTOR_SIZE]));
+        writer.nibbles(&EPILOGUE);
+        writer.sync(GAP3);
+    }
+    writer.track()
+}
+
+// Copies the sectors that can be decoded to the image of the track
+
+fn denibblize_track(format: DiskFormat, track: usize, nibbles: &[u8], sectors: &mut [u8]) {
+    let mut found = [false; SECTORS];
+    let mut i = 0;
+    while i + 11 <= nibbles.len() {
+        if nibbles[i..i + 3] != ADDRESS_PROLOGUE {
+            i += 1;
+            continue;
+        }
+        let field: Vec<u8> = nibbles[i + 3..i + 11].chunks(2).map(decode_4and4).collect();
+        i += 11;
+        let (volume, track_number, sector) = (field[0], field[1], field[2] as usize);
+        if volume ^ track_number ^ field[2] != field[3] || track_number as usize != track || sector >= SECTORS {
+            continue;
+        }
+        // The data field follows within a few nibbles, before the next
+        // address field
+        let Some(start) = (i..nibbles.len().min(i + 64)).find(|&j| nibbles[j..].starts_with(&DATA_PROLOGUE)) else {
+            continue;
+        };
+        if nibbles[i..start].windows(3).any(|w| w == ADDRESS_PROLOGUE) {
+            continue;
+        }
+        if let Some(data) = decode_6and2(&nibbles[start + 3..]) {
+            if !found[sector] {
+                let offset = format.image_sector(sector) * SECTOR_SIZE;
+                sectors[offset..offset + SECTOR_SIZE].copy_from_slice(&data);
+                found[sector] = true;
+            }
+        }
+    }
+}
+
+fn check_size(image: &[u8], size: usize) -> io::Result<()> {
+    if image.len() != size {
+        let message = format!("A disk image should be {} bytes but is {} bytes", size, image.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(())
+}
+
+// The map of an image with a track on every fourth quarter track. A track
+// can be read a quarter track next to it as well.
+
+fn whole_track_map() -> [Option<usize>; QUARTER_TRACKS] {
+    let mut track_map = [None; QUARTER_TRACKS];
+    for (quarter_track, track) in track_map.iter_mut().enumerate() {
+        let nearest = (quarter_track + 1) / 4;
+        if quarter_track % 4 != 2 && nearest < TRACKS {
+            *track = Some(nearest);
+        }
+    }
+    track_map
+}
+
+#[derive(Debug, Clone)]
+pub struct Disk {
+    pub format: DiskFormat,
+    pub write_protected: bool,
+    // Whether flush writes the tracks back to the file the disk was loaded
+    // from. WOZ images are often preservation copies, so they are not by
+    // default.
+    pub write_back: bool,
+    path: Option<PathBuf>,
+    image: Vec<u8>,
+    tracks: Vec<Track>,
+    track_map: [Option<usize>; QUARTER_TRACKS],
+    bit_timing: u8,
+    metadata: Vec<(String, String)>,
+    dirty: bool,
+}
+
+impl Disk {
+    fn new(format: DiskFormat, image: Vec<u8>, tracks: Vec<Track>, track_map: [Option<usize>; QUARTER_TRACKS]) -> Self {
+        Disk {
+            format,
+            write_protected: false,
+            write_back: format != DiskFormat::Woz,
+            path: None,
+            image,
+            tracks,
+            track_map,
+            bit_timing: DEFAULT_BIT_TIMING,
+            metadata: Vec::new(),
+            dirty: false,
+        }
+    }
+
+    pub fn from_sectors(format: DiskFormat, image: Vec<u8>) -> io::Result<Self> {
+        assert!(format.is_sector_image(), "{:?} is not a sector image format", format);
+        check_size(&image, SECTOR_IMAGE_SIZE)?;
+        let tracks = (0..TRACKS)
+            .map(|track| nibblize_track(format, track, &image[track * SECTORS * SECTOR_SIZE..]))
+            .collect();
+        Ok(Disk::new(format, image, tracks, whole_track_map()))
+    }
+
+    pub fn from_nib(image: Vec<u8>) -> io::Result<Self> {
+        check_size(&image, NIB_IMAGE_SIZE)?;
+        let tracks = image.chunks(NIB_TRACK_SIZE).map(|nibbles| Track::new(nibbles.to_vec(), NIB_TRACK_SIZE * 8)).collect();
+        Ok(Disk::new(DiskFormat::Nib, image, tracks, whole_track_map()))
+    }
+
+    pub fn from_woz(image: Vec<u8>) -> io::Result<Self> {
+        let woz = woz::read(&image)?;
+        let mut disk = Disk::new(DiskFormat::Woz, image, woz.tracks, woz.track_map);
+        disk.write_protected = woz.write_protected;
+        disk.bit_timing = woz.bit_timing;
+        disk.metadata = woz.metadata;
+        Ok(disk)
+    }
+
+    // Loads a .dsk, .do, .po, .nib or .woz file. The disk is write protected
+    // when the file cannot be written, or when a WOZ image says so.
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+        let format = DiskFormat::from_path(path)
+            .ok_or_else(|| with_path(io::Error::new(io::ErrorKind::Unsupported, "Unknown disk image format")))?;
+        let image = fs::read(path).map_err(with_path)?;
+        let mut disk = match format {
+            DiskFormat::Nib => Disk::from_nib(image),
+            DiskFormat::Woz => Disk::from_woz(image),
+            _ => Disk::from_sectors(format, image),
+        }
+        .map_err(with_path)?;
+        disk.write_protected |= fs::metadata(path).map_err(with_path)?.permissions().readonly();
+        disk.path = Some(path.to_path_buf());
+        Ok(disk)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // The file as of the last flush
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    pub fn bit_timing(&self) -> u8 {
+        self.bit_timing
+    }
+
+    // The META chunk of a WOZ image
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    pub fn track(&self, quarter_track: usize) -> Option<&Track> {
+        self.track_map[quarter_track].map(|track| &self.tracks[track])
+    }
+
+    pub fn track_mut(&mut self, quarter_track: usize) -> Option<&mut Track> {
+        let track = self.track_map[quarter_track]?;
+        self.dirty = true;
+        Some(&mut self.tracks[track])
+    }
+
+    // True when the tracks have been written since the last flush
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // The sectors, with what has been written to the tracks. Sectors of a
+    // nibble image that cannot be decoded are zeros.
+
+    pub fn to_sectors(&self) -> Vec<u8> {
+        let mut image = if self.format.is_sector_image() { self.image.clone() } else { vec![0; SECTOR_IMAGE_SIZE] };
+        for track in 0..TRACKS {
+            if let Some(bits) = self.track(track * 4) {
+                let sectors = &mut image[track * SECTORS * SECTOR_SIZE..(track + 1) * SECTORS * SECTOR_SIZE];
+                denibblize_track(self.format, track, &bits.nibbles(), sectors);
+            }
+        }
+        image
+    }
+
+    // The nibbles of one revolution of each track, as many as fit
+    fn to_nib(&self) -> Vec<u8> {
+        let mut image = Vec::with_capacity(NIB_IMAGE_SIZE);
+        for track in &self.tracks {
+            let mut nibbles = track.read_nibbles(track.bit_count());
+            nibbles.resize(NIB_TRACK_SIZE, 0xff);
+            image.extend(nibbles);
+        }
+        image
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.image = match self.format {
+            DiskFormat::Nib => self.to_nib(),
+            DiskFormat::Woz => woz::write(&self.image, &self.tracks),
+            _ => self.to_sectors(),
+        };
+        if let (Some(path), true) = (&self.path, self.write_back) {
+            fs::write(path, &self.image).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod disk_tests {
+    use super::*;
+
+    // Every sector is filled with its number in the image
+    fn image() -> Vec<u8> {
+        (0..SECTOR_IMAGE_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect()
+    }
+
+    #[test]
+    fn test_6and2() {
+        let sector: Vec<u8> = (0..SECTOR_SIZE).map(|i| (i * 7 + 3) as u8).collect();
+        let nibbles = encode_6and2(&sector);
+        assert_eq!(nibbles.len(), 343);
+        assert!(nibbles.iter().all(|b| WRITE_TABLE.contains(b)));
+        assert_eq!(decode_6and2(&nibbles), Some(sector));
+
+        // All zeros are all $96, including the checksum
+        assert_eq!(encode_6and2(&[0; SECTOR_SIZE]), vec![0x96; 343]);
+
+        let mut corrupt = nibbles.clone();
+        corrupt[100] = WRITE_TABLE[(WRITE_TABLE.iter().position(|&b| b == corrupt[100]).unwrap() + 1) % 64];
+        assert_eq!(decode_6and2(&corrupt), None);
+    }
+
+    #[test]
+    fn test_6and2_nibbles() {
+        // The first nibble holds the low bits of bytes $00, $56 and $AC, the
+        // 86th the low bits of byte $55 and the 342nd the high bits of $FF.
+        let mut sector = [0; SECTOR_SIZE];
+        sector[0x00] = 0x01;
+        sector[0x56] = 0x02;
+        sector[0xac] = 0x03;
+        sector[0x55] = 0x01;
+        sector[0xff] = 0xfc;
+
+        let mut expected = vec![0x96; 343];
+        expected[0] = 0xf5;
+        expected[1] = 0xf5;
+        expected[85] = 0x9a;
+        expected[86] = 0x9a;
+        expected[341] = 0xff;
+        expected[342] = 0xff;
+
+        assert_eq!(encode_6and2(&sector), expected);
+        assert_eq!(decode_6and2(&expected), Some(sector.to_vec()));
+    }
+
+    #[test]
+    fn test_4and4() {
+        assert_eq!(encode_4and4(0xfe), [0xff, 0xfe]);
+        assert_eq!(decode_4and4(&encode_4and4(0x5a)), 0x5a);
+    }
+
+    #[test]
+    fn test_track_layout() {
+        let disk = Disk::from_sectors(DiskFormat::Dos33, image()).unwrap();
+        let track = disk.track(4 * 17).unwrap();
+        assert!(track.bit_count() <= 51200);
+        let nibbles = track.nibbles();
+        assert_eq!(&nibbles[..4], &[0xff; 4]);
+
+        // The first address field is of physical sector 0, the second one of
+        // physical sector 1, which is sector 7 in DOS 3.3 order
+        let address = nibbles.windows(3).position(|w| w == ADDRESS_PROLOGUE).unwrap();
+        let field: Vec<u8> = nibbles[address + 3..address + 11].chunks(2).map(decode_4and4).collect();
+        assert_eq!(field, vec![254, 17, 0, 254 ^ 17]);
+        let data = address + 11 + 3 + GAP2;
+        assert_eq!(&nibbles[data..data + 3], &DATA_PROLOGUE);
+        assert_eq!(decode_6and2(&nibbles[data + 3..]), Some(vec![(17 * 16) as u8; SECTOR_SIZE]));
+
+        let next = data + 3 + 343 + 3 + GAP3;
+        assert_eq!(decode_4and4(&nibbles[next + 7..]), 1);
+        assert_eq!(decode_6and2(&nibbles[next + 14 + GAP2 + 3..]), Some(vec![(17 * 16 + 7) as u8; SECTOR_SIZE]));
+    }
+
+    #[test]
+    fn test_track_map() {
+        let disk = Disk::from_sectors(DiskFormat::ProDos, image()).unwrap();
+        assert!(disk.track(0).is_some());
+        assert_eq!(disk.track(1), disk.track(0));
+        assert_eq!(disk.track(2), None);
+        assert_eq!(disk.track(3), disk.track(4));
+        assert_eq!(disk.track(5), disk.track(4));
+        assert_ne!(disk.track(4), disk.track(0));
+        assert!(disk.track(4 * 34 + 1).is_some());
+        assert_eq!(disk.track(4 * 35), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in [DiskFormat::Dos33, DiskFormat::ProDos] {
+            let disk = Disk::from_sectors(format, image()).unwrap();
+            assert_eq!(disk.to_sectors(), image());
+        }
+        let error = Disk::from_sectors(DiskFormat::Dos33, vec![0; 1000]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_nib() {
+        let nib = Disk::from_sectors(DiskFormat::Dos33, image()).unwrap().to_nib();
+        assert_eq!(nib.len(), NIB_IMAGE_SIZE);
+        let disk = Disk::from_nib(nib.clone()).unwrap();
+        assert_eq!(disk.track(4).unwrap().bit_count(), NIB_TRACK_SIZE * 8);
+        assert_eq!(disk.track(2), None);
+        assert_eq!(disk.to_nib(), nib);
+        assert_eq!(disk.to_sectors(), image());
+        assert!(Disk::from_nib(vec![0xff; 6656]).is_err());
+    }
+
+    #[test]
+    fn test_written_sectors() {
+        let mut disk = Disk::from_sectors(DiskFormat::ProDos, image()).unwrap();
+        assert!(!disk.is_dirty());
+
+        // Write track 3 again with other data, and break one sector on it
+        let mut sectors = vec![0xa5; SECTORS * SECTOR_SIZE];
+        sectors[2 * SECTOR_SIZE] = 0x42;
+        let mut track = nibblize_track(DiskFormat::ProDos, 3, &sectors);
+        let broken = track.bit_count() - 1000;
+        for i in 0..64 {
+            track.set_bit(broken + i, 0);
+        }
+        *disk.track_mut(12).unwrap() = track;
+        assert!(disk.is_dirty());
+
+        let image = disk.to_sectors();
+        let track = &image[3 * SECTORS * SECTOR_SIZE..4 * SECTORS * SECTOR_SIZE];
+        assert_eq!(track[2 * SECTOR_SIZE], 0x42);
+        assert_eq!(&track[..SECTOR_SIZE], &[0xa5; SECTOR_SIZE]);
+        // Physical sector 15 is where the track was broken
+        assert_eq!(&track[15 * SECTOR_SIZE..], &[(3 * 16 + 15) as u8; SECTOR_SIZE]);
+        assert_eq!(&image[..SECTOR_SIZE], &[0; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn test_flush() {
+        let path = std::env::temp_dir().join(format!("rewm-disk-test-{}.po", std::process::id()));
+        fs::write(&path, image()).unwrap();
+        let mut disk = Disk::open(&path).unwrap();
+        assert_eq!(disk.format, DiskFormat::ProDos);
+        assert!(!disk.write_protected);
+
+        let track = nibblize_track(DiskFormat::ProDos, 0, &[0x5a; SECTORS * SECTOR_SIZE]);
+        *disk.track_mut(0).unwrap() = track;
+        disk.flush().unwrap();
+        assert!(!disk.is_dirty());
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&written[..SECTORS * SECTOR_SIZE], &[0x5a; SECTORS * SECTOR_SIZE]);
+        assert_eq!(&written[SECTORS * SECTOR_SIZE..], &image()[SECTORS * SECTOR_SIZE..]);
+
+        let error = Disk::open("disk.img").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
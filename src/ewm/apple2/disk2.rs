@@ -0,0 +1,505 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::io;
+
+use super::*;
+
+// The Disk II controller. Its I/O addresses are:
+//
+//   $C0n0-$C0n7  Stepper motor phases 0-3 off and on
+//   $C0n8-$C0n9  Motor off and on
+//   $C0nA-$C0nB  Drive 1 and 2
+//   $C0nC-$C0nD  Q6 off and on
+//   $C0nE-$C0nF  Q7 off and on
+//
+// Q6 and Q7 pick what the logic state sequencer does with the data latch:
+//
+//   Q7 off, Q6 off  Shift in the bits read from the disk, $C0nC reads them
+//   Q7 off, Q6 on   Sense write protect, which reads as bit 7 of $C0nE
+//   Q7 on,  Q6 on   Load the latch by writing to $C0nD or $C0nF
+//   Q7 on           Shift the latch out to the disk, bit 7 first
+//
+// A bit passes under the head every 4 cycles, or as often as the bit timing
+// of the disk says. A complete nibble, with bit 7 set, stays in the latch for
+// one more bit so that the read loop of the firmware, LDA $C08C,X / BPL,
+// cannot miss it.
+//
+// The drive only sees a one where the flux changes. When it goes more than
+// two bits without a change, the amplifier makes up random bits from the
+// noise. Copy protection counts on that.
+//
+// The P5 boot ROM at $Cn00 is not part of this repository, it has to be
+// loaded from a file.
+
+pub const DISK2_ROM_SIZE: usize = 256;
+// Where the firmware looks for a disk to boot from
+pub const DISK2_SLOT: usize = 6;
+
+#[derive(Debug, Default)]
+pub struct Drive {
+    pub disk: Option<Disk>,
+    quarter_track: usize,
+    bit_position: usize,
+}
+
+impl Drive {
+    pub fn quarter_track(&self) -> usize {
+        self.quarter_track
+    }
+}
+
+#[derive(Debug)]
+pub struct Disk2 {
+    rom: Vec<u8>,
+    pub drives: [Drive; 2],
+    selected: usize,
+    motor_on: bool,
+    // The magnets of the stepper motor that are on
+    phases: u8,
+    q6: bool,
+    q7: bool,
+    latch: u8,
+    shift: u8,
+    hold: bool,
+    // In units of 125 ns, an eighth of a cycle
+    time: u64,
+    zeros: usize,
+    noise: u32,
+}
+
+impl Disk2 {
+    pub fn new(rom: Vec<u8>) -> Result<Self, ComputerError> {
+        if rom.len() != DISK2_ROM_SIZE {
+            return Err(ComputerError::InvalidRom { expected: DISK2_ROM_SIZE, actual: rom.len() });
+        }
+        Ok(Disk2 {
+            rom,
+            drives: Default::default(),
+            selected: 0,
+            motor_on: false,
+            phases: 0,
+            q6: false,
+            q7: false,
+            latch: 0,
+            shift: 0,
+            hold: false,
+            time: 0,
+            zeros: 0,
+            noise: 0x2545_f491,
+        })
+    }
+
+    // Drives are numbered from 0
+    pub fn insert_disk(&mut self, drive: usize, disk: Disk) -> io::Result<Option<Disk>> {
+        let ejected = self.eject_disk(drive)?;
+        self.drives[drive].disk = Some(disk);
+        Ok(ejected)
+    }
+
+    pub fn eject_disk(&mut self, drive: usize) -> io::Result<Option<Disk>> {
+        if let Some(disk) = self.drives[drive].disk.as_mut() {
+            disk.flush()?;
+        }
+        Ok(self.drives[drive].disk.take())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        for disk in self.drives.iter_mut().filter_map(|drive| drive.disk.as_mut()) {
+            disk.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn motor_on(&self) -> bool {
+        self.motor_on
+    }
+
+    pub fn selected_drive(&self) -> usize {
+        self.selected
+    }
+
+    fn write_protected(&self) -> bool {
+        self.drives[self.selected].disk.as_ref().is_some_and(|disk| disk.write_protected)
+    }
+
+    fn access(&mut self, offset: u8) {
+        match offset {
+            0x0..=0x7 => {
+                let magnet = 1 << (offset >> 1);
+                if offset & 1 != 0 {
+                    self.phases |= magnet;
+                } else {
+                    self.phases &= !magnet;
+                }
+                self.step();
+            }
+            0x8 | 0x9 => self.motor_on = offset == 0x9,
+            0xa | 0xb => self.selected = (offset & 1) as usize,
+            0xc | 0xd => self.q6 = offset == 0xd,
+            _ => self.q7 = offset == 0xf,
+        }
+    }
+
+    // Each magnet that is on pulls the head towards the nearest position
+    // of its phase, every second quarter track. Two neighbouring magnets hold
+    // it between them, on an odd quarter track.
+
+    fn step(&mut self) {
+        let drive = &mut self.drives[self.selected];
+        let position = drive.quarter_track as i32;
+        let (mut pull, mut magnets) = (0, 0);
+        for phase in 0..4 {
+            if self.phases & (1 << phase) == 0 {
+                continue;
+            }
+            let offset = (phase * 2 - position).rem_euclid(8);
+            // The magnet on the opposite side does not move the head
+            if offset != 4 {
+                pull += if offset > 4 { offset - 8 } else { offset };
+                magnets += 1;
+            }
+        }
+        if magnets > 0 {
+            drive.quarter_track = (position + pull / magnets).clamp(0, QUARTER_TRACKS as i32 - 1) as usize;
+        }
+    }
+
+    fn random_bit(&mut self) -> u8 {
+        // Xorshift, with about 30% ones
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        (self.noise % 10 < 3) as u8
+    }
+
+    fn amplify(&mut self, bit: u8) -> u8 {
+        if bit != 0 {
+            self.zeros = 0;
+            return 1;
+        }
+        self.zeros += 1;
+        if self.zeros > 2 { self.random_bit() } else { 0 }
+    }
+
+    fn shift_in(&mut self, bit: u8) {
+        if self.shift & 0x80 != 0 {
+            self.shift = 0;
+        }
+        self.shift = (self.shift << 1) | bit;
+        if self.shift & 0x80 != 0 {
+            self.latch = self.shift;
+            self.hold = true;
+        } else if self.hold {
+            self.hold = false;
+        } else {
+            self.latch = self.shift;
+        }
+    }
+
+    // One bit passes under the head
+
+    fn rotate(&mut self) {
+        let write_protected = self.write_protected();
+        let drive = &mut self.drives[self.selected];
+        let Some(disk) = drive.disk.as_mut() else {
+            return;
+        };
+        let quarter_track = drive.quarter_track;
+        if self.q7 {
+            let bit = self.latch >> 7;
+            self.latch <<= 1;
+            if write_protected {
+                return;
+            }
+            if let Some(track) = disk.track_mut(quarter_track) {
+                drive.bit_position %= track.bit_count();
+                track.set_bit(drive.bit_position, bit);
+                drive.bit_position += 1;
+            }
+        } else {
+            // No flux changes where there is no track, only noise
+            let bit = match disk.track(quarter_track) {
+                Some(track) => {
+                    drive.bit_position %= track.bit_count();
+                    let bit = track.bit(drive.bit_position);
+                    drive.bit_position += 1;
+                    bit
+                }
+                None => 0,
+            };
+            let bit = self.amplify(bit);
+            self.shift_in(bit);
+        }
+    }
+}
+
+impl Computer<Apple2Bus> {
+    pub fn disk2(&mut self) -> Option<&mut Disk2> {
+        self.cpu.bus.card_as_mut(DISK2_SLOT)
+    }
+}
+
+impl Card for Disk2 {
+    fn read_io(&mut self, offset: u8) -> u8 {
+        self.access(offset);
+        self.peek_io(offset)
+    }
+
+    fn write_io(&mut self, offset: u8, b: u8) {
+        self.access(offset);
+        if self.q6 && self.q7 {
+            self.latch = b;
+        }
+    }
+
+    // Only the even addresses read the latch
+
+    fn peek_io(&self, offset: u8) -> u8 {
+        if offset & 1 != 0 {
+            0
+        } else if self.q6 && !self.q7 {
+            if self.write_protected() { 0x80 } else { 0x00 }
+        } else {
+            self.latch
+        }
+    }
+
+    fn peek_rom(&self, offset: u8) -> u8 {
+        self.rom[offset as usize]
+    }
+
+    // Reset clears the latch that holds the switches
+    fn reset(&mut self) {
+        self.motor_on = false;
+        self.phases = 0;
+        self.selected = 0;
+        self.q6 = false;
+        self.q7 = false;
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if !self.motor_on {
+            return;
+        }
+        let disk = self.drives[self.selected].disk.as_ref();
+        let bit_timing = disk.map_or(DEFAULT_BIT_TIMING, |disk| disk.bit_timing()) as u64;
+        self.time += cycles * 8;
+        while self.time >= bit_timing {
+            self.time -= bit_timing;
+            self.rotate();
+        }
+    }
+}
+
+#[cfg(test)]
+mod disk2_tests {
+    use super::*;
+    use super::super::super::asm::assemble;
+
+    fn disk() -> Disk {
+        let image = (0..SECTOR_IMAGE_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect();
+        Disk::from_sectors(DiskFormat::Dos33, image).unwrap()
+    }
+
+    fn disk2() -> Disk2 {
+        let mut disk2 = Disk2::new(vec![0x60; DISK2_ROM_SIZE]).unwrap();
+        disk2.insert_disk(0, disk()).unwrap();
+        disk2
+    }
+
+    // Reads like the firmware does, polling every 7 cycles and comparing
+    // what it read for another 4
+    fn read_nibble(disk2: &mut Disk2) -> u8 {
+        loop {
+            let b = disk2.read_io(0xc);
+            disk2.tick(7);
+            if b & 0x80 != 0 {
+                disk2.tick(4);
+                return b;
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_rom() {
+        let error = Disk2::new(vec![0; 2048]).unwrap_err();
+        assert_eq!(error, ComputerError::InvalidRom { expected: 256, actual: 2048 });
+    }
+
+    #[test]
+    fn test_stepper() {
+        let mut disk2 = disk2();
+        let mut position = Vec::new();
+        // Seek out two tracks in half track steps, the way DOS does it
+        for phase in [1, 2, 3, 0] {
+            disk2.access(phase * 2 + 1);
+            position.push(disk2.drives[0].quarter_track());
+            disk2.access(((phase + 3) % 4) * 2);
+            position.push(disk2.drives[0].quarter_track());
+        }
+        assert_eq!(position, vec![2, 2, 3, 4, 5, 6, 7, 8]);
+        disk2.access(0x0);
+
+        // Back out, with a stop in between two phases
+        disk2.access(0x7);
+        assert_eq!(disk2.drives[0].quarter_track(), 6);
+        disk2.access(0x5);
+        assert_eq!(disk2.drives[0].quarter_track(), 5);
+        disk2.access(0x6);
+        assert_eq!(disk2.drives[0].quarter_track(), 4);
+
+        // The head stops at track 0
+        disk2.access(0x4);
+        for phase in [1, 0, 3, 2, 1, 0, 3] {
+            disk2.access(phase * 2 + 1);
+            disk2.access(((phase + 1) % 4) * 2);
+        }
+        assert_eq!(disk2.drives[0].quarter_track(), 0);
+
+        // Only the selected drive moves
+        disk2.access(0x6);
+        disk2.access(0xb);
+        disk2.access(0x3);
+        assert_eq!(disk2.drives[0].quarter_track(), 0);
+        assert_eq!(disk2.drives[1].quarter_track(), 2);
+    }
+
+    #[test]
+    fn test_read() {
+        let mut disk2 = disk2();
+        disk2.access(0x9);
+
+        // Find the first address field, and the data of the sector
+        let mut nibbles = Vec::new();
+        while nibbles.len() < 3 || nibbles[nibbles.len() - 3..] != [0xd5, 0xaa, 0x96] {
+            nibbles.push(read_nibble(&mut disk2));
+        }
+        assert!(nibbles[..nibbles.len() - 3].iter().all(|&b| b == 0xff));
+        let field: Vec<u8> = (0..8).map(|_| read_nibble(&mut disk2)).collect();
+        assert_eq!(field, vec![0xff, 0xfe, 0xaa, 0xaa, 0xaa, 0xaa, 0xff, 0xfe]);
+        while read_nibble(&mut disk2) != 0xad {}
+        let data: Vec<u8> = (0..343).map(|_| read_nibble(&mut disk2)).collect();
+        assert_eq!(data, vec![0x96; 343]);
+
+        // Nothing is read with the motor off
+        disk2.access(0x8);
+        let latch = disk2.read_io(0xc);
+        disk2.tick(1000);
+        assert_eq!(disk2.read_io(0xc), latch);
+    }
+
+    #[test]
+    fn test_noise() {
+        let mut disk2 = disk2();
+        disk2.access(0x9);
+        disk2.access(0x3);
+        assert_eq!(disk2.drives[0].quarter_track(), 2);
+        let nibbles: Vec<u8> = (0..100).map(|_| read_nibble(&mut disk2)).collect();
+        assert!(nibbles.iter().any(|&b| b != nibbles[0]));
+    }
+
+    #[test]
+    fn test_write_protect() {
+        let mut disk2 = disk2();
+        disk2.read_io(0xd);
+        assert_eq!(disk2.read_io(0xe), 0x00);
+        disk2.drives[0].disk.as_mut().unwrap().write_protected = true;
+        disk2.read_io(0xd);
+        assert_eq!(disk2.read_io(0xe), 0x80);
+
+        // Nothing gets written
+        disk2.access(0x9);
+        disk2.read_io(0xd);
+        disk2.write_io(0xf, 0x00);
+        disk2.tick(40_000 * 4);
+        assert!(!disk2.drives[0].disk.as_ref().unwrap().is_dirty());
+    }
+
+    #[test]
+    fn test_write() {
+        let mut disk2 = disk2();
+        disk2.access(0x9);
+        disk2.tick(100 * 4);
+
+        // A sync byte takes 40 cycles, a nibble 32
+        disk2.read_io(0xd);
+        disk2.write_io(0xf, 0xff);
+        disk2.read_io(0xc);
+        disk2.tick(40);
+        for nibble in [0xd5, 0xaa, 0xad] {
+            disk2.write_io(0xd, nibble);
+            disk2.read_io(0xc);
+            disk2.tick(32);
+        }
+        disk2.read_io(0xe);
+
+        let disk = disk2.drives[0].disk.as_ref().unwrap();
+        assert!(disk.is_dirty());
+        let track = disk.track(0).unwrap();
+        let bits: String = (100..100 + 34).map(|i| char::from(b'0' + track.bit(i))).collect();
+        assert_eq!(bits, "1111111100110101011010101010101101");
+    }
+
+    #[test]
+    fn test_boot() {
+        // Reads the first nibble after the address prologue of the disk in
+        // slot 6, the way the boot ROM does
+        let program = assemble("
+            .org $0800
+                LDX #$60
+                LDA $C08E,X
+                LDA $C089,X
+        @prologue:
+                LDA $C08C,X
+                BPL @prologue
+                CMP #$D5
+                BNE @prologue
+        @second: LDA $C08C,X
+                BPL @second
+                CMP #$AA
+                BNE @prologue
+        @third: LDA $C08C,X
+                BPL @third
+                CMP #$96
+                BNE @prologue
+        @volume:
+                LDA $C08C,X
+                BPL @volume
+                STA $00
+        @done:  JMP @done
+        ").unwrap();
+
+        let mut rom = vec![0xea; APPLE2_ROM_SIZE];
+        rom[APPLE2_ROM_SIZE - 4] = 0x00;
+        rom[APPLE2_ROM_SIZE - 3] = 0x08;
+        let mut apple2 = Computer::apple2plus(rom).unwrap();
+        apple2.cpu.bus.insert_card(DISK2_SLOT, Box::new(disk2()));
+        for (i, b) in program.iter().enumerate() {
+            apple2.cpu.set_byte(0x0800 + i as u16, *b);
+        }
+        apple2.reset();
+        apple2.run_cycles(100_000).unwrap();
+        assert_eq!(apple2.cpu.get_byte(0x00), 0xff);
+        assert!(apple2.disk2().unwrap().motor_on());
+    }
+}
@@ -0,0 +1,292 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+
+// The //e memory switches. Writing to the even addresses of $C000-$C00F turns
+// them off and writing to the odd ones turns them on. Bit 7 of $C013-$C018
+// tells their state.
+//
+//   80STORE    PAGE2 switches the text page, and with HIRES also the hi-res
+//              page, between main and auxiliary memory instead of switching
+//              the display
+//   RAMRD      Read $0200-$BFFF from auxiliary memory
+//   RAMWRT     Write $0200-$BFFF to auxiliary memory
+//   INTCXROM   Internal ROM at $C100-$CFFF instead of the slots
+//   ALTZP      Zero page, stack and the language card in auxiliary memory
+//   SLOTC3ROM  The ROM of slot 3 at $C300 instead of the internal 80 column
+//              firmware
+//
+// Accessing $C3xx while the internal 80 column firmware is there also puts
+// the internal ROM at $C800-$CFFF, until $CFFF is accessed. That is INTC8ROM,
+// which cannot be switched directly.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySwitches {
+    pub store80: bool,
+    pub ramrd: bool,
+    pub ramwrt: bool,
+    pub intcxrom: bool,
+    pub altzp: bool,
+    pub slotc3rom: bool,
+    pub intc8rom: bool,
+}
+
+impl Apple2Bus {
+    // Whether an access to RAM goes to auxiliary memory
+
+    pub(super) fn aux_selected(&self, addr: u16, write: bool) -> bool {
+        let switches = &self.switches;
+        match addr {
+            0x0000..=0x01ff | 0xd000..=0xffff => switches.altzp,
+            0x0400..=0x07ff if switches.store80 => self.video.page2,
+            0x2000..=0x3fff if switches.store80 && self.video.hires => self.video.page2,
+            _ if write => switches.ramwrt,
+            _ => switches.ramrd,
+        }
+    }
+
+    // The page that the video hardware shows
+    pub fn display_page2(&self) -> bool {
+        self.video.page2 && !self.switches.store80
+    }
+
+    pub(super) fn peek_iie_switch(&self, addr: u16) -> Option<u8> {
+        let status = |on: bool| (self.keyboard & 0x7f) | if on { 0x80 } else { 0x00 };
+        let in_vbl = self.clock % CYCLES_PER_FRAME >= VBL_START;
+        match addr {
+            0xc010 => Some(self.keyboard),
+            0xc011 => Some(status(self.language_card.bank2)),
+            0xc012 => Some(status(self.language_card.read_ram)),
+            0xc013 => Some(status(self.switches.ramrd)),
+            0xc014 => Some(status(self.switches.ramwrt)),
+            0xc015 => Some(status(self.switches.intcxrom)),
+            0xc016 => Some(status(self.switches.altzp)),
+            0xc017 => Some(status(self.switches.slotc3rom)),
+            0xc018 => Some(status(self.switches.store80)),
+            0xc019 => Some(status(!in_vbl)),
+            0xc01a => Some(status(self.video.text)),
+            0xc01b => Some(status(self.video.mixed)),
+            0xc01c => Some(status(self.video.page2)),
+            0xc01d => Some(status(self.video.hires)),
+            0xc01e => Some(status(self.video.altchar)),
+            0xc01f => Some(status(self.video.col80)),
+            _ => None,
+        }
+    }
+
+    pub(super) fn access_iie_switch(&mut self, addr: u16, write: bool) {
+        let on = addr & 1 != 0;
+        match addr {
+            0xc000..=0xc00f if write => match addr & !1 {
+                0xc000 => self.switches.store80 = on,
+                0xc002 => self.switches.ramrd = on,
+                0xc004 => self.switches.ramwrt = on,
+                0xc006 => self.switches.intcxrom = on,
+                0xc008 => self.switches.altzp = on,
+                0xc00a => self.switches.slotc3rom = on,
+                0xc00c => self.video.col80 = on,
+                _ => self.video.altchar = on,
+            },
+            // Annunciator 3 is wired to double hi-res, which is on when the
+            // annunciator is off
+            0xc05e | 0xc05f => self.video.double_hires = !on,
+            _ => {}
+        }
+    }
+
+    pub(super) fn select_internal_c8_rom(&mut self, addr: u16) {
+        match addr {
+            0xc300..=0xc3ff if !self.switches.slotc3rom => self.switches.intc8rom = true,
+            0xcfff => self.switches.intc8rom = false,
+            _ => {}
+        }
+    }
+
+    pub(super) fn internal_rom_selected(&self, addr: u16) -> bool {
+        self.switches.intcxrom || match addr {
+            0xc300..=0xc3ff => !self.switches.slotc3rom,
+            0xc800..=0xcfff => self.switches.intc8rom,
+            _ => false,
+        }
+    }
+
+    pub(super) fn reset_iie(&mut self) {
+        self.switches = MemorySwitches::default();
+        self.video.col80 = false;
+        self.video.altchar = false;
+    }
+}
+
+#[cfg(test)]
+mod iie_tests {
+    use super::*;
+
+    // Every byte of the ROM is the number of its page, starting at $C000,
+    // and the reset vector points to $D000.
+    fn rom() -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..APPLE2E_ROM_SIZE).map(|i| (i >> 8) as u8).collect();
+        rom[APPLE2E_ROM_SIZE - 4] = 0x00;
+        rom[APPLE2E_ROM_SIZE - 3] = 0xd0;
+        rom
+    }
+
+    #[derive(Debug)]
+    struct SlotCard;
+
+    impl Card for SlotCard {
+        fn peek_rom(&self, _offset: u8) -> u8 {
+            0xaa
+        }
+
+        fn peek_expansion_rom(&self, _offset: u16) -> u8 {
+            0xbb
+        }
+    }
+
+    #[test]
+    fn test_models() {
+        assert_eq!(Computer::apple2e(rom()).unwrap().cpu.model, Model::Nmos6502);
+        assert_eq!(Computer::apple2e_enhanced(rom()).unwrap().cpu.model, Model::Cmos65C02);
+        let error = Computer::apple2e(vec![0; APPLE2_ROM_SIZE]).unwrap_err();
+        assert_eq!(error, ComputerError::InvalidRom { expected: 16 * 1024, actual: 12 * 1024 });
+    }
+
+    #[test]
+    fn test_aux_memory() {
+        let mut iie = Computer::apple2e(rom()).unwrap();
+        let cpu = &mut iie.cpu;
+        cpu.set_byte(0x1000, 0x11);
+        cpu.set_byte(0x0080, 0x11);
+
+        cpu.set_byte(0xc005, 0); // RAMWRT on
+        cpu.set_byte(0x1000, 0x22);
+        assert_eq!(cpu.get_byte(0x1000), 0x11);
+        cpu.set_byte(0xc003, 0); // RAMRD on
+        assert_eq!(cpu.get_byte(0x1000), 0x22);
+        assert_eq!(cpu.get_byte(0xc013), 0x80);
+        assert_eq!(cpu.get_byte(0xc014), 0x80);
+
+        // Zero page is switched by ALTZP only
+        assert_eq!(cpu.get_byte(0x0080), 0x11);
+        cpu.set_byte(0xc009, 0);
+        assert_eq!(cpu.get_byte(0x0080), 0x00);
+        assert_eq!(cpu.get_byte(0xc016), 0x80);
+
+        // Reading the switches does not change them
+        cpu.get_byte(0xc002);
+        assert!(cpu.bus.switches.ramrd);
+
+        iie.reset();
+        assert_eq!(iie.cpu.bus.switches, MemorySwitches::default());
+        assert_eq!(iie.cpu.get_byte(0x1000), 0x11);
+    }
+
+    #[test]
+    fn test_80store() {
+        let mut iie = Computer::apple2e(rom()).unwrap();
+        let cpu = &mut iie.cpu;
+        cpu.set_byte(0xc001, 0); // 80STORE on
+        cpu.get_byte(0xc055);    // PAGE2 now means aux memory
+        cpu.set_byte(0x0400, 0x22);
+        cpu.set_byte(0x2000, 0x22);
+        assert!(!cpu.bus.display_page2());
+        cpu.get_byte(0xc054);
+        assert_eq!(cpu.get_byte(0x0400), 0x00);
+        assert_eq!(cpu.get_byte(0x2000), 0x22); // Without HIRES it is not switched
+
+        cpu.get_byte(0xc057);
+        cpu.get_byte(0xc055);
+        cpu.set_byte(0x2000, 0x33);
+        cpu.get_byte(0xc054);
+        assert_eq!(cpu.get_byte(0x2000), 0x22);
+        cpu.get_byte(0xc055);
+        assert_eq!(cpu.get_byte(0x2000), 0x33);
+        assert_eq!(cpu.get_byte(0x0400), 0x22);
+
+        cpu.set_byte(0xc000, 0);
+        assert!(cpu.bus.display_page2());
+    }
+
+    #[test]
+    fn test_internal_rom() {
+        let mut iie = Computer::apple2e(rom()).unwrap();
+        iie.cpu.bus.insert_card(3, Box::new(SlotCard));
+        iie.cpu.bus.insert_card(6, Box::new(SlotCard));
+        let cpu = &mut iie.cpu;
+
+        // The 80 column firmware is in the way of slot 3
+        assert_eq!(cpu.get_byte(0xc600), 0xaa);
+        assert_eq!(cpu.get_byte(0xc800), 0xbb);
+        assert_eq!(cpu.get_byte(0xc300), 0x03);
+        assert_eq!(cpu.get_byte(0xc800), 0x08);
+        cpu.get_byte(0xcfff);
+        assert_eq!(cpu.get_byte(0xc800), 0x00); // No slot selected
+
+        cpu.set_byte(0xc00b, 0); // SLOTC3ROM on
+        assert_eq!(cpu.get_byte(0xc300), 0xaa);
+        assert_eq!(cpu.get_byte(0xc800), 0xbb);
+        assert_eq!(cpu.get_byte(0xc017), 0x80);
+
+        cpu.set_byte(0xc007, 0); // INTCXROM on
+        assert_eq!(cpu.get_byte(0xc600), 0x06);
+        assert_eq!(cpu.get_byte(0xc300), 0x03);
+        assert_eq!(cpu.get_byte(0xcfff), 0x0f);
+        assert_eq!(cpu.get_byte(0xd000), 0x10);
+    }
+
+    #[test]
+    fn test_display_switches() {
+        let mut iie = Computer::apple2e(rom()).unwrap();
+        let cpu = &mut iie.cpu;
+        cpu.set_byte(0xc00d, 0);
+        cpu.set_byte(0xc00f, 0);
+        cpu.get_byte(0xc05e);
+        assert!(cpu.bus.video.col80);
+        assert!(cpu.bus.video.altchar);
+        assert!(cpu.bus.video.double_hires);
+        assert_eq!(cpu.get_byte(0xc01f), 0x80);
+        assert_eq!(cpu.get_byte(0xc01e), 0x80);
+        assert_eq!(cpu.get_byte(0xc01a), 0x80);
+        cpu.get_byte(0xc05f);
+        assert!(!cpu.bus.video.double_hires);
+    }
+
+    #[test]
+    fn test_vertical_blank() {
+        let mut iie = Computer::apple2e(rom()).unwrap();
+        assert_eq!(iie.cpu.get_byte(0xc019) & 0x80, 0x80);
+        iie.cpu.bus.tick(VBL_START);
+        assert_eq!(iie.cpu.get_byte(0xc019) & 0x80, 0x00);
+        iie.cpu.bus.tick(CYCLES_PER_FRAME - VBL_START);
+        assert_eq!(iie.cpu.get_byte(0xc019) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_lower_case_keyboard() {
+        let mut iie = Computer::apple2e(rom()).unwrap();
+        iie.press_key(b'a');
+        assert_eq!(iie.cpu.get_byte(0xc000), 0xe1);
+        assert_eq!(iie.cpu.get_byte(0xc010), 0xe1);
+        assert_eq!(iie.cpu.get_byte(0xc010), 0x61);
+    }
+}
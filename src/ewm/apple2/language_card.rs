@@ -0,0 +1,154 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// The language card has 16K of RAM that can take the place of the ROM at
+// $D000-$FFFF. $D000-$DFFF has two banks of 4K. Accessing $C080-$C08F, reads
+// and writes alike, picks the configuration:
+//
+//   bit 3      Bank 1 of $D000-$DFFF when set, bank 2 when clear
+//   bits 0-1   0 read RAM, no writes
+//              1 read ROM, write RAM
+//              2 read ROM, no writes
+//              3 read RAM, write RAM
+//
+// Writing to the RAM is only enabled by two reads in a row of an odd
+// address. A write to an odd address does not count, and any access to an
+// even address disables writing right away.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LanguageCard {
+    pub read_ram: bool,
+    pub write_enabled: bool,
+    pub bank2: bool,
+    // The first of the two reads has happened
+    prewrite: bool,
+}
+
+impl LanguageCard {
+    pub fn access(&mut self, addr: u16, write: bool) {
+        self.bank2 = addr & 0x08 == 0;
+        self.read_ram = matches!(addr & 0x03, 0 | 3);
+        if addr & 0x01 == 0 {
+            self.write_enabled = false;
+            self.prewrite = false;
+        } else if write {
+            self.prewrite = false;
+        } else {
+            self.write_enabled |= self.prewrite;
+            self.prewrite = true;
+        }
+    }
+
+    // Reset leaves the ROM in place with bank 2 of the RAM writable, as
+    // after two reads of $C081
+    pub fn reset(&mut self) {
+        *self = LanguageCard { read_ram: false, write_enabled: true, bank2: true, prewrite: false };
+    }
+}
+
+#[cfg(test)]
+mod language_card_tests {
+    use super::*;
+    use super::super::{Apple2Model, Computer};
+    use super::super::fixtures::rom;
+
+    #[test]
+    fn test_write_enable() {
+        let mut card = LanguageCard::default();
+        card.access(0xc08b, false);
+        assert!(!card.write_enabled);
+        card.access(0xc08b, false);
+        assert!(card.write_enabled);
+        assert!(card.read_ram);
+        assert!(!card.bank2);
+
+        // Odd accesses keep writing enabled, even ones disable it
+        card.access(0xc089, true);
+        card.access(0xc081, false);
+        assert!(card.write_enabled);
+        card.access(0xc080, false);
+        assert!(!card.write_enabled);
+
+        // An even access in between breaks up the two reads
+        card.access(0xc083, false);
+        card.access(0xc082, false);
+        card.access(0xc083, false);
+        assert!(!card.write_enabled);
+
+        // So does a write
+        card.access(0xc083, true);
+        card.access(0xc083, false);
+        assert!(!card.write_enabled);
+        card.access(0xc083, false);
+        assert!(card.write_enabled);
+    }
+
+    #[test]
+    fn test_banks() {
+        let mut apple2 = Computer::apple2plus(rom(Apple2Model::Plus)).unwrap();
+        let cpu = &mut apple2.cpu;
+
+        // After reset the ROM is read and bank 2 is written
+        cpu.set_byte(0xd000, 0x22);
+        cpu.set_byte(0xe000, 0x33);
+        assert_eq!(cpu.get_byte(0xd000), 0xea);
+
+        // A single read of $C08B does not enable writing to bank 1 yet
+        cpu.get_byte(0xc080);
+        cpu.get_byte(0xc08b);
+        cpu.set_byte(0xd000, 0x11);
+        assert_eq!(cpu.get_byte(0xd000), 0x00);
+        cpu.get_byte(0xc08b);
+        cpu.set_byte(0xd000, 0x11);
+        assert_eq!(cpu.get_byte(0xd000), 0x11);
+        assert_eq!(cpu.get_byte(0xe000), 0x33); // Not banked
+
+        cpu.get_byte(0xc080);
+        assert_eq!(cpu.get_byte(0xd000), 0x22);
+        cpu.set_byte(0xd000, 0x44);
+        assert_eq!(cpu.get_byte(0xd000), 0x22);
+
+        cpu.get_byte(0xc082);
+        assert_eq!(cpu.get_byte(0xd000), 0xea);
+    }
+
+    #[test]
+    fn test_iie() {
+        let mut iie = Computer::apple2e(rom(Apple2Model::IIe)).unwrap();
+        let cpu = &mut iie.cpu;
+        assert_eq!(cpu.get_byte(0xc011), 0x80);
+        assert_eq!(cpu.get_byte(0xc012), 0x00);
+
+        cpu.get_byte(0xc08b);
+        cpu.get_byte(0xc08b);
+        assert_eq!(cpu.get_byte(0xc011), 0x00);
+        assert_eq!(cpu.get_byte(0xc012), 0x80);
+        cpu.set_byte(0xd000, 0x11);
+
+        // ALTZP switches the language card to auxiliary memory as well
+        cpu.set_byte(0xc009, 0);
+        assert_eq!(cpu.get_byte(0xd000), 0x00);
+        cpu.set_byte(0xd000, 0x22);
+        cpu.set_byte(0xc008, 0);
+        assert_eq!(cpu.get_byte(0xd000), 0x11);
+    }
+}
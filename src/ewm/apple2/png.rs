@@ -0,0 +1,170 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use super::*;
+use super::woz::crc32;
+
+// Just enough of PNG to write a screenshot: 8 bit RGB, no filters, and the
+// image data in stored deflate blocks. That makes files about as big as a
+// PPM but everything can open them.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// The most a stored deflate block holds
+const MAX_BLOCK_SIZE: usize = 0xffff;
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn chunk(png: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(id);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+// A zlib stream of stored blocks
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut start = 0;
+    // There is at least one block, the last one says so
+    loop {
+        let end = data.len().min(start + MAX_BLOCK_SIZE);
+        let size = (end - start) as u16;
+        stream.push((end == data.len()) as u8);
+        stream.extend(size.to_le_bytes());
+        stream.extend((!size).to_le_bytes());
+        stream.extend(&data[start..end]);
+        if end == data.len() {
+            break;
+        }
+        start = end;
+    }
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+impl Framebuffer {
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(13);
+        header.extend((self.width as u32).to_be_bytes());
+        header.extend((self.height as u32).to_be_bytes());
+        // 8 bits per sample, RGB, deflate, no filters, not interlaced
+        header.extend([8, 2, 0, 0, 0]);
+
+        // Each line starts with its filter type, which is none
+        let mut lines = Vec::with_capacity(self.height * (self.width * 3 + 1));
+        for line in self.pixels.chunks(self.width * 4) {
+            lines.push(0);
+            for pixel in line.chunks(4) {
+                lines.extend(&pixel[..3]);
+            }
+        }
+
+        let mut png = SIGNATURE.to_vec();
+        chunk(&mut png, b"IHDR", &header);
+        chunk(&mut png, b"IDAT", &zlib(&lines));
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+#[cfg(test)]
+mod png_tests {
+    use super::*;
+
+    // The chunks of a PNG, after checking their CRCs
+    fn chunks(png: &[u8]) -> Vec<(&[u8], &[u8])> {
+        assert_eq!(png[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut offset = 8;
+        while offset < png.len() {
+            let size = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+            let end = offset + 8 + size;
+            let crc = u32::from_be_bytes(png[end..end + 4].try_into().unwrap());
+            assert_eq!(crc, crc32(&png[offset + 4..end]));
+            chunks.push((&png[offset + 4..offset + 8], &png[offset + 8..end]));
+            offset = end + 4;
+        }
+        chunks
+    }
+
+    // The data of a zlib stream of stored blocks
+    fn unzlib(stream: &[u8]) -> Vec<u8> {
+        assert_eq!(stream[..2], [0x78, 0x01]);
+        let mut data = Vec::new();
+        let mut offset = 2;
+        loop {
+            let last = stream[offset] == 1;
+            let size = u16::from_le_bytes([stream[offset + 1], stream[offset + 2]]);
+            let check = u16::from_le_bytes([stream[offset + 3], stream[offset + 4]]);
+            assert_eq!(size, !check);
+            data.extend(&stream[offset + 5..offset + 5 + size as usize]);
+            offset += 5 + size as usize;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(stream[offset..], adler32(&data).to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_zlib() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let stream = zlib(&data);
+        // Four blocks of five bytes overhead
+        assert_eq!(stream.len(), data.len() + 2 + 4 * 5 + 4);
+        assert_eq!(unzlib(&stream), data);
+        assert_eq!(unzlib(&zlib(&[])), []);
+    }
+
+    #[test]
+    fn test_png() {
+        let mut framebuffer = Framebuffer::new(3, 2);
+        framebuffer.set_pixel(0, 0, WHITE);
+        framebuffer.set_pixel(2, 1, [1, 2, 3, 0xff]);
+        let png = framebuffer.to_png();
+        let chunks = chunks(&png);
+        let ids: Vec<_> = chunks.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        assert_eq!(unzlib(chunks[1].1), [
+            0, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+        ]);
+    }
+}
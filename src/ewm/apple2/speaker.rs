@@ -0,0 +1,297 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// The speaker is a 1 bit DAC: every access to $C030 moves the cone in or
+// out. We note the cycle of each toggle and turn the square wave they make
+// into 16 bit PCM at 44.1 kHz.
+//
+// Just taking the level at each sample would alias everything above 22 kHz
+// back into what we hear, so each step is drawn as a band limited step
+// instead: the integral of a windowed sinc with a cutoff of 18 kHz, which
+// spreads a step over STEP_WIDTH samples on either side. Samples are only
+// made once every step that can change them is known, which makes them lag
+// the CPU by STEP_WIDTH samples. A DC blocker takes out the level the cone
+// rests at, so silence is zero whichever way the cone was left.
+
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// The CPU runs at 14.31818 MHz / 14, but every 65th cycle is 2/14 longer
+pub const APPLE2_CLOCK: u64 = 1_020_484;
+
+const CUTOFF: f64 = 18_000.0;
+const STEP_WIDTH: usize = 16;
+// Entries in the step table per sample
+const PHASES: usize = 256;
+// The DC blocker is a high pass filter at about 20 Hz
+const DC_BLOCKER_POLE: f64 = 0.997;
+// Leaves room for the overshoot of the filters
+const VOLUME: f64 = 0.75 * i16::MAX as f64;
+// Samples that nobody takes are dropped after a few seconds, so a front end
+// without sound does not need to care
+const MAX_SAMPLES: usize = 4 * SAMPLE_RATE as usize;
+
+#[derive(Debug)]
+pub struct Speaker {
+    on: bool,
+    // The step response, from -STEP_WIDTH to STEP_WIDTH samples
+    step: Vec<f64>,
+    // Toggles that still change samples to come, in sample time, with the
+    // change of the level
+    toggles: VecDeque<(f64, f64)>,
+    // The level before those toggles
+    level: f64,
+    // The next sample to make, and the first cycle when it can be made
+    sample: u64,
+    sample_cycle: u64,
+    dc_input: f64,
+    dc_output: f64,
+    samples: VecDeque<i16>,
+}
+
+impl Default for Speaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn sample_time(cycle: u64) -> f64 {
+    cycle as f64 * SAMPLE_RATE as f64 / APPLE2_CLOCK as f64
+}
+
+// The first cycle at or after the time of the sample
+fn sample_cycle(sample: u64) -> u64 {
+    (sample * APPLE2_CLOCK).div_ceil(SAMPLE_RATE as u64)
+}
+
+fn step_response() -> Vec<f64> {
+    let fc = CUTOFF / SAMPLE_RATE as f64;
+    let width = STEP_WIDTH as f64;
+    let size = 2 * STEP_WIDTH * PHASES + 1;
+    let kernel = |x: f64| {
+        let sinc = if x == 0.0 { 2.0 * fc } else { (2.0 * PI * fc * x).sin() / (PI * x) };
+        // Blackman
+        let w = 0.42 + 0.5 * (PI * x / width).cos() + 0.08 * (2.0 * PI * x / width).cos();
+        sinc * w
+    };
+    let mut step = Vec::with_capacity(size);
+    let mut sum = 0.0;
+    for i in 0..size {
+        step.push(sum);
+        sum += kernel(i as f64 / PHASES as f64 - width);
+    }
+    step.iter().map(|s| s / sum).collect()
+}
+
+impl Speaker {
+    pub fn new() -> Self {
+        Speaker {
+            on: false,
+            step: step_response(),
+            toggles: VecDeque::new(),
+            level: 0.0,
+            sample: 0,
+            sample_cycle: sample_cycle(STEP_WIDTH as u64),
+            dc_input: 0.0,
+            dc_output: 0.0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn on(&self) -> bool {
+        self.on
+    }
+
+    pub fn toggle(&mut self, cycle: u64) {
+        self.on = !self.on;
+        let delta = if self.on { 1.0 } else { -1.0 };
+        self.toggles.push_back((sample_time(cycle), delta));
+    }
+
+    // How far a step at time t has gone at sample n
+    fn step_at(&self, n: f64, t: f64) -> f64 {
+        let x = (n - t + STEP_WIDTH as f64) * PHASES as f64;
+        if x <= 0.0 {
+            return 0.0;
+        }
+        let i = x as usize;
+        if i + 1 >= self.step.len() {
+            return 1.0;
+        }
+        let f = x - i as f64;
+        self.step[i] * (1.0 - f) + self.step[i + 1] * f
+    }
+
+    // Makes the samples that the toggles up to the cycle decide. The bus
+    // calls this after every instruction.
+
+    pub fn update(&mut self, cycle: u64) {
+        while cycle >= self.sample_cycle {
+            let n = self.sample as f64;
+            let mut value = self.level;
+            for &(t, delta) in &self.toggles {
+                value += delta * self.step_at(n, t);
+            }
+            // Toggles that are done changing samples become part of the level
+            while let Some(&(t, delta)) = self.toggles.front() {
+                if t > n + 1.0 - STEP_WIDTH as f64 {
+                    break;
+                }
+                self.level += delta;
+                self.toggles.pop_front();
+            }
+
+            self.dc_output = value - self.dc_input + DC_BLOCKER_POLE * self.dc_output;
+            self.dc_input = value;
+            if self.samples.len() == MAX_SAMPLES {
+                self.samples.pop_front();
+            }
+            let sample = (self.dc_output * VOLUME).round().clamp(i16::MIN as f64, i16::MAX as f64);
+            self.samples.push_back(sample as i16);
+
+            self.sample += 1;
+            self.sample_cycle = sample_cycle(self.sample + STEP_WIDTH as u64);
+        }
+    }
+
+    // The samples made so far that nobody took yet
+    pub fn samples(&self) -> impl Iterator<Item = i16> + '_ {
+        self.samples.iter().copied()
+    }
+
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        self.samples.drain(..).collect()
+    }
+}
+
+// A mono 16 bit WAV file
+pub fn to_wav(samples: &[i16]) -> Vec<u8> {
+    let data_size = samples.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend(b"RIFF");
+    wav.extend((36 + data_size).to_le_bytes());
+    wav.extend(b"WAVE");
+    wav.extend(b"fmt ");
+    wav.extend(16u32.to_le_bytes());
+    // PCM, one channel
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(SAMPLE_RATE.to_le_bytes());
+    wav.extend((SAMPLE_RATE * 2).to_le_bytes());
+    // Bytes per frame and bits per sample
+    wav.extend(2u16.to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(data_size.to_le_bytes());
+    for sample in samples {
+        wav.extend(sample.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod speaker_tests {
+    use super::*;
+
+    // A square wave of about the frequency for a second
+    fn square_wave(half_period: u64) -> Vec<i16> {
+        let mut speaker = Speaker::new();
+        for cycle in (0..APPLE2_CLOCK).step_by(half_period as usize) {
+            speaker.update(cycle);
+            speaker.toggle(cycle);
+        }
+        speaker.update(APPLE2_CLOCK);
+        speaker.take_samples()
+    }
+
+    fn crossings(samples: &[i16]) -> usize {
+        samples.windows(2).filter(|w| (w[0] < 0) != (w[1] < 0)).count()
+    }
+
+    #[test]
+    fn test_silence() {
+        let mut speaker = Speaker::new();
+        speaker.update(APPLE2_CLOCK);
+        // Samples lag behind
+        assert_eq!(speaker.samples().count(), SAMPLE_RATE as usize - STEP_WIDTH + 1);
+        assert!(speaker.samples().all(|s| s == 0));
+        assert_eq!(speaker.take_samples().len(), SAMPLE_RATE as usize - STEP_WIDTH + 1);
+        assert_eq!(speaker.samples().count(), 0);
+
+        // Whichever way the cone rests
+        speaker.toggle(APPLE2_CLOCK);
+        assert!(speaker.on());
+        speaker.update(2 * APPLE2_CLOCK);
+        let samples = speaker.take_samples();
+        assert!(samples.iter().take(2 * STEP_WIDTH).any(|&s| s > 1000));
+        assert_eq!(samples[samples.len() - 1], 0);
+    }
+
+    #[test]
+    fn test_square_wave() {
+        for half_period in [5000, 500, 100] {
+            // After the DC blocker has settled
+            let samples = &square_wave(half_period)[SAMPLE_RATE as usize / 2..];
+            let crossings = crossings(samples);
+            let expected = (samples.len() as u64 * APPLE2_CLOCK / half_period / SAMPLE_RATE as u64) as usize;
+            assert!(crossings.abs_diff(expected) <= 2, "{} crossings instead of {}", crossings, expected);
+            assert!(samples.iter().all(|s| s.unsigned_abs() < i16::MAX as u16));
+        }
+    }
+
+    #[test]
+    fn test_band_limited() {
+        // Above 22.05 kHz nothing should be left, instead of aliases
+        let samples = square_wave(17);
+        let peak = samples[SAMPLE_RATE as usize / 2..].iter().map(|s| s.unsigned_abs()).max().unwrap();
+        assert!(peak < (VOLUME / 20.0) as u16, "peak is {}", peak);
+
+        // A single step takes a few samples
+        let mut speaker = Speaker::new();
+        speaker.toggle(1000);
+        speaker.update(2000);
+        let samples = speaker.take_samples();
+        let rise: Vec<_> = samples.windows(2).map(|w| w[1] - w[0]).collect();
+        assert!(rise.iter().filter(|&&d| d > 1000).count() >= 2);
+        assert!(rise.iter().all(|&d| d < (VOLUME * 0.7) as i16));
+    }
+
+    #[test]
+    fn test_max_samples() {
+        let mut speaker = Speaker::new();
+        speaker.update(10 * APPLE2_CLOCK);
+        assert_eq!(speaker.samples().count(), MAX_SAMPLES);
+    }
+
+    #[test]
+    fn test_wav() {
+        let wav = to_wav(&[1, -2]);
+        assert_eq!(wav.len(), 48);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav[4..8], 40u32.to_le_bytes());
+        assert_eq!(wav[24..28], 44100u32.to_le_bytes());
+        assert_eq!(wav[40..44], 4u32.to_le_bytes());
+        assert_eq!(wav[44..], [0x01, 0x00, 0xfe, 0xff]);
+    }
+}
@@ -0,0 +1,799 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::*;
+
+// The picture is rendered from the memory and the soft switches alone, so it
+// can be tested without a window.
+//
+// Every mode is rendered 560 pixels wide, the resolution of 80 column text.
+// The dots of the 40 column modes are two pixels wide. Each line is first
+// turned into the 560 dots the video hardware sends out, one every 14 MHz
+// cycle, and then the monitor makes a picture of them.
+//
+// A monochrome monitor shows the dots as they are. On a color monitor four
+// dots in a row are one cycle of the color carrier, and which of them are on
+// gives the color. Lo-res simply sends the four bits of a color over and
+// over. Hi-res dots are two of those dots wide, so every other hi-res dot on
+// is violet or green, and bit 7 of a hi-res byte delays its dots by one to
+// make them blue or orange. Text is always white, the color killer turns the
+// color off for it.
+//
+// The text page has 24 rows of 40 characters, interleaved in memory: row r
+// starts at $0400 + (r % 8) * $80 + (r / 8) * $28, or the same in $0800 for
+// page 2. 80 column text takes the even columns from auxiliary memory. The
+// screen code of a character says how it looks:
+//
+//   $00-$3F  Inverse @A-Z[\]^_ space and !"#$%&'()*+,-./0-9:;<=>?
+//   $40-$7F  The same flashing, or with the //e alternate character set,
+//            MouseText at $40-$5F and inverse lower case at $60-$7F
+//   $80-$FF  Normal, with lower case at $E0-$FF on the //e
+//
+// Lo-res uses the text page, each byte is two blocks of 7 dots by 4 lines
+// with the top one in the low nibble. Hi-res has 192 lines of 40 bytes with
+// the dots in bits 0-6, line y starts at $2000 + (y % 8) * $400 +
+// (y / 8 % 8) * $80 + (y / 64) * $28, or the same in $4000 for page 2.
+//
+// Double hi-res on the //e needs 80 columns as well. It interleaves the
+// hi-res pages of auxiliary and main memory like 80 column text, 80 bytes of
+// 7 dots that are one pixel wide, and bit 7 does not delay them. Four dots
+// in a row again give one of the 16 colors.
+
+pub const SCREEN_WIDTH: usize = 560;
+pub const SCREEN_HEIGHT: usize = 192;
+
+// A frame is 262 lines of 65 cycles. The vertical blank starts after the 192
+// visible lines.
+pub const CYCLES_PER_FRAME: u64 = 262 * 65;
+pub const VBL_START: u64 = 192 * 65;
+
+// Flashing characters switch between normal and inverse every 16 frames
+const FLASH_FRAMES: u64 = 16;
+
+pub type Color = [u8; 4];
+
+pub const BLACK: Color = [0x00, 0x00, 0x00, 0xff];
+pub const WHITE: Color = [0xff, 0xff, 0xff, 0xff];
+
+// The 16 lo-res colors, each of them a pattern of four dots with the first
+// one in bit 0
+pub const COLORS: [Color; 16] = [
+    [0x00, 0x00, 0x00, 0xff], // Black
+    [0xdd, 0x00, 0x33, 0xff], // Magenta
+    [0x00, 0x00, 0x99, 0xff], // Dark blue
+    [0xdd, 0x22, 0xdd, 0xff], // Violet
+    [0x00, 0x77, 0x22, 0xff], // Dark green
+    [0x55, 0x55, 0x55, 0xff], // Grey
+    [0x22, 0x22, 0xff, 0xff], // Medium blue
+    [0x66, 0xaa, 0xff, 0xff], // Light blue
+    [0x88, 0x55, 0x00, 0xff], // Brown
+    [0xff, 0x66, 0x00, 0xff], // Orange
+    [0xaa, 0xaa, 0xaa, 0xff], // Grey
+    [0xff, 0x99, 0x88, 0xff], // Pink
+    [0x11, 0xdd, 0x00, 0xff], // Green
+    [0xff, 0xff, 0x00, 0xff], // Yellow
+    [0x44, 0xff, 0x99, 0xff], // Aquamarine
+    [0xff, 0xff, 0xff, 0xff], // White
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Monitor {
+    #[default]
+    Color,
+    Monochrome,
+}
+
+// RGBA pixels, row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer { width, height, pixels: BLACK.repeat(width * height) }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        let i = (y * self.width + x) * 4;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        let i = (y * self.width + x) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&color);
+    }
+
+    // A binary PPM, P6
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for pixel in self.pixels.chunks(4) {
+            ppm.extend(&pixel[..3]);
+        }
+        ppm
+    }
+
+    pub fn from_ppm(ppm: &[u8]) -> Option<Self> {
+        // The header is four numbers after P6, separated by white space
+        let mut fields = Vec::new();
+        let mut i = 2;
+        if !ppm.starts_with(b"P6") {
+            return None;
+        }
+        while fields.len() < 3 {
+            while ppm.get(i)?.is_ascii_whitespace() {
+                i += 1;
+            }
+            let start = i;
+            while ppm.get(i)?.is_ascii_digit() {
+                i += 1;
+            }
+            fields.push(std::str::from_utf8(&ppm[start..i]).ok()?.parse::<usize>().ok()?);
+        }
+        let (width, height) = (fields[0], fields[1]);
+        let data = ppm.get(i + 1..i + 1 + width * height * 3).filter(|_| fields[2] == 255)?;
+        let pixels = data.chunks(3).flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xff]).collect();
+        Some(Framebuffer { width, height, pixels })
+    }
+
+    // The differences to another framebuffer, for tests that compare with a
+    // golden image
+    pub fn diff(&self, other: &Framebuffer) -> Option<String> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return Some(format!("{}x{} is not {}x{}", self.width, self.height, other.width, other.height));
+        }
+        let mut diff = String::new();
+        let mut count = 0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.pixel(x, y) != other.pixel(x, y) {
+                    if count < 10 {
+                        writeln!(diff, "({}, {}): {:02X?} is not {:02X?}", x, y, self.pixel(x, y), other.pixel(x, y)).unwrap();
+                    }
+                    count += 1;
+                }
+            }
+        }
+        if count > 10 {
+            writeln!(diff, "and {} more", count - 10).unwrap();
+        }
+        if count > 0 { Some(diff) } else { None }
+    }
+}
+
+// The character generator: 8 rows of 7 dots for each of the 128 ASCII codes,
+// with the first dot in bit 0. $00-$1F are the MouseText characters.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharacterRom {
+    glyphs: Vec<u8>,
+}
+
+pub const CHARACTER_ROM_SIZE: usize = 128 * 8;
+
+impl CharacterRom {
+    pub fn new(glyphs: Vec<u8>) -> Result<Self, ComputerError> {
+        if glyphs.len() != CHARACTER_ROM_SIZE {
+            return Err(ComputerError::InvalidRom { expected: CHARACTER_ROM_SIZE, actual: glyphs.len() });
+        }
+        Ok(CharacterRom { glyphs })
+    }
+
+    pub fn row(&self, code: u8, row: usize) -> u8 {
+        self.glyphs[(code as usize & 0x7f) * 8 + row]
+    }
+}
+
+// A font of our own, as the ones of Apple are not part of this repository. It
+// has no MouseText, those show as upper case.
+
+impl Default for CharacterRom {
+    fn default() -> Self {
+        let mut glyphs = Vec::with_capacity(CHARACTER_ROM_SIZE);
+        glyphs.extend(FONT[0x20..0x40].iter().flatten());
+        glyphs.extend(FONT.iter().flatten());
+        CharacterRom { glyphs }
+    }
+}
+
+// What the video hardware looks at
+
+#[derive(Debug, Clone, Copy)]
+pub struct Display<'a> {
+    pub model: Apple2Model,
+    pub mode: VideoMode,
+    // The page that is shown, which is not what the PAGE2 switch says when
+    // 80STORE is on
+    pub page2: bool,
+    // Whether flashing characters are inverse at the moment
+    pub flash: bool,
+    pub main: &'a [u8],
+    // Empty on the ][+
+    pub aux: &'a [u8],
+    pub characters: &'a CharacterRom,
+    pub monitor: Monitor,
+}
+
+// The dots of a line, as they go to the monitor
+type Dots = [bool; SCREEN_WIDTH];
+
+fn text_address(page2: bool, row: usize) -> usize {
+    (if page2 { 0x0800 } else { 0x0400 }) + (row % 8) * 0x80 + (row / 8) * 0x28
+}
+
+fn hires_address(page2: bool, y: usize) -> usize {
+    (if page2 { 0x4000 } else { 0x2000 }) + (y % 8) * 0x400 + (y / 8 % 8) * 0x80 + (y / 64) * 0x28
+}
+
+// The color of a dot is the cycle of the color carrier around it
+fn artifact_color(dots: &Dots, x: usize) -> Color {
+    let start = x.saturating_sub(1).min(SCREEN_WIDTH - 4);
+    let mut color = 0;
+    for (i, &on) in dots.iter().enumerate().skip(start).take(4) {
+        color |= (on as usize) << (i % 4);
+    }
+    COLORS[color]
+}
+
+impl Display<'_> {
+    pub fn render(&self) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        for y in 0..SCREEN_HEIGHT {
+            // Mixed mode has four rows of text at the bottom
+            let text = self.mode.text || (self.mode.mixed && y >= 160);
+            let dots = match (text, self.mode.hires) {
+                (true, _) => self.text_line(y),
+                (false, true) if self.double_hires() => self.double_hires_line(y),
+                (false, true) => self.hires_line(y),
+                (false, false) => self.lores_line(y),
+            };
+            for (x, &on) in dots.iter().enumerate() {
+                let color = match self.monitor {
+                    Monitor::Color if !text => artifact_color(&dots, x),
+                    _ if on => WHITE,
+                    _ => BLACK,
+                };
+                framebuffer.set_pixel(x, y, color);
+            }
+        }
+        framebuffer
+    }
+
+    // The glyph and whether it is inverse
+    fn character(&self, code: u8) -> (u8, bool) {
+        // The ASCII code of the upper case and symbols in $00-$3F
+        let upper = |code: u8| if code & 0x20 == 0 { (code & 0x1f) | 0x40 } else { code & 0x3f };
+        match code {
+            0x00..=0x3f => (upper(code), true),
+            0x40..=0x5f if self.mode.altchar && self.model == Apple2Model::EnhancedIIe => (code & 0x1f, false),
+            0x40..=0x7f if self.mode.altchar && self.model.is_iie() => (if code < 0x60 { upper(code) } else { code }, true),
+            0x40..=0x7f => (upper(code), self.flash),
+            0xe0..=0xff if self.model.is_iie() => (code & 0x7f, false),
+            _ => (upper(code), false),
+        }
+    }
+
+    fn text_line(&self, y: usize) -> Dots {
+        let mut dots = [false; SCREEN_WIDTH];
+        let address = text_address(self.page2, y / 8);
+        let col80 = self.mode.col80 && self.model.is_iie();
+        let (columns, dot_width) = if col80 { (80, 1) } else { (40, 2) };
+        for column in 0..columns {
+            let code = match col80 {
+                true if column % 2 == 0 => self.aux[address + column / 2],
+                true => self.main[address + column / 2],
+                false => self.main[address + column],
+            };
+            let (glyph, inverse) = self.character(code);
+            let row = self.characters.row(glyph, y % 8);
+            for dot in 0..7 {
+                let x = (column * 7 + dot) * dot_width;
+                dots[x..x + dot_width].fill(((row >> dot) & 1 != 0) != inverse);
+            }
+        }
+        dots
+    }
+
+    fn lores_line(&self, y: usize) -> Dots {
+        let mut dots = [false; SCREEN_WIDTH];
+        let address = text_address(self.page2, y / 8);
+        for column in 0..40 {
+            let byte = self.main[address + column];
+            let color = if y % 8 < 4 { byte & 0x0f } else { byte >> 4 };
+            for (x, dot) in dots.iter_mut().enumerate().skip(column * 14).take(14) {
+                *dot = (color >> (x % 4)) & 1 != 0;
+            }
+        }
+        dots
+    }
+
+    fn hires_line(&self, y: usize) -> Dots {
+        let mut dots = [false; SCREEN_WIDTH];
+        let address = hires_address(self.page2, y);
+        for column in 0..40 {
+            let byte = self.main[address + column];
+            let x = column * 14;
+            let delay = (byte >> 7) as usize;
+            // The last dot of the byte before lasts a bit longer
+            if delay == 1 && x > 0 {
+                dots[x] = dots[x - 1];
+            }
+            for dot in 0..7 {
+                let start = x + dot * 2 + delay;
+                let end = (start + 2).min(SCREEN_WIDTH);
+                dots[start..end].fill((byte >> dot) & 1 != 0);
+            }
+        }
+        dots
+    }
+
+    fn double_hires(&self) -> bool {
+        self.mode.double_hires && self.mode.col80 && self.model.is_iie()
+    }
+
+    fn double_hires_line(&self, y: usize) -> Dots {
+        let mut dots = [false; SCREEN_WIDTH];
+        let address = hires_address(self.page2, y);
+        for column in 0..80 {
+            let memory = if column % 2 == 0 { self.aux } else { self.main };
+            let byte = memory[address + column / 2];
+            for dot in 0..7 {
+                dots[column * 7 + dot] = (byte >> dot) & 1 != 0;
+            }
+        }
+        dots
+    }
+}
+
+impl Apple2Bus {
+    pub fn display(&self) -> Display<'_> {
+        let (main, aux) = self.ram.split_at(0x10000);
+        Display {
+            model: self.model,
+            mode: self.video,
+            page2: self.display_page2(),
+            flash: (self.clock / (CYCLES_PER_FRAME * FLASH_FRAMES)) % 2 == 1,
+            main,
+            aux,
+            characters: &self.characters,
+            monitor: self.monitor,
+        }
+    }
+}
+
+impl Computer<Apple2Bus> {
+    pub fn render(&self) -> Framebuffer {
+        self.cpu.bus.display().render()
+    }
+
+    // Writes the screen to a PNG or PPM file, whichever the extension says
+    pub fn screenshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        let image = match extension.as_deref() {
+            Some("png") => self.render().to_png(),
+            Some("ppm") => self.render().to_ppm(),
+            _ => {
+                let message = format!("{}: screenshots are .png or .ppm files", path.display());
+                return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+            }
+        };
+        fs::write(path, image)
+    }
+}
+
+// $20-$7F of the built in font
+const FONT: [[u8; 8]; 96] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x08, 0x08, 0x08, 0x08, 0x08, 0x00, 0x08, 0x00], // !
+    [0x14, 0x14, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00], // "
+    [0x14, 0x14, 0x3e, 0x14, 0x3e, 0x14, 0x14, 0x00], // #
+    [0x08, 0x3c, 0x0a, 0x1c, 0x28, 0x1e, 0x08, 0x00], // $
+    [0x06, 0x26, 0x10, 0x08, 0x04, 0x32, 0x30, 0x00], // %
+    [0x04, 0x0a, 0x0a, 0x04, 0x2a, 0x12, 0x2c, 0x00], // &
+    [0x08, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00], // '
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08, 0x00], // (
+    [0x08, 0x10, 0x20, 0x20, 0x20, 0x10, 0x08, 0x00], // )
+    [0x08, 0x2a, 0x1c, 0x08, 0x1c, 0x2a, 0x08, 0x00], // *
+    [0x00, 0x08, 0x08, 0x3e, 0x08, 0x08, 0x00, 0x00], // +
+    [0x00, 0x00, 0x00, 0x00, 0x08, 0x08, 0x04, 0x00], // ,
+    [0x00, 0x00, 0x00, 0x3e, 0x00, 0x00, 0x00, 0x00], // -
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00], // .
+    [0x00, 0x20, 0x10, 0x08, 0x04, 0x02, 0x00, 0x00], // /
+    [0x1c, 0x22, 0x32, 0x2a, 0x26, 0x22, 0x1c, 0x00], // 0
+    [0x08, 0x0c, 0x08, 0x08, 0x08, 0x08, 0x1c, 0x00], // 1
+    [0x1c, 0x22, 0x20, 0x18, 0x04, 0x02, 0x3e, 0x00], // 2
+    [0x3e, 0x20, 0x10, 0x18, 0x20, 0x22, 0x1c, 0x00], // 3
+    [0x10, 0x18, 0x14, 0x12, 0x3e, 0x10, 0x10, 0x00], // 4
+    [0x3e, 0x02, 0x1e, 0x20, 0x20, 0x22, 0x1c, 0x00], // 5
+    [0x38, 0x04, 0x02, 0x1e, 0x22, 0x22, 0x1c, 0x00], // 6
+    [0x3e, 0x20, 0x10, 0x08, 0x04, 0x04, 0x04, 0x00], // 7
+    [0x1c, 0x22, 0x22, 0x1c, 0x22, 0x22, 0x1c, 0x00], // 8
+    [0x1c, 0x22, 0x22, 0x3c, 0x20, 0x10, 0x0e, 0x00], // 9
+    [0x00, 0x00, 0x08, 0x00, 0x08, 0x00, 0x00, 0x00], // :
+    [0x00, 0x00, 0x08, 0x00, 0x08, 0x08, 0x04, 0x00], // ;
+    [0x10, 0x08, 0x04, 0x02, 0x04, 0x08, 0x10, 0x00], // <
+    [0x00, 0x00, 0x3e, 0x00, 0x3e, 0x00, 0x00, 0x00], // =
+    [0x04, 0x08, 0x10, 0x20, 0x10, 0x08, 0x04, 0x00], // >
+    [0x1c, 0x22, 0x10, 0x08, 0x08, 0x00, 0x08, 0x00], // ?
+    [0x1c, 0x22, 0x2a, 0x3a, 0x1a, 0x02, 0x3c, 0x00], // @
+    [0x08, 0x14, 0x22, 0x22, 0x3e, 0x22, 0x22, 0x00], // A
+    [0x1e, 0x22, 0x22, 0x1e, 0x22, 0x22, 0x1e, 0x00], // B
+    [0x1c, 0x22, 0x02, 0x02, 0x02, 0x22, 0x1c, 0x00], // C
+    [0x1e, 0x22, 0x22, 0x22, 0x22, 0x22, 0x1e, 0x00], // D
+    [0x3e, 0x02, 0x02, 0x1e, 0x02, 0x02, 0x3e, 0x00], // E
+    [0x3e, 0x02, 0x02, 0x1e, 0x02, 0x02, 0x02, 0x00], // F
+    [0x3c, 0x02, 0x02, 0x02, 0x32, 0x22, 0x3c, 0x00], // G
+    [0x22, 0x22, 0x22, 0x3e, 0x22, 0x22, 0x22, 0x00], // H
+    [0x1c, 0x08, 0x08, 0x08, 0x08, 0x08, 0x1c, 0x00], // I
+    [0x20, 0x20, 0x20, 0x20, 0x20, 0x22, 0x1c, 0x00], // J
+    [0x22, 0x12, 0x0a, 0x06, 0x0a, 0x12, 0x22, 0x00], // K
+    [0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x3e, 0x00], // L
+    [0x22, 0x36, 0x2a, 0x2a, 0x22, 0x22, 0x22, 0x00], // M
+    [0x22, 0x22, 0x26, 0x2a, 0x32, 0x22, 0x22, 0x00], // N
+    [0x1c, 0x22, 0x22, 0x22, 0x22, 0x22, 0x1c, 0x00], // O
+    [0x1e, 0x22, 0x22, 0x1e, 0x02, 0x02, 0x02, 0x00], // P
+    [0x1c, 0x22, 0x22, 0x22, 0x2a, 0x12, 0x2c, 0x00], // Q
+    [0x1e, 0x22, 0x22, 0x1e, 0x0a, 0x12, 0x22, 0x00], // R
+    [0x1c, 0x22, 0x02, 0x1c, 0x20, 0x22, 0x1c, 0x00], // S
+    [0x3e, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x00], // T
+    [0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x1c, 0x00], // U
+    [0x22, 0x22, 0x22, 0x22, 0x22, 0x14, 0x08, 0x00], // V
+    [0x22, 0x22, 0x22, 0x2a, 0x2a, 0x36, 0x22, 0x00], // W
+    [0x22, 0x22, 0x14, 0x08, 0x14, 0x22, 0x22, 0x00], // X
+    [0x22, 0x22, 0x14, 0x08, 0x08, 0x08, 0x08, 0x00], // Y
+    [0x3e, 0x20, 0x10, 0x08, 0x04, 0x02, 0x3e, 0x00], // Z
+    [0x3e, 0x06, 0x06, 0x06, 0x06, 0x06, 0x3e, 0x00], // [
+    [0x00, 0x02, 0x04, 0x08, 0x10, 0x20, 0x00, 0x00], // \
+    [0x3e, 0x30, 0x30, 0x30, 0x30, 0x30, 0x3e, 0x00], // ]
+    [0x00, 0x00, 0x08, 0x14, 0x22, 0x00, 0x00, 0x00], // ^
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x3e, 0x00], // _
+    [0x04, 0x08, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00], // `
+    [0x00, 0x00, 0x1c, 0x20, 0x3c, 0x22, 0x3c, 0x00], // a
+    [0x02, 0x02, 0x1e, 0x22, 0x22, 0x22, 0x1e, 0x00], // b
+    [0x00, 0x00, 0x3c, 0x02, 0x02, 0x02, 0x3c, 0x00], // c
+    [0x20, 0x20, 0x3c, 0x22, 0x22, 0x22, 0x3c, 0x00], // d
+    [0x00, 0x00, 0x1c, 0x22, 0x3e, 0x02, 0x3c, 0x00], // e
+    [0x18, 0x24, 0x04, 0x1e, 0x04, 0x04, 0x04, 0x00], // f
+    [0x00, 0x00, 0x1c, 0x22, 0x3c, 0x20, 0x1c, 0x00], // g
+    [0x02, 0x02, 0x1e, 0x22, 0x22, 0x22, 0x22, 0x00], // h
+    [0x08, 0x00, 0x0c, 0x08, 0x08, 0x08, 0x1c, 0x00], // i
+    [0x10, 0x00, 0x18, 0x10, 0x10, 0x12, 0x0c, 0x00], // j
+    [0x02, 0x02, 0x22, 0x12, 0x0e, 0x12, 0x22, 0x00], // k
+    [0x0c, 0x08, 0x08, 0x08, 0x08, 0x08, 0x1c, 0x00], // l
+    [0x00, 0x00, 0x16, 0x2a, 0x2a, 0x2a, 0x22, 0x00], // m
+    [0x00, 0x00, 0x1e, 0x22, 0x22, 0x22, 0x22, 0x00], // n
+    [0x00, 0x00, 0x1c, 0x22, 0x22, 0x22, 0x1c, 0x00], // o
+    [0x00, 0x00, 0x1e, 0x22, 0x1e, 0x02, 0x02, 0x00], // p
+    [0x00, 0x00, 0x3c, 0x22, 0x3c, 0x20, 0x20, 0x00], // q
+    [0x00, 0x00, 0x3a, 0x06, 0x02, 0x02, 0x02, 0x00], // r
+    [0x00, 0x00, 0x3c, 0x02, 0x1c, 0x20, 0x1e, 0x00], // s
+    [0x04, 0x04, 0x1e, 0x04, 0x04, 0x24, 0x18, 0x00], // t
+    [0x00, 0x00, 0x22, 0x22, 0x22, 0x32, 0x2c, 0x00], // u
+    [0x00, 0x00, 0x22, 0x22, 0x22, 0x14, 0x08, 0x00], // v
+    [0x00, 0x00, 0x22, 0x22, 0x2a, 0x2a, 0x14, 0x00], // w
+    [0x00, 0x00, 0x22, 0x14, 0x08, 0x14, 0x22, 0x00], // x
+    [0x00, 0x00, 0x22, 0x22, 0x3c, 0x20, 0x1c, 0x00], // y
+    [0x00, 0x00, 0x3e, 0x10, 0x08, 0x04, 0x3e, 0x00], // z
+    [0x30, 0x08, 0x08, 0x04, 0x08, 0x08, 0x30, 0x00], // {
+    [0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x00], // |
+    [0x06, 0x08, 0x08, 0x10, 0x08, 0x08, 0x06, 0x00], // }
+    [0x24, 0x1a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ~
+    [0x00, 0x2a, 0x14, 0x2a, 0x14, 0x2a, 0x00, 0x00], // checkerboard
+];
+
+#[cfg(test)]
+mod golden_tests;
+
+#[cfg(test)]
+mod video_tests {
+    use super::*;
+    use super::super::fixtures::computer;
+
+    // The dots of a character cell, a line each, # for white
+    fn cell(framebuffer: &Framebuffer, x: usize, y: usize, dot_width: usize) -> Vec<String> {
+        (0..8).map(|line| {
+            (0..7).map(|dot| if framebuffer.pixel(x + dot * dot_width, y + line) == WHITE { '#' } else { '.' }).collect()
+        }).collect()
+    }
+
+    const A: [&str; 8] = ["...#...", "..#.#..", ".#...#.", ".#...#.", ".#####.", ".#...#.", ".#...#.", "......."];
+    const LOWER_A: [&str; 8] = [".......", ".......", "..###..", ".....#.", "..####.", ".#...#.", "..####.", "......."];
+
+    fn inverse(cell: &[&str]) -> Vec<String> {
+        cell.iter().map(|line| line.chars().map(|c| if c == '#' { '.' } else { '#' }).collect()).collect()
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut apple2 = computer(Apple2Model::Plus);
+        apple2.cpu.set_byte(0x0400, 0xc1);
+        apple2.cpu.set_byte(0x0480 + 39, 0xc1);
+        apple2.cpu.set_byte(0x0428, 0xc1);
+        apple2.cpu.set_byte(0x07d0 + 39, 0xc1);
+        let framebuffer = apple2.render();
+        assert_eq!(cell(&framebuffer, 0, 0, 2), A);
+        assert_eq!(cell(&framebuffer, 39 * 14, 8, 2), A);
+        assert_eq!(cell(&framebuffer, 0, 8 * 8, 2), A);
+        assert_eq!(cell(&framebuffer, 39 * 14, 23 * 8, 2), A);
+        // Dots are two pixels wide
+        assert_eq!(framebuffer.pixel(7, 0), WHITE);
+        assert_eq!(framebuffer.pixel(8, 0), BLACK);
+
+        // Page 2
+        apple2.cpu.get_byte(0xc055);
+        apple2.cpu.set_byte(0x0800, 0xc1);
+        assert_eq!(cell(&apple2.render(), 0, 0, 2), A);
+
+        // Without text, only the four rows of mixed mode are left
+        apple2.cpu.get_byte(0xc054);
+        apple2.cpu.get_byte(0xc050);
+        assert_ne!(cell(&apple2.render(), 39 * 14, 23 * 8, 2), A);
+        apple2.cpu.get_byte(0xc053);
+        let framebuffer = apple2.render();
+        assert_eq!(cell(&framebuffer, 39 * 14, 23 * 8, 2), A);
+        assert_eq!(framebuffer.pixel(7, 0), COLORS[0x01]);
+    }
+
+    #[test]
+    fn test_lores() {
+        let mut apple2 = computer(Apple2Model::Plus);
+        apple2.cpu.get_byte(0xc050);
+        apple2.cpu.set_byte(0x0400, 0x9d);
+        apple2.cpu.set_byte(0x07d0 + 39, 0x60);
+        let framebuffer = apple2.render();
+        // Blocks are 14 pixels by 4 lines, the top one in the low nibble
+        for (x, y) in [(0, 0), (11, 3)] {
+            assert_eq!(framebuffer.pixel(x, y), COLORS[0x0d]);
+        }
+        for (x, y) in [(0, 4), (11, 7)] {
+            assert_eq!(framebuffer.pixel(x, y), COLORS[0x09]);
+        }
+        assert_eq!(framebuffer.pixel(39 * 14 + 2, 188), COLORS[0x06]);
+        assert_eq!(framebuffer.pixel(559, 191), COLORS[0x06]);
+        assert_eq!(framebuffer.pixel(14, 0), BLACK);
+
+        // A monochrome monitor shows the dots of the color: yellow is 1011
+        apple2.cpu.bus.monitor = Monitor::Monochrome;
+        let framebuffer = apple2.render();
+        let dots: Vec<_> = (0..8).map(|x| framebuffer.pixel(x, 0)).collect();
+        assert_eq!(dots, [WHITE, BLACK, WHITE, WHITE, WHITE, BLACK, WHITE, WHITE]);
+    }
+
+    #[test]
+    fn test_hires_addresses() {
+        assert_eq!(hires_address(false, 0), 0x2000);
+        assert_eq!(hires_address(false, 1), 0x2400);
+        assert_eq!(hires_address(false, 8), 0x2080);
+        assert_eq!(hires_address(false, 64), 0x2028);
+        assert_eq!(hires_address(false, 191), 0x3fd0);
+        assert_eq!(hires_address(true, 0), 0x4000);
+    }
+
+    #[test]
+    fn test_hires() {
+        let mut apple2 = computer(Apple2Model::Plus);
+        apple2.cpu.get_byte(0xc050);
+        apple2.cpu.get_byte(0xc057);
+        apple2.cpu.bus.monitor = Monitor::Monochrome;
+        // A dot is two pixels, bit 0 first
+        apple2.cpu.set_byte(0x2000, 0x05);
+        let framebuffer = apple2.render();
+        let dots: Vec<_> = (0..6).map(|x| framebuffer.pixel(x, 0) == WHITE).collect();
+        assert_eq!(dots, [true, true, false, false, true, true]);
+
+        // Bit 7 delays the dots by a pixel, and the last dot of the byte
+        // before lasts a pixel longer
+        apple2.cpu.set_byte(0x2000, 0x40);
+        apple2.cpu.set_byte(0x2001, 0x80);
+        let framebuffer = apple2.render();
+        let dots: Vec<_> = (11..16).map(|x| framebuffer.pixel(x, 0) == WHITE).collect();
+        assert_eq!(dots, [false, true, true, true, false]);
+
+        // Page 2 and the last line
+        apple2.cpu.get_byte(0xc055);
+        apple2.cpu.set_byte(0x5fd0 + 39, 0x7f);
+        let framebuffer = apple2.render();
+        assert_eq!(framebuffer.pixel(12, 0), BLACK);
+        assert_eq!(framebuffer.pixel(559, 191), WHITE);
+    }
+
+    #[test]
+    fn test_artifact_colors() {
+        let mut apple2 = computer(Apple2Model::Plus);
+        apple2.cpu.get_byte(0xc050);
+        apple2.cpu.get_byte(0xc057);
+        // Every other dot, on even or odd dots, with or without the delay.
+        // Columns have an odd number of dots so the bytes alternate.
+        let patterns = [[0x55, 0x2a], [0x2a, 0x55], [0xd5, 0xaa], [0xaa, 0xd5], [0x7f, 0x7f], [0x00, 0x00]];
+        for (i, pattern) in patterns.into_iter().enumerate() {
+            for column in 0..40 {
+                apple2.cpu.set_byte((hires_address(false, i * 8) + column) as u16, pattern[column % 2]);
+            }
+        }
+        let framebuffer = apple2.render();
+        let colors = [COLORS[0x03], COLORS[0x0c], COLORS[0x06], COLORS[0x09], WHITE, BLACK];
+        for (i, color) in colors.into_iter().enumerate() {
+            assert_eq!(framebuffer.pixel(280, i * 8), color);
+        }
+
+        // Mixed mode text has no color
+        apple2.cpu.get_byte(0xc053);
+        apple2.cpu.set_byte(0x0650, 0x20);
+        let framebuffer = apple2.render();
+        assert!((0..SCREEN_WIDTH).all(|x| [BLACK, WHITE].contains(&framebuffer.pixel(x, 160))));
+    }
+
+    #[test]
+    fn test_inverse_and_flashing() {
+        let mut apple2 = computer(Apple2Model::Plus);
+        apple2.cpu.set_byte(0x0400, 0x01);
+        apple2.cpu.set_byte(0x0401, 0x41);
+        apple2.cpu.set_byte(0x0402, 0xe1);
+        apple2.cpu.set_byte(0x0403, 0xa1);
+        let framebuffer = apple2.render();
+        assert_eq!(cell(&framebuffer, 0, 0, 2), inverse(&A));
+        assert_eq!(cell(&framebuffer, 14, 0, 2), A);
+        // No lower case on the ][+
+        assert_eq!(cell(&framebuffer, 28, 0, 2), cell(&framebuffer, 42, 0, 2));
+
+        apple2.cpu.bus.tick(CYCLES_PER_FRAME * FLASH_FRAMES);
+        assert_eq!(cell(&apple2.render(), 14, 0, 2), inverse(&A));
+        apple2.cpu.bus.tick(CYCLES_PER_FRAME * FLASH_FRAMES);
+        assert_eq!(cell(&apple2.render(), 14, 0, 2), A);
+    }
+
+    #[test]
+    fn test_iie_characters() {
+        let mut iie = computer(Apple2Model::EnhancedIIe);
+        iie.cpu.set_byte(0x0400, 0xe1);
+        iie.cpu.set_byte(0x0401, 0x61);
+        iie.cpu.set_byte(0x0402, 0x41);
+        assert_eq!(cell(&iie.render(), 0, 0, 2), LOWER_A);
+
+        // The alternate character set has inverse lower case instead of
+        // flashing, and MouseText
+        iie.cpu.set_byte(0xc00f, 0);
+        let framebuffer = iie.render();
+        assert_eq!(cell(&framebuffer, 14, 0, 2), inverse(&LOWER_A));
+        assert_eq!(cell(&framebuffer, 28, 0, 2), A);
+    }
+
+    #[test]
+    fn test_80_columns() {
+        let mut iie = computer(Apple2Model::IIe);
+        iie.cpu.set_byte(0x0400, 0xc1);
+        iie.cpu.set_byte(0xc001, 0);
+        iie.cpu.get_byte(0xc055);
+        iie.cpu.set_byte(0x0401, 0xc1);
+        iie.cpu.get_byte(0xc054);
+        iie.cpu.set_byte(0xc00d, 0);
+        let framebuffer = iie.render();
+        // Auxiliary memory first
+        assert_eq!(cell(&framebuffer, 0, 0, 1), cell(&framebuffer, 21, 0, 1));
+        assert_eq!(cell(&framebuffer, 7, 0, 1), A);
+        assert_eq!(cell(&framebuffer, 14, 0, 1), A);
+        assert_ne!(cell(&framebuffer, 21, 0, 1), A);
+    }
+
+    #[test]
+    fn test_double_hires() {
+        let mut iie = computer(Apple2Model::IIe);
+        iie.cpu.get_byte(0xc050);
+        iie.cpu.get_byte(0xc057);
+        iie.cpu.bus.monitor = Monitor::Monochrome;
+        // Write the first bytes of auxiliary memory with 80STORE and PAGE2
+        iie.cpu.set_byte(0xc001, 0);
+        iie.cpu.get_byte(0xc055);
+        iie.cpu.set_byte(0x2000, 0x81);
+        iie.cpu.set_byte(0x3fd0 + 39, 0x40);
+        iie.cpu.get_byte(0xc054);
+        iie.cpu.set_byte(0x2000, 0x41);
+
+        // Without 80 columns it is still hi-res
+        iie.cpu.get_byte(0xc05e);
+        let framebuffer = iie.render();
+        assert_eq!(framebuffer.pixel(1, 0), WHITE);
+        assert_eq!(framebuffer.pixel(2, 0), BLACK);
+
+        // Auxiliary memory first, a pixel a dot and no delay
+        iie.cpu.set_byte(0xc00d, 0);
+        let framebuffer = iie.render();
+        let dots: Vec<_> = (0..14).map(|x| framebuffer.pixel(x, 0) == WHITE).collect();
+        let mut expected = [false; 14];
+        expected[0] = true;
+        expected[7] = true;
+        expected[13] = true;
+        assert_eq!(dots, expected);
+        assert_eq!(framebuffer.pixel(559 - 7, 191), WHITE);
+        assert_eq!(framebuffer.pixel(559, 191), BLACK);
+
+        // Four dots are a color, magenta is the first one of them
+        iie.cpu.bus.monitor = Monitor::Color;
+        iie.cpu.get_byte(0xc055);
+        for column in (0..40).step_by(2) {
+            iie.cpu.set_byte(0x2400 + column, 0x11);
+            iie.cpu.set_byte(0x2400 + column + 1, 0x44);
+        }
+        iie.cpu.get_byte(0xc054);
+        for column in (0..40).step_by(2) {
+            iie.cpu.set_byte(0x2400 + column, 0x22);
+            iie.cpu.set_byte(0x2400 + column + 1, 0x08);
+        }
+        let framebuffer = iie.render();
+        assert!((0..SCREEN_WIDTH).all(|x| framebuffer.pixel(x, 1) == COLORS[0x01]));
+
+        // Switching annunciator 3 back on turns it off
+        iie.cpu.get_byte(0xc05f);
+        assert_ne!(iie.render(), framebuffer);
+    }
+
+    #[test]
+    fn test_character_rom() {
+        let error = CharacterRom::new(vec![0; 2048]).unwrap_err();
+        assert_eq!(error, ComputerError::InvalidRom { expected: 1024, actual: 2048 });
+        let mut apple2 = computer(Apple2Model::Plus);
+        // Memory is all inverse @
+        apple2.cpu.bus.characters = CharacterRom::new(vec![0x00; CHARACTER_ROM_SIZE]).unwrap();
+        assert!(apple2.render().pixels.chunks(4).all(|pixel| pixel == WHITE));
+    }
+
+    #[test]
+    fn test_screenshot() {
+        let mut apple2 = computer(Apple2Model::Plus);
+        apple2.cpu.set_byte(0x0400, 0xc1);
+        let path = std::env::temp_dir().join(format!("rewm-screenshot-test-{}.ppm", std::process::id()));
+        apple2.screenshot(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), apple2.render().to_ppm());
+        let path = path.with_extension("PNG");
+        apple2.screenshot(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), apple2.render().to_png());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("ppm")).unwrap();
+
+        let error = apple2.screenshot(path.with_extension("gif")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_ppm() {
+        let mut framebuffer = Framebuffer::new(3, 2);
+        framebuffer.set_pixel(2, 1, [1, 2, 3, 0xff]);
+        let ppm = framebuffer.to_ppm();
+        assert_eq!(&ppm[..11], b"P6\n3 2\n255\n");
+        assert_eq!(&ppm[ppm.len() - 3..], &[1, 2, 3]);
+        assert_eq!(Framebuffer::from_ppm(&ppm), Some(framebuffer.clone()));
+        assert_eq!(Framebuffer::from_ppm(b"P6 3 2 255\n"), None);
+
+        let mut other = framebuffer.clone();
+        other.set_pixel(0, 0, WHITE);
+        assert_eq!(framebuffer.diff(&framebuffer), None);
+        assert_eq!(framebuffer.diff(&other).unwrap(), "(0, 0): [00, 00, 00, FF] is not [FF, FF, FF, FF]\n");
+    }
+}
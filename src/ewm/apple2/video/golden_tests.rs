@@ -0,0 +1,136 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2022 Stefan Arentz - http://github.com/st3fan/rewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Renders screens and compares them with the golden images in
+// tests/fixtures/video. Run with REWM_UPDATE_GOLDEN=1 to write the images
+// again after a change to the renderer, and look at them before checking
+// them in.
+
+use std::env;
+use std::path::PathBuf;
+
+use super::*;
+use super::super::fixtures::computer;
+
+// Puts text on a row of the screen, like the monitor would, with the high
+// bit set for normal characters
+fn print(apple2: &mut Computer<Apple2Bus>, row: u16, column: u16, text: &[u8], high_bit: u8) {
+    let address = 0x0400 + (row % 8) * 0x80 + (row / 8) * 0x28 + column;
+    for (i, &c) in text.iter().enumerate() {
+        let code = if high_bit == 0 { c & 0x3f } else { c | high_bit };
+        apple2.cpu.set_byte(address + i as u16, code);
+    }
+}
+
+fn check_golden(name: &str, framebuffer: &Framebuffer) {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "video", name].iter().collect();
+    if env::var_os("REWM_UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, framebuffer.to_ppm()).unwrap();
+        return;
+    }
+    let ppm = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let golden = Framebuffer::from_ppm(&ppm).unwrap_or_else(|| panic!("{} is not a PPM image", path.display()));
+    if let Some(diff) = framebuffer.diff(&golden) {
+        panic!("The screen is not like {}:\n{}", path.display(), diff);
+    }
+}
+
+#[test]
+fn test_text_40_columns() {
+    let mut apple2 = computer(Apple2Model::Plus);
+    for row in 0..24 {
+        print(&mut apple2, row, 0, &[b' '; 40], 0x80);
+    }
+    print(&mut apple2, 0, 15, b"APPLE ][", 0x80);
+    print(&mut apple2, 2, 0, b"]10 PRINT \"HELLO, WORLD!\"", 0x80);
+    print(&mut apple2, 3, 0, b"]20 GOTO 10", 0x80);
+    print(&mut apple2, 4, 0, b"]RUN", 0x80);
+    for row in 5..22 {
+        print(&mut apple2, row, 0, b"HELLO, WORLD!", 0x80);
+    }
+    print(&mut apple2, 22, 0, b"INVERSE 0123456789 @[\\]^_ !#$%&'()*+-./:;<=>?", 0);
+    print(&mut apple2, 23, 0, b"]", 0x80);
+    print(&mut apple2, 23, 1, b" ", 0x40);
+    check_golden("text40.ppm", &apple2.render());
+}
+
+#[test]
+fn test_text_80_columns() {
+    let mut iie = computer(Apple2Model::EnhancedIIe);
+    // 80STORE and PAGE2 to write the even columns in auxiliary memory
+    iie.cpu.set_byte(0xc001, 0);
+    iie.cpu.set_byte(0xc00d, 0);
+    let line = b"The quick brown fox jumps over the lazy dog. 0123456789 {|}~ `abcdefghijklmnopqr";
+    let even: Vec<u8> = line.iter().step_by(2).copied().collect();
+    let odd: Vec<u8> = line.iter().skip(1).step_by(2).copied().collect();
+    for row in 0..24 {
+        iie.cpu.get_byte(0xc055);
+        print(&mut iie, row, 0, &even, 0x80);
+        iie.cpu.get_byte(0xc054);
+        print(&mut iie, row, 0, &odd, 0x80);
+    }
+    check_golden("text80.ppm", &iie.render());
+}
+
+// Bars of the 16 colors, with the text of mixed mode below them
+#[test]
+fn test_lores() {
+    let mut apple2 = computer(Apple2Model::Plus);
+    apple2.cpu.get_byte(0xc050);
+    apple2.cpu.get_byte(0xc053);
+    for row in 0..20u16 {
+        for column in 0..40 {
+            let color = (column / 5 + if row < 10 { 0 } else { 8 }) as u8;
+            apple2.cpu.set_byte(0x0400 + (row % 8) * 0x80 + (row / 8) * 0x28 + column, color | color << 4);
+        }
+    }
+    for row in 20..24 {
+        print(&mut apple2, row, 0, &[b' '; 40], 0x80);
+    }
+    print(&mut apple2, 21, 0, b"]GR", 0x80);
+    print(&mut apple2, 22, 0, b"]COLOR=13: PLOT 10,20", 0x80);
+    check_golden("lores.ppm", &apple2.render());
+}
+
+// Stripes of every pattern of hi-res dots, in color and monochrome
+#[test]
+fn test_hires() {
+    let mut apple2 = computer(Apple2Model::Plus);
+    apple2.cpu.get_byte(0xc050);
+    apple2.cpu.get_byte(0xc057);
+    let patterns = [[0x55, 0x2a], [0x2a, 0x55], [0xd5, 0xaa], [0xaa, 0xd5], [0x7f, 0x7f], [0xff, 0xff], [0x33, 0x66], [0x00, 0x00]];
+    for y in 0..192u16 {
+        let address = 0x2000 + (y % 8) * 0x400 + (y / 8 % 8) * 0x80 + (y / 64) * 0x28;
+        for column in 0..40 {
+            let byte = patterns[(y / 24) as usize][(column % 2) as usize];
+            apple2.cpu.set_byte(address + column, byte);
+        }
+        // A line from the top left to the bottom right
+        let x = y * 280 / 192;
+        let byte = apple2.cpu.get_byte(address + x / 7);
+        apple2.cpu.set_byte(address + x / 7, byte ^ (1 << (x % 7)));
+    }
+    check_golden("hires.ppm", &apple2.render());
+    apple2.cpu.bus.monitor = Monitor::Monochrome;
+    check_golden("hires_mono.ppm", &apple2.render());
+}
@@ -0,0 +1,322 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::io;
+
+use super::*;
+
+// WOZ 1.0 and 2.0 images, from the Applesauce. A 12 byte header, "WOZ1" or
+// "WOZ2" followed by $FF $0A $0D $0A and the CRC32 of the rest of the file,
+// is followed by chunks of a four character id, a 32 bit size and the data.
+// All numbers are little endian.
+//
+//   INFO  Version, disk type, write protection and, since 2.0, the optimal
+//         bit timing
+//   TMAP  The index of the track at each of the 160 quarter tracks, $FF for
+//         none
+//   TRKS  1.0: a 6656 byte entry for each track, with 6646 bytes of bits,
+//         the number of bytes and the number of bits used
+//         2.0: 160 entries of the first 512 byte block of the bits in the
+//         file, the number of blocks and the number of bits
+//   META  Tab separated keys and values, a line each
+//
+// Tracks written to are stored back where they were read from, so
+// everything else in the file stays as it was.
+
+const HEADER_SIZE: usize = 12;
+const TRACK_V1_SIZE: usize = 6656;
+const TRACK_V1_BITS_SIZE: usize = 6646;
+const BLOCK_SIZE: usize = 512;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid WOZ image: {}", message))
+}
+
+fn word(data: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes([data[offset], data[offset + 1]]) as usize
+}
+
+fn long(data: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize
+}
+
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub(super) struct Woz {
+    pub tracks: Vec<Track>,
+    pub track_map: [Option<usize>; QUARTER_TRACKS],
+    pub write_protected: bool,
+    pub bit_timing: u8,
+    pub metadata: Vec<(String, String)>,
+}
+
+// Where the bits of each track are in the file
+struct Layout {
+    version: u8,
+    info: Option<usize>,
+    tmap: Option<usize>,
+    tracks: Vec<(usize, usize)>,
+    meta: Option<(usize, usize)>,
+}
+
+fn layout(data: &[u8]) -> io::Result<Layout> {
+    if data.len() < HEADER_SIZE || data[4..8] != [0xff, 0x0a, 0x0d, 0x0a] {
+        return Err(invalid("no header"));
+    }
+    let version = match &data[..4] {
+        b"WOZ1" => 1,
+        b"WOZ2" => 2,
+        _ => return Err(invalid("unknown version")),
+    };
+    let crc = long(data, 8) as u32;
+    if crc != 0 && crc != crc32(&data[HEADER_SIZE..]) {
+        return Err(invalid("CRC mismatch"));
+    }
+
+    let mut layout = Layout { version, info: None, tmap: None, tracks: Vec::new(), meta: None };
+    let mut offset = HEADER_SIZE;
+    while offset + 8 <= data.len() {
+        let id = &data[offset..offset + 4];
+        let size = long(data, offset + 4);
+        let start = offset + 8;
+        if start + size > data.len() {
+            return Err(invalid("truncated chunk"));
+        }
+        match id {
+            b"INFO" if size >= 60 => layout.info = Some(start),
+            b"TMAP" if size >= QUARTER_TRACKS => layout.tmap = Some(start),
+            b"TRKS" if version == 1 => {
+                for entry in (start..start + size - size % TRACK_V1_SIZE).step_by(TRACK_V1_SIZE) {
+                    let bit_count = word(data, entry + TRACK_V1_BITS_SIZE + 2);
+                    if bit_count > TRACK_V1_BITS_SIZE * 8 {
+                        return Err(invalid("track too long"));
+                    }
+                    layout.tracks.push((entry, bit_count));
+                }
+            }
+            b"TRKS" if size >= QUARTER_TRACKS * 8 => {
+                for entry in (start..start + QUARTER_TRACKS * 8).step_by(8) {
+                    let (block, blocks, bit_count) = (word(data, entry), word(data, entry + 2), long(data, entry + 4));
+                    if (block * BLOCK_SIZE + blocks * BLOCK_SIZE) > data.len() || bit_count > blocks * BLOCK_SIZE * 8 {
+                        return Err(invalid("track outside of the file"));
+                    }
+                    layout.tracks.push((block * BLOCK_SIZE, if block == 0 { 0 } else { bit_count }));
+                }
+            }
+            b"META" => layout.meta = Some((start, size)),
+            _ => {}
+        }
+        offset = start + size;
+    }
+    Ok(layout)
+}
+
+pub(super) fn read(data: &[u8]) -> io::Result<Woz> {
+    let layout = layout(data)?;
+    let info = layout.info.ok_or_else(|| invalid("no INFO chunk"))?;
+    let tmap = layout.tmap.ok_or_else(|| invalid("no TMAP chunk"))?;
+    if data[info + 1] != 1 {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "Only 5.25\" WOZ images are supported"));
+    }
+    let bit_timing = match data[info + 39] {
+        timing if layout.version >= 2 && timing != 0 => timing,
+        _ => DEFAULT_BIT_TIMING,
+    };
+
+    let tracks: Vec<Track> = layout.tracks.iter()
+        .map(|&(offset, bit_count)| Track::new(data[offset..offset + bit_count.div_ceil(8)].to_vec(), bit_count))
+        .collect();
+    let mut track_map = [None; QUARTER_TRACKS];
+    for (quarter_track, track) in track_map.iter_mut().enumerate() {
+        let index = data[tmap + quarter_track] as usize;
+        if tracks.get(index).is_some_and(|track| track.bit_count() > 0) {
+            *track = Some(index);
+        }
+    }
+
+    let metadata = match layout.meta {
+        Some((start, size)) => String::from_utf8_lossy(&data[start..start + size])
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(Woz { tracks, track_map, write_protected: data[info + 2] != 0, bit_timing, metadata })
+}
+
+// The image with the bits of the tracks, which have to be the tracks read
+// from it
+
+pub(super) fn write(data: &[u8], tracks: &[Track]) -> Vec<u8> {
+    let layout = layout(data).expect("The image was valid when it was read");
+    let mut data = data.to_vec();
+    for (&(offset, _), track) in layout.tracks.iter().zip(tracks) {
+        data[offset..offset + track.data().len()].copy_from_slice(track.data());
+    }
+    let crc = crc32(&data[HEADER_SIZE..]);
+    data[8..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+    data
+}
+
+#[cfg(test)]
+mod woz_tests {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    fn info(version: u8, write_protected: bool, bit_timing: u8) -> Vec<u8> {
+        let mut info = vec![0; 60];
+        info[0] = version;
+        info[1] = 1;
+        info[2] = write_protected as u8;
+        info[39] = bit_timing;
+        info
+    }
+
+    fn with_header(version: &[u8], chunks: Vec<u8>) -> Vec<u8> {
+        let mut data = version.to_vec();
+        data.extend([0xff, 0x0a, 0x0d, 0x0a]);
+        data.extend(crc32(&chunks).to_le_bytes());
+        data.extend(chunks);
+        data
+    }
+
+    // A WOZ 2 image of the whole tracks of a disk. The header, INFO, TMAP
+    // and the entries of TRKS take up the first three blocks, the bits follow.
+    fn woz2(disk: &Disk) -> Vec<u8> {
+        let mut tmap = [0xff; QUARTER_TRACKS];
+        let mut trks = Vec::new();
+        let mut bits = Vec::new();
+        for track in 0..TRACKS {
+            let track_bits = disk.track(track * 4).unwrap();
+            tmap[track * 4] = track as u8;
+            let start = bits.len();
+            let blocks = track_bits.data().len().div_ceil(BLOCK_SIZE);
+            trks.extend(((3 + start / BLOCK_SIZE) as u16).to_le_bytes());
+            trks.extend((blocks as u16).to_le_bytes());
+            trks.extend((track_bits.bit_count() as u32).to_le_bytes());
+            bits.extend(track_bits.data());
+            bits.resize(start + blocks * BLOCK_SIZE, 0);
+        }
+        trks.resize(QUARTER_TRACKS * 8, 0);
+        let mut chunks = chunk(b"INFO", &info(2, false, 32));
+        chunks.extend(chunk(b"TMAP", &tmap));
+        chunks.extend(chunk(b"TRKS", &[trks, bits].concat()));
+        chunks.extend(chunk(b"META", b"title\tTest disk\nside\tSide A\n"));
+        with_header(b"WOZ2", chunks)
+    }
+
+    fn sector_disk() -> Disk {
+        let image = (0..SECTOR_IMAGE_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect();
+        Disk::from_sectors(DiskFormat::Dos33, image).unwrap()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_woz2() {
+        let sectors = sector_disk();
+        let mut disk = Disk::from_woz(woz2(&sectors)).unwrap();
+        assert_eq!(disk.format, DiskFormat::Woz);
+        assert!(!disk.write_protected);
+        assert!(!disk.write_back);
+        assert_eq!(disk.bit_timing(), 32);
+        assert_eq!(disk.track(4 * 12), sectors.track(4 * 12));
+        assert_eq!(disk.track(4 * 12 + 1), None);
+        assert_eq!(disk.metadata()[0], ("title".to_string(), "Test disk".to_string()));
+        assert_eq!(disk.to_sectors(), sectors.to_sectors());
+
+        // Writes go to the bits in memory, and to the image on flush
+        let track = disk.track_mut(4).unwrap();
+        for i in 0..16 {
+            track.set_bit(i, 0);
+        }
+        disk.flush().unwrap();
+        let written = Disk::from_woz(disk.image().to_vec()).unwrap();
+        assert_eq!(written.track(4), disk.track(4));
+        assert_ne!(written.track(4), sectors.track(4));
+        assert_eq!(written.metadata(), disk.metadata());
+    }
+
+    #[test]
+    fn test_woz1() {
+        let sectors = sector_disk();
+        let mut tmap = [0xff; QUARTER_TRACKS];
+        tmap[0] = 0;
+        tmap[1] = 0;
+        tmap[4] = 1;
+        let mut trks = Vec::new();
+        for track in [sectors.track(0).unwrap(), sectors.track(4).unwrap()] {
+            let mut entry = track.data().to_vec();
+            entry.resize(TRACK_V1_BITS_SIZE, 0);
+            entry.extend((track.data().len() as u16).to_le_bytes());
+            entry.extend((track.bit_count() as u16).to_le_bytes());
+            entry.extend([0xff; 6]);
+            trks.extend(entry);
+        }
+        let mut chunks = chunk(b"INFO", &info(1, true, 0));
+        chunks.extend(chunk(b"TMAP", &tmap));
+        chunks.extend(chunk(b"TRKS", &trks));
+        let disk = Disk::from_woz(with_header(b"WOZ1", chunks)).unwrap();
+        assert!(disk.write_protected);
+        assert_eq!(disk.bit_timing(), DEFAULT_BIT_TIMING);
+        assert_eq!(disk.track(1), sectors.track(0));
+        assert_eq!(disk.track(4), sectors.track(4));
+        assert_eq!(disk.track(8), None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut image = woz2(&sector_disk());
+        image[HEADER_SIZE + 20] ^= 1;
+        assert_eq!(Disk::from_woz(image).unwrap_err().to_string(), "Invalid WOZ image: CRC mismatch");
+
+        // A CRC of zero is not checked
+        let mut image = woz2(&sector_disk());
+        image[8..12].copy_from_slice(&[0; 4]);
+        image[HEADER_SIZE + 20] ^= 1;
+        assert!(Disk::from_woz(image).is_ok());
+
+        let image = with_header(b"WOZ2", chunk(b"INFO", &info(2, false, 32)));
+        assert_eq!(Disk::from_woz(image).unwrap_err().to_string(), "Invalid WOZ image: no TMAP chunk");
+        assert!(Disk::from_woz(b"WOZ3\xff\x0a\x0d\x0a\0\0\0\0".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,1025 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::cpu::Model;
+use super::disasm::{decode, Mode};
+
+// A two pass assembler for the 6502 and 65C02.
+//
+// The first pass works out the address of every label. When an operand
+// refers to a label that is not defined yet, it assumes the absolute
+// version of the instruction and the second pass keeps that choice, so
+// that the addresses do not shift between passes.
+//
+// The syntax is the usual one:
+//
+//   ; A comment
+//           .org $0300
+//   COUT    = $FDED
+//   start:  LDX #0
+//   @loop:  LDA message,X       ; @labels are local to the last global label
+//           BEQ @done
+//           JSR COUT
+//           INX
+//           BNE @loop
+//   @done:  RTS
+//   message: .asciiz "HELLO"
+//
+// Numbers are decimal, $hex, %binary or 'c'haracters. Expressions can use
+// + - * / % & | ^ << >>, parentheses, unary - and ~, < for the low byte and
+// > for the high byte. A * on its own is the current address.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    pub file: String,
+    pub line: usize,
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub source: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub symbols: BTreeMap<String, u16>,
+    pub listing: Vec<ListingLine>,
+}
+
+impl Assembly {
+    // One line per source line, with at most three bytes on each line:
+    //
+    //   0300  A9 C1     LDA #$C1
+
+    pub fn listing_text(&self) -> String {
+        let mut text = String::new();
+        for line in &self.listing {
+            let mut chunks = line.bytes.chunks(3);
+            let first = chunks.next().unwrap_or(&[]);
+            text.push_str(format!("{:04X}  {:<9} {}", line.address, hex(first), line.source).trim_end());
+            text.push('\n');
+            let mut address = line.address.wrapping_add(first.len() as u16);
+            for chunk in chunks {
+                text.push_str(&format!("{:04X}  {}\n", address, hex(chunk)));
+                address = address.wrapping_add(chunk.len() as u16);
+            }
+        }
+        text
+    }
+
+    pub fn symbols_text(&self) -> String {
+        self.symbols.iter().map(|(name, value)| format!("{} = ${:04X}\n", name, value)).collect()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ")
+}
+
+// Assembles for the WDC 65C02, which knows all documented instructions, and
+// returns just the bytes. Handy for tests.
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    Assembler::new(Model::Wdc65C02).assemble(source).map(|assembly| assembly.bytes)
+}
+
+#[derive(Debug)]
+pub struct Assembler {
+    model: Model,
+    include_path: PathBuf,
+}
+
+// Include files can include other files, up to this depth
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+impl Assembler {
+    pub fn new(model: Model) -> Self {
+        Assembler { model, include_path: PathBuf::from(".") }
+    }
+
+    // Where .include looks for files when assembling a string. Files
+    // included from a file are looked up relative to that file.
+
+    pub fn with_include_path(model: Model, include_path: &Path) -> Self {
+        Assembler { model, include_path: include_path.to_path_buf() }
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Assembly, AsmError> {
+        let mut lines = Vec::new();
+        self.read_lines(source, "<source>", &self.include_path, 0, &mut lines)?;
+        self.assemble_lines(&lines)
+    }
+
+    pub fn assemble_file(&self, path: &Path) -> Result<Assembly, AsmError> {
+        let source = fs::read_to_string(path).map_err(|e| AsmError {
+            file: path.display().to_string(), line: 0, message: e.to_string(),
+        })?;
+        let directory = path.parent().unwrap_or(Path::new("."));
+        let mut lines = Vec::new();
+        self.read_lines(&source, &path.display().to_string(), directory, 0, &mut lines)?;
+        self.assemble_lines(&lines)
+    }
+
+    // Reads the source into lines, replacing .include directives with the
+    // lines of the file they include.
+
+    fn read_lines(&self, source: &str, file: &str, directory: &Path, depth: usize, lines: &mut Vec<Line>) -> Result<(), AsmError> {
+        for (number, text) in source.lines().enumerate() {
+            let line = Line { file: file.to_string(), number: number + 1, text: text.to_string() };
+            let statement = parse_line(text).map_err(|message| line.error(message))?;
+            match statement.operation {
+                Some((ref directive, ref operand)) if directive.eq_ignore_ascii_case(".include") => {
+                    if depth == MAX_INCLUDE_DEPTH {
+                        return Err(line.error("Too many nested includes".to_string()));
+                    }
+                    let name = parse_string(operand.trim()).map_err(|message| line.error(message))?;
+                    let path = directory.join(String::from_utf8_lossy(&name).as_ref());
+                    let included = fs::read_to_string(&path).map_err(|e| line.error(format!("Cannot include {}: {}", path.display(), e)))?;
+                    // Keep the label of the include line
+                    if let Some(label) = statement.label {
+                        lines.push(Line { text: format!("{}:", label), ..line });
+                    }
+                    let parent = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+                    self.read_lines(&included, &path.display().to_string(), &parent, depth + 1, lines)?;
+                }
+                _ => lines.push(line),
+            }
+        }
+        Ok(())
+    }
+
+    fn assemble_lines(&self, lines: &[Line]) -> Result<Assembly, AsmError> {
+        let mut state = State {
+            final_pass: false,
+            pc: 0,
+            origin: None,
+            scope: String::new(),
+            symbols: BTreeMap::new(),
+            modes: vec![None; lines.len()],
+            bytes: Vec::new(),
+            listing: Vec::new(),
+        };
+
+        for final_pass in [false, true] {
+            state.final_pass = final_pass;
+            state.pc = 0;
+            state.origin = None;
+            state.scope.clear();
+            state.bytes.clear();
+            state.listing.clear();
+            for (index, line) in lines.iter().enumerate() {
+                let start = state.pc;
+                let mut bytes = Vec::new();
+                self.assemble_line(line, index, &mut state, &mut bytes).map_err(|message| line.error(message))?;
+                if final_pass {
+                    state.emit(&bytes).map_err(|message| line.error(message))?;
+                    // Lines without code show where the next code goes, which
+                    // is what you want to see for an .org
+                    let address = if bytes.is_empty() { state.pc } else { start };
+                    state.listing.push(ListingLine {
+                        file: line.file.clone(), line: line.number, address, bytes, source: line.text.clone(),
+                    });
+                } else {
+                    state.pc = state.pc.wrapping_add(bytes.len() as u16);
+                }
+            }
+        }
+
+        Ok(Assembly { origin: state.origin.unwrap_or(0), bytes: state.bytes, symbols: state.symbols, listing: state.listing })
+    }
+
+    fn assemble_line(&self, line: &Line, index: usize, state: &mut State, bytes: &mut Vec<u8>) -> Result<(), String> {
+        let statement = parse_line(&line.text)?;
+
+        if let Some(label) = statement.label {
+            let value = state.pc;
+            state.define(&label, value, true)?;
+        }
+
+        let Some((operation, operand)) = statement.operation else {
+            return Ok(());
+        };
+        let operand = operand.trim();
+
+        // Assignments
+        if let Some(expression) = operand.strip_prefix('=') {
+            let value = state.evaluate(expression)?;
+            if operation == "*" {
+                let Some(value) = value else {
+                    return Err("The address in *= must be known in the first pass".to_string());
+                };
+                return state.org(value);
+            }
+            if let Some(value) = value {
+                state.define(&operation, value as u16, false)?;
+            }
+            return Ok(());
+        }
+
+        if operation.starts_with('.') {
+            return self.assemble_directive(&operation.to_ascii_lowercase(), operand, state, bytes);
+        }
+
+        self.assemble_instruction(&operation.to_ascii_uppercase(), operand, index, state, bytes)
+    }
+
+    fn assemble_directive(&self, directive: &str, operand: &str, state: &mut State, bytes: &mut Vec<u8>) -> Result<(), String> {
+        match directive {
+            ".org" => {
+                let Some(value) = state.evaluate(operand)? else {
+                    return Err("The address in .org must be known in the first pass".to_string());
+                };
+                state.org(value)
+            }
+            ".byte" => {
+                for item in split_operands(operand) {
+                    if item.starts_with('"') {
+                        bytes.extend(parse_string(&item)?);
+                    } else {
+                        bytes.push(state.byte(&item)?);
+                    }
+                }
+                Ok(())
+            }
+            ".word" => {
+                for item in split_operands(operand) {
+                    let value = state.word(&item)?;
+                    bytes.extend([value as u8, (value >> 8) as u8]);
+                }
+                Ok(())
+            }
+            ".ascii" => {
+                bytes.extend(parse_string(operand)?);
+                Ok(())
+            }
+            ".asciiz" => {
+                bytes.extend(parse_string(operand)?);
+                bytes.push(0);
+                Ok(())
+            }
+            _ => Err(format!("Unknown directive {}", directive)),
+        }
+    }
+
+    fn assemble_instruction(&self, mnemonic: &str, operand: &str, index: usize, state: &mut State, bytes: &mut Vec<u8>) -> Result<(), String> {
+        if !(0..=255).any(|opcode| decode(self.model, opcode).0 == mnemonic) {
+            return Err(format!("Unknown instruction {}", mnemonic));
+        }
+        let has = |mode: Mode| self.opcode(mnemonic, mode).is_some();
+
+        let syntax = parse_operand(operand)?;
+
+        // The addressing mode is decided in the first pass
+        let mode = match state.modes[index] {
+            Some(mode) => mode,
+            None => {
+                let zero_page_or_absolute = |expression: &str, zpg: Mode, abs: Mode| -> Result<Mode, String> {
+                    let fits = matches!(state.evaluate(expression)?, Some(0..=0xff));
+                    Ok(if has(zpg) && (fits || !has(abs)) { zpg } else { abs })
+                };
+                let mode = match &syntax {
+                    Operand::None => if has(Mode::Imp) { Mode::Imp } else { Mode::Acc },
+                    Operand::Accumulator => Mode::Acc,
+                    Operand::Immediate(_) => Mode::Imm,
+                    Operand::Direct(_) if has(Mode::Rel) => Mode::Rel,
+                    Operand::Direct(e) => zero_page_or_absolute(e, Mode::Zpg, Mode::Abs)?,
+                    Operand::DirectX(e) => zero_page_or_absolute(e, Mode::ZpgX, Mode::AbsX)?,
+                    Operand::DirectY(e) => zero_page_or_absolute(e, Mode::ZpgY, Mode::AbsY)?,
+                    Operand::Indirect(_) => if has(Mode::Ind) { Mode::Ind } else { Mode::ZpgInd },
+                    Operand::IndirectX(_) => if has(Mode::AbsXInd) { Mode::AbsXInd } else { Mode::IndX },
+                    Operand::IndirectY(_) => Mode::IndY,
+                    Operand::Pair(_, _) => Mode::ZpgRel,
+                };
+                state.modes[index] = Some(mode);
+                mode
+            }
+        };
+
+        let Some(opcode) = self.opcode(mnemonic, mode) else {
+            return Err(format!("Addressing mode not supported by {}", mnemonic));
+        };
+        bytes.push(opcode);
+
+        match (&syntax, mode) {
+            (_, Mode::Imp | Mode::Acc) => {}
+            (Operand::Immediate(e), _) => bytes.push(state.byte(e)?),
+            (Operand::Direct(e), Mode::Rel) => {
+                let next = state.pc.wrapping_add(2);
+                bytes.push(state.branch_offset(e, next)?);
+            }
+            (Operand::Pair(zpg, target), Mode::ZpgRel) => {
+                bytes.push(state.byte(zpg)?);
+                let next = state.pc.wrapping_add(3);
+                bytes.push(state.branch_offset(target, next)?);
+            }
+            (Operand::Direct(e) | Operand::DirectX(e) | Operand::DirectY(e)
+                | Operand::Indirect(e) | Operand::IndirectX(e) | Operand::IndirectY(e), _) => {
+                if mode.size() == 2 {
+                    bytes.push(state.byte(e)?);
+                } else {
+                    let value = state.word(e)?;
+                    bytes.extend([value as u8, (value >> 8) as u8]);
+                }
+            }
+            _ => return Err(format!("Addressing mode not supported by {}", mnemonic)),
+        }
+        Ok(())
+    }
+
+    // The opcode for an instruction, by looking it up in the disassembler
+    // tables. Where several opcodes do the same, the first one wins, except
+    // for NOP which has its own.
+
+    fn opcode(&self, mnemonic: &str, mode: Mode) -> Option<u8> {
+        if mnemonic == "NOP" && mode == Mode::Imp {
+            return Some(0xea);
+        }
+        (0..=255).find(|&opcode| decode(self.model, opcode) == (mnemonic, mode))
+    }
+}
+
+#[derive(Debug)]
+struct Line {
+    file: String,
+    number: usize,
+    text: String,
+}
+
+impl Line {
+    fn error(&self, message: String) -> AsmError {
+        AsmError { file: self.file.clone(), line: self.number, message }
+    }
+}
+
+struct State {
+    final_pass: bool,
+    pc: u16,
+    origin: Option<u16>,
+    scope: String,
+    symbols: BTreeMap<String, u16>,
+    modes: Vec<Option<Mode>>,
+    bytes: Vec<u8>,
+    listing: Vec<ListingLine>,
+}
+
+impl State {
+    // Local labels start with an @ and belong to the last global label
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('@') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn define(&mut self, name: &str, value: u16, label: bool) -> Result<(), String> {
+        if label && !name.starts_with('@') {
+            self.scope = name.to_string();
+        }
+        let name = self.qualify(name);
+        match self.symbols.get(&name) {
+            Some(_) if !self.final_pass => Err(format!("Duplicate symbol {}", name)),
+            Some(&old) if old != value => Err(format!("Symbol {} changed from ${:04X} to ${:04X} between passes", name, old, value)),
+            _ => {
+                self.symbols.insert(name, value);
+                Ok(())
+            }
+        }
+    }
+
+    fn org(&mut self, value: i64) -> Result<(), String> {
+        let value = u16::try_from(value).map_err(|_| format!("Address out of range: {}", value))?;
+        if self.final_pass {
+            if let Some(origin) = self.origin {
+                if (value as usize) < origin as usize + self.bytes.len() {
+                    return Err(format!("Cannot .org backwards to ${:04X}", value));
+                }
+            }
+        }
+        self.pc = value;
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let origin = *self.origin.get_or_insert(self.pc) as usize;
+        // The PC wraps around to $0000 after code that ends at $FFFF, so
+        // once that happened nothing fits anymore
+        let offset = self.pc.wrapping_sub(origin as u16) as usize;
+        if origin + self.bytes.len() == 0x10000 || offset + bytes.len() > 0x10000 - origin {
+            return Err("Code does not fit below $FFFF".to_string());
+        }
+        // The space between .orgs is filled with zeros
+        self.bytes.resize(offset, 0);
+        self.bytes.extend(bytes);
+        self.pc = self.pc.wrapping_add(bytes.len() as u16);
+        Ok(())
+    }
+
+    // Returns None for expressions with symbols that are not yet defined in
+    // the first pass.
+
+    fn evaluate(&self, expression: &str) -> Result<Option<i64>, String> {
+        let mut parser = Expression { state: self, chars: expression.chars().collect(), pos: 0 };
+        let value = parser.expression(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.chars.len() {
+            return Err(format!("Unexpected characters in expression: {}", expression.trim()));
+        }
+        Ok(value)
+    }
+
+    fn byte(&self, expression: &str) -> Result<u8, String> {
+        match self.evaluate(expression)? {
+            None => Ok(0),
+            Some(value @ -128..=255) => Ok(value as u8),
+            Some(value) => Err(format!("Value does not fit in a byte: {}", value)),
+        }
+    }
+
+    fn word(&self, expression: &str) -> Result<u16, String> {
+        match self.evaluate(expression)? {
+            None => Ok(0),
+            Some(value @ -32768..=65535) => Ok(value as u16),
+            Some(value) => Err(format!("Value does not fit in a word: {}", value)),
+        }
+    }
+
+    fn branch_offset(&self, expression: &str, next: u16) -> Result<u8, String> {
+        match self.evaluate(expression)? {
+            None => Ok(0),
+            Some(target) => {
+                let offset = target.checked_sub(next as i64).ok_or_else(out_of_range)?;
+                if !(-128..=127).contains(&offset) {
+                    return Err(format!("Branch target out of range: {} bytes", offset));
+                }
+                Ok(offset as u8)
+            }
+        }
+    }
+}
+
+// Operator precedence parser for expressions
+
+struct Expression<'a> {
+    state: &'a State,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+const BINARY_OPERATORS: [(&str, u8); 11] = [
+    ("<<", 4), (">>", 4), ("|", 1), ("^", 2), ("&", 3), ("+", 5), ("-", 5), ("*", 6), ("/", 6), ("%", 6), ("", 0),
+];
+
+impl Expression<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn binary_operator(&mut self) -> Option<(&'static str, u8)> {
+        self.skip_whitespace();
+        BINARY_OPERATORS.iter().copied().find(|(op, _)| {
+            !op.is_empty() && op.chars().enumerate().all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+        })
+    }
+
+    fn expression(&mut self, min_precedence: u8) -> Result<Option<i64>, String> {
+        let mut lhs = self.unary()?;
+        while let Some((op, precedence)) = self.binary_operator() {
+            if precedence <= min_precedence {
+                break;
+            }
+            self.pos += op.len();
+            let rhs = self.expression(precedence)?;
+            lhs = match (lhs, rhs) {
+                (Some(a), Some(b)) => Some(match op {
+                    "<<" => a << (b & 63),
+                    ">>" => a >> (b & 63),
+                    "|" => a | b,
+                    "^" => a ^ b,
+                    "&" => a & b,
+                    "+" => a.checked_add(b).ok_or_else(out_of_range)?,
+                    "-" => a.checked_sub(b).ok_or_else(out_of_range)?,
+                    "*" => a.checked_mul(b).ok_or_else(out_of_range)?,
+                    "/" | "%" if b == 0 => return Err("Division by zero".to_string()),
+                    "/" => a.checked_div(b).ok_or_else(out_of_range)?,
+                    _ => a.checked_rem(b).ok_or_else(out_of_range)?,
+                }),
+                _ => None,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Option<i64>, String> {
+        match self.peek() {
+            Some('-') => { self.pos += 1; self.unary()?.map(|v| v.checked_neg().ok_or_else(out_of_range)).transpose() }
+            Some('~') => { self.pos += 1; Ok(self.unary()?.map(|v| !v & 0xffff)) }
+            Some('<') => { self.pos += 1; Ok(self.unary()?.map(|v| v & 0xff)) }
+            Some('>') => { self.pos += 1; Ok(self.unary()?.map(|v| (v >> 8) & 0xff)) }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Option<i64>, String> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = self.expression(0)?;
+                if self.peek() != Some(')') {
+                    return Err("Missing )".to_string());
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some('*') => {
+                self.pos += 1;
+                Ok(Some(self.state.pc as i64))
+            }
+            Some('\'') => {
+                let c = self.chars.get(self.pos + 1).copied().ok_or("Missing character")?;
+                if self.chars.get(self.pos + 2) != Some(&'\'') {
+                    return Err("Missing ' after character".to_string());
+                }
+                self.pos += 3;
+                Ok(Some(c as i64))
+            }
+            Some('$') => { self.pos += 1; self.number(16) }
+            Some('%') => { self.pos += 1; self.number(2) }
+            Some(c) if c.is_ascii_digit() => self.number(10),
+            Some(c) if is_symbol_start(c) => {
+                let start = self.pos;
+                self.pos += 1;
+                while self.pos < self.chars.len() && is_symbol_char(self.chars[self.pos]) {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                match self.state.symbols.get(&self.state.qualify(&name)) {
+                    Some(&value) => Ok(Some(value as i64)),
+                    None if self.state.final_pass => Err(format!("Undefined symbol {}", name)),
+                    None => Ok(None),
+                }
+            }
+            Some(c) => Err(format!("Unexpected character {} in expression", c)),
+            None => Err("Missing expression".to_string()),
+        }
+    }
+
+    fn number(&mut self, radix: u32) -> Result<Option<i64>, String> {
+        let start = self.pos;
+        while self.pos < self.chars.len() && self.chars[self.pos].is_digit(radix) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        i64::from_str_radix(&digits, radix).map(Some).map_err(|_| "Bad number".to_string())
+    }
+}
+
+fn out_of_range() -> String {
+    "Value out of range".to_string()
+}
+
+fn is_symbol_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '@'
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Source lines
+
+#[derive(Debug, PartialEq)]
+struct Statement {
+    label: Option<String>,
+    operation: Option<(String, String)>,
+}
+
+fn parse_line(text: &str) -> Result<Statement, String> {
+    let text = strip_comment(text);
+    let mut rest = text.trim();
+
+    let mut label = None;
+    let end = rest.find(|c: char| !(is_symbol_char(c) || c == '@')).unwrap_or(rest.len());
+    if end > 0 && rest[end..].starts_with(':') {
+        let name = &rest[..end];
+        if !name.starts_with(is_symbol_start) {
+            return Err(format!("Invalid label {}", name));
+        }
+        label = Some(name.to_string());
+        rest = rest[end + 1..].trim();
+    }
+
+    if rest.is_empty() {
+        return Ok(Statement { label, operation: None });
+    }
+
+    // Assignments like FOO = $10 and *= $0300
+    if let Some(eq) = rest.find('=') {
+        let name = rest[..eq].trim();
+        if name == "*" || (!name.is_empty() && name.starts_with(is_symbol_start) && name[1..].chars().all(is_symbol_char)) {
+            return Ok(Statement { label, operation: Some((name.to_string(), rest[eq..].to_string())) });
+        }
+    }
+
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    Ok(Statement { label, operation: Some((rest[..end].to_string(), rest[end..].to_string())) })
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quote = Quote::default();
+    for (i, c) in text.char_indices() {
+        if c == ';' && !quote.is_open() {
+            return &text[..i];
+        }
+        quote.next(c);
+    }
+    text
+}
+
+// Keeps track of whether we are inside a string or character literal
+
+#[derive(Default)]
+struct Quote {
+    open: Option<char>,
+    escaped: bool,
+}
+
+impl Quote {
+    fn is_open(&self) -> bool {
+        self.open.is_some()
+    }
+
+    fn next(&mut self, c: char) {
+        match self.open {
+            None if c == '"' || c == '\'' => self.open = Some(c),
+            None => {}
+            Some(_) if self.escaped => self.escaped = false,
+            Some('"') if c == '\\' => self.escaped = true,
+            Some(q) if c == q => self.open = None,
+            Some(_) => {}
+        }
+    }
+}
+
+// Splits on commas that are not in a string or character literal
+fn split_operands(operand: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut quote = Quote::default();
+    for c in operand.chars() {
+        if c == ',' && !quote.is_open() {
+            items.push(current.trim().to_string());
+            current.clear();
+            continue;
+        }
+        quote.next(c);
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !items.is_empty() {
+        items.push(current.trim().to_string());
+    }
+    items
+}
+
+fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let Some(inner) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) else {
+        return Err(format!("Expected a string: {}", text));
+    };
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"')) => c,
+                _ => return Err("Unknown escape in string".to_string()),
+            }
+        } else {
+            c
+        };
+        if !c.is_ascii() {
+            return Err(format!("Not an ASCII character: {}", c));
+        }
+        bytes.push(c as u8);
+    }
+    Ok(bytes)
+}
+
+// The syntax of an operand, before we know which addressing mode it is
+
+#[derive(Debug, PartialEq)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(String),
+    Direct(String),
+    DirectX(String),
+    DirectY(String),
+    Indirect(String),
+    IndirectX(String),
+    IndirectY(String),
+    Pair(String, String),
+}
+
+fn parse_operand(operand: &str) -> Result<Operand, String> {
+    let operand = operand.trim();
+    let upper = operand.to_ascii_uppercase().replace(' ', "");
+    let inner = |suffix_len: usize| operand[..operand.len() - suffix_len].trim_end();
+
+    if operand.is_empty() {
+        return Ok(Operand::None);
+    }
+    if upper == "A" {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(e) = operand.strip_prefix('#') {
+        return Ok(Operand::Immediate(e.to_string()));
+    }
+    if operand.starts_with('(') && encloses(operand) {
+        let e = &operand[1..operand.len() - 1];
+        if e.to_ascii_uppercase().replace(' ', "").ends_with(",X") {
+            let comma = e.rfind(',').unwrap();
+            return Ok(Operand::IndirectX(e[..comma].to_string()));
+        }
+        return Ok(Operand::Indirect(e.to_string()));
+    }
+    if operand.starts_with('(') && upper.ends_with(",Y") {
+        let e = inner(1).trim_end_matches(|c: char| c == ',' || c.is_whitespace());
+        if e.ends_with(')') && encloses(e) {
+            return Ok(Operand::IndirectY(e[1..e.len() - 1].to_string()));
+        }
+    }
+
+    let items = split_operands(operand);
+    match items.as_slice() {
+        [e] => Ok(Operand::Direct(e.clone())),
+        [e, index] if index.eq_ignore_ascii_case("X") => Ok(Operand::DirectX(e.clone())),
+        [e, index] if index.eq_ignore_ascii_case("Y") => Ok(Operand::DirectY(e.clone())),
+        [a, b] => Ok(Operand::Pair(a.clone(), b.clone())),
+        _ => Err(format!("Invalid operand: {}", operand)),
+    }
+}
+
+// True when the opening parenthesis at the start matches the one at the end
+fn encloses(text: &str) -> bool {
+    if !text.ends_with(')') {
+        return false;
+    }
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i == text.len() - 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod asm_tests {
+    use super::*;
+    use super::super::bus::{Bus, Ram};
+    use super::super::cpu::CPU;
+    use super::super::disasm::disassemble;
+
+    #[test]
+    fn test_instructions() {
+        assert_eq!(assemble("LDA #$01\nBRK").unwrap(), vec![0xa9, 0x01, 0x00]);
+        assert_eq!(assemble("  asl\n  asl a\n  nop").unwrap(), vec![0x0a, 0x0a, 0xea]);
+        assert_eq!(assemble("LDA $12\nLDA $1234\nLDA $0012").unwrap(), vec![0xa5, 0x12, 0xad, 0x34, 0x12, 0xa5, 0x12]);
+        assert_eq!(assemble("LDA $12,X\nLDA $1234,y\nLDX $12,Y\nLDA $12,Y").unwrap(),
+                   vec![0xb5, 0x12, 0xb9, 0x34, 0x12, 0xb6, 0x12, 0xb9, 0x12, 0x00]);
+        assert_eq!(assemble("LDA ($12,X)\nLDA ($12),Y\nLDA ($12)\nJMP ($1234)\nJMP ($1234,X)").unwrap(),
+                   vec![0xa1, 0x12, 0xb1, 0x12, 0xb2, 0x12, 0x6c, 0x34, 0x12, 0x7c, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_labels_and_branches() {
+        let source = "
+                .org $0300
+        start:  LDX #0
+        @loop:  LDA message,X   ; Forward reference, so absolute
+                BEQ @done
+                INX
+                BNE @loop
+        @done:  RTS
+        message: .asciiz \"HI\"
+        ";
+        let assembly = Assembler::new(Model::Nmos6502).assemble(source).unwrap();
+        assert_eq!(assembly.origin, 0x0300);
+        assert_eq!(assembly.bytes, vec![
+            0xa2, 0x00,         // $0300 LDX #0
+            0xbd, 0x0b, 0x03,   // $0302 LDA $030B,X
+            0xf0, 0x03,         // $0305 BEQ $030A
+            0xe8,               // $0307 INX
+            0xd0, 0xf8,         // $0308 BNE $0302
+            0x60,               // $030A RTS
+            0x48, 0x49, 0x00,   // $030B "HI"
+        ]);
+        assert_eq!(assembly.symbols["start"], 0x0300);
+        assert_eq!(assembly.symbols["start@loop"], 0x0302);
+        assert_eq!(assembly.symbols["message"], 0x030b);
+    }
+
+    #[test]
+    fn test_local_labels_are_scoped() {
+        let source = "
+        a:  BNE @x
+        @x: RTS
+        b:  BNE @x
+        @x: RTS
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![0xd0, 0x00, 0x60, 0xd0, 0x00, 0x60]);
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = "
+        COUT = $FDED
+        BASE = $10 * 2 + 1
+            LDA #<COUT
+            LDX #>COUT
+            LDY #BASE & $0F
+            LDA #'A' | $80
+            .word COUT, *, (BASE + 1) << 8
+            .byte -1, %101, \"A;B\", ','     ; Not comments or separators
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0xa9, 0xed, 0xa2, 0xfd, 0xa0, 0x01, 0xa9, 0xc1,
+            0xed, 0xfd, 0x08, 0x00, 0x00, 0x22,
+            0xff, 0x05, 0x41, 0x3b, 0x42, 0x2c,
+        ]);
+    }
+
+    #[test]
+    fn test_org_fills_gaps() {
+        let assembly = Assembler::new(Model::Nmos6502).assemble("*= $1000\nNOP\n.org $1003\nNOP").unwrap();
+        assert_eq!(assembly.origin, 0x1000);
+        assert_eq!(assembly.bytes, vec![0xea, 0x00, 0x00, 0xea]);
+        assert!(assemble(".org $1000\nNOP\n.org $0F00\nNOP").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = assemble("NOP\nFOO #1").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.to_string(), "<source>:2: Unknown instruction FOO");
+        assert_eq!(assemble("LDA undefined").unwrap_err().message, "Undefined symbol undefined");
+        assert_eq!(assemble("x: NOP\nx: NOP").unwrap_err().message, "Duplicate symbol x");
+        assert_eq!(assemble("LDA #256").unwrap_err().message, "Value does not fit in a byte: 256");
+        assert_eq!(assemble("STX $1234,X").unwrap_err().message, "Addressing mode not supported by STX");
+        assert!(assemble("BNE *+200").unwrap_err().message.starts_with("Branch target out of range"));
+        assert!(Assembler::new(Model::Nmos6502).assemble("BRA *").is_err());
+        assert_eq!(assemble(".org $FFFE\nNOP\nNOP\nNOP").unwrap_err().message, "Code does not fit below $FFFF");
+        assert_eq!(assemble(".org $FFFE\nJMP $1234").unwrap_err().message, "Code does not fit below $FFFF");
+        assert_eq!(assemble(".org $FFFF\nNOP\n.org $FFFF").unwrap_err().message, "Cannot .org backwards to $FFFF");
+        assert_eq!(assemble(".org $FFFE\nNOP\nNOP").unwrap(), vec![0xea, 0xea]);
+        assert_eq!(assemble(".word $7FFFFFFFFFFFFFFF + 1").unwrap_err().message, "Value out of range");
+        assert_eq!(assemble(".word -$7FFFFFFFFFFFFFFF - 2").unwrap_err().message, "Value out of range");
+        assert_eq!(assemble(".word $7FFFFFFFFFFFFFFF * 2").unwrap_err().message, "Value out of range");
+        assert_eq!(assemble(".word (-$7FFFFFFFFFFFFFFF - 1) / -1").unwrap_err().message, "Value out of range");
+        assert_eq!(assemble(".word -(-$7FFFFFFFFFFFFFFF - 1)").unwrap_err().message, "Value out of range");
+        assert_eq!(assemble("BNE -$7FFFFFFFFFFFFFFF - 1").unwrap_err().message, "Value out of range");
+    }
+
+    #[test]
+    fn test_rockwell_bit_instructions() {
+        assert_eq!(assemble("here: BBR3 $12,here\nSMB7 $34").unwrap(), vec![0x3f, 0x12, 0xfd, 0xf7, 0x34]);
+    }
+
+    #[test]
+    fn test_wdc_instructions() {
+        assert_eq!(assemble("WAI\nSTP").unwrap(), vec![0xcb, 0xdb]);
+        assert!(Assembler::new(Model::Rockwell65C02).assemble("WAI").is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let directory = std::env::temp_dir().join(format!("rewm-asm-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("lib.s"), "print: RTS\n").unwrap();
+        fs::write(directory.join("main.s"), ".org $0300\nJSR print\nlib: .include \"lib.s\"\n").unwrap();
+
+        let assembly = Assembler::new(Model::Nmos6502).assemble_file(&directory.join("main.s")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(assembly.bytes, vec![0x20, 0x03, 0x03, 0x60]);
+        assert_eq!(assembly.symbols["lib"], 0x0303);
+        assert!(assembly.listing[3].file.ends_with("lib.s"));
+    }
+
+    #[test]
+    fn test_listing() {
+        let assembly = assemble_for_listing();
+        assert_eq!(assembly.listing_text(), "\
+0300            .org $0300
+0300            start:
+0300  A9 01     LDA #$01
+0302  01 02 03  .byte 1, 2, 3, 4
+0305  04
+");
+        assert_eq!(assembly.symbols_text(), "start = $0300\n");
+    }
+
+    fn assemble_for_listing() -> Assembly {
+        Assembler::new(Model::Nmos6502).assemble(".org $0300\nstart:\nLDA #$01\n.byte 1, 2, 3, 4").unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Everything the disassembler prints, the assembler turns back into
+        // the same bytes.
+        for model in [Model::Nmos6502, Model::Cmos65C02, Model::Rockwell65C02, Model::Wdc65C02] {
+            let assembler = Assembler::new(model);
+            for opcode in 0..=255u8 {
+                let mut ram = Ram::new();
+                ram.write(0x0300, opcode);
+                ram.write(0x0301, 0x80);
+                ram.write(0x0302, 0x12);
+                let instruction = disassemble(&ram, model, 0x0300);
+                let source = format!(".org $0300\n{} {}", instruction.mnemonic, instruction.operand_text());
+                let assembly = assembler.assemble(&source).unwrap_or_else(|e| panic!("{:?} {}: {}", model, source, e));
+                if assembly.bytes != instruction.bytes {
+                    // Duplicate opcodes for the same instruction
+                    assert_eq!(decode(model, assembly.bytes[0]), decode(model, opcode), "{:?} {}", model, source);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_program() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, assemble("
+            .org $0400
+                LDX #5
+                LDA #0
+        @loop:  CLC
+                ADC #3
+                DEX
+                BNE @loop
+                STA $10
+                BRK
+        ").unwrap());
+        cpu.pc = 0x0400;
+        while cpu.peek_byte(cpu.pc) != 0x00 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.peek_byte(0x10), 15);
+    }
+}
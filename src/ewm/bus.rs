@@ -0,0 +1,76 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Everything the CPU reads or writes goes through a Bus. Machine profiles
+// implement it to map RAM, ROM and memory mapped I/O into the address space.
+//
+// A read can have side effects, like clearing a keyboard strobe or toggling
+// the speaker. Debuggers, disassemblers and tests look at memory with peek,
+// which must never change the state of the machine.
+
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, b: u8);
+    fn peek(&self, addr: u16) -> u8;
+
+    // Called when the machine is reset, for devices that are wired to the
+    // reset line. Memory keeps its contents.
+    fn reset(&mut self) {}
+
+    // Called after every instruction with the number of cycles it took, for
+    // devices that need to know how much time has passed.
+    fn tick(&mut self, _cycles: u64) {}
+}
+
+// A flat 64K of RAM without any I/O. Good enough for tests and for running
+// plain 6502 code.
+
+#[derive(Debug)]
+pub struct Ram {
+    bytes: Vec<u8>,
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ram {
+    pub fn new() -> Self {
+        Ram { bytes: vec![0; 64*1024] }
+    }
+}
+
+impl Bus for Ram {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.bytes[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, b: u8) {
+        self.bytes[addr as usize] = b;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.bytes[addr as usize]
+    }
+}
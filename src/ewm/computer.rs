@@ -20,14 +20,28 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use super::cpu::CPU;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::bus::{Bus, Ram};
+use super::cpu::{CPU, CPUError};
+
+// A CPU together with the bus of a specific machine. The machine profiles,
+// like the Apple 1 in apple1.rs, add a constructor and whatever their front
+// ends need to talk to the machine.
 
 #[derive(Debug)]
-pub struct Computer {
-    pub cpu: CPU,
+pub struct Computer<B: Bus = Ram> {
+    pub cpu: CPU<B>,
 }
 
-// Public API - TODO This doesn't yet have a reason to exist
+impl Default for Computer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Computer {
     pub fn new() -> Self {
@@ -35,3 +49,54 @@ impl Computer {
     }
 }
 
+impl<B: Bus> Computer<B> {
+    pub fn with_cpu(cpu: CPU<B>) -> Self {
+        Computer { cpu }
+    }
+
+    // Pressing the reset button resets the devices as well as the CPU
+    pub fn reset(&mut self) {
+        self.cpu.bus.reset();
+        self.cpu.reset();
+    }
+
+    // Runs whole instructions until at least the given number of cycles
+    // have passed. Front ends call this once per frame.
+
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<(), CPUError> {
+        let end = self.cpu.cycles + cycles;
+        while self.cpu.cycles < end {
+            self.cpu.step()?;
+        }
+        Ok(())
+    }
+}
+
+// ROMs are often dumped chip by chip, like Applesoft and the Autostart
+// Monitor of the Apple ][+. This reads the files and puts them together in
+// the order given.
+
+pub fn load_rom_files<P: AsRef<Path>>(paths: &[P]) -> io::Result<Vec<u8>> {
+    let mut rom = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        rom.extend(bytes);
+    }
+    Ok(rom)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ComputerError {
+    InvalidRom { expected: usize, actual: usize },
+}
+
+impl fmt::Display for ComputerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ComputerError::InvalidRom { expected, actual } => {
+                write!(f, "ROM should be {} bytes but is {} bytes", expected, actual)
+            }
+        }
+    }
+}
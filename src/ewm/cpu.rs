@@ -20,8 +20,59 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use super::bus::{Bus, Ram};
+
+mod breakpoints;
+mod cmos;
+mod trace;
+mod undocumented;
+
+pub use breakpoints::*;
+pub use trace::*;
+
+// The CPU models we can emulate. The 65C02 adds instructions and addressing
+// modes and fixes some bugs of the original NMOS 6502. The Rockwell version
+// of the 65C02 also has the bit manipulation and bit test instructions, and
+// the WDC version adds WAI and STP to those.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Nmos6502,
+    Cmos65C02,
+    Rockwell65C02,
+    Wdc65C02,
+}
+
+impl Model {
+    pub fn is_cmos(&self) -> bool {
+        *self != Model::Nmos6502
+    }
+
+    pub fn has_bit_instructions(&self) -> bool {
+        matches!(self, Model::Rockwell65C02 | Model::Wdc65C02)
+    }
+
+    pub fn has_wait_and_stop(&self) -> bool {
+        *self == Model::Wdc65C02
+    }
+}
+
 #[derive(Debug)]
-pub struct CPU {
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<B: Bus = Ram> {
+    pub model: Model,
+
+    // Strict by default: undocumented NMOS opcodes result in an error
+    // unless this is turned on.
+    pub undocumented_opcodes: bool,
+
+    // Set when a JAM or STP instruction locked up the CPU. Only a reset
+    // helps.
+    pub halted: bool,
+
+    // Set when a WAI instruction waits for an interrupt
+    pub waiting: bool,
+
     // TODO Move this to State ?
     pub pc: u16,
     pub sp: u8,
@@ -37,52 +88,196 @@ pub struct CPU {
     pub z: bool,
     pub c: bool,
 
-    pub ram: Vec<u8>,
+    pub bus: B,
+
+    pub cycles: u64,
+
+    pub breakpoints: Breakpoints,
+
+    pub trace: Option<Trace>,
+
+    irq: u8,
+    nmi: bool,
+    nmi_pending: bool,
 }
 
+pub const NMI_VECTOR: u16 = 0xfffa;
+pub const RESET_VECTOR: u16 = 0xfffc;
+pub const IRQ_VECTOR: u16 = 0xfffe;
+
+// Base cycle counts for all opcodes on the NMOS 6502, including the undocumented
+// ones. Extra cycles for crossing pages and taking branches are added by the
+// addressing helpers and branch instructions.
+
+const NMOS_CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F
+];
+
+// The same for the 65C02. All undefined opcodes are NOPs on the 65C02, which
+// take between one and eight cycles. Columns 7 and F are the Rockwell bit
+// instructions, on a plain 65C02 these are single cycle NOPs.
+
+const CMOS_CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 2, 1, 5, 3, 5, 5, 3, 2, 2, 1, 6, 4, 6, 5, // 0
+    2, 5, 5, 1, 5, 4, 6, 5, 2, 4, 2, 1, 6, 4, 6, 5, // 1
+    6, 6, 2, 1, 3, 3, 5, 5, 4, 2, 2, 1, 4, 4, 6, 5, // 2
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 2, 1, 4, 4, 6, 5, // 3
+    6, 6, 2, 1, 3, 3, 5, 5, 3, 2, 2, 1, 3, 4, 6, 5, // 4
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 3, 1, 8, 4, 6, 5, // 5
+    6, 6, 2, 1, 3, 3, 5, 5, 4, 2, 2, 1, 6, 4, 6, 5, // 6
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 4, 1, 6, 4, 6, 5, // 7
+    2, 6, 2, 1, 3, 3, 3, 5, 2, 2, 2, 1, 4, 4, 4, 5, // 8
+    2, 6, 5, 1, 4, 4, 4, 5, 2, 5, 2, 1, 4, 5, 5, 5, // 9
+    2, 6, 2, 1, 3, 3, 3, 5, 2, 2, 2, 1, 4, 4, 4, 5, // A
+    2, 5, 5, 1, 4, 4, 4, 5, 2, 4, 2, 1, 4, 4, 4, 5, // B
+    2, 6, 2, 1, 3, 3, 5, 5, 2, 2, 2, 1, 4, 4, 6, 5, // C
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 3, 1, 4, 4, 7, 5, // D
+    2, 6, 2, 1, 3, 3, 5, 5, 2, 2, 2, 1, 4, 4, 6, 5, // E
+    2, 5, 5, 1, 4, 4, 6, 5, 2, 4, 4, 1, 4, 4, 7, 5, // F
+];
+
+fn base_cycles(model: Model, opcode: u8) -> u8 {
+    match model {
+        Model::Nmos6502 => NMOS_CYCLES[opcode as usize],
+        Model::Cmos65C02 if (opcode & 0x07) == 0x07 => 1,
+        Model::Wdc65C02 if opcode == 0xcb || opcode == 0xdb => 3,
+        _ => CMOS_CYCLES[opcode as usize],
+    }
+}
+
+const INTERRUPT_CYCLES: u64 = 7;
+
 // Public API
 
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CPU {
     pub fn new() -> Self {
+        CPU::with_bus(Ram::new())
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn with_bus(bus: B) -> Self {
+        CPU::with_model(bus, Model::Nmos6502)
+    }
+
+    pub fn with_model(bus: B, model: Model) -> Self {
         CPU {
-            pc: 0x0400, sp: 0xff,
+            model,
+            undocumented_opcodes: false,
+            halted: false,
+            waiting: false,
+            pc: 0x0000, sp: 0xff,
             a: 0, x: 0, y: 0,
             n: false, v: false, b: false, d: false, i: false, z: false, c: false,
-            ram: vec![0; 64*1024]
+            bus,
+            cycles: 0,
+            breakpoints: Breakpoints::default(),
+            trace: None,
+            irq: 0, nmi: false, nmi_pending: false,
         }
     }
 
-    pub fn get_byte(&self, addr: u16) -> u8 {
-        if addr > 2047 {
-            return 0
+    // Like the real thing, a reset does not touch the registers other than
+    // disabling interrupts and moving the stack pointer down three bytes.
+
+    pub fn reset(&mut self) {
+        self.sp = self.sp.wrapping_sub(3);
+        self.i = true;
+        self.irq = 0;
+        self.nmi = false;
+        self.nmi_pending = false;
+        self.halted = false;
+        self.waiting = false;
+        self.pc = self.get_word(RESET_VECTOR);
+    }
+
+    pub fn get_byte(&mut self, addr: u16) -> u8 {
+        if self.breakpoints.reads {
+            self.breakpoints.access(addr, Access::Read);
         }
-        return self.ram[addr as usize];
+        self.bus.read(addr)
     }
 
     pub fn set_byte(&mut self, addr: u16, b: u8) {
-        self.ram[addr as usize] = b;
+        if self.breakpoints.writes {
+            self.breakpoints.access(addr, Access::Write);
+        }
+        self.bus.write(addr, b);
+    }
+
+    pub fn peek_byte(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    pub fn get_word(&mut self, addr: u16) -> u16 {
+        (self.get_byte(addr) as u16) | (self.get_byte(addr.wrapping_add(1)) as u16) << 8
     }
 
-    pub fn get_word(&self, addr: u16) -> u16 {
-        eprintln!("Debug: get_word({:#04x})", addr);
-        return (self.get_byte(addr) as u16) | (self.get_byte(addr+1) as u16) << 8;
+    pub fn peek_word(&self, addr: u16) -> u16 {
+        (self.peek_byte(addr) as u16) | (self.peek_byte(addr.wrapping_add(1)) as u16) << 8
     }
 
     pub fn set_word(&mut self, addr: u16, w: u16) {
-        panic!("TODO");
+        self.set_byte(addr, (w & 0xff) as u8);
+        self.set_byte(addr.wrapping_add(1), (w >> 8) as u8);
     }
 
     // TODO These are also in Computer which makes no sense
 
     pub fn load(&mut self, addr: u16, program: Vec<u8>) {
-        for n in 0..program.len() {
-            self.ram[(addr + n as u16) as usize] = program[n];
+        for (n, b) in program.into_iter().enumerate() {
+            self.set_byte(addr.wrapping_add(n as u16), b);
+        }
+    }
+
+    // Runs until a breakpoint or an error stops the CPU. A breakpoint at
+    // the PC we start at does not count, so that running again goes on
+    // from where the last breakpoint stopped.
+
+    pub fn run(&mut self) -> StopReason {
+        let mut check_pc = false;
+        loop {
+            if let Some(stop) = self.step_with_breakpoints(check_pc) {
+                return stop;
+            }
+            check_pc = true;
         }
     }
 
-    pub fn run(&mut self) -> Result<(), CPUError> {
+    // Runs until the CPU gets stuck on an instruction that jumps or branches
+    // to itself, and returns its address. Test suites use such traps to signal
+    // success or failure, and a halted CPU is trapped too.
+
+    pub fn run_until_trap(&mut self) -> Result<u16, CPUError> {
         loop {
+            let pc = self.pc;
             self.step()?;
+            if self.pc == pc {
+                return Ok(pc);
+            }
         }
     }
 }
@@ -91,14 +286,13 @@ impl CPU {
 
 #[derive(Debug, PartialEq)]
 pub enum CPUError {
-    Break,
     IllegalOpcode,
 }
 
 // Status
 
-impl CPU {
-    fn get_status(&self) -> u8 {
+impl<B: Bus> CPU<B> {
+    pub fn get_status(&self) -> u8 {
         let mut status = 0;
         if self.n { status |= 0b10000000; }
         if self.v { status |= 0b01000000; }
@@ -110,7 +304,7 @@ impl CPU {
         status
     }
 
-    fn set_status(&mut self, status: u8) {
+    pub fn set_status(&mut self, status: u8) {
         self.n = (status & 0b10000000) != 0;
         self.v = (status & 0b01000000) != 0;
         self.b = (status & 0b00010000) != 0;
@@ -121,54 +315,137 @@ impl CPU {
     }
 }
 
+// Interrupts
+
+// The IRQ line is shared by all devices, so each device asserts and releases
+// its own line and the CPU sees an interrupt request as long as any of them
+// is asserted. NMI is edge triggered: only the transition from released to
+// asserted causes an interrupt.
+
+// One of the eight lines, 0-7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqLine(u8);
+
+impl IrqLine {
+    pub const fn new(line: u8) -> Option<IrqLine> {
+        if line < 8 {
+            Some(IrqLine(line))
+        } else {
+            None
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn assert_irq(&mut self, line: IrqLine) {
+        self.irq |= 1 << line.0;
+    }
+
+    pub fn release_irq(&mut self, line: IrqLine) {
+        self.irq &= !(1 << line.0);
+    }
+
+    pub fn assert_nmi(&mut self) {
+        if !self.nmi {
+            self.nmi_pending = true;
+        }
+        self.nmi = true;
+    }
+
+    pub fn release_nmi(&mut self) {
+        self.nmi = false;
+    }
+
+    fn interrupt(&mut self, vector: u16, brk: bool) {
+        self.push_word(self.pc);
+        let status = (self.get_status() & !0b00010000) | 0b00100000;
+        self.push_byte(if brk { status | 0b00010000 } else { status });
+        self.i = true;
+        if self.model.is_cmos() {
+            self.d = false;
+        }
+        self.pc = self.get_word(vector);
+    }
+}
+
 // Modifiers
 
-fn asl(b: u8) -> u8 {
-    b
+// Read-modify-write operations on memory or the accumulator. They take the
+// CPU because they update flags, and ROL and ROR also shift the carry in.
+
+pub type Modifier<B> = fn(&mut CPU<B>, u8) -> u8;
+
+fn asl<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = b << 1;
+    cpu.c = (b & 0x80) != 0;
+    cpu.update_nz(r);
+    r
+}
+
+fn lsr<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = b >> 1;
+    cpu.c = (b & 0x01) != 0;
+    cpu.update_nz(r);
+    r
+}
+
+fn rol<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = (b << 1) | cpu.c as u8;
+    cpu.c = (b & 0x80) != 0;
+    cpu.update_nz(r);
+    r
 }
 
-fn lsr(b: u8) -> u8 {
-    b
+fn ror<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = (b >> 1) | (cpu.c as u8) << 7;
+    cpu.c = (b & 0x01) != 0;
+    cpu.update_nz(r);
+    r
 }
 
-fn rol(b: u8) -> u8 {
-    b
+fn inc<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = b.wrapping_add(1);
+    cpu.update_nz(r);
+    r
 }
 
-fn ror(b: u8) -> u8 {
-    b
+fn dec<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = b.wrapping_sub(1);
+    cpu.update_nz(r);
+    r
 }
 
 //
 
-impl CPU {
+impl<B: Bus> CPU<B> {
+    // Fetching instructions goes straight to the bus, as it is not what
+    // watchpoints are looking for.
+
     fn fetch_byte(&mut self) -> u8 {
-        let v = self.get_byte(self.pc);
-        self.pc += 1;
+        let v = self.bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
         v
     }
 
     fn fetch_word(&mut self) -> u16 {
-        let v = self.get_word(self.pc);
-        self.pc += 2;
-        return v
+        let v = (self.bus.read(self.pc) as u16) | (self.bus.read(self.pc.wrapping_add(1)) as u16) << 8;
+        self.pc = self.pc.wrapping_add(2);
+        v
     }
 
     fn push_byte(&mut self, b: u8) {
-        self.ram[0x0100 + self.sp as usize] = b;
-        self.sp -= 1;
+        self.set_byte(0x0100 + self.sp as u16, b);
+        self.sp = self.sp.wrapping_sub(1);
     }
 
     fn pull_byte(&mut self) -> u8 {
-        self.sp += 1;
-        self.ram[0x0100 + self.sp as usize]
+        self.sp = self.sp.wrapping_add(1);
+        self.get_byte(0x0100 + self.sp as u16)
     }
 
     fn push_word(&mut self, w: u16) {
-        self.ram[0x0100 + self.sp as usize] = (w >> 8) as u8;
-        self.sp -= 1;
-        self.ram[0x0100 + self.sp as usize] = (w & 0xff) as u8;
-        self.sp -= 1;
+        self.push_byte((w >> 8) as u8);
+        self.push_byte((w & 0xff) as u8);
     }
 
     fn pull_word(&mut self) -> u16 {
@@ -182,8 +459,83 @@ impl CPU {
         self.z = v == 0;
     }
 
-    fn step(&mut self) -> Result<(), CPUError> {
+    // Branches take one extra cycle when taken and another one when the
+    // target is on a different page.
+
+    fn branch(&mut self, condition: bool, offset: u8) {
+        if condition {
+            let target = self.pc.wrapping_add(offset as i8 as u16);
+            self.dummy_read(self.pc);
+            self.cycles += 1;
+            if !same_page(self.pc, target) {
+                self.dummy_read(uncorrected(self.pc, target));
+                self.cycles += 1;
+            }
+            self.pc = target;
+        }
+    }
+
+    // The 6502 accesses the bus on every cycle, also when it has nothing to
+    // read. These reads are thrown away, but memory mapped I/O can see them.
+
+    fn dummy_read(&mut self, addr: u16) {
+        self.get_byte(addr);
+    }
+
+    // Executes one instruction, or handles a pending interrupt, and then
+    // lets the bus know how many cycles that took.
+
+    pub fn step(&mut self) -> Result<(), CPUError> {
+        let cycles = self.cycles;
+        let result = self.execute();
+        self.bus.tick(self.cycles - cycles);
+        result
+    }
+
+    fn execute(&mut self) -> Result<(), CPUError> {
+        if self.halted {
+            self.cycles += 1;
+            return Ok(());
+        }
+
+        // Any interrupt ends a WAI, also an IRQ while interrupts are
+        // disabled. Execution then simply continues after the WAI.
+        if self.waiting {
+            if !self.nmi_pending && self.irq == 0 {
+                self.cycles += 1;
+                return Ok(());
+            }
+            self.waiting = false;
+        }
+
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.dummy_read(self.pc);
+            self.dummy_read(self.pc);
+            self.interrupt(NMI_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES;
+            return Ok(());
+        }
+
+        if self.irq != 0 && !self.i {
+            self.dummy_read(self.pc);
+            self.dummy_read(self.pc);
+            self.interrupt(IRQ_VECTOR, false);
+            self.cycles += INTERRUPT_CYCLES;
+            return Ok(());
+        }
+
+        if self.trace.is_some() {
+            self.trace_instruction();
+        }
+
         let opcode = self.fetch_byte();
+        self.cycles += base_cycles(self.model, opcode) as u64;
+
+        if self.model.is_cmos() && self.step_cmos(opcode) {
+            return Ok(());
+        }
+
         match opcode {
             // Transfer Instructions
 
@@ -372,30 +724,36 @@ impl CPU {
             // Transfer between registers
 
             0xAA => { // TAX
+                self.dummy_read(self.pc);
                 self.x = self.a;
                 self.update_nz(self.x);
             }
 
             0xA8 => { // TAY
+                self.dummy_read(self.pc);
                 self.y = self.a;
                 self.update_nz(self.y);
             }
 
             0xBA => { // TSX
+                self.dummy_read(self.pc);
                 self.x = self.sp;
                 self.update_nz(self.x);
             }
 
             0x8A => { // TXA
+                self.dummy_read(self.pc);
                 self.a = self.x;
                 self.update_nz(self.a);
             }
 
             0x9A => { // TXS
+                self.dummy_read(self.pc);
                 self.sp = self.x;
             }
 
             0x98 => { // TYA
+                self.dummy_read(self.pc);
                 self.a = self.y;
                 self.update_nz(self.a);
             }
@@ -403,20 +761,26 @@ impl CPU {
             // Stack Instructions
 
             0x48 => { // PHA
+                self.dummy_read(self.pc);
                 self.push_byte(self.a);
             }
 
             0x08 => { // PHP
+                self.dummy_read(self.pc);
                 let oper = self.get_status() | 0b00110000;
                 self.push_byte(oper);
             }
 
             0x68 => { // PLA
+                self.dummy_read(self.pc);
+                self.dummy_read(0x0100 + self.sp as u16);
                 self.a = self.pull_byte();
                 self.update_nz(self.a);
             }
 
             0x28 => { // PLP
+                self.dummy_read(self.pc);
+                self.dummy_read(0x0100 + self.sp as u16);
                 let oper = self.pull_byte();
                 self.set_status(oper);
             }
@@ -424,77 +788,65 @@ impl CPU {
             // Decrements & Increments
 
             0xE6 => { // INC zpg
-                let oper = self.fetch_byte();
-                let v = self.get_byte(oper as u16).wrapping_add(1);
-                self.set_byte(oper as u16, v);
-                self.update_nz(v);
+                let addr = self.fetch_byte();
+                self.mem_mod_byte_zpg(addr, inc);
             }
-    
-            0xF6 => { // INC zpx
-                let oper = self.fetch_byte();
-                let v = self.get_byte((oper + self.x) as u16).wrapping_add(1);
-                self.set_byte((oper + self.x) as u16, v);
-                self.update_nz(v);
+
+            0xF6 => { // INC zpgx
+                let addr = self.fetch_byte();
+                self.mem_mod_byte_zpgx(addr, inc);
             }
 
             0xEE => { // INC abs
-                let oper = self.fetch_word();
-                let v = self.get_byte(oper).wrapping_add(1);
-                self.set_byte(oper, v);
-                self.update_nz(v);
+                let addr = self.fetch_word();
+                self.mem_mod_byte_abs(addr, inc);
             }
 
             0xFE => { // INC absx
-                let oper = self.fetch_word();
-                let v = self.get_byte(oper + self.x as u16).wrapping_add(1);
-                self.set_byte(oper + self.x as u16, v);
-                self.update_nz(v);
+                let addr = self.fetch_word();
+                self.mem_mod_byte_absx(addr, inc);
             }
 
             0xe8 => { // INX (NZ)
+                self.dummy_read(self.pc);
                 self.x = self.x.wrapping_add(1);
                 self.update_nz(self.x);
             }
 
             0xc8 => { // INY (NZ)
+                self.dummy_read(self.pc);
                 self.y = self.y.wrapping_add(1);
                 self.update_nz(self.y);
             }
 
             0xC6 => { // DEC zpg
-                let oper = self.fetch_byte();
-                let v = self.get_byte(oper as u16).wrapping_sub(1);
-                self.set_byte(oper as u16, v);
-                self.update_nz(v);
+                let addr = self.fetch_byte();
+                self.mem_mod_byte_zpg(addr, dec);
             }
-    
-            0xD6 => { // DEC zpx
-                let oper = self.fetch_byte();
-                let v = self.get_byte((oper + self.x) as u16).wrapping_sub(1);
-                self.set_byte((oper + self.x) as u16, v);
-                self.update_nz(v);
+
+            0xD6 => { // DEC zpgx
+                let addr = self.fetch_byte();
+                self.mem_mod_byte_zpgx(addr, dec);
             }
 
             0xCE => { // DEC abs
-                let oper = self.fetch_word();
-                let v = self.get_byte(oper).wrapping_sub(1);
-                self.set_byte(oper, v);
-                self.update_nz(v);
+                let addr = self.fetch_word();
+                self.mem_mod_byte_abs(addr, dec);
             }
 
             0xDE => { // DEC absx
-                let oper = self.fetch_word();
-                let v = self.get_byte(oper + self.x as u16).wrapping_sub(1);
-                self.set_byte(oper + self.x as u16, v);
-                self.update_nz(v);
+                let addr = self.fetch_word();
+                self.mem_mod_byte_absx(addr, dec);
             }
 
             0xCA => { // DEX (NZ)
+                self.dummy_read(self.pc);
                 self.x = self.x.wrapping_sub(1);
                 self.update_nz(self.x);
             }
 
             0x88 => { // DEY (NZ)
+                self.dummy_read(self.pc);
                 self.y = self.y.wrapping_sub(1);
                 self.update_nz(self.y);
             }
@@ -508,37 +860,44 @@ impl CPU {
 
             0x65 => { // ADC zpg
                 let addr = self.fetch_byte();
-                self.adc(self.mem_get_byte_zpg(addr));
+                let m = self.mem_get_byte_zpg(addr);
+                self.adc(m);
             }
 
             0x75 => { // ADC zpgx
                 let addr = self.fetch_byte();
-                self.adc(self.mem_get_byte_zpgx(addr));
+                let m = self.mem_get_byte_zpgx(addr);
+                self.adc(m);
             }
 
             0x6D => { // ADC abs
                 let addr = self.fetch_word();
-                self.adc(self.mem_get_byte_abs(addr));
+                let m = self.mem_get_byte_abs(addr);
+                self.adc(m);
             }
 
             0x7D => { // ADC absx
                 let addr = self.fetch_word();
-                self.adc(self.mem_get_byte_absx(addr));
+                let m = self.mem_get_byte_absx(addr);
+                self.adc(m);
             }
 
             0x79 => { // ADC absy
                 let addr = self.fetch_word();
-                self.adc(self.mem_get_byte_absy(addr));
+                let m = self.mem_get_byte_absy(addr);
+                self.adc(m);
             }
 
             0x61 => { // ADC indx
                 let addr = self.fetch_byte();
-                self.adc(self.mem_get_byte_indx(addr));
+                let m = self.mem_get_byte_indx(addr);
+                self.adc(m);
             }
 
             0x71 => { // ADC indy
                 let addr = self.fetch_byte();
-                self.adc(self.mem_get_byte_indy(addr));
+                let m = self.mem_get_byte_indy(addr);
+                self.adc(m);
             }
 
             0xE9 => { // SBC immediate
@@ -548,37 +907,44 @@ impl CPU {
 
             0xE5 => { // SBC zpg
                 let addr = self.fetch_byte();
-                self.sbc(self.mem_get_byte_zpg(addr));
+                let m = self.mem_get_byte_zpg(addr);
+                self.sbc(m);
             }
 
             0xF5 => { // SBC zpgx
                 let addr = self.fetch_byte();
-                self.sbc(self.mem_get_byte_zpgx(addr));
+                let m = self.mem_get_byte_zpgx(addr);
+                self.sbc(m);
             }
 
             0xED => { // SBC abs
                 let addr = self.fetch_word();
-                self.sbc(self.mem_get_byte_abs(addr));
+                let m = self.mem_get_byte_abs(addr);
+                self.sbc(m);
             }
 
             0xFD => { // SBC absx
                 let addr = self.fetch_word();
-                self.sbc(self.mem_get_byte_absx(addr));
+                let m = self.mem_get_byte_absx(addr);
+                self.sbc(m);
             }
 
             0xF9 => { // SBC absy
                 let addr = self.fetch_word();
-                self.sbc(self.mem_get_byte_absy(addr));
+                let m = self.mem_get_byte_absy(addr);
+                self.sbc(m);
             }
 
             0xE1 => { // SBC indx
                 let addr = self.fetch_byte();
-                self.sbc(self.mem_get_byte_indx(addr));
+                let m = self.mem_get_byte_indx(addr);
+                self.sbc(m);
             }
 
             0xF1 => { // SBC indy
                 let addr = self.fetch_byte();
-                self.sbc(self.mem_get_byte_indy(addr));
+                let m = self.mem_get_byte_indy(addr);
+                self.sbc(m);
             }
 
             // Jumps & Subroutines
@@ -589,19 +955,28 @@ impl CPU {
             }
 
             0x6c => { // JMP ind
+                // The NMOS 6502 does not carry into the high byte when
+                // the vector sits at the end of a page
                 let addr = self.fetch_word();
-                self.pc = self.get_word(addr);
+                let l = self.get_byte(addr);
+                let h = self.get_byte((addr & 0xff00) | (addr.wrapping_add(1) & 0x00ff));
+                self.pc = (l as u16) | (h as u16) << 8;
             }
 
-            0x20 => { // JSR abs
-                let addr = self.fetch_word();
+            0x20 => { // JSR abs - Pushes the address of its last byte
+                let l = self.fetch_byte();
+                self.dummy_read(0x0100 + self.sp as u16);
                 self.push_word(self.pc);
-                self.pc = addr;
+                let h = self.fetch_byte();
+                self.pc = (l as u16) | (h as u16) << 8;
             }
 
             0x60 => { // RTS
+                self.dummy_read(self.pc);
+                self.dummy_read(0x0100 + self.sp as u16);
                 let addr = self.pull_word();
-                self.pc = addr;
+                self.dummy_read(addr);
+                self.pc = addr.wrapping_add(1);
             }
 
             // Logical Operations: AND
@@ -613,37 +988,44 @@ impl CPU {
 
             0x25 => { // AND zp
                 let oper = self.fetch_byte();
-                self.and(self.mem_get_byte_zpg(oper));
+                let m = self.mem_get_byte_zpg(oper);
+                self.and(m);
             }
 
             0x35 => { // AND zpx
                 let oper = self.fetch_byte();
-                self.and(self.mem_get_byte_zpgx(oper));
+                let m = self.mem_get_byte_zpgx(oper);
+                self.and(m);
             }
 
             0x2D => { // AND abs
                 let oper = self.fetch_word();
-                self.and(self.mem_get_byte_abs(oper));
+                let m = self.mem_get_byte_abs(oper);
+                self.and(m);
             }
 
             0x3D => { // AND absx
                 let oper = self.fetch_word();
-                self.and(self.mem_get_byte_absx(oper));
+                let m = self.mem_get_byte_absx(oper);
+                self.and(m);
             }
 
             0x39 => { // AND absy
                 let oper = self.fetch_word();
-                self.and(self.mem_get_byte_absy(oper));
+                let m = self.mem_get_byte_absy(oper);
+                self.and(m);
             }
 
-            0x31 => { // AND indx
+            0x21 => { // AND indx
                 let oper = self.fetch_byte();
-                self.and(self.mem_get_byte_indx(oper));
+                let m = self.mem_get_byte_indx(oper);
+                self.and(m);
             }
 
-            0x21 => { // AND indy
+            0x31 => { // AND indy
                 let oper = self.fetch_byte();
-                self.and(self.mem_get_byte_indy(oper));
+                let m = self.mem_get_byte_indy(oper);
+                self.and(m);
             }
 
             // Logical operations: EOR
@@ -655,37 +1037,44 @@ impl CPU {
 
             0x45 => { // EOR zp
                 let oper = self.fetch_byte();
-                self.eor(self.mem_get_byte_zpg(oper));
+                let m = self.mem_get_byte_zpg(oper);
+                self.eor(m);
             }
 
             0x55 => { // EOR zpx
                 let oper = self.fetch_byte();
-                self.eor(self.mem_get_byte_zpgx(oper));
+                let m = self.mem_get_byte_zpgx(oper);
+                self.eor(m);
             }
 
             0x4D => { // EOR abs
                 let oper = self.fetch_word();
-                self.eor(self.mem_get_byte_abs(oper));
+                let m = self.mem_get_byte_abs(oper);
+                self.eor(m);
             }
 
             0x5D => { // EOR absx
                 let oper = self.fetch_word();
-                self.eor(self.mem_get_byte_absx(oper));
+                let m = self.mem_get_byte_absx(oper);
+                self.eor(m);
             }
 
             0x59 => { // EOR absy
                 let oper = self.fetch_word();
-                self.eor(self.mem_get_byte_absy(oper));
+                let m = self.mem_get_byte_absy(oper);
+                self.eor(m);
             }
 
             0x41 => { // EOR indx
                 let oper = self.fetch_byte();
-                self.eor(self.mem_get_byte_indx(oper));
+                let m = self.mem_get_byte_indx(oper);
+                self.eor(m);
             }
 
             0x51 => { // EOR indy
                 let oper = self.fetch_byte();
-                self.eor(self.mem_get_byte_indy(oper));
+                let m = self.mem_get_byte_indy(oper);
+                self.eor(m);
             }
 
             // Logical operations: ORA
@@ -697,43 +1086,52 @@ impl CPU {
 
             0x05 => { // ORA zp
                 let oper = self.fetch_byte();
-                self.ora(self.mem_get_byte_zpg(oper));
+                let m = self.mem_get_byte_zpg(oper);
+                self.ora(m);
             }
 
             0x15 => { // ORA zpx
                 let oper = self.fetch_byte();
-                self.ora(self.mem_get_byte_zpgx(oper));
+                let m = self.mem_get_byte_zpgx(oper);
+                self.ora(m);
             }
 
             0x0D => { // ORA abs
                 let oper = self.fetch_word();
-                self.ora(self.mem_get_byte_abs(oper));
+                let m = self.mem_get_byte_abs(oper);
+                self.ora(m);
             }
 
             0x1D => { // ORA absx
                 let oper = self.fetch_word();
-                self.ora(self.mem_get_byte_absx(oper));
+                let m = self.mem_get_byte_absx(oper);
+                self.ora(m);
             }
 
             0x19 => { // ORA absy
                 let oper = self.fetch_word();
-                self.ora(self.mem_get_byte_absy(oper));
+                let m = self.mem_get_byte_absy(oper);
+                self.ora(m);
             }
 
             0x01 => { // ORA indx
                 let oper = self.fetch_byte();
-                self.ora(self.mem_get_byte_indx(oper));
+                let m = self.mem_get_byte_indx(oper);
+                self.ora(m);
             }
 
-            0x11 => { // EOR indy
+            0x11 => { // ORA indy
                 let oper = self.fetch_byte();
-                self.eor(self.mem_get_byte_indy(oper));
+                let m = self.mem_get_byte_indy(oper);
+                self.ora(m);
             }
 
             // Shift & Rotate Instructions
 
             0x0A => { // ASL
-                self.a = asl(self.a);
+                self.dummy_read(self.pc);
+                let a = self.a;
+                self.a = asl(self, a);
             }
 
             0x06 => { // ASL zpg
@@ -757,7 +1155,9 @@ impl CPU {
             }
 
             0x4A => { // LSR
-                self.a = lsr(self.a);
+                self.dummy_read(self.pc);
+                let a = self.a;
+                self.a = lsr(self, a);
             }
 
             0x46 => { // LSR zpg
@@ -781,7 +1181,9 @@ impl CPU {
             }
 
             0x2A => { // ROL
-                self.a = rol(self.a);
+                self.dummy_read(self.pc);
+                let a = self.a;
+                self.a = rol(self, a);
             }
 
             0x26 => { // ROL zpg
@@ -805,7 +1207,9 @@ impl CPU {
             }
 
             0x6A => { // ROR
-                self.a = ror(self.a);
+                self.dummy_read(self.pc);
+                let a = self.a;
+                self.a = ror(self, a);
             }
 
             0x66 => { // ROR zpg
@@ -831,30 +1235,37 @@ impl CPU {
             // Flag Instructions
 
             0x18 => { // CLC
+                self.dummy_read(self.pc);
                 self.c = false;
             }
 
             0xD8 => { // CLD
+                self.dummy_read(self.pc);
                 self.d = false;
             }
 
             0x58 => { // CLI
+                self.dummy_read(self.pc);
                 self.i = false;
             }
 
             0xB8 => { // CLV
+                self.dummy_read(self.pc);
                 self.v = false;
             }
 
             0x38 => { // SEC
+                self.dummy_read(self.pc);
                 self.c = true;
             }
 
             0xF8 => { // SED
+                self.dummy_read(self.pc);
                 self.d = true;
             }
 
             0x78 => { // SEI
+                self.dummy_read(self.pc);
                 self.i = true;
             }
 
@@ -869,37 +1280,44 @@ impl CPU {
 
             0xc5 => { // CMP zpg
                 let oper = self.fetch_byte();
-                self.cmp(self.mem_get_byte_zpg(oper));
+                let m = self.mem_get_byte_zpg(oper);
+                self.cmp(m);
             }
 
             0xd5 => { // CMP zpx
                 let oper = self.fetch_byte();
-                self.cmp(self.mem_get_byte_zpgx(oper));
+                let m = self.mem_get_byte_zpgx(oper);
+                self.cmp(m);
             }
 
             0xcd => { // CMP abs
                 let oper = self.fetch_word();
-                self.cmp(self.mem_get_byte_abs(oper));
+                let m = self.mem_get_byte_abs(oper);
+                self.cmp(m);
             }
 
             0xDD => { // CMP absx
                 let oper = self.fetch_word();
-                self.cmp(self.mem_get_byte_absx(oper));
+                let m = self.mem_get_byte_absx(oper);
+                self.cmp(m);
             }
 
             0xD9 => { // CMP absy
                 let oper = self.fetch_word();
-                self.cmp(self.mem_get_byte_absy(oper));
+                let m = self.mem_get_byte_absy(oper);
+                self.cmp(m);
             }
 
             0xC1 => { // CMP indx
                 let oper = self.fetch_byte();
-                self.cmp(self.mem_get_byte_indx(oper));
+                let m = self.mem_get_byte_indx(oper);
+                self.cmp(m);
             }
 
             0xD1 => { // CMP indy
                 let oper = self.fetch_byte();
-                self.eor(self.mem_get_byte_indy(oper));
+                let m = self.mem_get_byte_indy(oper);
+                self.cmp(m);
             }
 
             // CPX
@@ -911,12 +1329,14 @@ impl CPU {
 
             0xE4 => { // CPX zpg
                 let oper = self.fetch_byte();
-                self.cpx(self.mem_get_byte_zpg(oper));
+                let m = self.mem_get_byte_zpg(oper);
+                self.cpx(m);
             }
 
             0xEC => { // CPX abs
                 let oper = self.fetch_word();
-                self.cpx(self.mem_get_byte_abs(oper));
+                let m = self.mem_get_byte_abs(oper);
+                self.cpx(m);
             }
 
             // CPY
@@ -928,104 +1348,99 @@ impl CPU {
 
             0xC4 => { // CPY zpg
                 let oper = self.fetch_byte();
-                self.cpy(self.mem_get_byte_zpg(oper));
+                let m = self.mem_get_byte_zpg(oper);
+                self.cpy(m);
             }
 
             0xCC => { // CPY abs
                 let oper = self.fetch_word();
-                self.cpy(self.mem_get_byte_abs(oper));
+                let m = self.mem_get_byte_abs(oper);
+                self.cpy(m);
             }
 
             // Branches
 
             0x90 => { // BCC
                 let oper = self.fetch_byte();
-                if !self.c {
-                    self.pc = self.pc.wrapping_add(oper as u16);
-                }
+                self.branch(!self.c, oper);
             }
 
             0xB0 => { // BCS
                 let oper = self.fetch_byte();
-                if self.c {
-                    self.pc = self.pc.wrapping_add(oper as u16);
-                }
+                self.branch(self.c, oper);
             }
 
             0xF0 => { // BEQ
                 let oper = self.fetch_byte();
-                if self.z {
-                    self.pc = self.pc.wrapping_add(oper as u16);
-                }
+                self.branch(self.z, oper);
             }
 
             0x30 => { // BMI
                 let oper = self.fetch_byte();
-                if self.n {
-                    self.pc = self.pc.wrapping_add(oper as u16);
-                }
+                self.branch(self.n, oper);
             }
 
             0xD0 => { // BNE
                 let oper = self.fetch_byte();
-                if !self.z {
-                    self.pc = self.pc.wrapping_add(oper as u16);
-                }
+                self.branch(!self.z, oper);
             }
 
             0x10 => { // BPL
                 let oper = self.fetch_byte();
-                if !self.n {
-                    self.pc = self.pc.wrapping_add(oper as u16);
-                }
+                self.branch(!self.n, oper);
             }
 
             0x50 => { // BVC
                 let oper = self.fetch_byte();
-                if !self.v {
-                    self.pc = self.pc.wrapping_add(oper as u16);
-                }
+                self.branch(!self.v, oper);
             }
 
             0x70 => { // BVS
                 let oper = self.fetch_byte();
-                if self.v {
-                    self.pc = self.pc.wrapping_add(oper as u16);
-                }
+                self.branch(self.v, oper);
             }
 
             // Interrupts
 
             0x00 => { // BRK
-                // TODO This is incorrect
-                self.pc -= 1;
-                return Err(CPUError::Break);
+                self.fetch_byte(); // Padding byte, BRK returns to PC+2
+                self.interrupt(IRQ_VECTOR, true);
             }
 
             0x40 => { // RTI
-                // TODO
+                self.dummy_read(self.pc);
+                self.dummy_read(0x0100 + self.sp as u16);
+                let status = self.pull_byte();
+                self.set_status(status);
+                self.pc = self.pull_word();
             }
 
             // Other
             
             0x24 => { // BIT zpg
                 let oper = self.fetch_byte();
-                self.bit(self.mem_get_byte_zpg(oper));
+                let m = self.mem_get_byte_zpg(oper);
+                self.bit(m);
             }
 
             0x2C => { // BIT abs
                 let oper = self.fetch_word();
-                self.bit(self.mem_get_byte_abs(oper));
+                let m = self.mem_get_byte_abs(oper);
+                self.bit(m);
             }
 
             0xEA => { // NOP
+                self.dummy_read(self.pc);
             }
 
-            // Anything else results in an error. This won't work well because some code depends on
-            // either behaviour of undefined 6502 opcodes or tries to detect the 65C02. For later.
+            // Anything else results in an error, unless we have been told to
+            // emulate the undocumented opcodes that some software depends on.
 
             _ => {
-                self.pc -= 1;
+                if self.undocumented_opcodes && !self.model.is_cmos() && self.step_undocumented(opcode) {
+                    return Ok(());
+                }
+                self.pc = self.pc.wrapping_sub(1);
                 return Err(CPUError::IllegalOpcode);
             }
         }
@@ -1035,7 +1450,7 @@ impl CPU {
 
 // TODO How to split this up into cpu_micro_ops.rs
 
-impl CPU {
+impl<B: Bus> CPU<B> {
     // Logic Operations
     
     fn and(&mut self, m: u8) {
@@ -1085,12 +1500,112 @@ impl CPU {
         self.z = t == 0;
     }
 
+    // Arithmetic
+
     fn adc(&mut self, m: u8) {
-        // TODO
+        if !self.d {
+            self.adc_binary(m);
+        } else if self.model.is_cmos() {
+            self.adc_decimal_cmos(m);
+        } else {
+            self.adc_decimal(m);
+        }
     }
 
     fn sbc(&mut self, m: u8) {
-        // TODO
+        if !self.d {
+            self.adc_binary(!m);
+        } else if self.model.is_cmos() {
+            self.sbc_decimal_cmos(m);
+        } else {
+            self.sbc_decimal(m);
+        }
+    }
+
+    fn adc_binary(&mut self, m: u8) {
+        let t = self.a as u16 + m as u16 + self.c as u16;
+        self.v = ((self.a ^ t as u8) & (m ^ t as u8) & 0x80) != 0;
+        self.c = t > 0xff;
+        self.a = t as u8;
+        self.update_nz(self.a);
+    }
+
+    // On the NMOS 6502 the N and V flags in decimal mode are taken from the
+    // intermediate result, before the high nibble is adjusted, and Z is taken
+    // from the plain binary sum. For invalid BCD operands the result is
+    // whatever this sequence produces, which is what real hardware does too.
+
+    fn adc_decimal(&mut self, m: u8) {
+        let c = self.c as u16;
+
+        let mut al = (self.a & 0x0f) as u16 + (m & 0x0f) as u16 + c;
+        if al >= 0x0a {
+            al = ((al + 0x06) & 0x0f) + 0x10;
+        }
+
+        let mut t = (self.a & 0xf0) as u16 + (m & 0xf0) as u16 + al;
+
+        self.z = (self.a as u16 + m as u16 + c) & 0xff == 0;
+        self.n = (t & 0x80) != 0;
+        self.v = ((self.a ^ t as u8) & (m ^ t as u8) & 0x80) != 0;
+
+        if t >= 0xa0 {
+            t += 0x60;
+        }
+
+        self.c = t >= 0x100;
+        self.a = t as u8;
+    }
+
+    // In decimal mode SBC sets all flags exactly like the binary subtraction
+    // would, only the accumulator gets the decimal adjusted result.
+
+    fn sbc_decimal(&mut self, m: u8) {
+        let a = self.a;
+        let borrow = !self.c as i16;
+
+        self.adc_binary(!m);
+
+        let mut al = (a & 0x0f) as i16 - (m & 0x0f) as i16 - borrow;
+        if al < 0 {
+            al = ((al - 0x06) & 0x0f) - 0x10;
+        }
+
+        let mut t = (a & 0xf0) as i16 - (m & 0xf0) as i16 + al;
+        if t < 0 {
+            t -= 0x60;
+        }
+
+        self.a = t as u8;
+    }
+
+    // The 65C02 fixes the N and Z flags in decimal mode by taking them from
+    // the final result, at the cost of an extra cycle.
+
+    fn adc_decimal_cmos(&mut self, m: u8) {
+        self.adc_decimal(m);
+        self.update_nz(self.a);
+        self.cycles += 1;
+    }
+
+    fn sbc_decimal_cmos(&mut self, m: u8) {
+        let a = self.a;
+        let borrow = !self.c as i16;
+
+        self.adc_binary(!m);
+
+        let al = (a & 0x0f) as i16 - (m & 0x0f) as i16 - borrow;
+        let mut t = a as i16 - m as i16 - borrow;
+        if t < 0 {
+            t -= 0x60;
+        }
+        if al < 0 {
+            t -= 0x06;
+        }
+
+        self.a = t as u8;
+        self.update_nz(self.a);
+        self.cycles += 1;
     }
 }
 
@@ -1103,9 +1618,9 @@ mod operations_tests {
         let mut cpu = CPU::new();
         cpu.a = 0x42;
         cpu.cmp(0x42);
-        assert_eq!(cpu.z, true);
-        assert_eq!(cpu.c, true);
-        assert_eq!(cpu.n, false);
+        assert!(cpu.z);
+        assert!(cpu.c);
+        assert!(!cpu.n);
     }
 
     #[test]
@@ -1113,9 +1628,9 @@ mod operations_tests {
         let mut cpu = CPU::new();
         cpu.a = 0x42;
         cpu.cmp(0x21);
-        assert_eq!(cpu.z, false);
-        assert_eq!(cpu.c, true);
-        assert_eq!(cpu.n, false);
+        assert!(!cpu.z);
+        assert!(cpu.c);
+        assert!(!cpu.n);
     }
 
     #[test]
@@ -1123,9 +1638,9 @@ mod operations_tests {
         let mut cpu = CPU::new();
         cpu.a = 0x84;
         cpu.cmp(0x01);
-        assert_eq!(cpu.z, false);
-        assert_eq!(cpu.c, true);
-        assert_eq!(cpu.n, true);
+        assert!(!cpu.z);
+        assert!(cpu.c);
+        assert!(cpu.n);
     }
 
     #[test]
@@ -1133,9 +1648,9 @@ mod operations_tests {
         let mut cpu = CPU::new();
         cpu.a = 0x42;
         cpu.cmp(0x84);
-        assert_eq!(cpu.z, false);
-        assert_eq!(cpu.c, false);
-        assert_eq!(cpu.n, true);
+        assert!(!cpu.z);
+        assert!(!cpu.c);
+        assert!(cpu.n);
     }
 
     #[test]
@@ -1143,47 +1658,393 @@ mod operations_tests {
         let mut cpu = CPU::new();
         cpu.a = 0x01;
         cpu.cmp(0x84);
-        assert_eq!(cpu.z, false);
-        assert_eq!(cpu.c, false);
-        assert_eq!(cpu.n, false);
+        assert!(!cpu.z);
+        assert!(!cpu.c);
+        assert!(!cpu.n);
+    }
+
+    #[test]
+    fn test_asl() {
+        let mut cpu = CPU::new();
+        assert_eq!(asl(&mut cpu, 0x81), 0x02);
+        assert!(cpu.c);
+        assert!(!cpu.n);
+        assert!(!cpu.z);
+        assert_eq!(asl(&mut cpu, 0x40), 0x80);
+        assert!(!cpu.c);
+        assert!(cpu.n);
+    }
+
+    #[test]
+    fn test_lsr() {
+        let mut cpu = CPU::new();
+        assert_eq!(lsr(&mut cpu, 0x01), 0x00);
+        assert!(cpu.c);
+        assert!(cpu.z);
+        assert!(!cpu.n);
+        assert_eq!(lsr(&mut cpu, 0x80), 0x40);
+        assert!(!cpu.c);
+    }
+
+    #[test]
+    fn test_rol() {
+        let mut cpu = CPU::new();
+        cpu.c = true;
+        assert_eq!(rol(&mut cpu, 0x80), 0x01);
+        assert!(cpu.c);
+        assert_eq!(rol(&mut cpu, 0x40), 0x81);
+        assert!(!cpu.c);
+        assert!(cpu.n);
+    }
+
+    #[test]
+    fn test_ror() {
+        let mut cpu = CPU::new();
+        cpu.c = true;
+        assert_eq!(ror(&mut cpu, 0x01), 0x80);
+        assert!(cpu.c);
+        assert!(cpu.n);
+        assert_eq!(ror(&mut cpu, 0x02), 0x81);
+        assert!(!cpu.c);
+    }
+}
+
+#[cfg(test)]
+mod arithmetic_tests {
+    use super::*;
+    use super::computer_tests::run_until_brk;
+
+    // Reference implementations, written down as directly as possible from
+    // Bruce Clark's "Decimal Mode" tutorial on 6502.org, so that they share
+    // as little as possible with the implementations above.
+
+    struct Result {
+        a: u8,
+        n: bool,
+        v: bool,
+        z: bool,
+        c: bool,
+    }
+
+    fn reference_adc_binary(a: u8, m: u8, c: bool) -> Result {
+        let unsigned = a as i32 + m as i32 + c as i32;
+        let signed = a as i8 as i32 + m as i8 as i32 + c as i32;
+        Result {
+            a: unsigned as u8,
+            n: (unsigned & 0x80) != 0,
+            v: !(-128..=127).contains(&signed),
+            z: (unsigned & 0xff) == 0,
+            c: unsigned > 0xff,
+        }
+    }
+
+    fn reference_sbc_binary(a: u8, m: u8, c: bool) -> Result {
+        let unsigned = a as i32 - m as i32 - !c as i32;
+        let signed = a as i8 as i32 - m as i8 as i32 - !c as i32;
+        Result {
+            a: unsigned as u8,
+            n: (unsigned & 0x80) != 0,
+            v: !(-128..=127).contains(&signed),
+            z: (unsigned & 0xff) == 0,
+            c: unsigned >= 0,
+        }
+    }
+
+    fn reference_adc_decimal(a: u8, m: u8, c: bool) -> Result {
+        let (a, m, c) = (a as i32, m as i32, c as i32);
+
+        // Seq. 1 gives the accumulator and carry
+        let mut al = (a & 0x0f) + (m & 0x0f) + c;
+        if al >= 0x0a {
+            al = ((al + 0x06) & 0x0f) + 0x10;
+        }
+        let mut r = (a & 0xf0) + (m & 0xf0) + al;
+        if r >= 0xa0 {
+            r += 0x60;
+        }
+
+        // Seq. 2 gives N and V using signed arithmetic
+        let mut al2 = (a & 0x0f) + (m & 0x0f) + c;
+        if al2 >= 0x0a {
+            al2 = ((al2 + 0x06) & 0x0f) + 0x10;
+        }
+        let s = (a & 0xf0) as u8 as i8 as i32 + (m & 0xf0) as u8 as i8 as i32 + al2;
+
+        Result {
+            a: r as u8,
+            n: (s & 0x80) != 0,
+            v: !(-128..=127).contains(&s),
+            z: ((a + m + c) & 0xff) == 0,
+            c: r >= 0x100,
+        }
+    }
+
+    fn reference_sbc_decimal(a: u8, m: u8, c: bool) -> Result {
+        let binary = reference_sbc_binary(a, m, c);
+        let (a, m, c) = (a as i32, m as i32, c as i32);
+
+        // Seq. 3
+        let mut al = (a & 0x0f) - (m & 0x0f) + c - 1;
+        if al < 0 {
+            al = ((al - 0x06) & 0x0f) - 0x10;
+        }
+        let mut r = (a & 0xf0) - (m & 0xf0) + al;
+        if r < 0 {
+            r -= 0x60;
+        }
+
+        Result { a: r as u8, ..binary }
+    }
+
+    // The 65C02 takes N and Z from the final result
+
+    fn reference_adc_decimal_cmos(a: u8, m: u8, c: bool) -> Result {
+        let r = reference_adc_decimal(a, m, c);
+        Result { n: (r.a & 0x80) != 0, z: r.a == 0, ..r }
+    }
+
+    fn reference_sbc_decimal_cmos(a: u8, m: u8, c: bool) -> Result {
+        let binary = reference_sbc_binary(a, m, c);
+        let (a, m, c) = (a as i32, m as i32, c as i32);
+
+        // Seq. 4
+        let al = (a & 0x0f) - (m & 0x0f) + c - 1;
+        let mut r = a - m + c - 1;
+        if r < 0 {
+            r -= 0x60;
+        }
+        if al < 0 {
+            r -= 0x06;
+        }
+
+        let r = r as u8;
+        Result { a: r, n: (r & 0x80) != 0, z: r == 0, ..binary }
+    }
+
+    fn check(op: fn(&mut CPU, u8), reference: fn(u8, u8, bool) -> Result, d: bool) {
+        check_model(Model::Nmos6502, op, reference, d);
+    }
+
+    fn check_model(model: Model, op: fn(&mut CPU, u8), reference: fn(u8, u8, bool) -> Result, d: bool) {
+        let mut cpu = CPU::with_model(Ram::new(), model);
+        for a in 0..=255u8 {
+            for m in 0..=255u8 {
+                for c in [false, true] {
+                    cpu.a = a;
+                    cpu.c = c;
+                    cpu.d = d;
+                    op(&mut cpu, m);
+
+                    let r = reference(a, m, c);
+                    let context = format!("a={:02X} m={:02X} c={}", a, m, c);
+                    assert_eq!(cpu.a, r.a, "A {}", context);
+                    assert_eq!(cpu.n, r.n, "N {}", context);
+                    assert_eq!(cpu.v, r.v, "V {}", context);
+                    assert_eq!(cpu.z, r.z, "Z {}", context);
+                    assert_eq!(cpu.c, r.c, "C {}", context);
+                    assert_eq!(cpu.d, d, "D {}", context);
+                }
+            }
+        }
+    }
+
+    fn bcd(v: u8) -> u8 {
+        ((v / 10) << 4) | (v % 10)
+    }
+
+    #[test]
+    fn test_adc_binary_exhaustive() {
+        check(CPU::adc, reference_adc_binary, false);
+    }
+
+    #[test]
+    fn test_sbc_binary_exhaustive() {
+        check(CPU::sbc, reference_sbc_binary, false);
+    }
+
+    #[test]
+    fn test_adc_decimal_exhaustive() {
+        check(CPU::adc, reference_adc_decimal, true);
+    }
+
+    #[test]
+    fn test_sbc_decimal_exhaustive() {
+        check(CPU::sbc, reference_sbc_decimal, true);
+    }
+
+    #[test]
+    fn test_adc_binary_exhaustive_cmos() {
+        check_model(Model::Cmos65C02, CPU::adc, reference_adc_binary, false);
+    }
+
+    #[test]
+    fn test_sbc_binary_exhaustive_cmos() {
+        check_model(Model::Cmos65C02, CPU::sbc, reference_sbc_binary, false);
+    }
+
+    #[test]
+    fn test_adc_decimal_exhaustive_cmos() {
+        check_model(Model::Cmos65C02, CPU::adc, reference_adc_decimal_cmos, true);
+    }
+
+    #[test]
+    fn test_sbc_decimal_exhaustive_cmos() {
+        check_model(Model::Cmos65C02, CPU::sbc, reference_sbc_decimal_cmos, true);
+    }
+
+    #[test]
+    fn test_adc_decimal_valid_bcd() {
+        let mut cpu = CPU::new();
+        cpu.d = true;
+        for a in 0..100u8 {
+            for m in 0..100u8 {
+                for c in [false, true] {
+                    cpu.a = bcd(a);
+                    cpu.c = c;
+                    cpu.adc(bcd(m));
+                    let sum = a as u16 + m as u16 + c as u16;
+                    assert_eq!(cpu.a, bcd((sum % 100) as u8));
+                    assert_eq!(cpu.c, sum >= 100);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sbc_decimal_valid_bcd() {
+        let mut cpu = CPU::new();
+        cpu.d = true;
+        for a in 0..100u8 {
+            for m in 0..100u8 {
+                for c in [false, true] {
+                    cpu.a = bcd(a);
+                    cpu.c = c;
+                    cpu.sbc(bcd(m));
+                    let difference = a as i16 - m as i16 - !c as i16;
+                    assert_eq!(cpu.a, bcd(difference.rem_euclid(100) as u8));
+                    assert_eq!(cpu.c, difference >= 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_adc_known_values() {
+        let mut cpu = CPU::new();
+
+        cpu.a = 0x50; cpu.c = false;
+        cpu.adc(0x50);
+        assert_eq!(cpu.a, 0xa0);
+        assert!(cpu.v);
+        assert!(cpu.n);
+        assert!(!cpu.c);
+
+        cpu.d = true;
+        cpu.a = 0x99; cpu.c = false;
+        cpu.adc(0x01);
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.c);
+        assert!(!cpu.z); // Z comes from the binary result $9A on the NMOS 6502
+        assert!(cpu.n);
+    }
+
+    #[test]
+    fn test_sbc_program() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, vec![
+            0xF8,               // $0400 SED
+            0x38,               // $0401 SEC
+            0xA9, 0x42,         // $0402 LDA #$42
+            0xE9, 0x13,         // $0404 SBC #$13
+            0x00,               // $0406 BRK
+        ]);
+
+        run_until_brk(&mut cpu);
+        assert_eq!(cpu.a, 0x29);
+        assert!(cpu.c);
     }
 }
 
 // TODO How to split this up into cpu_addressing.rs
 
-impl CPU {
+fn same_page(a: u16, b: u16) -> bool {
+    (a & 0xff00) == (b & 0xff00)
+}
+
+// The address an indexed access sees before the carry into the high byte
+
+fn uncorrected(base: u16, effective: u16) -> u16 {
+    (base & 0xff00) | (effective & 0x00ff)
+}
+
+impl<B: Bus> CPU<B> {
+    // Pointers in the zero page wrap around to $00 instead of reaching
+    // into the stack page.
+
+    fn get_word_zpg(&mut self, addr: u8) -> u16 {
+        (self.get_byte(addr as u16) as u16) | (self.get_byte(addr.wrapping_add(1) as u16) as u16) << 8
+    }
+
     // Getters
 
-    pub fn mem_get_byte_zpg(&self, addr: u8) -> u8 {
+    pub fn mem_get_byte_zpg(&mut self, addr: u8) -> u8 {
         self.get_byte(addr as u16)
     }
 
-    pub fn mem_get_byte_zpgx(&self, addr: u8) -> u8 {
+    pub fn mem_get_byte_zpgx(&mut self, addr: u8) -> u8 {
+        self.dummy_read(addr as u16);
         self.get_byte(addr.wrapping_add(self.x) as u16)
     }
 
-    pub fn mem_get_byte_zpgy(&self, addr: u8) -> u8 {
+    pub fn mem_get_byte_zpgy(&mut self, addr: u8) -> u8 {
+        self.dummy_read(addr as u16);
         self.get_byte(addr.wrapping_add(self.y) as u16)
     }
 
-    pub fn mem_get_byte_abs(&self, addr: u16) -> u8 {
+    pub fn mem_get_byte_abs(&mut self, addr: u16) -> u8 {
         self.get_byte(addr)
     }
 
-    pub fn mem_get_byte_absx(&self, addr: u16) -> u8 {
-        self.get_byte(addr.wrapping_add(self.x as u16))
+    pub fn mem_get_byte_absx(&mut self, addr: u16) -> u8 {
+        let effective = addr.wrapping_add(self.x as u16);
+        self.page_crossing_penalty(addr, effective);
+        self.get_byte(effective)
     }
 
-    pub fn mem_get_byte_absy(&self, addr: u16) -> u8 {
-        self.get_byte(addr.wrapping_add(self.y as u16))
+    pub fn mem_get_byte_absy(&mut self, addr: u16) -> u8 {
+        let effective = addr.wrapping_add(self.y as u16);
+        self.page_crossing_penalty(addr, effective);
+        self.get_byte(effective)
     }
 
-    pub fn mem_get_byte_indx(&self, addr: u8) -> u8 {
-        self.get_byte(self.get_word(addr.wrapping_add(self.x) as u16))
+    pub fn mem_get_byte_ind(&mut self, addr: u8) -> u8 {
+        let addr = self.get_word_zpg(addr);
+        self.get_byte(addr)
     }
 
-    pub fn mem_get_byte_indy(&self, addr: u8) -> u8 {
-        self.get_byte(self.get_word(addr as u16).wrapping_add(self.y as u16))
+    pub fn mem_get_byte_indx(&mut self, addr: u8) -> u8 {
+        self.dummy_read(addr as u16);
+        let addr = self.get_word_zpg(addr.wrapping_add(self.x));
+        self.get_byte(addr)
+    }
+
+    pub fn mem_get_byte_indy(&mut self, addr: u8) -> u8 {
+        let base = self.get_word_zpg(addr);
+        let effective = base.wrapping_add(self.y as u16);
+        self.page_crossing_penalty(base, effective);
+        self.get_byte(effective)
+    }
+
+    // Indexed reads take an extra cycle when the index carries into the high
+    // byte of the address, during which the CPU reads from the address it had
+    // before fixing up the high byte. Writes and read-modify-write always take
+    // that cycle, which is already accounted for in their base cycle count.
+
+    fn page_crossing_penalty(&mut self, base: u16, effective: u16) {
+        if !same_page(base, effective) {
+            self.dummy_read(uncorrected(base, effective));
+            self.cycles += 1;
+        }
     }
 
     // Setters
@@ -1193,10 +2054,12 @@ impl CPU {
     }
 
     pub fn mem_set_byte_zpgx(&mut self, addr: u8, b: u8) {
+        self.dummy_read(addr as u16);
         self.set_byte(addr.wrapping_add(self.x) as u16, b);
     }
 
     pub fn mem_set_byte_zpgy(&mut self, addr: u8, b: u8) {
+        self.dummy_read(addr as u16);
         self.set_byte(addr.wrapping_add(self.y) as u16, b);
     }
 
@@ -1205,40 +2068,88 @@ impl CPU {
     }
 
     pub fn mem_set_byte_absx(&mut self, addr: u16, b: u8) {
-        self.set_byte(addr.wrapping_add(self.x as u16), b);
+        let effective = addr.wrapping_add(self.x as u16);
+        self.dummy_read(uncorrected(addr, effective));
+        self.set_byte(effective, b);
     }
 
     pub fn mem_set_byte_absy(&mut self, addr: u16, b: u8) {
-        self.set_byte(addr.wrapping_add(self.y as u16), b);
+        let effective = addr.wrapping_add(self.y as u16);
+        self.dummy_read(uncorrected(addr, effective));
+        self.set_byte(effective, b);
+    }
+
+    pub fn mem_set_byte_ind(&mut self, addr: u8, b: u8) {
+        let addr = self.get_word_zpg(addr);
+        self.set_byte(addr, b);
     }
 
     pub fn mem_set_byte_indx(&mut self, addr: u8, b: u8) {
-        self.set_byte(self.get_word(addr.wrapping_add(self.x) as u16), b);
+        self.dummy_read(addr as u16);
+        let addr = self.get_word_zpg(addr.wrapping_add(self.x));
+        self.set_byte(addr, b);
     }
 
     pub fn mem_set_byte_indy(&mut self, addr: u8, b: u8) {
-        self.set_byte(self.get_word(addr as u16).wrapping_add(self.y as u16), b);
+        let base = self.get_word_zpg(addr);
+        let effective = base.wrapping_add(self.y as u16);
+        self.dummy_read(uncorrected(base, effective));
+        self.set_byte(effective, b);
     }
 
     // Modifiers
 
-    pub fn mod_byte(&mut self, addr: u16, modifier: fn(u8) -> u8) {
+    // The NMOS 6502 writes the unmodified value back before writing the
+    // modified one, the 65C02 reads it twice instead. Memory mapped I/O can
+    // observe this, so we do the same.
+
+    pub fn mod_byte(&mut self, addr: u16, modifier: Modifier<B>) {
+        let b = self.get_byte(addr);
+        if self.model.is_cmos() {
+            self.get_byte(addr);
+        } else {
+            self.set_byte(addr, b);
+        }
+        let r = modifier(self, b);
+        self.set_byte(addr, r);
     }
 
-    pub fn mem_mod_byte_zpg(&mut self, addr: u8, modifier: fn(u8) -> u8) {
+    pub fn mem_mod_byte_zpg(&mut self, addr: u8, modifier: Modifier<B>) {
         self.mod_byte(addr as u16, modifier);
     }
 
-    pub fn mem_mod_byte_zpgx(&mut self, addr: u8, modifier: fn(u8) -> u8) {
-        self.mod_byte(addr as u16, modifier);
+    pub fn mem_mod_byte_zpgx(&mut self, addr: u8, modifier: Modifier<B>) {
+        self.dummy_read(addr as u16);
+        self.mod_byte(addr.wrapping_add(self.x) as u16, modifier);
     }
 
-    pub fn mem_mod_byte_abs(&mut self, addr: u16, modifier: fn(u8) -> u8) {
-        self.mod_byte(addr as u16, modifier);
+    pub fn mem_mod_byte_abs(&mut self, addr: u16, modifier: Modifier<B>) {
+        self.mod_byte(addr, modifier);
     }
 
-    pub fn mem_mod_byte_absx(&mut self, addr: u16, modifier: fn(u8) -> u8) {
-        self.mod_byte(addr as u16, modifier);
+    pub fn mem_mod_byte_absx(&mut self, addr: u16, modifier: Modifier<B>) {
+        let effective = addr.wrapping_add(self.x as u16);
+        self.dummy_read(uncorrected(addr, effective));
+        self.mod_byte(effective, modifier);
+    }
+
+    pub fn mem_mod_byte_absy(&mut self, addr: u16, modifier: Modifier<B>) {
+        let effective = addr.wrapping_add(self.y as u16);
+        self.dummy_read(uncorrected(addr, effective));
+        self.mod_byte(effective, modifier);
+    }
+
+    pub fn mem_mod_byte_indx(&mut self, addr: u8, modifier: Modifier<B>) {
+        self.dummy_read(addr as u16);
+        let addr = self.get_word_zpg(addr.wrapping_add(self.x));
+        self.mod_byte(addr, modifier);
+    }
+
+    pub fn mem_mod_byte_indy(&mut self, addr: u8, modifier: Modifier<B>) {
+        let base = self.get_word_zpg(addr);
+        let effective = base.wrapping_add(self.y as u16);
+        self.dummy_read(uncorrected(base, effective));
+        self.mod_byte(effective, modifier);
     }
 }
 
@@ -1326,12 +2237,70 @@ mod tests {
         cpu.y = 0x20;
         assert_eq!(cpu.mem_get_byte_indy(5), 0x42);
     }
+
+    // A bus that records all writes so tests can see what the CPU does on the bus
+
+    #[derive(Debug)]
+    struct RecordingBus {
+        ram: Ram,
+        writes: Vec<(u16, u8)>,
+    }
+
+    impl Bus for RecordingBus {
+        fn read(&mut self, addr: u16) -> u8 {
+            self.ram.read(addr)
+        }
+
+        fn write(&mut self, addr: u16, b: u8) {
+            self.writes.push((addr, b));
+            self.ram.write(addr, b);
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.ram.peek(addr)
+        }
+    }
+
+    #[test]
+    fn test_mem_mod_byte_writes_twice() {
+        let mut cpu = CPU::with_bus(RecordingBus { ram: Ram::new(), writes: Vec::new() });
+        cpu.bus.ram.write(0x10, 0x21);
+        cpu.mem_mod_byte_zpg(0x10, asl);
+        assert_eq!(cpu.bus.writes, vec![(0x10, 0x21), (0x10, 0x42)]);
+    }
+
+    #[test]
+    fn test_mem_mod_byte_zpgx_wrapping() {
+        let mut cpu = CPU::new();
+        cpu.set_byte(0x10, 0x41);
+        cpu.x = 0x20;
+        cpu.mem_mod_byte_zpgx(0xf0, inc);
+        assert_eq!(cpu.get_byte(0x10), 0x42);
+    }
+
+    #[test]
+    fn test_mem_mod_byte_absx() {
+        let mut cpu = CPU::new();
+        cpu.set_byte(0x0510, 0x21);
+        cpu.x = 0x20;
+        cpu.mem_mod_byte_absx(0x04f0, asl);
+        assert_eq!(cpu.get_byte(0x0510), 0x42);
+    }
 }
 
 #[cfg(test)]
 mod computer_tests {
     use super::*;
 
+    // Runs the program loaded at $0400 until it reaches a BRK instruction
+
+    pub(super) fn run_until_brk(cpu: &mut CPU) {
+        cpu.pc = 0x0400;
+        while cpu.peek_byte(cpu.pc) != 0x00 {
+            cpu.step().unwrap();
+        }
+    }
+
     #[test]
     fn test_initialized() {
         let cpu = CPU::new();
@@ -1339,8 +2308,18 @@ mod computer_tests {
         assert_eq!(cpu.x, 0x00);
         assert_eq!(cpu.y, 0x00);
         assert_eq!(cpu.sp, 0xff);
-        assert_eq!(cpu.pc, 0x0400);
-        assert_eq!(cpu.ram.len(), 64*1024);
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.peek_byte(0xffff), 0x00);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut cpu = CPU::new();
+        cpu.set_word(RESET_VECTOR, 0xff00);
+        cpu.reset();
+        assert_eq!(cpu.pc, 0xff00);
+        assert_eq!(cpu.sp, 0xfc);
+        assert!(cpu.i);
     }
 
     #[test]
@@ -1383,10 +2362,104 @@ mod computer_tests {
     }
 
     #[test]
-    fn test_breaks_without_program() {
+    fn test_brk_rti() {
         let mut cpu = CPU::new();
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
-        assert_eq!(cpu.pc, 0x0400);
+        cpu.set_word(IRQ_VECTOR, 0x0500);
+        cpu.load(0x0400, vec![
+            0x00, 0xEA,         // $0400 BRK
+            0xA9, 0x42,         // $0402 LDA #$42
+        ]);
+        cpu.load(0x0500, vec![
+            0x40,               // $0500 RTI
+        ]);
+
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0500);
+        assert_eq!(cpu.sp, 0xfc);
+        assert_eq!(cpu.get_byte(0x01ff), 0x04);
+        assert_eq!(cpu.get_byte(0x01fe), 0x02);
+        assert_eq!(cpu.get_byte(0x01fd), 0b00110000);
+        assert!(cpu.i);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0402);
+        assert_eq!(cpu.sp, 0xff);
+        assert!(!cpu.i);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x42);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut cpu = CPU::new();
+        cpu.set_word(IRQ_VECTOR, 0x0500);
+        cpu.load(0x0400, vec![
+            0x78,               // $0400 SEI
+            0xEA,               // $0401 NOP
+            0x58,               // $0402 CLI
+            0xEA,               // $0403 NOP
+        ]);
+
+        let line = IrqLine::new(3).unwrap();
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        cpu.assert_irq(line);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0402);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0403);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0500);
+        assert_eq!(cpu.get_byte(0x01fd), 0b00100000);
+        assert!(cpu.i);
+
+        cpu.release_irq(line);
+        cpu.i = false;
+        cpu.pc = 0x0403;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0404);
+    }
+
+    #[test]
+    fn test_irq_lines() {
+        assert_eq!(IrqLine::new(7), Some(IrqLine(7)));
+        assert_eq!(IrqLine::new(8), None);
+
+        // The request stays until every line that asserted it releases it
+        let mut cpu = CPU::new();
+        let (a, b) = (IrqLine::new(0).unwrap(), IrqLine::new(7).unwrap());
+        cpu.assert_irq(a);
+        cpu.assert_irq(b);
+        cpu.release_irq(a);
+        assert_eq!(cpu.irq, 0x80);
+        cpu.release_irq(b);
+        assert_eq!(cpu.irq, 0x00);
+    }
+
+    #[test]
+    fn test_nmi_is_edge_triggered() {
+        let mut cpu = CPU::new();
+        cpu.set_word(NMI_VECTOR, 0x0500);
+        cpu.load(0x0500, vec![
+            0xEA,               // $0500 NOP
+            0xEA,               // $0501 NOP
+        ]);
+
+        cpu.i = true;
+        cpu.assert_nmi();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0500);
+
+        cpu.assert_nmi();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0501);
+
+        cpu.release_nmi();
+        cpu.assert_nmi();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0500);
     }
 
     #[test]
@@ -1401,7 +2474,7 @@ mod computer_tests {
         let mut cpu = CPU::new();
         cpu.load(0x0400, program);
 
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
+        run_until_brk(&mut cpu);
 
         assert_eq!(cpu.a, 0x11);
         assert_eq!(cpu.x, 0x22);
@@ -1421,7 +2494,7 @@ mod computer_tests {
             0x00                // $0407 BRK
         ]);
 
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
+        run_until_brk(&mut cpu);
         assert_eq!(cpu.pc, 0x0407);
         assert_eq!(cpu.a, 0x42);
     }
@@ -1438,7 +2511,7 @@ mod computer_tests {
             0x05, 0x04          // $0408
         ]);
 
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
+        run_until_brk(&mut cpu);
         assert_eq!(cpu.pc, 0x0407);
         assert_eq!(cpu.a, 0x42);
     }
@@ -1454,12 +2527,98 @@ mod computer_tests {
             0x60,               // $0407 RTS
         ]);
 
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
+        run_until_brk(&mut cpu);
         assert_eq!(cpu.pc, 0x0403);
         assert_eq!(cpu.a, 0x42);
         assert_eq!(cpu.sp, 0xff);
     }
 
+    #[test]
+    fn test_jsr_pushes_address_of_last_byte() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, vec![
+            0x20, 0x05, 0x04,   // $0400 JSR $0405
+        ]);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x01ff), 0x04);
+        assert_eq!(cpu.peek_byte(0x01fe), 0x02);
+    }
+
+    #[test]
+    fn test_and_indirect() {
+        let mut cpu = CPU::new();
+        cpu.set_word(0x10, 0x1000);
+        cpu.set_byte(0x1000, 0x0f);
+        cpu.set_byte(0x1001, 0x3c);
+        cpu.load(0x0400, vec![
+            0xA9, 0xFF,         // $0400 LDA #$FF
+            0xA2, 0x00,         // $0402 LDX #$00
+            0x21, 0x10,         // $0404 AND ($10,X)
+            0x00,               // $0406 BRK
+        ]);
+        run_until_brk(&mut cpu);
+        assert_eq!(cpu.a, 0x0f);
+
+        cpu.load(0x0400, vec![
+            0xA9, 0xFF,         // $0400 LDA #$FF
+            0xA0, 0x01,         // $0402 LDY #$01
+            0x31, 0x10,         // $0404 AND ($10),Y
+            0x00,               // $0406 BRK
+        ]);
+        run_until_brk(&mut cpu);
+        assert_eq!(cpu.a, 0x3c);
+    }
+
+    #[test]
+    fn test_ora_and_cmp_indirect_indexed() {
+        let mut cpu = CPU::new();
+        cpu.set_word(0x10, 0x1000);
+        cpu.set_byte(0x1001, 0x3c);
+        cpu.load(0x0400, vec![
+            0xA9, 0xF0,         // $0400 LDA #$F0
+            0xA0, 0x01,         // $0402 LDY #$01
+            0x11, 0x10,         // $0404 ORA ($10),Y
+            0x00,               // $0406 BRK
+        ]);
+        run_until_brk(&mut cpu);
+        assert_eq!(cpu.a, 0xfc);
+
+        // CMP leaves A alone and sets the flags like a subtraction
+        cpu.load(0x0400, vec![
+            0xA9, 0x3C,         // $0400 LDA #$3C
+            0xD1, 0x10,         // $0402 CMP ($10),Y
+            0x00,               // $0404 BRK
+        ]);
+        run_until_brk(&mut cpu);
+        assert_eq!(cpu.a, 0x3c);
+        assert!(cpu.z);
+        assert!(cpu.c);
+    }
+
+    #[test]
+    fn test_run_until_trap() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, vec![
+            0xA2, 0x03,         // $0400 LDX #$03
+            0xCA,               // $0402 DEX
+            0xD0, 0xFD,         // $0403 BNE $0402
+            0xF0, 0xFE,         // $0405 BEQ $0405
+        ]);
+        cpu.pc = 0x0400;
+        assert_eq!(cpu.run_until_trap(), Ok(0x0405));
+        assert_eq!(cpu.x, 0x00);
+    }
+
+    #[test]
+    fn test_illegal_opcode_at_end_of_memory() {
+        let mut cpu = CPU::new();
+        cpu.load(0xffff, vec![0x02]);
+        cpu.pc = 0xffff;
+        assert_eq!(cpu.step(), Err(CPUError::IllegalOpcode));
+        assert_eq!(cpu.pc, 0xffff);
+    }
+
     #[test]
     fn test_inx_wrapping() {
         let mut cpu = CPU::new();
@@ -1469,7 +2628,7 @@ mod computer_tests {
             0x00,               // $0403 BRK
         ]);
 
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
+        run_until_brk(&mut cpu);
         assert_eq!(cpu.pc, 0x0403);
         assert_eq!(cpu.x, 0x00);
     }
@@ -1483,7 +2642,7 @@ mod computer_tests {
             0x00,               // $0403 BRK
         ]);
 
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
+        run_until_brk(&mut cpu);
         assert_eq!(cpu.pc, 0x0403);
         assert_eq!(cpu.x, 0xFF);
     }
@@ -1498,7 +2657,7 @@ mod computer_tests {
             0x00,               // $0405 BRK
         ]);
 
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
+        run_until_brk(&mut cpu);
         assert_eq!(cpu.pc, 0x0405);
     }
 
@@ -1512,7 +2671,102 @@ mod computer_tests {
             0x00,               // $0405 BRK
         ]);
 
-        assert_eq!(cpu.run().unwrap_err(), CPUError::Break);
+        run_until_brk(&mut cpu);
         assert_eq!(cpu.pc, 0x0404);
     }
+
+    #[test]
+    fn test_shift_and_rotate() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, vec![
+            0xA9, 0x81,         // $0400 LDA #$81
+            0x0A,               // $0402 ASL
+            0x2A,               // $0403 ROL
+            0x85, 0x10,         // $0404 STA $10
+            0x46, 0x10,         // $0406 LSR $10
+            0x66, 0x10,         // $0408 ROR $10
+            0x00,               // $040A BRK
+        ]);
+
+        run_until_brk(&mut cpu);
+        assert_eq!(cpu.a, 0x05);
+        assert_eq!(cpu.get_byte(0x10), 0x81);
+        assert!(!cpu.c);
+        assert!(cpu.n);
+    }
+}
+
+#[cfg(test)]
+mod timing_tests {
+    use super::*;
+
+    fn cycles(program: Vec<u8>, steps: usize, setup: fn(&mut CPU)) -> u64 {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, program);
+        cpu.pc = 0x0400;
+        setup(&mut cpu);
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+        cpu.cycles
+    }
+
+    #[test]
+    fn test_instruction_cycles() {
+        assert_eq!(cycles(vec![0xA9, 0x42], 1, |_| {}), 2);             // LDA #$42
+        assert_eq!(cycles(vec![0xAD, 0x00, 0x10], 1, |_| {}), 4);       // LDA $1000
+        assert_eq!(cycles(vec![0x20, 0x00, 0x10], 1, |_| {}), 6);       // JSR $1000
+        assert_eq!(cycles(vec![0xFE, 0xFF, 0x10], 1, |cpu| cpu.x = 1), 7); // INC $10FF,X
+    }
+
+    #[test]
+    fn test_page_crossing_cycles() {
+        assert_eq!(cycles(vec![0xBD, 0x00, 0x10], 1, |cpu| cpu.x = 0xff), 4); // LDA $1000,X
+        assert_eq!(cycles(vec![0xBD, 0x01, 0x10], 1, |cpu| cpu.x = 0xff), 5); // LDA $1001,X
+        assert_eq!(cycles(vec![0xB9, 0xff, 0x10], 1, |cpu| cpu.y = 0x01), 5); // LDA $10FF,Y
+        assert_eq!(cycles(vec![0x9D, 0x01, 0x10], 1, |cpu| cpu.x = 0xff), 5); // STA $1001,X
+    }
+
+    #[test]
+    fn test_indy_page_crossing_cycles() {
+        let setup = |cpu: &mut CPU| {
+            cpu.set_word(0x0010, 0x10f0);
+            cpu.y = 0x10;
+        };
+        assert_eq!(cycles(vec![0xB1, 0x10], 1, setup), 6); // LDA ($10),Y
+        assert_eq!(cycles(vec![0x91, 0x10], 1, setup), 6); // STA ($10),Y
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        assert_eq!(cycles(vec![0xD0, 0x10], 1, |cpu| cpu.z = true), 2);  // BNE not taken
+        assert_eq!(cycles(vec![0xD0, 0x10], 1, |cpu| cpu.z = false), 3); // BNE taken
+        assert_eq!(cycles(vec![0xD0, 0x80], 1, |cpu| cpu.z = false), 4); // BNE taken to $0382
+    }
+
+    #[test]
+    fn test_branch_backwards() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, vec![
+            0xA2, 0x03,         // $0400 LDX #$03
+            0xCA,               // $0402 DEX
+            0xD0, 0xFD,         // $0403 BNE $0402
+            0x00,               // $0405 BRK
+        ]);
+
+        cpu.pc = 0x0400;
+        while cpu.peek_byte(cpu.pc) != 0x00 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.x, 0x00);
+        assert_eq!(cpu.cycles, 2 + 3 * 2 + 2 * 3 + 2);
+    }
+
+    #[test]
+    fn test_interrupt_cycles() {
+        let mut cpu = CPU::new();
+        cpu.assert_nmi();
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycles, 7);
+    }
 }
@@ -0,0 +1,652 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Breakpoints and watchpoints. Both are a range of addresses and the kinds
+// of access that trigger them: executing an instruction that starts in the
+// range, or reading or writing memory in it. Reads and writes are seen where
+// the CPU goes to the bus, so dummy reads and the double writes of
+// read-modify-write instructions count too, but fetching instructions does
+// not. A breakpoint with a condition only triggers when the condition holds
+// after the instruction, or before it for execute breakpoints. It counts a
+// hit each time it triggers, and lets the ignore count go by first.
+//
+// Conditions are expressions over the registers A, X, Y, SP, PC and P, the
+// flags N, V, B, D, I, Z and C, and memory as mem[address]. Numbers with a
+// $ or 0x are hex, others decimal. From loose to tight binding:
+//
+//   ||  &&  == != < <= > >=  |  ^  &  + -  unary ! -
+//
+// Like in C, a comparison is 1 or 0 and anything that is not 0 is true:
+//
+//   A == $20 && mem[$FF] > 3
+//
+// When nothing watches memory this costs the CPU one check of a flag for
+// each read and write.
+
+use std::fmt;
+
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "execute",
+        })
+    }
+}
+
+// Why CPU::run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // The address that was accessed, which for execute breakpoints is the PC
+    Breakpoint { id: usize, address: u16, access: Access },
+    IllegalOpcode { pc: u16 },
+    Halted,
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    pub enabled: bool,
+    pub condition: Option<Expression>,
+    pub hits: u64,
+    pub ignore: u64,
+}
+
+impl Breakpoint {
+    // Breaks before the instruction at the address runs
+    pub fn at(address: u16) -> Self {
+        Breakpoint::watch(address, address, &[Access::Execute])
+    }
+
+    pub fn watch(start: u16, end: u16, accesses: &[Access]) -> Self {
+        Breakpoint {
+            id: 0,
+            start,
+            end,
+            read: accesses.contains(&Access::Read),
+            write: accesses.contains(&Access::Write),
+            execute: accesses.contains(&Access::Execute),
+            enabled: true,
+            condition: None,
+            hits: 0,
+            ignore: 0,
+        }
+    }
+
+    fn watches(&self, address: u16, access: Access) -> bool {
+        let kind = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        };
+        kind && self.enabled && (self.start..=self.end).contains(&address)
+    }
+}
+
+// Like the debugger shows it:
+//
+//   1  $0300-$03FF  rw  hits 2  ignore 1  if A == $20
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let range = if self.start == self.end { format!("${:04X}", self.start) } else { format!("${:04X}-${:04X}", self.start, self.end) };
+        let accesses: String = [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')].iter().filter(|(on, _)| *on).map(|(_, c)| c).collect();
+        write!(f, "{:<2} {:<12} {:<3} hits {}", self.id, range, accesses, self.hits)?;
+        if self.ignore > 0 {
+            write!(f, "  ignore {}", self.ignore)?;
+        }
+        if !self.enabled {
+            write!(f, "  disabled")?;
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, "  if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    // Whether any breakpoint cares, so the CPU can skip the rest
+    pub(super) reads: bool,
+    pub(super) writes: bool,
+    pub(super) executes: bool,
+    // Accesses of the current instruction that hit a watchpoint, by index.
+    // Only step_with_breakpoints looks at them, so only it records them.
+    pending: Vec<(usize, u16, Access)>,
+    recording: bool,
+}
+
+impl Breakpoints {
+    // Returns the id of the new breakpoint
+    pub fn add(&mut self, mut breakpoint: Breakpoint) -> usize {
+        self.next_id += 1;
+        breakpoint.id = self.next_id;
+        self.breakpoints.push(breakpoint);
+        self.update();
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Breakpoint> {
+        let index = self.breakpoints.iter().position(|breakpoint| breakpoint.id == id)?;
+        self.pending.clear();
+        let breakpoint = self.breakpoints.remove(index);
+        self.update();
+        Some(breakpoint)
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.pending.clear();
+        self.update();
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Breakpoint> {
+        self.breakpoints.iter().find(|breakpoint| breakpoint.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        self.change(id, |breakpoint| breakpoint.enabled = enabled)
+    }
+
+    pub fn set_ignore(&mut self, id: usize, ignore: u64) -> bool {
+        self.change(id, |breakpoint| breakpoint.ignore = ignore)
+    }
+
+    pub fn set_condition(&mut self, id: usize, condition: Option<Expression>) -> bool {
+        self.change(id, |breakpoint| breakpoint.condition = condition)
+    }
+
+    fn change(&mut self, id: usize, change: impl FnOnce(&mut Breakpoint)) -> bool {
+        let Some(breakpoint) = self.breakpoints.iter_mut().find(|breakpoint| breakpoint.id == id) else {
+            return false;
+        };
+        change(breakpoint);
+        self.update();
+        true
+    }
+
+    fn update(&mut self) {
+        let enabled = || self.breakpoints.iter().filter(|breakpoint| breakpoint.enabled);
+        self.reads = enabled().any(|breakpoint| breakpoint.read);
+        self.writes = enabled().any(|breakpoint| breakpoint.write);
+        self.executes = enabled().any(|breakpoint| breakpoint.execute);
+    }
+
+    // Called by the CPU for reads and writes while something watches them
+    pub(super) fn access(&mut self, address: u16, access: Access) {
+        if !self.recording {
+            return;
+        }
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            if breakpoint.watches(address, access) {
+                self.pending.push((index, address, access));
+            }
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // Counts a hit if the condition holds, and says whether to stop
+    fn trigger(&mut self, index: usize, address: u16, access: Access) -> Option<StopReason> {
+        let breakpoint = &self.breakpoints.breakpoints[index];
+        if let Some(condition) = &breakpoint.condition {
+            if condition.evaluate(self) == 0 {
+                return None;
+            }
+        }
+        let breakpoint = &mut self.breakpoints.breakpoints[index];
+        breakpoint.hits += 1;
+        if breakpoint.ignore > 0 {
+            breakpoint.ignore -= 1;
+            return None;
+        }
+        Some(StopReason::Breakpoint { id: breakpoint.id, address, access })
+    }
+
+    // Executes an instruction unless a breakpoint at the PC stops it first,
+    // and tells why the CPU should stop, if it should. Going on from a
+    // breakpoint takes a step without checking the PC.
+
+    pub fn step_with_breakpoints(&mut self, check_pc: bool) -> Option<StopReason> {
+        let pc = self.pc;
+        if check_pc && self.breakpoints.executes {
+            for index in 0..self.breakpoints.breakpoints.len() {
+                if self.breakpoints.breakpoints[index].watches(pc, Access::Execute) {
+                    if let Some(stop) = self.trigger(index, pc, Access::Execute) {
+                        return Some(stop);
+                    }
+                }
+            }
+        }
+
+        self.breakpoints.pending.clear();
+        self.breakpoints.recording = true;
+        let result = self.step();
+        self.breakpoints.recording = false;
+        if result.is_err() {
+            return Some(StopReason::IllegalOpcode { pc });
+        }
+        if self.halted {
+            return Some(StopReason::Halted);
+        }
+
+        let mut stop = None;
+        let pending = std::mem::take(&mut self.breakpoints.pending);
+        for &(index, address, access) in &pending {
+            // All hits count, but the first one says why we stop
+            if let Some(reason) = self.trigger(index, address, access) {
+                stop = stop.or(Some(reason));
+            }
+        }
+        self.breakpoints.pending = pending;
+        stop
+    }
+}
+
+// Conditions
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    A, X, Y, SP, PC, P,
+    N, V, B, D, I, Z, C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Or, And,
+    Equal, NotEqual, Less, LessOrEqual, Greater, GreaterOrEqual,
+    BitOr, BitXor, BitAnd,
+    Add, Subtract,
+    Not, Negate,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Node>),
+    Unary(Operator, Box<Node>),
+    Binary(Operator, Box<Node>, Box<Node>),
+}
+
+// A parsed condition, which shows as the text it was parsed from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    text: String,
+    root: Node,
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// Operators by how loose they bind, with the longer ones first so that ||
+// is not taken for |
+const LEVELS: [&[(&str, Operator)]; 7] = [
+    &[("||", Operator::Or)],
+    &[("&&", Operator::And)],
+    &[("==", Operator::Equal), ("!=", Operator::NotEqual), ("<=", Operator::LessOrEqual), (">=", Operator::GreaterOrEqual), ("<", Operator::Less), (">", Operator::Greater)],
+    &[("|", Operator::BitOr)],
+    &[("^", Operator::BitXor)],
+    &[("&", Operator::BitAnd)],
+    &[("+", Operator::Add), ("-", Operator::Subtract)],
+];
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn skip_space(&mut self) {
+        while self.text[self.position..].starts_with(char::is_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn take(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.text[self.position..].starts_with(token) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("{} at column {} of {}", message, self.position + 1, self.text))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut node = self.binary(level + 1)?;
+        'more: loop {
+            for &(token, operator) in LEVELS[level] {
+                // A single | or & is not the start of || or &&
+                let doubled = token.len() == 1 && self.text[self.position..].trim_start().starts_with(&token.repeat(2));
+                if !doubled && self.take(token) {
+                    node = Node::Binary(operator, Box::new(node), Box::new(self.binary(level + 1)?));
+                    continue 'more;
+                }
+            }
+            return Ok(node);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.take("!") {
+            Ok(Node::Unary(Operator::Not, Box::new(self.unary()?)))
+        } else if self.take("-") {
+            Ok(Node::Unary(Operator::Negate, Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        if self.take("(") {
+            let node = self.binary(0)?;
+            return if self.take(")") { Ok(node) } else { self.error("Missing )") };
+        }
+        self.skip_space();
+        let rest = &self.text[self.position..];
+        let length = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '$' || c == '_')).unwrap_or(rest.len());
+        let word = &rest[..length];
+        if word.is_empty() {
+            return self.error("Expected a value");
+        }
+        let number = if let Some(hex) = word.strip_prefix('$').or_else(|| word.strip_prefix("0x")) {
+            i64::from_str_radix(hex, 16).ok()
+        } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+            word.parse().ok()
+        } else {
+            None
+        };
+        let node = match (number, word.to_ascii_uppercase().as_str()) {
+            (Some(number), _) => Node::Number(number),
+            (None, "MEM") => {
+                self.position += length;
+                if !self.take("[") {
+                    return self.error("Expected [");
+                }
+                let address = self.binary(0)?;
+                if !self.take("]") {
+                    return self.error("Missing ]");
+                }
+                return Ok(Node::Memory(Box::new(address)));
+            }
+            (None, "A") => Node::Register(Register::A),
+            (None, "X") => Node::Register(Register::X),
+            (None, "Y") => Node::Register(Register::Y),
+            (None, "SP" | "S") => Node::Register(Register::SP),
+            (None, "PC") => Node::Register(Register::PC),
+            (None, "P") => Node::Register(Register::P),
+            (None, "N") => Node::Register(Register::N),
+            (None, "V") => Node::Register(Register::V),
+            (None, "B") => Node::Register(Register::B),
+            (None, "D") => Node::Register(Register::D),
+            (None, "I") => Node::Register(Register::I),
+            (None, "Z") => Node::Register(Register::Z),
+            (None, "C") => Node::Register(Register::C),
+            _ => return self.error(&format!("Unknown value {}", word)),
+        };
+        self.position += length;
+        Ok(node)
+    }
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let mut parser = Parser { text: text.trim(), position: 0 };
+        let root = parser.binary(0)?;
+        parser.skip_space();
+        if parser.position < parser.text.len() {
+            return parser.error("Unexpected text");
+        }
+        Ok(Expression { text: parser.text.to_string(), root })
+    }
+
+    pub fn evaluate<B: Bus>(&self, cpu: &CPU<B>) -> i64 {
+        evaluate(&self.root, cpu)
+    }
+}
+
+fn evaluate<B: Bus>(node: &Node, cpu: &CPU<B>) -> i64 {
+    match node {
+        Node::Number(number) => *number,
+        Node::Register(register) => match register {
+            Register::A => cpu.a as i64,
+            Register::X => cpu.x as i64,
+            Register::Y => cpu.y as i64,
+            Register::SP => cpu.sp as i64,
+            Register::PC => cpu.pc as i64,
+            Register::P => (cpu.get_status() | 0x20) as i64,
+            Register::N => cpu.n as i64,
+            Register::V => cpu.v as i64,
+            Register::B => cpu.b as i64,
+            Register::D => cpu.d as i64,
+            Register::I => cpu.i as i64,
+            Register::Z => cpu.z as i64,
+            Register::C => cpu.c as i64,
+        },
+        Node::Memory(address) => cpu.peek_byte(evaluate(address, cpu) as u16) as i64,
+        Node::Unary(operator, operand) => {
+            let value = evaluate(operand, cpu);
+            match operator {
+                Operator::Not => (value == 0) as i64,
+                _ => value.wrapping_neg(),
+            }
+        }
+        Node::Binary(operator, left, right) => {
+            let left = evaluate(left, cpu);
+            // Like in C, the right side of && and || only counts if needed
+            match operator {
+                Operator::And => return (left != 0 && evaluate(right, cpu) != 0) as i64,
+                Operator::Or => return (left != 0 || evaluate(right, cpu) != 0) as i64,
+                _ => {}
+            }
+            let right = evaluate(right, cpu);
+            match operator {
+                Operator::Equal => (left == right) as i64,
+                Operator::NotEqual => (left != right) as i64,
+                Operator::Less => (left < right) as i64,
+                Operator::LessOrEqual => (left <= right) as i64,
+                Operator::Greater => (left > right) as i64,
+                Operator::GreaterOrEqual => (left >= right) as i64,
+                Operator::BitOr => left | right,
+                Operator::BitXor => left ^ right,
+                Operator::BitAnd => left & right,
+                Operator::Add => left.wrapping_add(right),
+                _ => left.wrapping_sub(right),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::fixtures::cpu;
+
+    const PROGRAM: &str = "
+        LDX #$00
+loop:   LDA $1000,X
+        STA $0400,X
+        INX
+        CPX #$10
+        BNE loop
+        INC $FF
+        JMP $0300";
+
+    fn evaluate(cpu: &CPU, text: &str) -> i64 {
+        Expression::parse(text).unwrap().evaluate(cpu)
+    }
+
+    #[test]
+    fn test_expressions() {
+        let mut cpu = cpu(PROGRAM);
+        cpu.a = 0x20;
+        cpu.x = 7;
+        cpu.c = true;
+        cpu.set_byte(0x00ff, 4);
+        assert_eq!(evaluate(&cpu, "A == $20 && mem[$FF] > 3"), 1);
+        assert_eq!(evaluate(&cpu, "a == 0x20 && MEM[255] > 4"), 0);
+        assert_eq!(evaluate(&cpu, "x + 1 == 8 || mem[$FF]"), 1);
+        assert_eq!(evaluate(&cpu, "!(X <= 7) || C & 0"), 0);
+        assert_eq!(evaluate(&cpu, "1 + 2 - 3 == 0"), 1);
+        assert_eq!(evaluate(&cpu, "A | X ^ 1 & 3"), 0x26);
+        assert_eq!(evaluate(&cpu, "mem[PC + 1]"), 0x00);
+        assert_eq!(evaluate(&cpu, "mem[PC]"), 0xa2);
+        assert_eq!(evaluate(&cpu, "-1 < 0"), 1);
+        assert_eq!(evaluate(&cpu, "P & 1 == C"), 1);
+        assert_eq!(evaluate(&cpu, "SP + PC"), 0x3ff);
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        assert_eq!(Expression::parse("A == ").unwrap_err(), "Expected a value at column 5 of A ==");
+        assert_eq!(Expression::parse("Q == 1").unwrap_err(), "Unknown value Q at column 1 of Q == 1");
+        assert_eq!(Expression::parse("(A == 1").unwrap_err(), "Missing ) at column 8 of (A == 1");
+        assert_eq!(Expression::parse("mem[1").unwrap_err(), "Missing ] at column 6 of mem[1");
+        assert_eq!(Expression::parse("A 1").unwrap_err(), "Unexpected text at column 3 of A 1");
+        assert_eq!(Expression::parse("  A==1 ").unwrap().to_string(), "A==1");
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut cpu = cpu(PROGRAM);
+        let id = cpu.breakpoints.add(Breakpoint::at(0x0305));
+        assert_eq!(cpu.run(), StopReason::Breakpoint { id, address: 0x0305, access: Access::Execute });
+        assert_eq!(cpu.pc, 0x0305);
+        assert_eq!(cpu.x, 0);
+        // Going on steps over the breakpoint we are at
+        assert_eq!(cpu.run(), StopReason::Breakpoint { id, address: 0x0305, access: Access::Execute });
+        assert_eq!(cpu.x, 1);
+        assert_eq!(cpu.breakpoints.get(id).unwrap().hits, 2);
+
+        // Conditions and the ignore count
+        cpu.breakpoints.set_condition(id, Some(Expression::parse("X == 8").unwrap()));
+        cpu.run();
+        assert_eq!(cpu.x, 8);
+        cpu.breakpoints.set_ignore(id, 2);
+        cpu.breakpoints.set_condition(id, Some(Expression::parse("X >= 10").unwrap()));
+        cpu.run();
+        assert_eq!(cpu.x, 12);
+        assert_eq!(cpu.breakpoints.get(id).unwrap().hits, 6);
+        assert_eq!(cpu.breakpoints.get(id).unwrap().ignore, 0);
+
+        cpu.breakpoints.set_enabled(id, false);
+        let other = cpu.breakpoints.add(Breakpoint::at(0x0300));
+        assert_eq!(cpu.run(), StopReason::Breakpoint { id: other, address: 0x0300, access: Access::Execute });
+        assert!(cpu.breakpoints.remove(other).is_some());
+        assert!(cpu.breakpoints.remove(other).is_none());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = cpu(PROGRAM);
+        let writes = cpu.breakpoints.add(Breakpoint::watch(0x0408, 0x040f, &[Access::Write]));
+        let reads = cpu.breakpoints.add(Breakpoint::watch(0x1008, 0x1008, &[Access::Read]));
+        assert_eq!(cpu.run(), StopReason::Breakpoint { id: reads, address: 0x1008, access: Access::Read });
+        // After the instruction
+        assert_eq!(cpu.pc, 0x0305);
+        assert_eq!(cpu.run(), StopReason::Breakpoint { id: writes, address: 0x0408, access: Access::Write });
+        assert_eq!(cpu.pc, 0x0308);
+        cpu.breakpoints.remove(writes);
+
+        // Fetching instructions does not count, but RMW instructions read
+        // and write twice
+        cpu.breakpoints.add(Breakpoint::watch(0x0300, 0x0308, &[Access::Read]));
+        let zero_page = cpu.breakpoints.add(Breakpoint::watch(0x00ff, 0x00ff, &[Access::Read, Access::Write]));
+        assert_eq!(cpu.run(), StopReason::Breakpoint { id: zero_page, address: 0x00ff, access: Access::Read });
+        assert_eq!(cpu.breakpoints.get(zero_page).unwrap().hits, 3);
+        assert_eq!(cpu.pc, 0x030f);
+
+        // The debugger peeks
+        cpu.breakpoints.clear();
+        cpu.breakpoints.add(Breakpoint::watch(0x0000, 0xffff, &[Access::Read]));
+        assert_eq!(evaluate(&cpu, "mem[$1000]"), 0);
+        assert!(cpu.breakpoints.pending.is_empty());
+
+        // Nor do steps without breakpoints, like Computer::run_cycles takes
+        cpu.pc = 0x0300;
+        for _ in 0..1000 {
+            cpu.step().unwrap();
+        }
+        cpu.get_byte(0x1000);
+        assert!(cpu.breakpoints.pending.is_empty());
+        assert_eq!(cpu.breakpoints.iter().next().unwrap().hits, 0);
+    }
+
+    #[test]
+    fn test_stop_reasons() {
+        let mut cpu = cpu(".byte $02");
+        assert_eq!(cpu.run(), StopReason::IllegalOpcode { pc: 0x0300 });
+        cpu.pc = 0x0300;
+        cpu.undocumented_opcodes = true;
+        assert_eq!(cpu.run(), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_display() {
+        let mut breakpoint = Breakpoint::watch(0x0300, 0x03ff, &[Access::Read, Access::Write]);
+        breakpoint.id = 1;
+        breakpoint.ignore = 1;
+        breakpoint.condition = Some(Expression::parse("A == $20").unwrap());
+        assert_eq!(breakpoint.to_string(), "1  $0300-$03FF  rw  hits 0  ignore 1  if A == $20");
+        let mut breakpoint = Breakpoint::at(0xfded);
+        breakpoint.id = 12;
+        breakpoint.enabled = false;
+        assert_eq!(breakpoint.to_string(), "12 $FDED        x   hits 0  disabled");
+    }
+}
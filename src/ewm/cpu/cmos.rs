@@ -0,0 +1,601 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Instructions and addressing modes that are new or different on the 65C02.
+// Everything that behaves the same as on the NMOS 6502 is left to the main
+// instruction decoder in cpu.rs.
+
+use super::*;
+
+fn tsb<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    cpu.z = (cpu.a & b) == 0;
+    b | cpu.a
+}
+
+fn trb<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    cpu.z = (cpu.a & b) == 0;
+    b & !cpu.a
+}
+
+impl<B: Bus> CPU<B> {
+    // Returns true if the opcode was handled here
+
+    pub(super) fn step_cmos(&mut self, opcode: u8) -> bool {
+        match opcode {
+            // Branches
+
+            0x80 => { // BRA
+                let oper = self.fetch_byte();
+                self.branch(true, oper);
+            }
+
+            // Stack Instructions
+
+            0xDA => { // PHX
+                self.dummy_read(self.pc);
+                self.push_byte(self.x);
+            }
+
+            0x5A => { // PHY
+                self.dummy_read(self.pc);
+                self.push_byte(self.y);
+            }
+
+            0xFA => { // PLX
+                self.dummy_read(self.pc);
+                self.dummy_read(0x0100 + self.sp as u16);
+                self.x = self.pull_byte();
+                self.update_nz(self.x);
+            }
+
+            0x7A => { // PLY
+                self.dummy_read(self.pc);
+                self.dummy_read(0x0100 + self.sp as u16);
+                self.y = self.pull_byte();
+                self.update_nz(self.y);
+            }
+
+            // STZ
+
+            0x64 => { // STZ zpg
+                let oper = self.fetch_byte();
+                self.mem_set_byte_zpg(oper, 0);
+            }
+
+            0x74 => { // STZ zpgx
+                let oper = self.fetch_byte();
+                self.mem_set_byte_zpgx(oper, 0);
+            }
+
+            0x9C => { // STZ abs
+                let oper = self.fetch_word();
+                self.mem_set_byte_abs(oper, 0);
+            }
+
+            0x9E => { // STZ absx
+                let oper = self.fetch_word();
+                self.mem_set_byte_absx(oper, 0);
+            }
+
+            // TSB & TRB
+
+            0x04 => { // TSB zpg
+                let addr = self.fetch_byte();
+                self.mem_mod_byte_zpg(addr, tsb);
+            }
+
+            0x0C => { // TSB abs
+                let addr = self.fetch_word();
+                self.mem_mod_byte_abs(addr, tsb);
+            }
+
+            0x14 => { // TRB zpg
+                let addr = self.fetch_byte();
+                self.mem_mod_byte_zpg(addr, trb);
+            }
+
+            0x1C => { // TRB abs
+                let addr = self.fetch_word();
+                self.mem_mod_byte_abs(addr, trb);
+            }
+
+            // Decrements & Increments
+
+            0x1A => { // INC
+                self.dummy_read(self.pc);
+                let a = self.a;
+                self.a = inc(self, a);
+            }
+
+            0x3A => { // DEC
+                self.dummy_read(self.pc);
+                let a = self.a;
+                self.a = dec(self, a);
+            }
+
+            // Shifts with absolute indexed addressing only take the extra
+            // cycle when crossing a page on the 65C02
+
+            0x1E | 0x3E | 0x5E | 0x7E => { // ASL ROL LSR ROR absx
+                let addr = self.fetch_word();
+                if !same_page(addr, addr.wrapping_add(self.x as u16)) {
+                    self.cycles += 1;
+                }
+                let modifier = match opcode {
+                    0x1E => asl,
+                    0x3E => rol,
+                    0x5E => lsr,
+                    _ => ror,
+                };
+                self.mem_mod_byte_absx(addr, modifier);
+            }
+
+            // Zero page indirect addressing
+
+            0x12 => { // ORA ind
+                let oper = self.fetch_byte();
+                let m = self.mem_get_byte_ind(oper);
+                self.ora(m);
+            }
+
+            0x32 => { // AND ind
+                let oper = self.fetch_byte();
+                let m = self.mem_get_byte_ind(oper);
+                self.and(m);
+            }
+
+            0x52 => { // EOR ind
+                let oper = self.fetch_byte();
+                let m = self.mem_get_byte_ind(oper);
+                self.eor(m);
+            }
+
+            0x72 => { // ADC ind
+                let oper = self.fetch_byte();
+                let m = self.mem_get_byte_ind(oper);
+                self.adc(m);
+            }
+
+            0x92 => { // STA ind
+                let oper = self.fetch_byte();
+                self.mem_set_byte_ind(oper, self.a);
+            }
+
+            0xB2 => { // LDA ind
+                let oper = self.fetch_byte();
+                self.a = self.mem_get_byte_ind(oper);
+                self.update_nz(self.a);
+            }
+
+            0xD2 => { // CMP ind
+                let oper = self.fetch_byte();
+                let m = self.mem_get_byte_ind(oper);
+                self.cmp(m);
+            }
+
+            0xF2 => { // SBC ind
+                let oper = self.fetch_byte();
+                let m = self.mem_get_byte_ind(oper);
+                self.sbc(m);
+            }
+
+            // BIT
+
+            0x89 => { // BIT imm - Only affects the Z flag
+                let oper = self.fetch_byte();
+                self.z = (self.a & oper) == 0;
+            }
+
+            0x34 => { // BIT zpgx
+                let oper = self.fetch_byte();
+                let m = self.mem_get_byte_zpgx(oper);
+                self.bit(m);
+            }
+
+            0x3C => { // BIT absx
+                let oper = self.fetch_word();
+                let m = self.mem_get_byte_absx(oper);
+                self.bit(m);
+            }
+
+            // Jumps
+
+            0x6C => { // JMP ind - Without the page wrapping bug
+                let addr = self.fetch_word();
+                self.pc = self.get_word(addr);
+            }
+
+            0x7C => { // JMP absx ind
+                let addr = self.fetch_word().wrapping_add(self.x as u16);
+                self.pc = self.get_word(addr);
+            }
+
+            // WDC wait and stop
+
+            0xCB if self.model.has_wait_and_stop() => { // WAI
+                self.dummy_read(self.pc);
+                self.dummy_read(self.pc);
+                self.waiting = true;
+            }
+
+            0xDB if self.model.has_wait_and_stop() => { // STP
+                self.dummy_read(self.pc);
+                self.dummy_read(self.pc);
+                self.halted = true;
+            }
+
+            // Rockwell bit instructions
+
+            0x07 | 0x17 | 0x27 | 0x37 | 0x47 | 0x57 | 0x67 | 0x77 if self.model.has_bit_instructions() => { // RMB
+                let addr = self.fetch_byte() as u16;
+                let m = self.get_byte(addr);
+                self.get_byte(addr);
+                self.set_byte(addr, m & !(1 << (opcode >> 4)));
+            }
+
+            0x87 | 0x97 | 0xA7 | 0xB7 | 0xC7 | 0xD7 | 0xE7 | 0xF7 if self.model.has_bit_instructions() => { // SMB
+                let addr = self.fetch_byte() as u16;
+                let m = self.get_byte(addr);
+                self.get_byte(addr);
+                self.set_byte(addr, m | (1 << ((opcode >> 4) & 0x07)));
+            }
+
+            0x0F | 0x1F | 0x2F | 0x3F | 0x4F | 0x5F | 0x6F | 0x7F if self.model.has_bit_instructions() => { // BBR
+                let addr = self.fetch_byte() as u16;
+                let offset = self.fetch_byte();
+                let m = self.get_byte(addr);
+                self.branch((m & (1 << (opcode >> 4))) == 0, offset);
+            }
+
+            0x8F | 0x9F | 0xAF | 0xBF | 0xCF | 0xDF | 0xEF | 0xFF if self.model.has_bit_instructions() => { // BBS
+                let addr = self.fetch_byte() as u16;
+                let offset = self.fetch_byte();
+                let m = self.get_byte(addr);
+                self.branch((m & (1 << ((opcode >> 4) & 0x07))) != 0, offset);
+            }
+
+            // All undefined opcodes are NOPs on the 65C02, but they do not
+            // all have the same size
+
+            0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xC2 | 0xE2 => { // NOP imm
+                self.fetch_byte();
+            }
+
+            0x44 => { // NOP zpg
+                let oper = self.fetch_byte();
+                self.mem_get_byte_zpg(oper);
+            }
+
+            0x54 | 0xD4 | 0xF4 => { // NOP zpgx
+                let oper = self.fetch_byte();
+                self.mem_get_byte_zpgx(oper);
+            }
+
+            0x5C | 0xDC | 0xFC => { // NOP abs
+                self.fetch_word();
+            }
+
+            _ if (opcode & 0x03) == 0x03 => { // NOP - Columns 3, 7, B and F
+            }
+
+            _ => {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(model: Model, program: Vec<u8>, steps: usize) -> CPU {
+        let mut cpu = CPU::with_model(Ram::new(), model);
+        cpu.load(0x0400, program);
+        cpu.pc = 0x0400;
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_bra() {
+        let cpu = run(Model::Cmos65C02, vec![
+            0x80, 0x02,         // $0400 BRA $0404
+        ], 1);
+        assert_eq!(cpu.pc, 0x0404);
+        assert_eq!(cpu.cycles, 3);
+    }
+
+    #[test]
+    fn test_nmos_does_not_know_bra() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, vec![0x80, 0x02]);
+        cpu.pc = 0x0400;
+        assert_eq!(cpu.step().unwrap_err(), CPUError::IllegalOpcode);
+    }
+
+    #[test]
+    fn test_phx_ply() {
+        let cpu = run(Model::Cmos65C02, vec![
+            0xA2, 0x80,         // $0400 LDX #$80
+            0xDA,               // $0402 PHX
+            0x7A,               // $0403 PLY
+        ], 3);
+        assert_eq!(cpu.y, 0x80);
+        assert_eq!(cpu.sp, 0xff);
+        assert!(cpu.n);
+    }
+
+    #[test]
+    fn test_stz() {
+        let mut cpu = CPU::with_model(Ram::new(), Model::Cmos65C02);
+        cpu.set_byte(0x10, 0xff);
+        cpu.set_byte(0x1234, 0xff);
+        cpu.load(0x0400, vec![
+            0x64, 0x10,         // $0400 STZ $10
+            0x9C, 0x34, 0x12,   // $0402 STZ $1234
+        ]);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x00);
+        assert_eq!(cpu.peek_byte(0x1234), 0x00);
+    }
+
+    #[test]
+    fn test_tsb_trb() {
+        let mut cpu = CPU::with_model(Ram::new(), Model::Cmos65C02);
+        cpu.set_byte(0x10, 0x0f);
+        cpu.load(0x0400, vec![
+            0xA9, 0x30,         // $0400 LDA #$30
+            0x04, 0x10,         // $0402 TSB $10
+            0xA9, 0x03,         // $0404 LDA #$03
+            0x14, 0x10,         // $0406 TRB $10
+        ]);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x3f);
+        assert!(cpu.z);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x3c);
+        assert!(!cpu.z);
+    }
+
+    #[test]
+    fn test_inc_dec_accumulator() {
+        let cpu = run(Model::Cmos65C02, vec![
+            0xA9, 0xff,         // $0400 LDA #$FF
+            0x1A,               // $0402 INC
+            0x1A,               // $0403 INC
+            0x3A,               // $0404 DEC
+            0x3A,               // $0405 DEC
+        ], 5);
+        assert_eq!(cpu.a, 0xff);
+        assert!(cpu.n);
+    }
+
+    #[test]
+    fn test_zero_page_indirect() {
+        let mut cpu = CPU::with_model(Ram::new(), Model::Cmos65C02);
+        cpu.set_word(0xff, 0x1234);
+        cpu.set_byte(0x1234, 0x42);
+        cpu.set_byte(0x0100, 0x20); // Must not be used for the high byte of the pointer
+        cpu.load(0x0400, vec![
+            0xB2, 0xff,         // $0400 LDA ($FF)
+        ]);
+        cpu.pc = 0x0400;
+        cpu.set_byte(0x00, 0x12);
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.cycles, 5);
+    }
+
+    #[test]
+    fn test_bit_immediate() {
+        let cpu = run(Model::Cmos65C02, vec![
+            0xA9, 0x01,         // $0400 LDA #$01
+            0x89, 0xC0,         // $0402 BIT #$C0
+        ], 2);
+        assert!(cpu.z);
+        assert!(!cpu.n);
+        assert!(!cpu.v);
+    }
+
+    #[test]
+    fn test_jmp_absx_ind() {
+        let mut cpu = CPU::with_model(Ram::new(), Model::Cmos65C02);
+        cpu.set_word(0x1236, 0x5678);
+        cpu.load(0x0400, vec![
+            0xA2, 0x02,         // $0400 LDX #$02
+            0x7C, 0x34, 0x12,   // $0402 JMP ($1234,X)
+        ]);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x5678);
+    }
+
+    #[test]
+    fn test_jmp_ind_page_bug() {
+        for (model, pc) in [(Model::Nmos6502, 0x1234), (Model::Cmos65C02, 0x5634)] {
+            let mut cpu = CPU::with_model(Ram::new(), model);
+            cpu.set_byte(0x10ff, 0x34);
+            cpu.set_byte(0x1000, 0x12);
+            cpu.set_byte(0x1100, 0x56);
+            cpu.load(0x0400, vec![
+                0x6C, 0xff, 0x10,   // $0400 JMP ($10FF)
+            ]);
+            cpu.pc = 0x0400;
+            cpu.step().unwrap();
+            assert_eq!(cpu.pc, pc);
+        }
+    }
+
+    #[test]
+    fn test_decimal_flags() {
+        let cpu = run(Model::Cmos65C02, vec![
+            0xF8,               // $0400 SED
+            0x18,               // $0401 CLC
+            0xA9, 0x99,         // $0402 LDA #$99
+            0x69, 0x01,         // $0404 ADC #$01
+        ], 4);
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.z);
+        assert!(!cpu.n);
+        assert!(cpu.c);
+        assert_eq!(cpu.cycles, 2 + 2 + 2 + 3);
+    }
+
+    #[test]
+    fn test_brk_clears_decimal() {
+        let cpu = run(Model::Cmos65C02, vec![
+            0xF8,               // $0400 SED
+            0x00, 0x00,         // $0401 BRK
+        ], 2);
+        assert!(!cpu.d);
+    }
+
+    #[test]
+    fn test_rmb_smb() {
+        let mut cpu = CPU::with_model(Ram::new(), Model::Rockwell65C02);
+        cpu.set_byte(0x10, 0x0f);
+        cpu.load(0x0400, vec![
+            0x37, 0x10,         // $0400 RMB3 $10
+            0xF7, 0x10,         // $0402 SMB7 $10
+        ]);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x07);
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x87);
+    }
+
+    #[test]
+    fn test_bbr_bbs() {
+        let mut cpu = CPU::with_model(Ram::new(), Model::Rockwell65C02);
+        cpu.set_byte(0x10, 0x04);
+        cpu.load(0x0400, vec![
+            0x2F, 0x10, 0x10,   // $0400 BBR2 $10,$0413
+            0xAF, 0x10, 0x10,   // $0403 BBS2 $10,$0416
+        ]);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0403);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0416);
+    }
+
+    #[test]
+    fn test_bit_instructions_are_nops_without_rockwell() {
+        let cpu = run(Model::Cmos65C02, vec![
+            0x37,               // $0400 NOP
+            0x2F,               // $0401 NOP
+        ], 2);
+        assert_eq!(cpu.pc, 0x0402);
+        assert_eq!(cpu.cycles, 2);
+    }
+
+    #[test]
+    fn test_wai() {
+        let mut cpu = CPU::with_model(Ram::new(), Model::Wdc65C02);
+        cpu.load(0x0400, vec![
+            0xCB,               // $0400 WAI
+            0xE8,               // $0401 INX
+        ]);
+        cpu.set_word(IRQ_VECTOR, 0x0500);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        assert!(cpu.waiting);
+        assert_eq!(cpu.cycles, 3);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0401);
+        assert_eq!(cpu.cycles, 4);
+
+        // An IRQ ends the wait, and is taken when interrupts are enabled
+        let line = IrqLine::new(0).unwrap();
+        cpu.assert_irq(line);
+        cpu.step().unwrap();
+        assert!(!cpu.waiting);
+        assert_eq!(cpu.pc, 0x0500);
+
+        // With interrupts disabled, execution continues after the WAI
+        cpu.release_irq(line);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        cpu.assert_irq(line);
+        cpu.step().unwrap();
+        assert!(!cpu.waiting);
+        assert_eq!(cpu.pc, 0x0402);
+        assert_eq!(cpu.x, 1);
+    }
+
+    #[test]
+    fn test_stp() {
+        let mut cpu = CPU::with_model(Ram::new(), Model::Wdc65C02);
+        cpu.load(0x0400, vec![
+            0xDB,               // $0400 STP
+        ]);
+        cpu.set_word(RESET_VECTOR, 0x0400);
+        cpu.pc = 0x0400;
+        cpu.step().unwrap();
+        assert!(cpu.halted);
+        assert_eq!(cpu.cycles, 3);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0401);
+
+        // Only a reset helps, also against an interrupt
+        cpu.assert_nmi();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0401);
+        cpu.reset();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x0400);
+    }
+
+    #[test]
+    fn test_wai_and_stp_are_nops_without_wdc() {
+        let cpu = run(Model::Rockwell65C02, vec![
+            0xCB,               // $0400 NOP
+            0xDB,               // $0401 NOP
+        ], 2);
+        assert_eq!(cpu.pc, 0x0402);
+        assert_eq!(cpu.cycles, 2);
+        assert!(!cpu.waiting && !cpu.halted);
+    }
+
+    #[test]
+    fn test_undefined_opcodes_are_nops() {
+        let cpu = run(Model::Cmos65C02, vec![
+            0x02, 0xff,         // $0400 NOP #$FF
+            0x03,               // $0402 NOP
+            0x5C, 0x34, 0x12,   // $0403 NOP $1234
+            0xF4, 0x10,         // $0406 NOP $10,X
+        ], 4);
+        assert_eq!(cpu.pc, 0x0408);
+        assert_eq!(cpu.cycles, 2 + 1 + 8 + 4);
+    }
+}
@@ -0,0 +1,215 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// A log of every instruction the CPU executes, to compare a run with what
+// other emulators do. Each line shows the state before the instruction in
+// the format of the well known nestest.log, without the PPU columns:
+//
+//   C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7
+//
+// Set CPU::trace to start tracing and back to None to stop, at any time.
+// Only instructions that start in the range of addresses are logged, and
+// while the trace is None the CPU only checks that once per instruction.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::super::disasm::disassemble;
+use super::*;
+
+pub struct Trace {
+    output: Box<dyn Write>,
+    pub start: u16,
+    pub end: u16,
+    // The first write that failed, after which the trace stays quiet
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Trace {{ start: {:#06x}, end: {:#06x}, error: {:?} }}", self.start, self.end, self.error)
+    }
+}
+
+impl Trace {
+    pub fn new(output: impl Write + 'static) -> Self {
+        Trace { output: Box::new(output), start: 0x0000, end: 0xffff, error: None }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Trace::new(BufWriter::new(File::create(path)?)))
+    }
+
+    // Only logs instructions from start up to and including end
+    pub fn with_range(self, start: u16, end: u16) -> Self {
+        Trace { start, end, ..self }
+    }
+
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    fn log(&mut self, line: &str) {
+        if self.error.is_none() {
+            if let Err(error) = self.output.write_all(line.as_bytes()) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub(super) fn trace_instruction(&mut self) {
+        let Some(trace) = &self.trace else {
+            return;
+        };
+        if !(trace.start..=trace.end).contains(&self.pc) {
+            return;
+        }
+        let instruction = disassemble(&self.bus, self.model, self.pc);
+        let bytes: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let text = format!("{} {}", instruction.mnemonic, instruction.operand_text());
+        let line = format!(
+            "{:04X}  {:<8}  {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}\n",
+            self.pc, bytes.join(" "), text.trim_end(), self.a, self.x, self.y, self.get_status() | 0x20, self.sp, self.cycles
+        );
+        if let Some(trace) = &mut self.trace {
+            trace.log(&line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::super::asm::assemble;
+
+    // Lets the test read what the CPU wrote
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn cpu() -> CPU {
+        let source = "
+            .org $C000
+            JMP start
+start:      LDX #$02
+loop:       STA $0200,X
+            DEX
+            BNE loop
+            JSR sub
+done:       JMP done
+sub:        RTS";
+        let mut cpu = CPU::new();
+        cpu.load(0xc000, assemble(source).unwrap());
+        cpu.pc = 0xc000;
+        cpu.sp = 0xfd;
+        cpu.i = true;
+        cpu.cycles = 7;
+        cpu
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut cpu = cpu();
+        let output = Shared::default();
+        cpu.trace = Some(Trace::new(output.clone()));
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        // Stopping and starting again
+        cpu.trace = None;
+        cpu.step().unwrap();
+        cpu.trace = Some(Trace::new(output.clone()));
+        cpu.step().unwrap();
+        assert_eq!(output.text(), concat!(
+            "C000  4C 03 C0  JMP $C003                       A:00 X:00 Y:00 P:24 SP:FD CYC:7\n",
+            "C003  A2 02     LDX #$02                        A:00 X:00 Y:00 P:24 SP:FD CYC:10\n",
+            "C005  9D 00 02  STA $0200,X                     A:00 X:02 Y:00 P:24 SP:FD CYC:12\n",
+            "C008  CA        DEX                             A:00 X:02 Y:00 P:24 SP:FD CYC:17\n",
+            "C009  D0 FA     BNE $C005                       A:00 X:01 Y:00 P:24 SP:FD CYC:19\n",
+            "C005  9D 00 02  STA $0200,X                     A:00 X:01 Y:00 P:24 SP:FD CYC:22\n",
+            "C009  D0 FA     BNE $C005                       A:00 X:00 Y:00 P:26 SP:FD CYC:29\n",
+        ));
+    }
+
+    #[test]
+    fn test_range() {
+        let mut cpu = cpu();
+        let output = Shared::default();
+        cpu.trace = Some(Trace::new(output.clone()).with_range(0xc00e, 0xc0ff));
+        for _ in 0..12 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(output.text(), concat!(
+            "C011  60        RTS                             A:00 X:00 Y:00 P:26 SP:FB CYC:37\n",
+            "C00E  4C 0E C0  JMP $C00E                       A:00 X:00 Y:00 P:26 SP:FD CYC:43\n",
+            "C00E  4C 0E C0  JMP $C00E                       A:00 X:00 Y:00 P:26 SP:FD CYC:46\n",
+        ));
+    }
+
+    #[test]
+    fn test_error() {
+        let mut cpu = cpu();
+        cpu.trace = Some(Trace::new(Broken));
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xc005);
+        assert_eq!(cpu.trace.as_ref().unwrap().error().unwrap().to_string(), "broken");
+    }
+}
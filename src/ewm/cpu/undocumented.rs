@@ -0,0 +1,496 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// The stable undocumented opcodes of the NMOS 6502. Most of them combine a
+// read-modify-write instruction with an ALU instruction, using the same
+// addressing modes as the documented instructions in the same column.
+//
+// The unstable ones (ANE, LXA, SHA, SHX, SHY, TAS, LAS) depend on things like
+// chip temperature and are left out. They still result in an IllegalOpcode.
+
+use super::*;
+
+fn slo<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = asl(cpu, b);
+    cpu.ora(r);
+    r
+}
+
+fn rla<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = rol(cpu, b);
+    cpu.and(r);
+    r
+}
+
+fn sre<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = lsr(cpu, b);
+    cpu.eor(r);
+    r
+}
+
+fn rra<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = ror(cpu, b);
+    cpu.adc(r);
+    r
+}
+
+fn dcp<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = b.wrapping_sub(1);
+    cpu.cmp(r);
+    r
+}
+
+fn isc<B: Bus>(cpu: &mut CPU<B>, b: u8) -> u8 {
+    let r = b.wrapping_add(1);
+    cpu.sbc(r);
+    r
+}
+
+impl<B: Bus> CPU<B> {
+    // Returns true if the opcode was handled here
+
+    pub(super) fn step_undocumented(&mut self, opcode: u8) -> bool {
+        match opcode {
+            // Read-modify-write combined with an ALU operation
+
+            0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1B | 0x1F => { // SLO
+                self.mod_byte_undocumented(opcode, slo);
+            }
+
+            0x23 | 0x27 | 0x2F | 0x33 | 0x37 | 0x3B | 0x3F => { // RLA
+                self.mod_byte_undocumented(opcode, rla);
+            }
+
+            0x43 | 0x47 | 0x4F | 0x53 | 0x57 | 0x5B | 0x5F => { // SRE
+                self.mod_byte_undocumented(opcode, sre);
+            }
+
+            0x63 | 0x67 | 0x6F | 0x73 | 0x77 | 0x7B | 0x7F => { // RRA
+                self.mod_byte_undocumented(opcode, rra);
+            }
+
+            0xC3 | 0xC7 | 0xCF | 0xD3 | 0xD7 | 0xDB | 0xDF => { // DCP
+                self.mod_byte_undocumented(opcode, dcp);
+            }
+
+            0xE3 | 0xE7 | 0xEF | 0xF3 | 0xF7 | 0xFB | 0xFF => { // ISC
+                self.mod_byte_undocumented(opcode, isc);
+            }
+
+            // LAX
+
+            0xA3 => { // LAX indx
+                let oper = self.fetch_byte();
+                self.a = self.mem_get_byte_indx(oper);
+                self.x = self.a;
+                self.update_nz(self.a);
+            }
+
+            0xA7 => { // LAX zpg
+                let oper = self.fetch_byte();
+                self.a = self.mem_get_byte_zpg(oper);
+                self.x = self.a;
+                self.update_nz(self.a);
+            }
+
+            0xAF => { // LAX abs
+                let oper = self.fetch_word();
+                self.a = self.mem_get_byte_abs(oper);
+                self.x = self.a;
+                self.update_nz(self.a);
+            }
+
+            0xB3 => { // LAX indy
+                let oper = self.fetch_byte();
+                self.a = self.mem_get_byte_indy(oper);
+                self.x = self.a;
+                self.update_nz(self.a);
+            }
+
+            0xB7 => { // LAX zpgy
+                let oper = self.fetch_byte();
+                self.a = self.mem_get_byte_zpgy(oper);
+                self.x = self.a;
+                self.update_nz(self.a);
+            }
+
+            0xBF => { // LAX absy
+                let oper = self.fetch_word();
+                self.a = self.mem_get_byte_absy(oper);
+                self.x = self.a;
+                self.update_nz(self.a);
+            }
+
+            // SAX
+
+            0x83 => { // SAX indx
+                let oper = self.fetch_byte();
+                self.mem_set_byte_indx(oper, self.a & self.x);
+            }
+
+            0x87 => { // SAX zpg
+                let oper = self.fetch_byte();
+                self.mem_set_byte_zpg(oper, self.a & self.x);
+            }
+
+            0x8F => { // SAX abs
+                let oper = self.fetch_word();
+                self.mem_set_byte_abs(oper, self.a & self.x);
+            }
+
+            0x97 => { // SAX zpgy
+                let oper = self.fetch_byte();
+                self.mem_set_byte_zpgy(oper, self.a & self.x);
+            }
+
+            // Immediate operations
+
+            0x0B | 0x2B => { // ANC imm
+                let oper = self.fetch_byte();
+                self.and(oper);
+                self.c = self.n;
+            }
+
+            0x4B => { // ALR imm
+                let oper = self.fetch_byte();
+                self.and(oper);
+                let a = self.a;
+                self.a = lsr(self, a);
+            }
+
+            0x6B => { // ARR imm
+                let oper = self.fetch_byte();
+                self.arr(oper);
+            }
+
+            0xCB => { // SBX imm
+                let oper = self.fetch_byte();
+                let t = self.a & self.x;
+                self.c = t >= oper;
+                self.x = t.wrapping_sub(oper);
+                self.update_nz(self.x);
+            }
+
+            0xEB => { // SBC imm
+                let oper = self.fetch_byte();
+                self.sbc(oper);
+            }
+
+            // NOPs
+
+            0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => { // NOP
+                self.dummy_read(self.pc);
+            }
+
+            0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => { // NOP imm
+                self.fetch_byte();
+            }
+
+            0x04 | 0x44 | 0x64 => { // NOP zpg
+                let oper = self.fetch_byte();
+                self.mem_get_byte_zpg(oper);
+            }
+
+            0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => { // NOP zpgx
+                let oper = self.fetch_byte();
+                self.mem_get_byte_zpgx(oper);
+            }
+
+            0x0C => { // NOP abs
+                let oper = self.fetch_word();
+                self.mem_get_byte_abs(oper);
+            }
+
+            0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => { // NOP absx
+                let oper = self.fetch_word();
+                self.mem_get_byte_absx(oper);
+            }
+
+            // JAM - Locks up the CPU until the next reset
+
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => {
+                self.pc = self.pc.wrapping_sub(1);
+                self.halted = true;
+            }
+
+            _ => {
+                return false;
+            }
+        }
+        true
+    }
+
+    // The combined read-modify-write opcodes all use the addressing mode that
+    // belongs to their column
+
+    fn mod_byte_undocumented(&mut self, opcode: u8, modifier: Modifier<B>) {
+        match opcode & 0x1f {
+            0x03 => {
+                let oper = self.fetch_byte();
+                self.mem_mod_byte_indx(oper, modifier);
+            }
+            0x07 => {
+                let oper = self.fetch_byte();
+                self.mem_mod_byte_zpg(oper, modifier);
+            }
+            0x0F => {
+                let oper = self.fetch_word();
+                self.mem_mod_byte_abs(oper, modifier);
+            }
+            0x13 => {
+                let oper = self.fetch_byte();
+                self.mem_mod_byte_indy(oper, modifier);
+            }
+            0x17 => {
+                let oper = self.fetch_byte();
+                self.mem_mod_byte_zpgx(oper, modifier);
+            }
+            0x1B => {
+                let oper = self.fetch_word();
+                self.mem_mod_byte_absy(oper, modifier);
+            }
+            _ => {
+                let oper = self.fetch_word();
+                self.mem_mod_byte_absx(oper, modifier);
+            }
+        }
+    }
+
+    // ARR is an AND followed by a ROR, but the flags come out of the adder
+    // and in decimal mode it also does a BCD fixup of both nibbles.
+
+    fn arr(&mut self, m: u8) {
+        let t = self.a & m;
+        let r = (t >> 1) | (self.c as u8) << 7;
+
+        if !self.d {
+            self.a = r;
+            self.update_nz(r);
+            self.c = (r & 0x40) != 0;
+            self.v = ((r >> 6) ^ (r >> 5)) & 0x01 != 0;
+            return;
+        }
+
+        self.n = self.c;
+        self.z = r == 0;
+        self.v = ((t ^ r) & 0x40) != 0;
+
+        let mut r = r;
+        if (t & 0x0f) + (t & 0x01) > 0x05 {
+            r = (r & 0xf0) | (r.wrapping_add(0x06) & 0x0f);
+        }
+        self.c = (t >> 4) + ((t >> 4) & 0x01) > 0x05;
+        if self.c {
+            r = r.wrapping_add(0x60);
+        }
+        self.a = r;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu_with_program(program: Vec<u8>) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.undocumented_opcodes = true;
+        cpu.load(0x0400, program);
+        cpu.pc = 0x0400;
+        cpu
+    }
+
+    #[test]
+    fn test_strict_by_default() {
+        let mut cpu = CPU::new();
+        cpu.load(0x0400, vec![0xA7, 0x10]);
+        cpu.pc = 0x0400;
+        assert_eq!(cpu.step().unwrap_err(), CPUError::IllegalOpcode);
+        assert_eq!(cpu.pc, 0x0400);
+    }
+
+    #[test]
+    fn test_lax_sax() {
+        let mut cpu = cpu_with_program(vec![
+            0xA7, 0x10,         // $0400 LAX $10
+            0xA9, 0x0F,         // $0402 LDA #$0F
+            0x87, 0x11,         // $0404 SAX $11
+        ]);
+        cpu.set_byte(0x10, 0x81);
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x81);
+        assert_eq!(cpu.x, 0x81);
+        assert!(cpu.n);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x11), 0x01);
+    }
+
+    #[test]
+    fn test_dcp_isc() {
+        let mut cpu = cpu_with_program(vec![
+            0xA9, 0x41,         // $0400 LDA #$41
+            0xC7, 0x10,         // $0402 DCP $10
+            0x38,               // $0404 SEC
+            0xE7, 0x11,         // $0405 ISC $11
+        ]);
+        cpu.set_byte(0x10, 0x42);
+        cpu.set_byte(0x11, 0x00);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x41);
+        assert!(cpu.z);
+        assert!(cpu.c);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x11), 0x01);
+        assert_eq!(cpu.a, 0x40);
+    }
+
+    #[test]
+    fn test_slo_rla_sre_rra() {
+        let mut cpu = cpu_with_program(vec![
+            0xA9, 0x01,         // $0400 LDA #$01
+            0x07, 0x10,         // $0402 SLO $10
+            0x27, 0x10,         // $0404 RLA $10
+            0x47, 0x10,         // $0406 SRE $10
+            0x18,               // $0408 CLC
+            0x67, 0x10,         // $0409 RRA $10
+        ]);
+        cpu.set_byte(0x10, 0x40);
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x80);
+        assert_eq!(cpu.a, 0x81);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x00);
+        assert!(cpu.c);
+        assert_eq!(cpu.a, 0x00);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x00);
+        assert!(!cpu.c);
+
+        cpu.set_byte(0x10, 0x03);
+        cpu.a = 0x10;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x10), 0x01);
+        assert_eq!(cpu.a, 0x12); // $10 + $01 + carry out of ROR
+    }
+
+    #[test]
+    fn test_indirect_indexed_rmw() {
+        let mut cpu = cpu_with_program(vec![
+            0xA0, 0x10,         // $0400 LDY #$10
+            0xD3, 0x20,         // $0402 DCP ($20),Y
+        ]);
+        cpu.set_word(0x20, 0x12f8);
+        cpu.set_byte(0x1308, 0x05);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_byte(0x1308), 0x04);
+        assert_eq!(cpu.cycles, 2 + 8);
+    }
+
+    #[test]
+    fn test_immediate_operations() {
+        let mut cpu = cpu_with_program(vec![
+            0xA9, 0xF0,         // $0400 LDA #$F0
+            0x0B, 0x80,         // $0402 ANC #$80
+            0xA9, 0x03,         // $0404 LDA #$03
+            0x4B, 0x03,         // $0406 ALR #$03
+            0xA9, 0xFF,         // $0408 LDA #$FF
+            0xA2, 0x0F,         // $040A LDX #$0F
+            0xCB, 0x05,         // $040C SBX #$05
+        ]);
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.c);
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.c);
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.x, 0x0A);
+        assert!(cpu.c);
+    }
+
+    #[test]
+    fn test_arr() {
+        let mut cpu = cpu_with_program(vec![
+            0x38,               // $0400 SEC
+            0xA9, 0xFF,         // $0401 LDA #$FF
+            0x6B, 0xC0,         // $0403 ARR #$C0
+        ]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0xE0);
+        assert!(cpu.n);
+        assert!(cpu.c);
+        assert!(!cpu.v);
+    }
+
+    #[test]
+    fn test_nops() {
+        let mut cpu = cpu_with_program(vec![
+            0x1A,               // $0400 NOP
+            0x80, 0xFF,         // $0401 NOP #$FF
+            0x04, 0x10,         // $0403 NOP $10
+            0x1C, 0xFF, 0x10,   // $0405 NOP $10FF,X
+        ]);
+        cpu.x = 1;
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, 0x0408);
+        assert_eq!(cpu.cycles, 2 + 2 + 3 + 5);
+    }
+
+    #[test]
+    fn test_jam_halts_until_reset() {
+        let mut cpu = cpu_with_program(vec![
+            0x02,               // $0400 JAM
+        ]);
+        cpu.set_word(RESET_VECTOR, 0x0500);
+        cpu.step().unwrap();
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x0400);
+
+        cpu.assert_nmi();
+        cpu.step().unwrap();
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x0400);
+
+        cpu.reset();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x0500);
+    }
+}
@@ -0,0 +1,690 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::io::{self, BufRead, Write};
+
+use super::bus::Bus;
+use super::cpu::{Access, Breakpoint, Expression, StopReason, Trace, CPU};
+use super::disasm::disassemble_range;
+
+// A machine language monitor for a CPU on any bus, so it works for a bare
+// CPU as well as for the CPU of a Computer. Commands are read a line at a
+// time, numbers are hex with or without a $, and an empty line repeats the
+// last command:
+//
+//   s [n]               Step over n instructions, 1 by default
+//   n                   Step, but run a JSR until it returns
+//   f                   Run until the current subroutine returns
+//   c                   Run until something stops the CPU
+//   g <address>         Jump to the address and run from there
+//   r [<reg> <value>]   Show the registers, or change one of them: a, x,
+//                       y, sp, pc, p or one of the flags nvbdizc
+//   m [<address> [n]]   Show n bytes of memory, $80 by default
+//   e <address> <b>...  Write bytes to memory
+//   l [<address> [n]]   Disassemble n instructions, 10 by default. Without
+//                       an address around the PC, or where the last one
+//                       stopped.
+//   b <address> [if <condition>]
+//                       Break before the instruction at the address runs
+//   w <rwx> <start> [<end>] [if <condition>]
+//                       Watch the addresses for reads, writes or
+//                       execution, any mix of r, w and x
+//   bl                  List the breakpoints with their hits
+//   bd <id>             Delete a breakpoint
+//   enable <id>         Enable a breakpoint again
+//   disable <id>        Keep a breakpoint but let it go by
+//   ignore <id> <n>     Let the next n hits of a breakpoint go by
+//   cond <id> [<condition>]
+//                       Change or remove the condition of a breakpoint
+//   trace <file> [<start> <end>]
+//                       Log the instructions that run in the range, all by
+//                       default, to the file in the format of nestest.log
+//   trace off           Stop tracing
+//   reset               Press the reset button
+//   q                   Quit
+//
+// Breakpoint ids and ignore counts are decimal. Conditions are expressions
+// like A == $20 && mem[$FF] > 3, see cpu/breakpoints.rs for all of it.
+//
+// Running stops at breakpoints, an illegal opcode, a halted CPU, or an
+// instruction that jumps to itself, which is how most test programs end.
+// Stepping also stops at breakpoints, but never before the first step.
+
+const HELP: &str = "\
+s [n]               step n instructions
+n                   step over a JSR
+f                   run until the subroutine returns
+c                   continue
+g <address>         go to the address and continue
+r [<reg> <value>]   show or change registers: a x y sp pc p n v b d i z c
+m [<address> [n]]   dump memory
+e <address> <b>...  edit memory
+l [<address> [n]]   disassemble
+b <address> [if <condition>]
+                    break at the address
+w <rwx> <start> [<end>] [if <condition>]
+                    watch memory for reads, writes or execution
+bl                  list breakpoints
+bd <id>             delete a breakpoint
+enable <id>         enable a breakpoint
+disable <id>        disable a breakpoint
+ignore <id> <n>     ignore the next n hits
+cond <id> [<condition>]
+                    change the condition, like A == $20 && mem[$FF] > 3
+trace <file> [<start> <end>]
+                    log instructions to the file
+trace off           stop tracing
+reset               reset the machine
+q                   quit
+";
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+
+const DUMP_SIZE: u16 = 0x80;
+const LIST_SIZE: usize = 10;
+// Instructions shown before the PC
+const LIST_BEFORE: usize = 3;
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    last_command: String,
+    // Where m and l without an address go on
+    next_dump: u16,
+    next_list: Option<u16>,
+}
+
+// Why running stopped
+enum Stop {
+    Done,
+    Trap(u16),
+    Stopped(StopReason),
+}
+
+fn number(text: &str) -> Result<u16, String> {
+    let hex = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(hex, 16).map_err(|_| format!("{} is not a number", text))
+}
+
+fn byte(text: &str) -> Result<u8, String> {
+    number(text).and_then(|n| u8::try_from(n).map_err(|_| format!("{} is not a byte", text)))
+}
+
+fn decimal<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("{} is not a decimal number", text))
+}
+
+// Splits the arguments at an if and parses the condition after it
+fn condition<'a>(args: &'a [&'a str]) -> Result<(&'a [&'a str], Option<Expression>), String> {
+    match args.iter().position(|&arg| arg == "if") {
+        Some(i) => Ok((&args[..i], Some(Expression::parse(&args[i + 1..].join(" "))?))),
+        None => Ok((args, None)),
+    }
+}
+
+fn accesses(text: &str) -> Result<Vec<Access>, String> {
+    text.chars().map(|c| match c.to_ascii_lowercase() {
+        'r' => Ok(Access::Read),
+        'w' => Ok(Access::Write),
+        'x' => Ok(Access::Execute),
+        _ => Err(format!("{} is not a mix of r, w and x", text)),
+    }).collect()
+}
+
+pub fn registers<B: Bus>(cpu: &CPU<B>) -> String {
+    let flags: String = [(cpu.n, 'n'), (cpu.v, 'v'), (true, '-'), (cpu.b, 'b'), (cpu.d, 'd'), (cpu.i, 'i'), (cpu.z, 'z'), (cpu.c, 'c')]
+        .iter()
+        .map(|&(set, flag)| if set { flag.to_ascii_uppercase() } else { flag })
+        .collect();
+    format!(
+        "PC={:04X} A={:02X} X={:02X} Y={:02X} SP={:02X} P={:02X} {} CYC={}",
+        cpu.pc, cpu.a, cpu.x, cpu.y, cpu.sp, cpu.get_status() | 0x20, flags, cpu.cycles
+    )
+}
+
+// Runs until done says so after an instruction, or the CPU stops
+fn run_until<B: Bus>(cpu: &mut CPU<B>, mut done: impl FnMut(&CPU<B>, u8, u8) -> bool) -> Stop {
+    let mut check_pc = false;
+    loop {
+        let (pc, sp) = (cpu.pc, cpu.sp);
+        let opcode = cpu.peek_byte(pc);
+        if let Some(stop) = cpu.step_with_breakpoints(check_pc) {
+            return Stop::Stopped(stop);
+        }
+        check_pc = true;
+        if done(cpu, opcode, sp) {
+            return Stop::Done;
+        }
+        if cpu.pc == pc {
+            return Stop::Trap(pc);
+        }
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Reads and runs commands until the input ends or the user quits
+    pub fn repl<B: Bus>(&mut self, cpu: &mut CPU<B>, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        self.show_state(cpu, output)?;
+        loop {
+            write!(output, "* ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 || !self.execute(cpu, &line, output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Runs one command. Returns false when it was the one to quit.
+    pub fn execute<B: Bus>(&mut self, cpu: &mut CPU<B>, line: &str, output: &mut impl Write) -> io::Result<bool> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        if matches!(command, "q" | "quit") {
+            return Ok(false);
+        }
+        if let Err(message) = self.command(cpu, command, args, output)? {
+            writeln!(output, "{}", message)?;
+        }
+        Ok(true)
+    }
+
+    fn command<B: Bus>(&mut self, cpu: &mut CPU<B>, command: &str, args: &[&str], output: &mut impl Write) -> io::Result<Result<(), String>> {
+        let arg = |i: usize| args.get(i).map(|text| number(text)).transpose();
+        let stop = match (command, args.len()) {
+            ("s" | "step", 0..=1) => {
+                let count = match arg(0) {
+                    Ok(count) => count.unwrap_or(1),
+                    Err(message) => return Ok(Err(message)),
+                };
+                let mut steps = 0;
+                run_until(cpu, |_, _, _| {
+                    steps += 1;
+                    steps >= count
+                })
+            }
+            ("n" | "next", 0) => {
+                let (pc, sp) = (cpu.pc, cpu.sp);
+                if cpu.peek_byte(pc) == JSR {
+                    let ret = pc.wrapping_add(3);
+                    run_until(cpu, |cpu, _, _| cpu.pc == ret && cpu.sp == sp)
+                } else {
+                    run_until(cpu, |_, _, _| true)
+                }
+            }
+            ("f" | "finish", 0) => {
+                // An RTS that takes the stack above where it is now returns
+                // from this subroutine, one that does not from a nested one
+                let sp = cpu.sp;
+                run_until(cpu, |cpu, opcode, _| opcode == RTS && cpu.sp > sp)
+            }
+            ("c" | "continue", 0) => run_until(cpu, |_, _, _| false),
+            ("g" | "go", 1) => {
+                match arg(0) {
+                    Ok(address) => cpu.pc = address.unwrap(),
+                    Err(message) => return Ok(Err(message)),
+                }
+                run_until(cpu, |_, _, _| false)
+            }
+            ("r" | "regs", 0) => {
+                writeln!(output, "{}", registers(cpu))?;
+                return Ok(Ok(()));
+            }
+            ("r" | "regs", 2) => {
+                let result = Self::set_register(cpu, args[0], args[1]);
+                if result.is_ok() {
+                    writeln!(output, "{}", registers(cpu))?;
+                }
+                return Ok(result);
+            }
+            ("m" | "mem", 0..=2) => {
+                return Ok(match (arg(0), arg(1)) {
+                    (Ok(address), Ok(size)) => self.dump(cpu, address, size.unwrap_or(DUMP_SIZE), output).map(Ok)?,
+                    (Err(message), _) | (_, Err(message)) => Err(message),
+                });
+            }
+            ("e" | "edit", 2..) => {
+                let address = match number(args[0]) {
+                    Ok(address) => address,
+                    Err(message) => return Ok(Err(message)),
+                };
+                let bytes = match args[1..].iter().map(|text| byte(text)).collect::<Result<Vec<u8>, String>>() {
+                    Ok(bytes) => bytes,
+                    Err(message) => return Ok(Err(message)),
+                };
+                for (i, &b) in bytes.iter().enumerate() {
+                    cpu.set_byte(address.wrapping_add(i as u16), b);
+                }
+                return Ok(Ok(()));
+            }
+            ("l" | "list", 0..=2) => {
+                return Ok(match (arg(0), arg(1)) {
+                    (Ok(address), Ok(count)) => self.list(cpu, address, count.map_or(LIST_SIZE, usize::from), output).map(Ok)?,
+                    (Err(message), _) | (_, Err(message)) => Err(message),
+                });
+            }
+            ("b" | "break", 1..) | ("w" | "watch", 2..) => {
+                let breakpoint = match Self::breakpoint(command, args) {
+                    Ok(breakpoint) => breakpoint,
+                    Err(message) => return Ok(Err(message)),
+                };
+                let id = cpu.breakpoints.add(breakpoint);
+                writeln!(output, "{}", cpu.breakpoints.get(id).unwrap())?;
+                return Ok(Ok(()));
+            }
+            ("bl", 0) => {
+                if cpu.breakpoints.is_empty() {
+                    writeln!(output, "No breakpoints")?;
+                }
+                for breakpoint in cpu.breakpoints.iter() {
+                    writeln!(output, "{}", breakpoint)?;
+                }
+                return Ok(Ok(()));
+            }
+            ("bd" | "enable" | "disable" | "ignore" | "cond", 1..) => {
+                let id = match decimal(args[0]) {
+                    Ok(id) => id,
+                    Err(message) => return Ok(Err(message)),
+                };
+                let changed = match (command, &args[1..]) {
+                    ("bd", []) => cpu.breakpoints.remove(id).is_some(),
+                    ("enable", []) => cpu.breakpoints.set_enabled(id, true),
+                    ("disable", []) => cpu.breakpoints.set_enabled(id, false),
+                    ("ignore", [count]) => match decimal(count) {
+                        Ok(count) => cpu.breakpoints.set_ignore(id, count),
+                        Err(message) => return Ok(Err(message)),
+                    },
+                    ("cond", []) => cpu.breakpoints.set_condition(id, None),
+                    ("cond", expression) => match Expression::parse(&expression.join(" ")) {
+                        Ok(expression) => cpu.breakpoints.set_condition(id, Some(expression)),
+                        Err(message) => return Ok(Err(message)),
+                    },
+                    _ => return Ok(Err(format!("Wrong arguments for {}, try help", command))),
+                };
+                return Ok(if changed { Ok(()) } else { Err(format!("No breakpoint {}", id)) });
+            }
+            ("trace", 1) if args[0] == "off" => {
+                let Some(mut trace) = cpu.trace.take() else {
+                    return Ok(Err("Not tracing".to_string()));
+                };
+                let flushed = trace.flush();
+                return Ok(match trace.error() {
+                    Some(error) => Err(format!("The trace failed: {}", error)),
+                    None => flushed.map_err(|error| format!("The trace failed: {}", error)),
+                });
+            }
+            ("trace", 1 | 3) => {
+                let (start, end) = match (arg(1), arg(2)) {
+                    (Ok(start), Ok(end)) => (start.unwrap_or(0x0000), end.unwrap_or(0xffff)),
+                    (Err(message), _) | (_, Err(message)) => return Ok(Err(message)),
+                };
+                return Ok(match Trace::to_file(args[0]) {
+                    Ok(trace) => {
+                        cpu.trace = Some(trace.with_range(start, end));
+                        Ok(())
+                    }
+                    Err(error) => Err(format!("{}: {}", args[0], error)),
+                });
+            }
+            ("reset", 0) => {
+                cpu.bus.reset();
+                cpu.reset();
+                Stop::Done
+            }
+            ("h" | "help" | "?", _) => {
+                write!(output, "{}", HELP)?;
+                return Ok(Ok(()));
+            }
+            ("s" | "step" | "n" | "next" | "f" | "finish" | "c" | "continue" | "g" | "go" | "r" | "regs" | "m" | "mem" | "e" | "edit" | "l" | "list" | "b" | "break" | "w" | "watch" | "bl" | "bd" | "enable" | "disable" | "ignore" | "cond" | "trace", _) => {
+                return Ok(Err(format!("Wrong arguments for {}, try help", command)));
+            }
+            _ => return Ok(Err(format!("Unknown command {}, try help", command))),
+        };
+
+        match stop {
+            Stop::Done => {}
+            Stop::Trap(pc) => writeln!(output, "Trapped at ${:04X}", pc)?,
+            Stop::Stopped(StopReason::Halted) => writeln!(output, "The CPU is halted, only a reset helps")?,
+            Stop::Stopped(StopReason::IllegalOpcode { pc }) => writeln!(output, "Illegal opcode at ${:04X}", pc)?,
+            Stop::Stopped(StopReason::Breakpoint { id, address, access: Access::Execute }) => {
+                writeln!(output, "Breakpoint {} at ${:04X}", id, address)?
+            }
+            Stop::Stopped(StopReason::Breakpoint { id, address, access }) => {
+                writeln!(output, "Watchpoint {} saw a {} of ${:04X}", id, access, address)?
+            }
+        }
+        self.next_list = None;
+        self.show_state(cpu, output)?;
+        Ok(Ok(()))
+    }
+
+    // From b <address> or w <rwx> <start> [<end>], and maybe a condition
+    fn breakpoint(command: &str, args: &[&str]) -> Result<Breakpoint, String> {
+        let (args, condition) = condition(args)?;
+        let mut breakpoint = match (command, args) {
+            ("b" | "break", [address]) => Breakpoint::at(number(address)?),
+            ("w" | "watch", [kinds, start]) => Breakpoint::watch(number(start)?, number(start)?, &accesses(kinds)?),
+            ("w" | "watch", [kinds, start, end]) => {
+                let (start, end) = (number(start)?, number(end)?);
+                if end < start {
+                    return Err(format!("${:04X} is before ${:04X}", end, start));
+                }
+                Breakpoint::watch(start, end, &accesses(kinds)?)
+            }
+            _ => return Err(format!("Wrong arguments for {}, try help", command)),
+        };
+        breakpoint.condition = condition;
+        Ok(breakpoint)
+    }
+
+    fn set_register<B: Bus>(cpu: &mut CPU<B>, register: &str, value: &str) -> Result<(), String> {
+        let value = number(value)?;
+        let flag = value != 0;
+        let byte = || u8::try_from(value).map_err(|_| format!("${:X} does not fit in {}", value, register));
+        match register.to_ascii_lowercase().as_str() {
+            "a" => cpu.a = byte()?,
+            "x" => cpu.x = byte()?,
+            "y" => cpu.y = byte()?,
+            "sp" | "s" => cpu.sp = byte()?,
+            "pc" => cpu.pc = value,
+            "p" => cpu.set_status(byte()?),
+            "n" => cpu.n = flag,
+            "v" => cpu.v = flag,
+            "b" => cpu.b = flag,
+            "d" => cpu.d = flag,
+            "i" => cpu.i = flag,
+            "z" => cpu.z = flag,
+            "c" => cpu.c = flag,
+            _ => return Err(format!("Unknown register {}", register)),
+        }
+        Ok(())
+    }
+
+    fn show_state<B: Bus>(&self, cpu: &CPU<B>, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "{}", registers(cpu))?;
+        writeln!(output, "{}", cpu.disassemble(cpu.pc))
+    }
+
+    // 16 bytes a line, with their ASCII, without side effects on I/O
+    fn dump<B: Bus>(&mut self, cpu: &CPU<B>, address: Option<u16>, size: u16, output: &mut impl Write) -> io::Result<()> {
+        let start = address.unwrap_or(self.next_dump);
+        let mut offset = 0;
+        while offset < size {
+            let line = start.wrapping_add(offset);
+            let count = (size - offset).min(16);
+            let bytes: Vec<u8> = (0..count).map(|i| cpu.peek_byte(line.wrapping_add(i))).collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text: String = bytes.iter().map(|&b| match b & 0x7f {
+                c @ 0x20..=0x7e => c as char,
+                _ => '.',
+            }).collect();
+            writeln!(output, "{:04X}: {:<47}  {}", line, hex.join(" "), text)?;
+            offset += count;
+        }
+        self.next_dump = start.wrapping_add(size);
+        Ok(())
+    }
+
+    fn list<B: Bus>(&mut self, cpu: &CPU<B>, address: Option<u16>, count: usize, output: &mut impl Write) -> io::Result<()> {
+        let start = address.or(self.next_list).unwrap_or_else(|| Self::list_start(cpu));
+        let instructions = disassemble_range(&cpu.bus, cpu.model, start, count);
+        for instruction in &instructions {
+            let marker = if instruction.address == cpu.pc { '>' } else { ' ' };
+            writeln!(output, "{}{}", marker, instruction)?;
+        }
+        self.next_list = instructions.last().map(|instruction| instruction.next_address());
+        Ok(())
+    }
+
+    // Instructions have different lengths, so there is no telling where
+    // the ones before the PC start. We look for a start a few instructions
+    // back that ends up at the PC, trying the furthest first as decoding
+    // tends to get in step the longer it goes.
+
+    fn list_start<B: Bus>(cpu: &CPU<B>) -> u16 {
+        for back in (LIST_BEFORE..=LIST_BEFORE * 3).rev() {
+            let start = cpu.pc.wrapping_sub(back as u16);
+            let instructions = disassemble_range(&cpu.bus, cpu.model, start, LIST_BEFORE);
+            if instructions.last().map(|instruction| instruction.next_address()) == Some(cpu.pc) {
+                return start;
+            }
+        }
+        cpu.pc
+    }
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use super::*;
+    use super::super::fixtures::cpu;
+
+    fn run(debugger: &mut Debugger, cpu: &mut CPU, line: &str) -> String {
+        let mut output = Vec::new();
+        assert!(debugger.execute(cpu, line, &mut output).unwrap());
+        String::from_utf8(output).unwrap()
+    }
+
+    const PROGRAM: &str = "
+        LDX #$03
+loop:   JSR double
+        DEX
+        BNE loop
+done:   JMP done
+double: ASL A
+        JSR inc
+        RTS
+inc:    CLC
+        ADC #$01
+        RTS";
+
+    #[test]
+    fn test_step() {
+        let mut cpu = cpu(PROGRAM);
+        let mut debugger = Debugger::new();
+        assert_eq!(run(&mut debugger, &mut cpu, "s"), "\
+PC=0302 A=00 X=03 Y=00 SP=FF P=20 nv-bdizc CYC=2
+0302-   20 0B 03    JSR   $030B
+");
+        run(&mut debugger, &mut cpu, "s 2");
+        assert_eq!(cpu.pc, 0x030c);
+        // An empty line repeats the last command
+        run(&mut debugger, &mut cpu, "");
+        assert_eq!(cpu.pc, 0x0311);
+    }
+
+    #[test]
+    fn test_next_and_finish() {
+        let mut cpu = cpu(PROGRAM);
+        let mut debugger = Debugger::new();
+        run(&mut debugger, &mut cpu, "s");
+        run(&mut debugger, &mut cpu, "n");
+        assert_eq!((cpu.pc, cpu.a, cpu.sp), (0x0305, 0x01, 0xff));
+        // Not a JSR, so just a step
+        run(&mut debugger, &mut cpu, "n");
+        assert_eq!(cpu.pc, 0x0306);
+
+        // From inside the nested subroutine, and then from double
+        run(&mut debugger, &mut cpu, "s 5");
+        assert_eq!(cpu.pc, 0x0311);
+        run(&mut debugger, &mut cpu, "f");
+        assert_eq!((cpu.pc, cpu.sp), (0x030f, 0xfd));
+        run(&mut debugger, &mut cpu, "f");
+        assert_eq!((cpu.pc, cpu.sp), (0x0305, 0xff));
+    }
+
+    #[test]
+    fn test_continue() {
+        let mut cpu = cpu(PROGRAM);
+        let mut debugger = Debugger::new();
+        let output = run(&mut debugger, &mut cpu, "c");
+        assert!(output.starts_with("Trapped at $0308\n"), "{}", output);
+        assert_eq!((cpu.a, cpu.x), (0x07, 0x00));
+
+        // Going somewhere, from where RTS returns to nowhere
+        let output = run(&mut debugger, &mut cpu, "g 30B");
+        assert!(output.starts_with("Trapped at $0000\n"), "{}", output);
+
+        let mut cpu = self::cpu(".byte $02");
+        cpu.undocumented_opcodes = true;
+        let output = run(&mut debugger, &mut cpu, "c");
+        assert!(output.starts_with("The CPU is halted"), "{}", output);
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut cpu = cpu(PROGRAM);
+        let mut debugger = Debugger::new();
+        run(&mut debugger, &mut cpu, "r a 42");
+        run(&mut debugger, &mut cpu, "r pc $0310");
+        run(&mut debugger, &mut cpu, "r c 1");
+        assert_eq!(run(&mut debugger, &mut cpu, "r"), "PC=0310 A=42 X=00 Y=00 SP=FF P=21 nv-bdizC CYC=0\n");
+        run(&mut debugger, &mut cpu, "r p ff");
+        assert!(cpu.n && cpu.v && cpu.d && cpu.i && cpu.z);
+        assert_eq!(run(&mut debugger, &mut cpu, "r x 100"), "$100 does not fit in x\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "r q 1"), "Unknown register q\n");
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut cpu = cpu(PROGRAM);
+        let mut debugger = Debugger::new();
+        run(&mut debugger, &mut cpu, "e 1000 c8 c5 cc cc cf 00");
+        assert_eq!(run(&mut debugger, &mut cpu, "m 1000 8"), "1000: C8 C5 CC CC CF 00 00 00                          HELLO...\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "m").lines().count(), 8);
+        assert!(run(&mut debugger, &mut cpu, "m").starts_with("1088: "));
+        assert_eq!(run(&mut debugger, &mut cpu, "e 1000 100"), "100 is not a byte\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "m xyz"), "xyz is not a number\n");
+    }
+
+    #[test]
+    fn test_list() {
+        let mut cpu = cpu(PROGRAM);
+        let mut debugger = Debugger::new();
+        run(&mut debugger, &mut cpu, "s 3");
+        assert_eq!(run(&mut debugger, &mut cpu, "l 300 3"), concat!(
+            " 0300-   A2 03       LDX   #$03\n",
+            " 0302-   20 0B 03    JSR   $030B\n",
+            " 0305-   CA          DEX\n",
+        ));
+        // Around the PC
+        let output = run(&mut debugger, &mut cpu, "l");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), LIST_SIZE);
+        assert_eq!(lines[0], " 0306-   D0 FA       BNE   $0302");
+        assert_eq!(lines[3], ">030C-   20 10 03    JSR   $0310");
+        // And on from there
+        assert!(run(&mut debugger, &mut cpu, "l").starts_with(" 0316-"));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut cpu = cpu(PROGRAM);
+        let mut debugger = Debugger::new();
+        assert_eq!(run(&mut debugger, &mut cpu, "b 305"), "1  $0305        x   hits 0\n");
+        let output = run(&mut debugger, &mut cpu, "c");
+        assert!(output.starts_with("Breakpoint 1 at $0305\n"), "{}", output);
+        assert_eq!((cpu.pc, cpu.x), (0x0305, 3));
+        // Stepping from a breakpoint does not stop at it
+        run(&mut debugger, &mut cpu, "s");
+        assert_eq!(cpu.pc, 0x0306);
+
+        run(&mut debugger, &mut cpu, "ignore 1 2");
+        let output = run(&mut debugger, &mut cpu, "c");
+        assert!(output.starts_with("Trapped at $0308\n"), "{}", output);
+        assert_eq!(run(&mut debugger, &mut cpu, "bl"), "1  $0305        x   hits 3\n");
+
+        run(&mut debugger, &mut cpu, "r pc 300");
+        run(&mut debugger, &mut cpu, "r a 0");
+        run(&mut debugger, &mut cpu, "cond 1 X == 1");
+        let output = run(&mut debugger, &mut cpu, "c");
+        assert!(output.starts_with("Breakpoint 1 at $0305\n"), "{}", output);
+        assert_eq!(cpu.x, 1);
+
+        run(&mut debugger, &mut cpu, "r pc 300");
+        run(&mut debugger, &mut cpu, "r a 0");
+        assert_eq!(run(&mut debugger, &mut cpu, "w w 00 1ff if A > 2"), "2  $0000-$01FF  w   hits 0  if A > 2\n");
+        run(&mut debugger, &mut cpu, "disable 1");
+        let output = run(&mut debugger, &mut cpu, "c");
+        assert!(output.starts_with("Watchpoint 2 saw a write of $01FF\n"), "{}", output);
+        assert_eq!((cpu.pc, cpu.a), (0x030b, 0x03));
+        assert_eq!(run(&mut debugger, &mut cpu, "bl"), concat!(
+            "1  $0305        x   hits 4  disabled  if X == 1\n",
+            "2  $0000-$01FF  w   hits 2  if A > 2\n",
+        ));
+
+        run(&mut debugger, &mut cpu, "bd 1");
+        run(&mut debugger, &mut cpu, "bd 2");
+        assert_eq!(run(&mut debugger, &mut cpu, "bl"), "No breakpoints\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "bd 2"), "No breakpoint 2\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "w q 300"), "q is not a mix of r, w and x\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "w r 300 200"), "$0200 is before $0300\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "b 300 if A =="), "Expected a value at column 5 of A ==\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "ignore 1 x"), "x is not a decimal number\n");
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut cpu = cpu(PROGRAM);
+        let mut debugger = Debugger::new();
+        let path = std::env::temp_dir().join(format!("rewm-debugger-trace-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(run(&mut debugger, &mut cpu, &format!("trace {} 300 30A", path)), "");
+        run(&mut debugger, &mut cpu, "s 9");
+        assert_eq!(run(&mut debugger, &mut cpu, "trace off"), "");
+        let log = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(log, concat!(
+            "0300  A2 03     LDX #$03                        A:00 X:00 Y:00 P:20 SP:FF CYC:0\n",
+            "0302  20 0B 03  JSR $030B                       A:00 X:03 Y:00 P:20 SP:FF CYC:2\n",
+            "0305  CA        DEX                             A:01 X:03 Y:00 P:20 SP:FF CYC:32\n",
+        ));
+        assert_eq!(run(&mut debugger, &mut cpu, "trace off"), "Not tracing\n");
+        assert_eq!(run(&mut debugger, &mut cpu, "trace x 300"), "Wrong arguments for trace, try help\n");
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut cpu = cpu(PROGRAM);
+        let mut input = "x\ns 2\nm 0300 4\nq\nr\n".as_bytes();
+        let mut output = Vec::new();
+        Debugger::new().repl(&mut cpu, &mut input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output, "\
+PC=0300 A=00 X=00 Y=00 SP=FF P=20 nv-bdizc CYC=0
+0300-   A2 03       LDX   #$03
+* Unknown command x, try help
+* PC=030B A=00 X=03 Y=00 SP=FD P=20 nv-bdizc CYC=8
+030B-   0A          ASL
+* 0300: A2 03 20 0B                                      \". .
+* ");
+    }
+}
@@ -0,0 +1,389 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::fmt;
+
+use super::bus::Bus;
+use super::cpu::{CPU, Model};
+
+// A table driven disassembler. Every opcode maps to a mnemonic and an
+// addressing mode, which together tell us how many bytes the instruction
+// takes and how to print its operand.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Imp,     // CLC
+    Acc,     // ASL
+    Imm,     // LDA #$42
+    Zpg,     // LDA $42
+    ZpgX,    // LDA $42,X
+    ZpgY,    // LDX $42,Y
+    Abs,     // LDA $1234
+    AbsX,    // LDA $1234,X
+    AbsY,    // LDA $1234,Y
+    Ind,     // JMP ($1234)
+    IndX,    // LDA ($42,X)
+    IndY,    // LDA ($42),Y
+    ZpgInd,  // LDA ($42) - 65C02
+    AbsXInd, // JMP ($1234,X) - 65C02
+    Rel,     // BNE $1234
+    ZpgRel,  // BBR0 $42,$1234 - Rockwell 65C02
+}
+
+impl Mode {
+    pub fn size(&self) -> u16 {
+        match self {
+            Mode::Imp | Mode::Acc => 1,
+            Mode::Imm | Mode::Zpg | Mode::ZpgX | Mode::ZpgY | Mode::IndX | Mode::IndY | Mode::ZpgInd | Mode::Rel => 2,
+            Mode::Abs | Mode::AbsX | Mode::AbsY | Mode::Ind | Mode::AbsXInd | Mode::ZpgRel => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    pub operand: Option<u16>,
+    // Where a branch, JMP or JSR goes to, when we know that without
+    // looking at memory.
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.len())
+    }
+
+    pub fn operand_text(&self) -> String {
+        let operand = self.operand.unwrap_or(0);
+        let target = self.target.unwrap_or(0);
+        match self.mode {
+            Mode::Imp | Mode::Acc => String::new(),
+            Mode::Imm => format!("#${:02X}", operand),
+            Mode::Zpg => format!("${:02X}", operand),
+            Mode::ZpgX => format!("${:02X},X", operand),
+            Mode::ZpgY => format!("${:02X},Y", operand),
+            Mode::Abs => format!("${:04X}", operand),
+            Mode::AbsX => format!("${:04X},X", operand),
+            Mode::AbsY => format!("${:04X},Y", operand),
+            Mode::Ind => format!("(${:04X})", operand),
+            Mode::IndX => format!("(${:02X},X)", operand),
+            Mode::IndY => format!("(${:02X}),Y", operand),
+            Mode::ZpgInd => format!("(${:02X})", operand),
+            Mode::AbsXInd => format!("(${:04X},X)", operand),
+            Mode::Rel => format!("${:04X}", target),
+            Mode::ZpgRel => format!("${:02X},${:04X}", operand, target),
+        }
+    }
+}
+
+// Formats the instruction like the Apple ][ monitor does:
+//
+//   0300-   20 ED FD    JSR   $FDED
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let line = format!("{:04X}-   {:<12}{:<6}{}", self.address, bytes.join(" "), self.mnemonic, self.operand_text());
+        f.write_str(line.trim_end())
+    }
+}
+
+pub fn disassemble<B: Bus>(bus: &B, model: Model, address: u16) -> Instruction {
+    let opcode = bus.peek(address);
+    let (mnemonic, mode) = decode(model, opcode);
+
+    let bytes: Vec<u8> = (0..mode.size()).map(|i| bus.peek(address.wrapping_add(i))).collect();
+    let next = address.wrapping_add(mode.size());
+
+    let (operand, target) = match mode {
+        Mode::Imp | Mode::Acc => (None, None),
+        Mode::Rel => {
+            let offset = bytes[1] as i8 as u16;
+            (Some(bytes[1] as u16), Some(next.wrapping_add(offset)))
+        }
+        Mode::ZpgRel => {
+            let offset = bytes[2] as i8 as u16;
+            (Some(bytes[1] as u16), Some(next.wrapping_add(offset)))
+        }
+        _ if mode.size() == 2 => (Some(bytes[1] as u16), None),
+        _ => {
+            let operand = (bytes[1] as u16) | (bytes[2] as u16) << 8;
+            let target = if mode == Mode::Abs && (mnemonic == "JMP" || mnemonic == "JSR") {
+                Some(operand)
+            } else {
+                None
+            };
+            (Some(operand), target)
+        }
+    };
+
+    Instruction { address, bytes, mnemonic, mode, operand, target }
+}
+
+// Disassembles count instructions starting at address
+
+pub fn disassemble_range<B: Bus>(bus: &B, model: Model, address: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        let instruction = disassemble(bus, model, address);
+        address = instruction.next_address();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn disassemble(&self, address: u16) -> Instruction {
+        disassemble(&self.bus, self.model, address)
+    }
+}
+
+pub fn decode(model: Model, opcode: u8) -> (&'static str, Mode) {
+    match model {
+        Model::Nmos6502 => NMOS[opcode as usize],
+        // Without the bit instructions, columns 7 and F are one byte NOPs
+        Model::Cmos65C02 if (opcode & 0x07) == 0x07 => ("NOP", Mode::Imp),
+        Model::Wdc65C02 if opcode == 0xcb => ("WAI", Mode::Imp),
+        Model::Wdc65C02 if opcode == 0xdb => ("STP", Mode::Imp),
+        Model::Cmos65C02 | Model::Rockwell65C02 | Model::Wdc65C02 => CMOS[opcode as usize],
+    }
+}
+
+use Mode::*;
+
+// The NMOS 6502, including the undocumented opcodes. Those that we do not
+// emulate have their commonly used names.
+
+const NMOS: [(&str, Mode); 256] = [
+    // 0x00
+    ("BRK", Imp), ("ORA", IndX), ("JAM", Imp), ("SLO", IndX), ("NOP", Zpg), ("ORA", Zpg), ("ASL", Zpg), ("SLO", Zpg),
+    ("PHP", Imp), ("ORA", Imm), ("ASL", Acc), ("ANC", Imm), ("NOP", Abs), ("ORA", Abs), ("ASL", Abs), ("SLO", Abs),
+    // 0x10
+    ("BPL", Rel), ("ORA", IndY), ("JAM", Imp), ("SLO", IndY), ("NOP", ZpgX), ("ORA", ZpgX), ("ASL", ZpgX), ("SLO", ZpgX),
+    ("CLC", Imp), ("ORA", AbsY), ("NOP", Imp), ("SLO", AbsY), ("NOP", AbsX), ("ORA", AbsX), ("ASL", AbsX), ("SLO", AbsX),
+    // 0x20
+    ("JSR", Abs), ("AND", IndX), ("JAM", Imp), ("RLA", IndX), ("BIT", Zpg), ("AND", Zpg), ("ROL", Zpg), ("RLA", Zpg),
+    ("PLP", Imp), ("AND", Imm), ("ROL", Acc), ("ANC", Imm), ("BIT", Abs), ("AND", Abs), ("ROL", Abs), ("RLA", Abs),
+    // 0x30
+    ("BMI", Rel), ("AND", IndY), ("JAM", Imp), ("RLA", IndY), ("NOP", ZpgX), ("AND", ZpgX), ("ROL", ZpgX), ("RLA", ZpgX),
+    ("SEC", Imp), ("AND", AbsY), ("NOP", Imp), ("RLA", AbsY), ("NOP", AbsX), ("AND", AbsX), ("ROL", AbsX), ("RLA", AbsX),
+    // 0x40
+    ("RTI", Imp), ("EOR", IndX), ("JAM", Imp), ("SRE", IndX), ("NOP", Zpg), ("EOR", Zpg), ("LSR", Zpg), ("SRE", Zpg),
+    ("PHA", Imp), ("EOR", Imm), ("LSR", Acc), ("ALR", Imm), ("JMP", Abs), ("EOR", Abs), ("LSR", Abs), ("SRE", Abs),
+    // 0x50
+    ("BVC", Rel), ("EOR", IndY), ("JAM", Imp), ("SRE", IndY), ("NOP", ZpgX), ("EOR", ZpgX), ("LSR", ZpgX), ("SRE", ZpgX),
+    ("CLI", Imp), ("EOR", AbsY), ("NOP", Imp), ("SRE", AbsY), ("NOP", AbsX), ("EOR", AbsX), ("LSR", AbsX), ("SRE", AbsX),
+    // 0x60
+    ("RTS", Imp), ("ADC", IndX), ("JAM", Imp), ("RRA", IndX), ("NOP", Zpg), ("ADC", Zpg), ("ROR", Zpg), ("RRA", Zpg),
+    ("PLA", Imp), ("ADC", Imm), ("ROR", Acc), ("ARR", Imm), ("JMP", Ind), ("ADC", Abs), ("ROR", Abs), ("RRA", Abs),
+    // 0x70
+    ("BVS", Rel), ("ADC", IndY), ("JAM", Imp), ("RRA", IndY), ("NOP", ZpgX), ("ADC", ZpgX), ("ROR", ZpgX), ("RRA", ZpgX),
+    ("SEI", Imp), ("ADC", AbsY), ("NOP", Imp), ("RRA", AbsY), ("NOP", AbsX), ("ADC", AbsX), ("ROR", AbsX), ("RRA", AbsX),
+    // 0x80
+    ("NOP", Imm), ("STA", IndX), ("NOP", Imm), ("SAX", IndX), ("STY", Zpg), ("STA", Zpg), ("STX", Zpg), ("SAX", Zpg),
+    ("DEY", Imp), ("NOP", Imm), ("TXA", Imp), ("ANE", Imm), ("STY", Abs), ("STA", Abs), ("STX", Abs), ("SAX", Abs),
+    // 0x90
+    ("BCC", Rel), ("STA", IndY), ("JAM", Imp), ("SHA", IndY), ("STY", ZpgX), ("STA", ZpgX), ("STX", ZpgY), ("SAX", ZpgY),
+    ("TYA", Imp), ("STA", AbsY), ("TXS", Imp), ("TAS", AbsY), ("SHY", AbsX), ("STA", AbsX), ("SHX", AbsY), ("SHA", AbsY),
+    // 0xA0
+    ("LDY", Imm), ("LDA", IndX), ("LDX", Imm), ("LAX", IndX), ("LDY", Zpg), ("LDA", Zpg), ("LDX", Zpg), ("LAX", Zpg),
+    ("TAY", Imp), ("LDA", Imm), ("TAX", Imp), ("LXA", Imm), ("LDY", Abs), ("LDA", Abs), ("LDX", Abs), ("LAX", Abs),
+    // 0xB0
+    ("BCS", Rel), ("LDA", IndY), ("JAM", Imp), ("LAX", IndY), ("LDY", ZpgX), ("LDA", ZpgX), ("LDX", ZpgY), ("LAX", ZpgY),
+    ("CLV", Imp), ("LDA", AbsY), ("TSX", Imp), ("LAS", AbsY), ("LDY", AbsX), ("LDA", AbsX), ("LDX", AbsY), ("LAX", AbsY),
+    // 0xC0
+    ("CPY", Imm), ("CMP", IndX), ("NOP", Imm), ("DCP", IndX), ("CPY", Zpg), ("CMP", Zpg), ("DEC", Zpg), ("DCP", Zpg),
+    ("INY", Imp), ("CMP", Imm), ("DEX", Imp), ("SBX", Imm), ("CPY", Abs), ("CMP", Abs), ("DEC", Abs), ("DCP", Abs),
+    // 0xD0
+    ("BNE", Rel), ("CMP", IndY), ("JAM", Imp), ("DCP", IndY), ("NOP", ZpgX), ("CMP", ZpgX), ("DEC", ZpgX), ("DCP", ZpgX),
+    ("CLD", Imp), ("CMP", AbsY), ("NOP", Imp), ("DCP", AbsY), ("NOP", AbsX), ("CMP", AbsX), ("DEC", AbsX), ("DCP", AbsX),
+    // 0xE0
+    ("CPX", Imm), ("SBC", IndX), ("NOP", Imm), ("ISC", IndX), ("CPX", Zpg), ("SBC", Zpg), ("INC", Zpg), ("ISC", Zpg),
+    ("INX", Imp), ("SBC", Imm), ("NOP", Imp), ("SBC", Imm), ("CPX", Abs), ("SBC", Abs), ("INC", Abs), ("ISC", Abs),
+    // 0xF0
+    ("BEQ", Rel), ("SBC", IndY), ("JAM", Imp), ("ISC", IndY), ("NOP", ZpgX), ("SBC", ZpgX), ("INC", ZpgX), ("ISC", ZpgX),
+    ("SED", Imp), ("SBC", AbsY), ("NOP", Imp), ("ISC", AbsY), ("NOP", AbsX), ("SBC", AbsX), ("INC", AbsX), ("ISC", AbsX),
+];
+
+// The Rockwell 65C02. Unused opcodes are NOPs of different sizes.
+
+const CMOS: [(&str, Mode); 256] = [
+    // 0x00
+    ("BRK", Imp), ("ORA", IndX), ("NOP", Imm), ("NOP", Imp), ("TSB", Zpg), ("ORA", Zpg), ("ASL", Zpg), ("RMB0", Zpg),
+    ("PHP", Imp), ("ORA", Imm), ("ASL", Acc), ("NOP", Imp), ("TSB", Abs), ("ORA", Abs), ("ASL", Abs), ("BBR0", ZpgRel),
+    // 0x10
+    ("BPL", Rel), ("ORA", IndY), ("ORA", ZpgInd), ("NOP", Imp), ("TRB", Zpg), ("ORA", ZpgX), ("ASL", ZpgX), ("RMB1", Zpg),
+    ("CLC", Imp), ("ORA", AbsY), ("INC", Acc), ("NOP", Imp), ("TRB", Abs), ("ORA", AbsX), ("ASL", AbsX), ("BBR1", ZpgRel),
+    // 0x20
+    ("JSR", Abs), ("AND", IndX), ("NOP", Imm), ("NOP", Imp), ("BIT", Zpg), ("AND", Zpg), ("ROL", Zpg), ("RMB2", Zpg),
+    ("PLP", Imp), ("AND", Imm), ("ROL", Acc), ("NOP", Imp), ("BIT", Abs), ("AND", Abs), ("ROL", Abs), ("BBR2", ZpgRel),
+    // 0x30
+    ("BMI", Rel), ("AND", IndY), ("AND", ZpgInd), ("NOP", Imp), ("BIT", ZpgX), ("AND", ZpgX), ("ROL", ZpgX), ("RMB3", Zpg),
+    ("SEC", Imp), ("AND", AbsY), ("DEC", Acc), ("NOP", Imp), ("BIT", AbsX), ("AND", AbsX), ("ROL", AbsX), ("BBR3", ZpgRel),
+    // 0x40
+    ("RTI", Imp), ("EOR", IndX), ("NOP", Imm), ("NOP", Imp), ("NOP", Zpg), ("EOR", Zpg), ("LSR", Zpg), ("RMB4", Zpg),
+    ("PHA", Imp), ("EOR", Imm), ("LSR", Acc), ("NOP", Imp), ("JMP", Abs), ("EOR", Abs), ("LSR", Abs), ("BBR4", ZpgRel),
+    // 0x50
+    ("BVC", Rel), ("EOR", IndY), ("EOR", ZpgInd), ("NOP", Imp), ("NOP", ZpgX), ("EOR", ZpgX), ("LSR", ZpgX), ("RMB5", Zpg),
+    ("CLI", Imp), ("EOR", AbsY), ("PHY", Imp), ("NOP", Imp), ("NOP", Abs), ("EOR", AbsX), ("LSR", AbsX), ("BBR5", ZpgRel),
+    // 0x60
+    ("RTS", Imp), ("ADC", IndX), ("NOP", Imm), ("NOP", Imp), ("STZ", Zpg), ("ADC", Zpg), ("ROR", Zpg), ("RMB6", Zpg),
+    ("PLA", Imp), ("ADC", Imm), ("ROR", Acc), ("NOP", Imp), ("JMP", Ind), ("ADC", Abs), ("ROR", Abs), ("BBR6", ZpgRel),
+    // 0x70
+    ("BVS", Rel), ("ADC", IndY), ("ADC", ZpgInd), ("NOP", Imp), ("STZ", ZpgX), ("ADC", ZpgX), ("ROR", ZpgX), ("RMB7", Zpg),
+    ("SEI", Imp), ("ADC", AbsY), ("PLY", Imp), ("NOP", Imp), ("JMP", AbsXInd), ("ADC", AbsX), ("ROR", AbsX), ("BBR7", ZpgRel),
+    // 0x80
+    ("BRA", Rel), ("STA", IndX), ("NOP", Imm), ("NOP", Imp), ("STY", Zpg), ("STA", Zpg), ("STX", Zpg), ("SMB0", Zpg),
+    ("DEY", Imp), ("BIT", Imm), ("TXA", Imp), ("NOP", Imp), ("STY", Abs), ("STA", Abs), ("STX", Abs), ("BBS0", ZpgRel),
+    // 0x90
+    ("BCC", Rel), ("STA", IndY), ("STA", ZpgInd), ("NOP", Imp), ("STY", ZpgX), ("STA", ZpgX), ("STX", ZpgY), ("SMB1", Zpg),
+    ("TYA", Imp), ("STA", AbsY), ("TXS", Imp), ("NOP", Imp), ("STZ", Abs), ("STA", AbsX), ("STZ", AbsX), ("BBS1", ZpgRel),
+    // 0xA0
+    ("LDY", Imm), ("LDA", IndX), ("LDX", Imm), ("NOP", Imp), ("LDY", Zpg), ("LDA", Zpg), ("LDX", Zpg), ("SMB2", Zpg),
+    ("TAY", Imp), ("LDA", Imm), ("TAX", Imp), ("NOP", Imp), ("LDY", Abs), ("LDA", Abs), ("LDX", Abs), ("BBS2", ZpgRel),
+    // 0xB0
+    ("BCS", Rel), ("LDA", IndY), ("LDA", ZpgInd), ("NOP", Imp), ("LDY", ZpgX), ("LDA", ZpgX), ("LDX", ZpgY), ("SMB3", Zpg),
+    ("CLV", Imp), ("LDA", AbsY), ("TSX", Imp), ("NOP", Imp), ("LDY", AbsX), ("LDA", AbsX), ("LDX", AbsY), ("BBS3", ZpgRel),
+    // 0xC0
+    ("CPY", Imm), ("CMP", IndX), ("NOP", Imm), ("NOP", Imp), ("CPY", Zpg), ("CMP", Zpg), ("DEC", Zpg), ("SMB4", Zpg),
+    ("INY", Imp), ("CMP", Imm), ("DEX", Imp), ("NOP", Imp), ("CPY", Abs), ("CMP", Abs), ("DEC", Abs), ("BBS4", ZpgRel),
+    // 0xD0
+    ("BNE", Rel), ("CMP", IndY), ("CMP", ZpgInd), ("NOP", Imp), ("NOP", ZpgX), ("CMP", ZpgX), ("DEC", ZpgX), ("SMB5", Zpg),
+    ("CLD", Imp), ("CMP", AbsY), ("PHX", Imp), ("NOP", Imp), ("NOP", Abs), ("CMP", AbsX), ("DEC", AbsX), ("BBS5", ZpgRel),
+    // 0xE0
+    ("CPX", Imm), ("SBC", IndX), ("NOP", Imm), ("NOP", Imp), ("CPX", Zpg), ("SBC", Zpg), ("INC", Zpg), ("SMB6", Zpg),
+    ("INX", Imp), ("SBC", Imm), ("NOP", Imp), ("NOP", Imp), ("CPX", Abs), ("SBC", Abs), ("INC", Abs), ("BBS6", ZpgRel),
+    // 0xF0
+    ("BEQ", Rel), ("SBC", IndY), ("SBC", ZpgInd), ("NOP", Imp), ("NOP", ZpgX), ("SBC", ZpgX), ("INC", ZpgX), ("SMB7", Zpg),
+    ("SED", Imp), ("SBC", AbsY), ("PLX", Imp), ("NOP", Imp), ("NOP", Abs), ("SBC", AbsX), ("INC", AbsX), ("BBS7", ZpgRel),
+];
+
+#[cfg(test)]
+mod disasm_tests {
+    use super::*;
+    use super::super::bus::Ram;
+
+    fn ram(address: u16, bytes: &[u8]) -> Ram {
+        let mut ram = Ram::new();
+        for (i, b) in bytes.iter().enumerate() {
+            ram.write(address.wrapping_add(i as u16), *b);
+        }
+        ram
+    }
+
+    fn text(model: Model, bytes: &[u8]) -> String {
+        disassemble(&ram(0x0300, bytes), model, 0x0300).to_string()
+    }
+
+    #[test]
+    fn test_apple_monitor_format() {
+        assert_eq!(text(Model::Nmos6502, &[0x20, 0xed, 0xfd]), "0300-   20 ED FD    JSR   $FDED");
+        assert_eq!(text(Model::Nmos6502, &[0xa9, 0xc1]), "0300-   A9 C1       LDA   #$C1");
+        assert_eq!(text(Model::Nmos6502, &[0x60]), "0300-   60          RTS");
+        assert_eq!(text(Model::Nmos6502, &[0x0a]), "0300-   0A          ASL");
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        assert_eq!(text(Model::Nmos6502, &[0xb5, 0x42]), "0300-   B5 42       LDA   $42,X");
+        assert_eq!(text(Model::Nmos6502, &[0xb6, 0x42]), "0300-   B6 42       LDX   $42,Y");
+        assert_eq!(text(Model::Nmos6502, &[0xbd, 0x34, 0x12]), "0300-   BD 34 12    LDA   $1234,X");
+        assert_eq!(text(Model::Nmos6502, &[0xb9, 0x34, 0x12]), "0300-   B9 34 12    LDA   $1234,Y");
+        assert_eq!(text(Model::Nmos6502, &[0x6c, 0x34, 0x12]), "0300-   6C 34 12    JMP   ($1234)");
+        assert_eq!(text(Model::Nmos6502, &[0xa1, 0x42]), "0300-   A1 42       LDA   ($42,X)");
+        assert_eq!(text(Model::Nmos6502, &[0xb1, 0x42]), "0300-   B1 42       LDA   ($42),Y");
+        assert_eq!(text(Model::Cmos65C02, &[0xb2, 0x42]), "0300-   B2 42       LDA   ($42)");
+        assert_eq!(text(Model::Cmos65C02, &[0x7c, 0x34, 0x12]), "0300-   7C 34 12    JMP   ($1234,X)");
+    }
+
+    #[test]
+    fn test_branch_targets() {
+        let instruction = disassemble(&ram(0x0300, &[0xd0, 0xfe]), Model::Nmos6502, 0x0300);
+        assert_eq!(instruction.target, Some(0x0300));
+        assert_eq!(instruction.to_string(), "0300-   D0 FE       BNE   $0300");
+
+        let instruction = disassemble(&ram(0x0300, &[0x0f, 0x42, 0x10]), Model::Rockwell65C02, 0x0300);
+        assert_eq!(instruction.target, Some(0x0313));
+        assert_eq!(instruction.to_string(), "0300-   0F 42 10    BBR0  $42,$0313");
+
+        let instruction = disassemble(&ram(0x0300, &[0x4c, 0x00, 0x04]), Model::Nmos6502, 0x0300);
+        assert_eq!(instruction.target, Some(0x0400));
+        assert_eq!(instruction.operand, Some(0x0400));
+    }
+
+    #[test]
+    fn test_models() {
+        // The same opcode means something else on every model
+        assert_eq!(text(Model::Nmos6502, &[0x07, 0x42]), "0300-   07 42       SLO   $42");
+        assert_eq!(text(Model::Cmos65C02, &[0x07, 0x42]), "0300-   07          NOP");
+        assert_eq!(text(Model::Rockwell65C02, &[0x07, 0x42]), "0300-   07 42       RMB0  $42");
+        assert_eq!(text(Model::Rockwell65C02, &[0xcb]), "0300-   CB          NOP");
+        assert_eq!(text(Model::Wdc65C02, &[0xcb]), "0300-   CB          WAI");
+        assert_eq!(text(Model::Wdc65C02, &[0xdb]), "0300-   DB          STP");
+        assert_eq!(text(Model::Nmos6502, &[0x80, 0x10]), "0300-   80 10       NOP   #$10");
+        assert_eq!(text(Model::Cmos65C02, &[0x80, 0x10]), "0300-   80 10       BRA   $0312");
+    }
+
+    #[test]
+    fn test_disassemble_range() {
+        let bus = ram(0xfffe, &[0xa9, 0x01, 0xea]);
+        let instructions = disassemble_range(&bus, Model::Nmos6502, 0xfffe, 2);
+        assert_eq!(instructions[0].bytes, vec![0xa9, 0x01]);
+        assert_eq!(instructions[1].address, 0x0000);
+        assert_eq!(instructions[1].mnemonic, "NOP");
+    }
+
+    #[test]
+    fn test_sizes_match_cpu() {
+        // With zero operands, branches fall through to the next instruction,
+        // so everything but jumps, returns and JAM advances the PC by the
+        // size of the instruction.
+        for model in [Model::Nmos6502, Model::Cmos65C02, Model::Rockwell65C02, Model::Wdc65C02] {
+            for opcode in 0..=255u8 {
+                let (mnemonic, mode) = decode(model, opcode);
+                if ["BRK", "JMP", "JSR", "RTI", "RTS", "JAM"].contains(&mnemonic) {
+                    continue;
+                }
+                let mut cpu = CPU::with_model(ram(0x0300, &[opcode]), model);
+                cpu.undocumented_opcodes = true;
+                cpu.pc = 0x0300;
+                if cpu.step().is_err() {
+                    continue; // Undocumented opcodes that we do not emulate
+                }
+                assert_eq!(cpu.pc, 0x0300 + mode.size(), "{:?} ${:02X} {}", model, opcode, mnemonic);
+            }
+        }
+    }
+}
@@ -0,0 +1,591 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use super::bus::Bus;
+use super::cpu::{Breakpoint, StopReason, CPU};
+
+// A stub for the GDB remote serial protocol, so that debuggers that speak
+// it can control a CPU on any bus over a TCP connection. GDB itself does not
+// know the 6502, but the target description below tells front ends what
+// the registers are:
+//
+//   0  a    8 bits
+//   1  x    8 bits
+//   2  y    8 bits
+//   3  p    8 bits, the status with bit 5 always set
+//   4  sp   8 bits, the low byte of the stack address
+//   5  pc   16 bits, little endian like all values on the wire
+//
+// Memory is read without side effects on I/O and written through the bus.
+// Software breakpoints (Z0) become PC breakpoints of the CPU, and so do
+// hardware ones (Z1). Continuing runs until a breakpoint, an illegal opcode
+// or a halted CPU stops it, or the client sends a Control-C.
+//
+// There is one thread, which the stub reports as thread 1 when asked.
+
+pub const GDB_TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rewm.6502.cpu">
+    <flags id="status" size="1">
+      <field name="C" start="0" end="0"/>
+      <field name="Z" start="1" end="1"/>
+      <field name="I" start="2" end="2"/>
+      <field name="D" start="3" end="3"/>
+      <field name="B" start="4" end="4"/>
+      <field name="V" start="6" end="6"/>
+      <field name="N" start="7" end="7"/>
+    </flags>
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="status"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// The sizes of the registers in bytes, in the order above
+const REGISTERS: [usize; 6] = [1, 1, 1, 1, 1, 2];
+
+// Signals in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// How many instructions run between looking for a Control-C
+const POLL_INSTRUCTIONS: u32 = 10_000;
+
+// The largest packet we send or take, with the $, # and checksum. Memory
+// reads are cut short to fit, and the client asks for the rest.
+const PACKET_SIZE: usize = 0x1000;
+const MAX_MEMORY_READ: u32 = (PACKET_SIZE as u32 - 4) / 2;
+
+const INTERRUPT: u8 = 0x03;
+
+// Waits for one client on the listener and serves it until it detaches,
+// kills the target or goes away.
+
+pub fn serve_gdb<B: Bus>(cpu: &mut CPU<B>, listener: &TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbStub::new(stream)?.session(cpu)
+}
+
+struct GdbStub {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    ack: bool,
+    // The last packet we sent, for when the client asks for it again
+    last_packet: Vec<u8>,
+    // Our breakpoints in the CPU, by address
+    breakpoints: HashMap<u16, usize>,
+}
+
+enum Reply {
+    Packet(String),
+    Quit,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn number(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn address(text: &str) -> Option<u16> {
+    number(text).and_then(|n| u16::try_from(n).ok())
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn stop_reply(signal: u8) -> Reply {
+    Reply::Packet(format!("T{:02x}thread:1;", signal))
+}
+
+fn error(code: u8) -> Reply {
+    Reply::Packet(format!("E{:02x}", code))
+}
+
+fn registers<B: Bus>(cpu: &CPU<B>) -> Vec<u8> {
+    let [pc_low, pc_high] = cpu.pc.to_le_bytes();
+    vec![cpu.a, cpu.x, cpu.y, cpu.get_status() | 0x20, cpu.sp, pc_low, pc_high]
+}
+
+fn set_register<B: Bus>(cpu: &mut CPU<B>, register: usize, bytes: &[u8]) {
+    match register {
+        0 => cpu.a = bytes[0],
+        1 => cpu.x = bytes[0],
+        2 => cpu.y = bytes[0],
+        3 => cpu.set_status(bytes[0]),
+        4 => cpu.sp = bytes[0],
+        _ => cpu.pc = u16::from_le_bytes([bytes[0], bytes[1]]),
+    }
+}
+
+// A part of a document for qXfer, m if there is more and l if not
+fn transfer(document: &str, offset: usize, length: usize) -> Reply {
+    let start = offset.min(document.len());
+    let end = offset.saturating_add(length).min(document.len());
+    let more = if end < document.len() { 'm' } else { 'l' };
+    Reply::Packet(format!("{}{}", more, &document[start..end]))
+}
+
+impl GdbStub {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        Ok(GdbStub {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+            ack: true,
+            last_packet: Vec::new(),
+            breakpoints: HashMap::new(),
+        })
+    }
+
+    fn session<B: Bus>(&mut self, cpu: &mut CPU<B>) -> io::Result<()> {
+        let result = self.serve_packets(cpu);
+        // Breakpoints of a client that is gone would only get in the way
+        for (_, id) in self.breakpoints.drain() {
+            cpu.breakpoints.remove(id);
+        }
+        result
+    }
+
+    fn serve_packets<B: Bus>(&mut self, cpu: &mut CPU<B>) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(cpu, &packet)? {
+                Reply::Packet(reply) => self.write_packet(&reply)?,
+                Reply::Quit => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Returns the next packet, or None when the client hung up. Acks and a
+    // Control-C while the CPU is not running are of no interest here.
+
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(b'-') => {
+                    let packet = self.last_packet.clone();
+                    self.writer.write_all(&packet)?;
+                    continue;
+                }
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            self.reader.read_until(b'#', &mut data)?;
+            if data.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum)?;
+            let valid = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok()) == Some(checksum(&data));
+            if self.ack {
+                self.writer.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        // Replies are hex or XML, but just in case
+        for &b in data.as_bytes() {
+            if matches!(b, b'$' | b'#' | b'}' | b'*') {
+                packet.extend([b'}', b ^ 0x20]);
+            } else {
+                packet.push(b);
+            }
+        }
+        let sum = checksum(&packet[1..]);
+        packet.extend(format!("#{:02x}", sum).bytes());
+        self.writer.write_all(&packet)?;
+        self.last_packet = packet;
+        Ok(())
+    }
+
+    // Whether the client sent a Control-C, or hung up, while we ran
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.reader.get_ref().set_nonblocking(true)?;
+        let result = match self.reader.fill_buf() {
+            Ok([]) => Ok(true),
+            Ok(&[INTERRUPT, ..]) => {
+                self.reader.consume(1);
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        };
+        self.reader.get_ref().set_nonblocking(false)?;
+        result
+    }
+
+    fn run<B: Bus>(&mut self, cpu: &mut CPU<B>) -> io::Result<Reply> {
+        let mut check_pc = false;
+        let mut count = 0;
+        loop {
+            match cpu.step_with_breakpoints(check_pc) {
+                None => {}
+                Some(StopReason::IllegalOpcode { pc }) => {
+                    cpu.pc = pc;
+                    return Ok(stop_reply(SIGILL));
+                }
+                Some(_) => return Ok(stop_reply(SIGTRAP)),
+            }
+            check_pc = true;
+            count += 1;
+            if count == POLL_INSTRUCTIONS {
+                count = 0;
+                if self.interrupted()? {
+                    return Ok(stop_reply(SIGINT));
+                }
+            }
+        }
+    }
+
+    fn handle<B: Bus>(&mut self, cpu: &mut CPU<B>, packet: &str) -> io::Result<Reply> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        Ok(match command {
+            "?" => stop_reply(SIGTRAP),
+            "g" => Reply::Packet(hex(&registers(cpu))),
+            "G" => match from_hex(args) {
+                Some(bytes) if bytes.len() == REGISTERS.iter().sum() => {
+                    let mut offset = 0;
+                    for (register, size) in REGISTERS.iter().enumerate() {
+                        set_register(cpu, register, &bytes[offset..offset + size]);
+                        offset += size;
+                    }
+                    Reply::Packet("OK".to_string())
+                }
+                _ => error(1),
+            },
+            "p" => match number(args).map(|n| n as usize) {
+                Some(register) if register < REGISTERS.len() => {
+                    let offset: usize = REGISTERS[..register].iter().sum();
+                    Reply::Packet(hex(&registers(cpu)[offset..offset + REGISTERS[register]]))
+                }
+                _ => error(1),
+            },
+            "P" => {
+                let register = args.split_once('=').and_then(|(register, value)| Some((number(register)? as usize, from_hex(value)?)));
+                match register {
+                    Some((register, bytes)) if register < REGISTERS.len() && bytes.len() == REGISTERS[register] => {
+                        set_register(cpu, register, &bytes);
+                        Reply::Packet("OK".to_string())
+                    }
+                    _ => error(1),
+                }
+            }
+            "m" => match args.split_once(',').and_then(|(start, length)| Some((address(start)?, number(length)?))) {
+                Some((start, length)) => {
+                    let bytes: Vec<u8> = (0..length.min(MAX_MEMORY_READ)).map(|i| cpu.peek_byte(start.wrapping_add(i as u16))).collect();
+                    Reply::Packet(hex(&bytes))
+                }
+                None => error(1),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (start, length) = range.split_once(',')?;
+                    Some((address(start)?, number(length)?, from_hex(data)?))
+                });
+                match write {
+                    Some((start, length, bytes)) if bytes.len() == length as usize => {
+                        for (i, b) in bytes.into_iter().enumerate() {
+                            cpu.set_byte(start.wrapping_add(i as u16), b);
+                        }
+                        Reply::Packet("OK".to_string())
+                    }
+                    _ => error(1),
+                }
+            }
+            "Z" | "z" => self.breakpoint(cpu, command == "Z", args),
+            "s" | "c" => {
+                if !args.is_empty() {
+                    match address(args) {
+                        Some(pc) => cpu.pc = pc,
+                        None => return Ok(error(1)),
+                    }
+                }
+                if command == "c" {
+                    self.run(cpu)?
+                } else {
+                    match cpu.step_with_breakpoints(false) {
+                        Some(StopReason::IllegalOpcode { pc }) => {
+                            cpu.pc = pc;
+                            stop_reply(SIGILL)
+                        }
+                        _ => stop_reply(SIGTRAP),
+                    }
+                }
+            }
+            "H" | "T" => Reply::Packet("OK".to_string()),
+            "D" => {
+                self.write_packet("OK")?;
+                Reply::Quit
+            }
+            "k" => Reply::Quit,
+            "q" | "Q" => self.query(packet),
+            _ => Reply::Packet(String::new()),
+        })
+    }
+
+    fn breakpoint<B: Bus>(&mut self, cpu: &mut CPU<B>, insert: bool, args: &str) -> Reply {
+        let mut parts = args.split(',');
+        let (Some(kind), Some(Some(address))) = (parts.next(), parts.next().map(address)) else {
+            return error(1);
+        };
+        // Only breakpoints, watchpoints are left to the client
+        if kind != "0" && kind != "1" {
+            return Reply::Packet(String::new());
+        }
+        if insert {
+            self.breakpoints.entry(address).or_insert_with(|| cpu.breakpoints.add(Breakpoint::at(address)));
+        } else if let Some(id) = self.breakpoints.remove(&address) {
+            cpu.breakpoints.remove(id);
+        }
+        Reply::Packet("OK".to_string())
+    }
+
+    fn query(&mut self, packet: &str) -> Reply {
+        let reply = |text: &str| Reply::Packet(text.to_string());
+        if packet.starts_with("qSupported") {
+            return Reply::Packet(format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE));
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:") {
+            let request = args.split_once(':').and_then(|(annex, range)| {
+                let (offset, length) = range.split_once(',')?;
+                Some((annex, number(offset)? as usize, number(length)? as usize))
+            });
+            return match request {
+                Some(("target.xml", offset, length)) => transfer(GDB_TARGET_XML, offset, length),
+                Some(_) => error(0),
+                None => error(1),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                // This one is still acked, the next ones not
+                self.ack = false;
+                reply("OK")
+            }
+            "qAttached" => reply("1"),
+            "qC" => reply("QC1"),
+            "qfThreadInfo" => reply("m1"),
+            "qsThreadInfo" => reply("l"),
+            _ => reply(""),
+        }
+    }
+}
+
+#[cfg(test)]
+mod gdb_tests {
+    use std::thread;
+
+    use super::*;
+    use super::super::fixtures::cpu;
+
+    // A scripted client, which sends packets and returns the replies
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        ack: bool,
+    }
+
+    impl Client {
+        fn connect(port: u16) -> Self {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            Client { writer: stream.try_clone().unwrap(), reader: BufReader::new(stream), ack: true }
+        }
+
+        fn send(&mut self, data: &str) {
+            write!(self.writer, "${}#{:02x}", data, checksum(data.as_bytes())).unwrap();
+            if self.ack {
+                assert_eq!(self.byte(), b'+');
+            }
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.reader.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn receive(&mut self) -> String {
+            assert_eq!(self.byte(), b'$');
+            let mut data = Vec::new();
+            self.reader.read_until(b'#', &mut data).unwrap();
+            data.pop();
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum).unwrap();
+            assert_eq!(u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap(), checksum(&data));
+            if self.ack {
+                self.writer.write_all(b"+").unwrap();
+            }
+            String::from_utf8(data).unwrap()
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.receive()
+        }
+    }
+
+    // Serves a CPU with the program at $0300 to the client
+    fn serve_program(source: &str, client: impl FnOnce(Client) + Send + 'static) -> CPU {
+        let mut cpu = cpu(source);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || client(Client::connect(port)));
+        serve_gdb(&mut cpu, &listener).unwrap();
+        client.join().unwrap();
+        cpu
+    }
+
+    const PROGRAM: &str = "
+        LDX #$03
+loop:   STX $10
+        DEX
+        BNE loop
+        LDA #$42
+done:   JMP done";
+
+    #[test]
+    fn test_session() {
+        let cpu = serve_program(PROGRAM, |mut client| {
+            assert!(client.request("qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+            assert_eq!(client.request("QStartNoAckMode"), "OK");
+            client.ack = false;
+            assert_eq!(client.request("?"), "T05thread:1;");
+            assert_eq!(client.request("qfThreadInfo"), "m1");
+            assert_eq!(client.request("vMustReplyEmpty"), "");
+
+            // Registers
+            assert_eq!(client.request("g"), "00000020ff0003");
+            assert_eq!(client.request("P0=7f"), "OK");
+            assert_eq!(client.request("p0"), "7f");
+            assert_eq!(client.request("p5"), "0003");
+            assert_eq!(client.request("p6"), "E01");
+
+            // Memory
+            assert_eq!(client.request("m300,3"), "a20386");
+            // Long reads are cut short to fit in a packet
+            let memory = client.request("m0,ffff");
+            assert_eq!(memory.len(), MAX_MEMORY_READ as usize * 2);
+            assert!(memory.len() + 4 <= PACKET_SIZE);
+            assert_eq!(client.request("M1000,2:cafe"), "OK");
+            assert_eq!(client.request("m1000,2"), "cafe");
+            assert_eq!(client.request("M1000,2:ca"), "E01");
+
+            // A step, and on to a breakpoint
+            assert_eq!(client.request("s"), "T05thread:1;");
+            assert_eq!(client.request("p5"), "0203");
+            assert_eq!(client.request("Z0,304,1"), "OK");
+            assert_eq!(client.request("c"), "T05thread:1;");
+            assert_eq!(client.request("g"), "7f030020ff0403");
+            assert_eq!(client.request("c"), "T05thread:1;");
+            assert_eq!(client.request("p1"), "02");
+            assert_eq!(client.request("m10,1"), "02");
+            assert_eq!(client.request("z0,304,1"), "OK");
+            assert_eq!(client.request("Z2,10,1"), "");
+
+            // Control-C stops the loop at the end
+            client.send("c");
+            client.writer.write_all(&[INTERRUPT]).unwrap();
+            assert_eq!(client.receive(), "T02thread:1;");
+            assert_eq!(client.request("g"), "42000020ff0903");
+
+            client.send("D");
+            assert_eq!(client.receive(), "OK");
+        });
+        assert_eq!((cpu.pc, cpu.a, cpu.x), (0x0309, 0x42, 0x00));
+        assert!(cpu.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_target_xml() {
+        serve_program(PROGRAM, |mut client| {
+            let mut xml = String::new();
+            loop {
+                let reply = client.request(&format!("qXfer:features:read:target.xml:{:x},80", xml.len()));
+                xml.push_str(&reply[1..]);
+                if reply.starts_with('l') {
+                    break;
+                }
+                assert!(reply.starts_with('m'));
+            }
+            assert_eq!(xml, GDB_TARGET_XML);
+            assert_eq!(client.request("qXfer:features:read:other.xml:0,80"), "E00");
+            client.send("k");
+        });
+    }
+
+    #[test]
+    fn test_errors() {
+        let cpu = serve_program(".byte $02", |mut client| {
+            // A packet with a bad checksum is asked for again
+            client.writer.write_all(b"$g#00").unwrap();
+            assert_eq!(client.byte(), b'-');
+            assert_eq!(client.request("g"), "00000020ff0003");
+            // And so is a reply
+            client.writer.write_all(b"-").unwrap();
+            assert_eq!(client.receive(), "00000020ff0003");
+            assert_eq!(client.request("c"), "T04thread:1;");
+            assert_eq!(client.request("p5"), "0003");
+            assert_eq!(client.request("Z0,xyz,1"), "E01");
+            assert_eq!(client.request("c 12"), "E01");
+        });
+        assert_eq!(cpu.pc, 0x0300);
+    }
+}
@@ -1,3 +1,14 @@
+mod apple1;
+pub use apple1::*;
+
+mod apple2;
+pub use apple2::*;
+
+mod asm;
+pub use asm::*;
+
+mod bus;
+pub use bus::*;
 
 mod computer;
 pub use computer::*;
@@ -5,3 +16,18 @@ pub use computer::*;
 mod cpu;
 pub use cpu::*;
 
+mod debugger;
+pub use debugger::*;
+
+mod disasm;
+pub use disasm::*;
+
+#[cfg(test)]
+mod fixtures;
+
+mod gdb;
+pub use gdb::*;
+
+mod pia;
+pub use pia::*;
+
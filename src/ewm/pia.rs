@@ -0,0 +1,202 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2015 Stefan Arentz - http://github.com/st3fan/ewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// The Motorola 6821 Peripheral Interface Adapter. It has two 8 bit ports, A
+// and B, and every port has three registers behind two addresses:
+//
+//   0  Port A data or data direction register, selected by bit 2 of CRA
+//   1  CRA, the control register of port A
+//   2  Port B data or data direction register, selected by bit 2 of CRB
+//   3  CRB, the control register of port B
+//
+// A bit set in a data direction register makes that pin an output. Bit 7 of
+// a control register is set by an active transition on the CA1 or CB1 input
+// and cleared when the CPU reads the data register of that port. We do not
+// emulate the interrupt outputs or the CA2 and CB2 lines.
+
+const CR_DATA_SELECT: u8 = 0b00000100;
+const CR_IRQ1: u8 = 0b10000000;
+const CR_IRQ2: u8 = 0b01000000;
+
+#[derive(Debug, Default)]
+struct Port {
+    control: u8,
+    direction: u8,
+    output: u8,
+    input: u8,
+}
+
+impl Port {
+    fn data(&self) -> u8 {
+        (self.output & self.direction) | (self.input & !self.direction)
+    }
+
+    fn peek(&self, register: u16) -> u8 {
+        match register & 1 {
+            0 if self.control & CR_DATA_SELECT != 0 => self.data(),
+            0 => self.direction,
+            _ => self.control,
+        }
+    }
+
+    fn read(&mut self, register: u16) -> u8 {
+        let b = self.peek(register);
+        if register & 1 == 0 && self.control & CR_DATA_SELECT != 0 {
+            self.control &= !(CR_IRQ1 | CR_IRQ2);
+        }
+        b
+    }
+
+    // Returns true when the data register was written
+    fn write(&mut self, register: u16, b: u8) -> bool {
+        match register & 1 {
+            0 if self.control & CR_DATA_SELECT != 0 => {
+                self.output = b;
+                return true;
+            }
+            0 => self.direction = b,
+            _ => self.control = (self.control & (CR_IRQ1 | CR_IRQ2)) | (b & !(CR_IRQ1 | CR_IRQ2)),
+        }
+        false
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Pia {
+    a: Port,
+    b: Port,
+    // The last value written to the port B data register that the device
+    // on the other side has not picked up yet
+    port_b_written: Option<u8>,
+}
+
+impl Pia {
+    pub fn new() -> Self {
+        Pia::default()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Pia::default();
+    }
+
+    // Registers are selected by the lowest two bits of the address
+
+    pub fn read(&mut self, register: u16) -> u8 {
+        match register & 0b10 {
+            0 => self.a.read(register),
+            _ => self.b.read(register),
+        }
+    }
+
+    pub fn peek(&self, register: u16) -> u8 {
+        match register & 0b10 {
+            0 => self.a.peek(register),
+            _ => self.b.peek(register),
+        }
+    }
+
+    pub fn write(&mut self, register: u16, b: u8) {
+        match register & 0b10 {
+            0 => {
+                self.a.write(register, b);
+            }
+            _ => {
+                if self.b.write(register, b) {
+                    self.port_b_written = Some(self.b.output);
+                }
+            }
+        }
+    }
+
+    // The device side of the ports
+
+    pub fn set_port_a_input(&mut self, b: u8) {
+        self.a.input = b;
+    }
+
+    pub fn set_port_b_input(&mut self, b: u8) {
+        self.b.input = b;
+    }
+
+    pub fn ca1(&mut self) {
+        self.a.control |= CR_IRQ1;
+    }
+
+    pub fn cb1(&mut self) {
+        self.b.control |= CR_IRQ1;
+    }
+
+    // True while the CPU has not read port A since the last CA1 transition
+    pub fn ca1_pending(&self) -> bool {
+        self.a.control & CR_IRQ1 != 0
+    }
+
+    pub fn take_port_b_output(&mut self) -> Option<u8> {
+        self.port_b_written.take()
+    }
+}
+
+#[cfg(test)]
+mod pia_tests {
+    use super::*;
+
+    #[test]
+    fn test_data_direction_select() {
+        let mut pia = Pia::new();
+        pia.write(2, 0x7f); // CRB bit 2 is clear, so this is DDRB
+        assert_eq!(pia.take_port_b_output(), None);
+        assert_eq!(pia.peek(2), 0x7f);
+
+        pia.write(3, CR_DATA_SELECT);
+        pia.write(2, 0xc1);
+        assert_eq!(pia.take_port_b_output(), Some(0xc1));
+        assert_eq!(pia.take_port_b_output(), None);
+
+        // Bit 7 is an input
+        pia.set_port_b_input(0x80);
+        assert_eq!(pia.peek(2), 0xc1);
+        pia.set_port_b_input(0x00);
+        assert_eq!(pia.peek(2), 0x41);
+    }
+
+    #[test]
+    fn test_ca1_flag() {
+        let mut pia = Pia::new();
+        pia.write(1, 0xa7);
+        assert_eq!(pia.read(1), 0x27);
+
+        pia.set_port_a_input(0xc1);
+        pia.ca1();
+        assert!(pia.ca1_pending());
+        assert_eq!(pia.peek(1), 0xa7);
+
+        // Writing the control register does not touch the flag
+        pia.write(1, 0x27);
+        assert!(pia.ca1_pending());
+
+        // Peeking does not clear it either, reading the data does
+        assert_eq!(pia.peek(0), 0xc1);
+        assert!(pia.ca1_pending());
+        assert_eq!(pia.read(0), 0xc1);
+        assert!(!pia.ca1_pending());
+    }
+}
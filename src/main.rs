@@ -20,21 +20,480 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-mod ewm;
-use ewm::{CPU, CPUError};
+mod terminal;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use rewm::ewm::{
+    load_rom_files, serve_gdb, to_wav, Apple1Bus, Apple1Memory, Apple2Bus, Apple2Model, Assembler, Bus, CPU,
+    CPUError, CharacterRom, Computer, Debugger, Disk, Disk2, Model, Monitor, Ram, Trace, APPLE2_CLOCK,
+    CYCLES_PER_FRAME, DISK2_SLOT,
+};
+
+use terminal::{Terminal, Throttle, KEY_QUIT, KEY_RESET};
+
+const USAGE: &str = "\
+Usage: rewm [command]
+
+Commands:
+  apple1 --rom <file> [--ram 4|8|32]
+      Run an Apple 1 in the terminal. The ROM is the 256 byte Woz Monitor.
+      RAM defaults to 8K. Control-R presses reset, Control-C quits.
+
+  apple2 --rom <file>... [--model plus|iie|enhanced] [--characters <file>]
+         [--disk-rom <file> --disk <file>...] [--monitor color|mono]
+         [--cycles <n>] [--until <address>] [--screenshot <file>] [--wav <file>]
+         [--trace <file> [--trace-range <start>-<end>]]
+      Run an Apple ][ without a display until the given number of cycles
+      have passed or the PC reaches the address, in hex. Without cycles,
+      the address must be reached within a minute of emulated time. Then
+      write the screen to a .png or .ppm file, and what the speaker played
+      to a .wav file. ROMs given more than once are put together in order.
+      The Disk II ROM goes with up to two disks. A trace logs the
+      instructions in the range, all by default, in the format of
+      nestest.log.
+
+  asm <source> [-o <binary>] [--listing <file>] [--symbols <file>] [--model <model>]
+      Assemble a source file. The binary defaults to the source with a .bin
+      extension. Models are 6502, 65c02, rockwell65c02 (the default)
+      and wdc65c02.
+
+  debug [--model <model>] [--load <address> <file>]... [--pc <address>]
+  debug apple1|apple2 <options>
+      Debug a CPU with 64K of RAM, or one of the machines with the options
+      it takes above. Files are loaded at the address, in hex. The PC starts
+      at the reset vector unless given. Type help for the commands.
+
+  gdb [--port <port>] [<debug options>]
+      Like debug, but wait for a debugger that speaks the GDB remote
+      protocol on the port of localhost, 6502 by default, and let it drive.
+
+Without a command, runs the CPU from its reset vector until it traps.";
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => run(),
+        Some("apple1") => apple1(&args[1..]),
+        Some("apple2") => apple2(&args[1..]),
+        Some("asm") => asm(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("gdb") => gdb(&args[1..]),
+        Some("help" | "-h" | "--help") => println!("{}", USAGE),
+        Some(command) => usage(&format!("unknown command {}", command)),
+    }
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("rewm: {}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+// Without a program, the BRK at the reset vector of empty memory goes to
+// $0000, where it finds another BRK, which is a trap like any other.
+
+fn run() {
     let mut cpu = CPU::new();
-    match cpu.run() {
-        Ok(_) => { },
+    cpu.reset();
+    match cpu.run_until_trap() {
+        Ok(pc) => eprintln!("CPU trapped at {}", cpu.disassemble(pc)),
         Err(CPUError::IllegalOpcode) => {
-            eprintln!("CPU Error: illegal opcode {} at {}", cpu.get_byte(cpu.pc), cpu.pc);
-            std::process::exit(1);
+            eprintln!("CPU Error: illegal opcode at {}", cpu.disassemble(cpu.pc));
+            exit(1);
+        }
+    }
+}
+
+fn fail(error: impl fmt::Display) -> ! {
+    eprintln!("rewm: {}", error);
+    exit(1);
+}
+
+fn read_rom(paths: &[PathBuf]) -> Vec<u8> {
+    load_rom_files(paths).unwrap_or_else(|error| {
+        eprintln!("rewm: cannot read ROM: {}", error);
+        exit(1);
+    })
+}
+
+fn illegal_opcode<B: Bus>(computer: &Computer<B>) -> ! {
+    eprintln!("CPU Error: illegal opcode at {}", computer.cpu.disassemble(computer.cpu.pc));
+    exit(1);
+}
+
+const APPLE1_CLOCK: u64 = 1_022_727;
+const FRAMES_PER_SECOND: u32 = 60;
+
+fn apple1_computer(args: &[String]) -> Computer<Apple1Bus> {
+    let mut rom = None;
+    let mut memory = Apple1Memory::Ram8K;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage(&format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "--rom" => rom = Some(PathBuf::from(value())),
+            "--ram" => memory = match value().as_str() {
+                "4" => Apple1Memory::Ram4K,
+                "8" => Apple1Memory::Ram8K,
+                "32" => Apple1Memory::Ram32K,
+                other => usage(&format!("unsupported amount of RAM {}", other)),
+            },
+            _ => usage(&format!("unexpected argument {}", arg)),
+        }
+    }
+    let Some(rom) = rom else {
+        usage("apple1 needs the Woz Monitor ROM");
+    };
+
+    Computer::apple1(memory, read_rom(std::slice::from_ref(&rom))).unwrap_or_else(|error| {
+        eprintln!("rewm: {}: {}", rom.display(), error);
+        exit(1);
+    })
+}
+
+fn apple1(args: &[String]) {
+    let mut apple1 = apple1_computer(args);
+
+    let mut terminal = Terminal::new();
+    let mut throttle = Throttle::new(FRAMES_PER_SECOND);
+    let mut keys = VecDeque::new();
+    // Once stdin is closed we keep running for a second to show the output
+    let mut frames_left = FRAMES_PER_SECOND;
+
+    loop {
+        while let Some(key) = terminal.key() {
+            match key {
+                KEY_QUIT => return,
+                KEY_RESET => apple1.reset(),
+                key => keys.push_back(key),
+            }
+        }
+
+        // The keyboard holds one key, so one key per frame is all we feed it
+        if apple1.keyboard_ready() {
+            if let Some(key) = keys.pop_front() {
+                apple1.press_key(key);
+            }
+        }
+
+        if apple1.run_cycles(APPLE1_CLOCK / FRAMES_PER_SECOND as u64).is_err() {
+            drop(terminal);
+            illegal_opcode(&apple1);
+        }
+
+        let output: String = apple1.take_output().iter().filter_map(|&c| match c {
+            b'\r' => Some('\n'),
+            0x20..=0x7e => Some(c as char),
+            _ => None,
+        }).collect();
+        if !output.is_empty() {
+            print!("{}", output);
+            io::stdout().flush().ok();
+        }
+
+        if terminal.closed() && keys.is_empty() && apple1.keyboard_ready() {
+            frames_left -= 1;
+            if frames_left == 0 {
+                break;
+            }
+        }
+        throttle.wait();
+    }
+}
+
+fn parse_address(text: &str) -> u16 {
+    let hex = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).unwrap_or_else(|_| usage(&format!("invalid address {}", text)))
+}
+
+// What to run the Apple ][ for, when it is not in the debugger
+#[derive(Debug, Default, PartialEq)]
+struct Apple2Run {
+    cycles: Option<u64>,
+    until: Option<u16>,
+    screenshot: Option<PathBuf>,
+    wav: Option<PathBuf>,
+    trace: Option<PathBuf>,
+    trace_range: Option<(u16, u16)>,
+}
+
+fn apple2_computer(args: &[String]) -> (Computer<Apple2Bus>, Apple2Run) {
+    let mut roms = Vec::new();
+    let mut model = Apple2Model::Plus;
+    let mut characters = None;
+    let mut disk_rom = None;
+    let mut disks = Vec::new();
+    let mut monitor = Monitor::Color;
+    let mut run = Apple2Run::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage(&format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "--rom" => roms.push(PathBuf::from(value())),
+            "--model" => model = match value().as_str() {
+                "plus" => Apple2Model::Plus,
+                "iie" => Apple2Model::IIe,
+                "enhanced" => Apple2Model::EnhancedIIe,
+                other => usage(&format!("unknown model {}", other)),
+            },
+            "--characters" => characters = Some(PathBuf::from(value())),
+            "--disk-rom" => disk_rom = Some(PathBuf::from(value())),
+            "--disk" => disks.push(PathBuf::from(value())),
+            "--monitor" => monitor = match value().as_str() {
+                "color" => Monitor::Color,
+                "mono" => Monitor::Monochrome,
+                other => usage(&format!("unknown monitor {}", other)),
+            },
+            "--cycles" => {
+                let text = value();
+                run.cycles = Some(text.parse::<u64>().unwrap_or_else(|_| usage(&format!("invalid number of cycles {}", text))));
+            }
+            "--until" => run.until = Some(parse_address(&value())),
+            "--screenshot" => run.screenshot = Some(PathBuf::from(value())),
+            "--wav" => run.wav = Some(PathBuf::from(value())),
+            "--trace" => run.trace = Some(PathBuf::from(value())),
+            "--trace-range" => {
+                let text = value();
+                let Some((start, end)) = text.split_once('-') else {
+                    usage(&format!("invalid range {}", text));
+                };
+                run.trace_range = Some((parse_address(start), parse_address(end)));
+            }
+            _ => usage(&format!("unexpected argument {}", arg)),
+        }
+    }
+    if roms.is_empty() {
+        usage("apple2 needs the ROM");
+    }
+    if disks.len() > 2 || (!disks.is_empty() && disk_rom.is_none()) {
+        usage("up to two disks go with the Disk II ROM");
+    }
+
+    let mut apple2 = Computer::apple2(model, read_rom(&roms)).unwrap_or_else(|error| fail(error));
+    apple2.cpu.bus.monitor = monitor;
+    if let Some(path) = characters {
+        let rom = CharacterRom::new(read_rom(std::slice::from_ref(&path))).unwrap_or_else(|error| fail(format!("{}: {}", path.display(), error)));
+        apple2.cpu.bus.characters = rom;
+    }
+    if let Some(path) = disk_rom {
+        let disk2 = Disk2::new(read_rom(std::slice::from_ref(&path))).unwrap_or_else(|error| fail(format!("{}: {}", path.display(), error)));
+        apple2.cpu.bus.insert_card(DISK2_SLOT, Box::new(disk2));
+        for (drive, path) in disks.iter().enumerate() {
+            let disk = Disk::open(path).unwrap_or_else(|error| fail(error));
+            apple2.disk2().unwrap().insert_disk(drive, disk).unwrap_or_else(|error| fail(error));
+        }
+    }
+    (apple2, run)
+}
+
+// Without --cycles, --until gives up after a minute
+const UNTIL_CYCLES: u64 = 60 * APPLE2_CLOCK;
+
+fn apple2(args: &[String]) {
+    let (mut apple2, Apple2Run { cycles, until, screenshot, wav, trace, trace_range }) = apple2_computer(args);
+    if cycles.is_none() && until.is_none() {
+        usage("apple2 needs --cycles or --until to know when to stop");
+    }
+    if let Some(path) = &trace {
+        let trace = Trace::to_file(path).unwrap_or_else(|error| fail(format!("{}: {}", path.display(), error)));
+        let (start, end) = trace_range.unwrap_or((0x0000, 0xffff));
+        apple2.cpu.trace = Some(trace.with_range(start, end));
+    } else if trace_range.is_some() {
+        usage("--trace-range goes with --trace");
+    }
+
+    let end = apple2.cpu.cycles + cycles.unwrap_or(UNTIL_CYCLES);
+    let mut samples = Vec::new();
+    let mut frame_end = apple2.cpu.cycles + CYCLES_PER_FRAME;
+    while apple2.cpu.cycles < end && Some(apple2.cpu.pc) != until {
+        if apple2.cpu.step().is_err() {
+            illegal_opcode(&apple2);
+        }
+        // The speaker drops samples that are not taken for a while
+        if apple2.cpu.cycles >= frame_end {
+            samples.extend(apple2.cpu.bus.speaker.take_samples());
+            frame_end += CYCLES_PER_FRAME;
+        }
+    }
+    samples.extend(apple2.cpu.bus.speaker.take_samples());
+
+    if let (Some(path), Some(mut trace)) = (trace, apple2.cpu.trace.take()) {
+        let flushed = trace.flush();
+        if let Some(error) = trace.error() {
+            fail(format!("{}: {}", path.display(), error));
+        }
+        flushed.unwrap_or_else(|error| fail(format!("{}: {}", path.display(), error)));
+    }
+    flush_disks(&mut apple2);
+    if let (None, Some(until)) = (cycles, until) {
+        if apple2.cpu.pc != until {
+            fail(format!("the PC did not reach ${:04X} in {} cycles, use --cycles to run longer", until, UNTIL_CYCLES));
+        }
+    }
+    if let Some(path) = screenshot {
+        apple2.screenshot(&path).unwrap_or_else(|error| fail(error));
+    }
+    if let Some(path) = wav {
+        write(&path, &to_wav(&samples));
+    }
+}
+
+fn flush_disks(apple2: &mut Computer<Apple2Bus>) {
+    if let Some(disk2) = apple2.disk2() {
+        disk2.flush().unwrap_or_else(|error| fail(error));
+    }
+}
+
+fn debug(args: &[String]) {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let mut debugger = Debugger::new();
+    let result = match args.first().map(String::as_str) {
+        Some("apple1") => debugger.repl(&mut apple1_computer(&args[1..]).cpu, &mut input, &mut output),
+        Some("apple2") => {
+            let mut apple2 = debug_apple2(&args[1..]);
+            let result = debugger.repl(&mut apple2.cpu, &mut input, &mut output);
+            flush_disks(&mut apple2);
+            result
+        }
+        _ => debugger.repl(&mut cpu(args), &mut input, &mut output),
+    };
+    result.unwrap_or_else(|error| fail(error));
+}
+
+fn debug_apple2(args: &[String]) -> Computer<Apple2Bus> {
+    let (apple2, run) = apple2_computer(args);
+    if run != Apple2Run::default() {
+        usage("the debugger runs the Apple ][ for as long as you want");
+    }
+    apple2
+}
+
+// 6502, what else
+const GDB_PORT: u16 = 6502;
+
+fn gdb(args: &[String]) {
+    let (port, args) = match args {
+        [option, port, args @ ..] if option == "--port" => {
+            (port.parse::<u16>().unwrap_or_else(|_| usage(&format!("invalid port {}", port))), args)
         }
-        Err(CPUError::Break) => {
-            eprintln!("CPU Error: break at {}", cpu.pc);
-            std::process::exit(1);
+        _ => (GDB_PORT, args),
+    };
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|error| fail(format!("port {}: {}", port, error)));
+    eprintln!("rewm: waiting for GDB on port {}", port);
+    let result = match args.first().map(String::as_str) {
+        Some("apple1") => serve_gdb(&mut apple1_computer(&args[1..]).cpu, &listener),
+        Some("apple2") => {
+            let mut apple2 = debug_apple2(&args[1..]);
+            let result = serve_gdb(&mut apple2.cpu, &listener);
+            flush_disks(&mut apple2);
+            result
         }
+        _ => serve_gdb(&mut cpu(args), &listener),
     };
+    result.unwrap_or_else(|error| fail(error));
+}
+
+// A bare CPU with 64K of RAM
+fn cpu(args: &[String]) -> CPU {
+    let mut model = Model::Nmos6502;
+    let mut files = Vec::new();
+    let mut pc = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage(&format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "--model" => model = parse_model(&value()),
+            "--load" => {
+                let address = parse_address(&value());
+                files.push((address, PathBuf::from(value())));
+            }
+            "--pc" => pc = Some(parse_address(&value())),
+            _ => usage(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let mut cpu = CPU::with_model(Ram::new(), model);
+    for (address, path) in files {
+        let bytes = fs::read(&path).unwrap_or_else(|error| fail(format!("{}: {}", path.display(), error)));
+        if address as usize + bytes.len() > 0x10000 {
+            fail(format!("{} does not fit at ${:04X}", path.display(), address));
+        }
+        cpu.load(address, bytes);
+    }
+    match pc {
+        Some(pc) => cpu.pc = pc,
+        None => cpu.reset(),
+    }
+    cpu
+}
+
+fn parse_model(name: &str) -> Model {
+    match name.to_ascii_lowercase().as_str() {
+        "6502" => Model::Nmos6502,
+        "65c02" => Model::Cmos65C02,
+        "rockwell65c02" => Model::Rockwell65C02,
+        "wdc65c02" => Model::Wdc65C02,
+        _ => usage(&format!("unknown model {}", name)),
+    }
 }
 
+fn asm(args: &[String]) {
+    let mut source = None;
+    let mut output = None;
+    let mut listing = None;
+    let mut symbols = None;
+    let mut model = Model::Rockwell65C02;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage(&format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(PathBuf::from(value())),
+            "--listing" => listing = Some(PathBuf::from(value())),
+            "--symbols" => symbols = Some(PathBuf::from(value())),
+            "--model" => model = parse_model(&value()),
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(PathBuf::from(arg)),
+            _ => usage(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let Some(source) = source else {
+        usage("asm needs a source file");
+    };
+    let output = output.unwrap_or_else(|| source.with_extension("bin"));
+
+    let assembly = match Assembler::new(model).assemble_file(&source) {
+        Ok(assembly) => assembly,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit(1);
+        }
+    };
+
+    write(&output, &assembly.bytes);
+    if let Some(listing) = listing {
+        write(&listing, assembly.listing_text().as_bytes());
+    }
+    if let Some(symbols) = symbols {
+        write(&symbols, assembly.symbols_text().as_bytes());
+    }
+}
+
+fn write(path: &Path, contents: &[u8]) {
+    if let Err(error) = fs::write(path, contents) {
+        eprintln!("rewm: cannot write {}: {}", path.display(), error);
+        exit(1);
+    }
+}
@@ -0,0 +1,125 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2022 Stefan Arentz - http://github.com/st3fan/rewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Terminal helpers for the machines that run in a terminal. Keys are read
+// from stdin on a separate thread. When stdin is a terminal, it is switched
+// to unbuffered input without echo with stty, so that the emulated machine
+// sees every key press and does its own echoing.
+
+use std::io::{self, IsTerminal, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The keys that control the emulator instead of the emulated machine
+pub const KEY_QUIT: u8 = 0x03;  // Control-C
+pub const KEY_RESET: u8 = 0x12; // Control-R
+
+pub struct Terminal {
+    keys: Receiver<u8>,
+    saved_settings: Option<String>,
+    closed: bool,
+}
+
+impl Terminal {
+    pub fn new() -> Self {
+        let saved_settings = if io::stdin().is_terminal() { raw_mode() } else { None };
+
+        let (sender, keys) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin().lock();
+            let mut buffer = [0u8; 256];
+            while let Ok(n) = stdin.read(&mut buffer) {
+                if n == 0 || buffer[..n].iter().any(|&b| sender.send(b).is_err()) {
+                    break;
+                }
+            }
+        });
+
+        Terminal { keys, saved_settings, closed: false }
+    }
+
+    // The next key, if one was typed. Once stdin is closed, closed() is true.
+    pub fn key(&mut self) -> Option<u8> {
+        match self.keys.try_recv() {
+            Ok(key) => Some(key),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                None
+            }
+        }
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if let Some(settings) = &self.saved_settings {
+            stty(&[settings]);
+        }
+    }
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn raw_mode() -> Option<String> {
+    let saved = stty(&["-g"])?;
+    stty(&["-icanon", "-echo", "-isig", "min", "1"])?;
+    Some(saved)
+}
+
+// Keeps the emulation running at the speed of the real machine by sleeping
+// away what is left of every frame.
+
+pub struct Throttle {
+    frame: Duration,
+    next: Instant,
+}
+
+impl Throttle {
+    pub fn new(frames_per_second: u32) -> Self {
+        let frame = Duration::from_secs(1) / frames_per_second;
+        Throttle { frame, next: Instant::now() + frame }
+    }
+
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+            self.next += self.frame;
+        } else {
+            // We are behind, do not try to catch up
+            self.next = now + self.frame;
+        }
+    }
+}
@@ -0,0 +1,20 @@
+#!/bin/sh
+#
+# Replaces the vectors in this directory with the first ones of every opcode
+# from a checkout of https://github.com/SingleStepTests/ProcessorTests:
+#
+#   tests/fixtures/processor_tests/update.sh /path/to/ProcessorTests [count]
+
+set -e
+
+upstream=${1:?usage: $0 <ProcessorTests checkout> [count]}
+count=${2:-20}
+cd "$(dirname "$0")"
+
+for model in 6502 synertek65c02 rockwell65c02 wdc65c02; do
+    rm -f "$model"/*.json
+    mkdir -p "$model"
+    for file in "$upstream/$model/v1"/*.json; do
+        jq -c ".[:$count]" "$file" > "$model/$(basename "$file")"
+    done
+done
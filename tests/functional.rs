@@ -0,0 +1,98 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2022 Stefan Arentz - http://github.com/st3fan/rewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Runs Klaus Dormann's 6502 functional and decimal test suites, which can be
+// found at https://github.com/Klaus2m5/6502_65C02_functional_tests. The
+// binaries are not part of this repository, see tests/fixtures/README.md for
+// how to build them. The tests are ignored by default, run them with
+// `cargo test --test functional -- --ignored` once the binaries are there.
+
+use std::fs;
+use std::path::PathBuf;
+
+use rewm::ewm::{CPU, CPUError};
+
+// Addresses for the binaries as built with the default settings of the
+// test sources.
+
+const FUNCTIONAL_TEST: &str = "6502_functional_test.bin";
+const FUNCTIONAL_TEST_START: u16 = 0x0400;
+const FUNCTIONAL_TEST_SUCCESS: u16 = 0x3469;
+const FUNCTIONAL_TEST_CASE: u16 = 0x0200;
+
+const DECIMAL_TEST: &str = "6502_decimal_test.bin";
+const DECIMAL_TEST_START: u16 = 0x0200;
+const DECIMAL_TEST_ERROR: u16 = 0x000b;
+
+// The decimal test ends with a 65C02 STP instruction, which we do not know
+// on the NMOS 6502, so we stop when we reach one.
+const STP: u8 = 0xdb;
+
+fn load_fixture(name: &str) -> CPU {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name].iter().collect();
+    let image = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}, see tests/fixtures/README.md", path.display(), e));
+    assert_eq!(image.len(), 64 * 1024, "{} must be a 64K memory image", name);
+
+    let mut cpu = CPU::new();
+    cpu.load(0x0000, image);
+    cpu
+}
+
+#[test]
+#[ignore = "needs tests/fixtures/6502_functional_test.bin"]
+fn test_functional() {
+    let mut cpu = load_fixture(FUNCTIONAL_TEST);
+
+    cpu.pc = FUNCTIONAL_TEST_START;
+    let trap = match cpu.run_until_trap() {
+        Ok(trap) => trap,
+        Err(CPUError::IllegalOpcode) => panic!(
+            "Illegal opcode ${:02X} at ${:04X} in test case ${:02X}",
+            cpu.peek_byte(cpu.pc), cpu.pc, cpu.peek_byte(FUNCTIONAL_TEST_CASE)
+        ),
+    };
+
+    assert_eq!(
+        trap, FUNCTIONAL_TEST_SUCCESS,
+        "Trapped at ${:04X} in test case ${:02X}", trap, cpu.peek_byte(FUNCTIONAL_TEST_CASE)
+    );
+}
+
+#[test]
+#[ignore = "needs tests/fixtures/6502_decimal_test.bin"]
+fn test_decimal() {
+    let mut cpu = load_fixture(DECIMAL_TEST);
+
+    cpu.pc = DECIMAL_TEST_START;
+    loop {
+        let pc = cpu.pc;
+        if cpu.peek_byte(pc) == STP {
+            break;
+        }
+        cpu.step().unwrap_or_else(|_| panic!("Illegal opcode ${:02X} at ${:04X}", cpu.peek_byte(pc), pc));
+        if cpu.pc == pc {
+            break;
+        }
+    }
+
+    assert_eq!(cpu.peek_byte(DECIMAL_TEST_ERROR), 0, "Decimal test failed at ${:04X}", cpu.pc);
+}
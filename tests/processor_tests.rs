@@ -0,0 +1,409 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2022 Stefan Arentz - http://github.com/st3fan/rewm
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Single-step conformance tests in the format of Tom Harte's ProcessorTests,
+// https://github.com/SingleStepTests/ProcessorTests. Every opcode has a file
+// with test vectors: the registers and memory before and after executing one
+// instruction, and the address, value and direction of every bus cycle in
+// between.
+//
+// The vectors live in tests/fixtures/processor_tests/<model>/<opcode>.json.
+// The full upstream set is run from a checkout of the repository when
+// PROCESSOR_TESTS points at it.
+//
+// Nothing is skipped quietly: opcodes that are not checked are listed below,
+// and any other opcode the model does not know fails the test. The 65C02
+// models do the dummy reads of the NMOS 6502, so for them only the number of
+// bus cycles is compared, test_65c02_bus_cycles compares them all.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rewm::ewm::{Bus, CPU, CPUError, Model};
+
+// The B flag and bit 5 do not exist in the status register, they only show
+// up when it is pushed on the stack.
+const STATUS_MASK: u8 = 0b11001111;
+
+// The unstable undocumented NMOS opcodes (ANE, SHA, TAS, SHY, SHX, LXA, LAS)
+// are not implemented, and JAM halts the CPU without the bus cycles that the
+// vectors record.
+const NMOS_UNCHECKED: [u8; 20] = [
+    0x8b, 0x93, 0x9b, 0x9c, 0x9e, 0x9f, 0xab, 0xbb,
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xb2, 0xd2, 0xf2,
+];
+
+// Just enough JSON to read the test vectors: no escapes in strings, no
+// fractions or exponents in numbers.
+
+#[derive(Debug)]
+enum Json {
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { bytes: text.as_bytes(), pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(format!("Trailing characters at offset {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+                .unwrap_or_else(|| panic!("Missing key {}", key)),
+            _ => panic!("Not an object when looking up {}", key),
+        }
+    }
+
+    fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(elements) => elements,
+            _ => panic!("Not an array: {:?}", self),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Json::String(s) => s,
+            _ => panic!("Not a string: {:?}", self),
+        }
+    }
+
+    fn as_u8(&self) -> u8 {
+        match self {
+            Json::Number(n) => u8::try_from(*n).unwrap_or_else(|_| panic!("Not a byte: {}", n)),
+            _ => panic!("Not a number: {:?}", self),
+        }
+    }
+
+    fn as_u16(&self) -> u16 {
+        match self {
+            Json::Number(n) => u16::try_from(*n).unwrap_or_else(|_| panic!("Not a word: {}", n)),
+            _ => panic!("Not a number: {:?}", self),
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(format!("Expected '{}' at offset {}", c as char, self.pos));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'[') => {
+                self.pos += 1;
+                let mut elements = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => { self.pos += 1; return Ok(Json::Array(elements)); }
+                        _ => return Err(format!("Expected ',' or ']' at offset {}", self.pos)),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    let key = match self.value()? {
+                        Json::String(key) => key,
+                        _ => return Err(format!("Expected a key at offset {}", self.pos)),
+                    };
+                    self.expect(b':')?;
+                    members.push((key, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => { self.pos += 1; return Ok(Json::Object(members)); }
+                        _ => return Err(format!("Expected ',' or '}}' at offset {}", self.pos)),
+                    }
+                }
+            }
+            Some(b'"') => {
+                self.pos += 1;
+                let start = self.pos;
+                while self.pos < self.bytes.len() && self.bytes[self.pos] != b'"' {
+                    self.pos += 1;
+                }
+                let s = String::from_utf8_lossy(&self.bytes[start..self.pos]).into_owned();
+                self.expect(b'"')?;
+                Ok(Json::String(s))
+            }
+            Some(c) if c == b'-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                self.pos += 1;
+                while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_digit() {
+                    self.pos += 1;
+                }
+                let s = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+                s.parse().map(Json::Number).map_err(|e| format!("Bad number {}: {}", s, e))
+            }
+            _ => Err(format!("Unexpected character at offset {}", self.pos)),
+        }
+    }
+}
+
+// A flat 64K of memory that records every bus cycle.
+
+#[derive(Debug, PartialEq)]
+enum Access {
+    Read,
+    Write,
+}
+
+struct TestBus {
+    bytes: Vec<u8>,
+    cycles: Vec<(u16, u8, Access)>,
+}
+
+impl Bus for TestBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let b = self.bytes[addr as usize];
+        self.cycles.push((addr, b, Access::Read));
+        b
+    }
+
+    fn write(&mut self, addr: u16, b: u8) {
+        self.bytes[addr as usize] = b;
+        self.cycles.push((addr, b, Access::Write));
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.bytes[addr as usize]
+    }
+}
+
+enum Outcome {
+    Passed,
+    Unimplemented,
+    Failed(String),
+}
+
+fn run_test(model: Model, test: &Json, bus_cycles: bool) -> Outcome {
+    let initial = test.get("initial");
+    let mut cpu = CPU::with_model(TestBus { bytes: vec![0; 64 * 1024], cycles: Vec::new() }, model);
+    cpu.undocumented_opcodes = true;
+    cpu.pc = initial.get("pc").as_u16();
+    cpu.sp = initial.get("s").as_u8();
+    cpu.a = initial.get("a").as_u8();
+    cpu.x = initial.get("x").as_u8();
+    cpu.y = initial.get("y").as_u8();
+    cpu.set_status(initial.get("p").as_u8());
+    for entry in initial.get("ram").as_array() {
+        let entry = entry.as_array();
+        cpu.bus.bytes[entry[0].as_u16() as usize] = entry[1].as_u8();
+    }
+
+    match cpu.step() {
+        Ok(_) => {}
+        Err(CPUError::IllegalOpcode) => return Outcome::Unimplemented,
+    }
+
+    let mut errors = String::new();
+    let expected = test.get("final");
+    for (name, actual, expected) in [
+        ("pc", cpu.pc, expected.get("pc").as_u16()),
+        ("s", cpu.sp as u16, expected.get("s").as_u8() as u16),
+        ("a", cpu.a as u16, expected.get("a").as_u8() as u16),
+        ("x", cpu.x as u16, expected.get("x").as_u8() as u16),
+        ("y", cpu.y as u16, expected.get("y").as_u8() as u16),
+    ] {
+        if actual != expected {
+            writeln!(errors, "  {}: expected ${:02X} got ${:02X}", name, expected, actual).unwrap();
+        }
+    }
+
+    let status = cpu.get_status() & STATUS_MASK;
+    let expected_status = expected.get("p").as_u8() & STATUS_MASK;
+    if status != expected_status {
+        writeln!(errors, "  p: expected {:08b} got {:08b} (NV--DIZC)", expected_status, status).unwrap();
+    }
+
+    for entry in expected.get("ram").as_array() {
+        let entry = entry.as_array();
+        let (addr, b) = (entry[0].as_u16(), entry[1].as_u8());
+        if cpu.bus.peek(addr) != b {
+            writeln!(errors, "  ${:04X}: expected ${:02X} got ${:02X}", addr, b, cpu.bus.peek(addr)).unwrap();
+        }
+    }
+
+    let expected_cycles: Vec<(u16, u8, Access)> = test.get("cycles").as_array().iter().map(|cycle| {
+        let cycle = cycle.as_array();
+        let access = match cycle[2].as_str() {
+            "read" => Access::Read,
+            "write" => Access::Write,
+            other => panic!("Unknown bus access {}", other),
+        };
+        (cycle[0].as_u16(), cycle[1].as_u8(), access)
+    }).collect();
+    if bus_cycles && cpu.bus.cycles != expected_cycles {
+        writeln!(errors, "  bus cycles:").unwrap();
+        for i in 0..expected_cycles.len().max(cpu.bus.cycles.len()) {
+            let expected = expected_cycles.get(i).map_or("-".to_string(), |c| format!("${:04X} ${:02X} {:?}", c.0, c.1, c.2));
+            let actual = cpu.bus.cycles.get(i).map_or("-".to_string(), |c| format!("${:04X} ${:02X} {:?}", c.0, c.1, c.2));
+            let marker = if expected == actual { " " } else { "*" };
+            writeln!(errors, "   {} expected {:<18} got {}", marker, expected, actual).unwrap();
+        }
+    }
+
+    if cpu.cycles != expected_cycles.len() as u64 {
+        writeln!(errors, "  cycles: expected {} got {}", expected_cycles.len(), cpu.cycles).unwrap();
+    }
+
+    if errors.is_empty() {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(errors)
+    }
+}
+
+// Runs the vectors in a directory, skipping the files of the unchecked
+// opcodes. Returns the number of vectors that passed.
+
+fn run_tests(path: &Path, model: Model, bus_cycles: bool, unchecked: &[u8]) -> usize {
+    let mut files: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "No test vectors in {}", path.display());
+
+    let mut passed = 0;
+    let mut failures = String::new();
+    for file in &files {
+        let opcode = file.file_stem().and_then(|stem| u8::from_str_radix(&stem.to_string_lossy(), 16).ok());
+        if opcode.is_some_and(|opcode| unchecked.contains(&opcode)) {
+            continue;
+        }
+        let tests = load_tests(file);
+        for test in tests.as_array() {
+            let errors = match run_test(model, test, bus_cycles) {
+                Outcome::Passed => {
+                    passed += 1;
+                    continue;
+                }
+                Outcome::Unimplemented => "  not implemented\n".to_string(),
+                Outcome::Failed(errors) => errors,
+            };
+            writeln!(failures, "{} [{}]:\n{}", file.file_name().unwrap().to_string_lossy(),
+                     test.get("name").as_str(), errors).unwrap();
+        }
+    }
+
+    assert!(failures.is_empty(), "{:?}: {} tests passed, failures:\n{}", model, passed, failures);
+    passed
+}
+
+fn fixtures(directory: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "processor_tests", directory].iter().collect()
+}
+
+fn load_tests(file: &Path) -> Json {
+    let text = fs::read_to_string(file).unwrap_or_else(|e| panic!("Cannot read {}: {}", file.display(), e));
+    Json::parse(&text).unwrap_or_else(|e| panic!("Cannot parse {}: {}", file.display(), e))
+}
+
+#[test]
+fn test_nmos6502() {
+    run_tests(&fixtures("6502"), Model::Nmos6502, true, &NMOS_UNCHECKED);
+}
+
+#[test]
+fn test_cmos65c02() {
+    run_tests(&fixtures("synertek65c02"), Model::Cmos65C02, false, &[]);
+}
+
+#[test]
+fn test_rockwell65c02() {
+    run_tests(&fixtures("rockwell65c02"), Model::Rockwell65C02, false, &[]);
+}
+
+#[test]
+fn test_wdc65c02() {
+    run_tests(&fixtures("wdc65c02"), Model::Wdc65C02, false, &[]);
+}
+
+#[test]
+#[ignore = "the 65C02 models do the dummy reads of the NMOS 6502"]
+fn test_65c02_bus_cycles() {
+    run_tests(&fixtures("synertek65c02"), Model::Cmos65C02, true, &[]);
+    run_tests(&fixtures("rockwell65c02"), Model::Rockwell65C02, true, &[]);
+    run_tests(&fixtures("wdc65c02"), Model::Wdc65C02, true, &[]);
+}
+
+// The upstream vectors are in <model>/v1 of the repository, with 10,000 of
+// them per opcode.
+
+#[test]
+#[ignore = "needs a ProcessorTests checkout in PROCESSOR_TESTS"]
+fn test_upstream() {
+    let root = PathBuf::from(env::var_os("PROCESSOR_TESTS").expect("PROCESSOR_TESTS is not set"));
+    for (directory, model, bus_cycles, unchecked) in [
+        ("6502", Model::Nmos6502, true, &NMOS_UNCHECKED[..]),
+        ("synertek65c02", Model::Cmos65C02, false, &[]),
+        ("rockwell65c02", Model::Rockwell65C02, false, &[]),
+        ("wdc65c02", Model::Wdc65C02, false, &[]),
+    ] {
+        let passed = run_tests(&root.join(directory).join("v1"), model, bus_cycles, unchecked);
+        println!("{:?}: {} tests passed", model, passed);
+    }
+}